@@ -50,13 +50,14 @@ pub enum GreatError {
     InterruptedSystemCall = 4,    // EINTR    - Interrupted system call
     ArgumentListTooLong = 7,      // E2BIG    - Arg list too long
     BadAddress = 14,              // EFAULT   - Bad address
-    DeviceOrResourceBusy = 15,    // EBUSY    - Device or resource busy
+    DeviceOrResourceBusy = 16,    // EBUSY    - Device or resource busy
     InvalidArgument = 22,         // EINVAL   - Invalid argument
     NoSpaceLeftOnDevice = 28,     // ENOSPC   - No space left on device
     NoMessageOfType = 35,         // ENOMSG   - No message of desired type
     NoData = 61,                  // ENODATA  - No data
     BadMessage = 77,              // EBADMSG  - Bad message
     NoBufferSpaceAvailable = 105, // ENOBUFS - No buffer space available
+    OperationNowInProgress = 119, // EINPROGRESS - Operation now in progress
 
     // TODO consider just using libgreat/errno.h errors above
     GcpClassNotFound(crate::gcp::class::ClassId) = 0xf000,
@@ -70,6 +71,52 @@ pub enum GreatError {
     DynError(&'static (dyn core::error::Error)),
 }
 
+impl GreatError {
+    /// Errors that have an equivalent in libgreat/errno.h
+    const ERRNO_VARIANTS: [GreatError; 13] = [
+        GreatError::NotOwner,
+        GreatError::NoSuchFileOrDirectory,
+        GreatError::InterruptedSystemCall,
+        GreatError::ArgumentListTooLong,
+        GreatError::BadAddress,
+        GreatError::DeviceOrResourceBusy,
+        GreatError::InvalidArgument,
+        GreatError::NoSpaceLeftOnDevice,
+        GreatError::NoMessageOfType,
+        GreatError::NoData,
+        GreatError::BadMessage,
+        GreatError::NoBufferSpaceAvailable,
+        GreatError::OperationNowInProgress,
+    ];
+
+    /// Returns the `#[repr(u32)]` discriminant of this error.
+    fn discriminant(&self) -> u32 {
+        // SAFETY: a `#[repr(u32)]` enum is laid out as a `#[repr(C)]`
+        // union of `#[repr(C)]` structs that all start with the `u32`
+        // discriminant.
+        unsafe { *(self as *const Self as *const u32) }
+    }
+
+    /// Returns the errno reported to the host for this error.
+    ///
+    /// Errors without an equivalent in libgreat/errno.h are reported
+    /// as `EINVAL`.
+    pub fn errno(&self) -> u32 {
+        match self.discriminant() {
+            errno if errno < 0xf000 => errno,
+            _ => GreatError::InvalidArgument.discriminant(),
+        }
+    }
+
+    /// Returns the error for an errno reported by a device.
+    pub fn from_errno(errno: u32) -> Option<Self> {
+        Self::ERRNO_VARIANTS
+            .iter()
+            .find(|error| error.discriminant() == errno)
+            .copied()
+    }
+}
+
 // impl<'a> From<&'a GreatError> for &'a dyn core::error::Error {
 //     fn from(error: &'a GreatError) -> Self {
 //         error
@@ -107,6 +154,8 @@ impl core::fmt::Display for GreatError {
 #[cfg(test)]
 mod tests {
     //use super::*;
+    use super::GreatError;
+    use crate::gcp::ClassId;
 
     // - fixtures -------------------------------------------------------------
 
@@ -216,6 +265,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_errno() {
+        assert_eq!(GreatError::InvalidArgument.errno(), 22);
+        assert_eq!(GreatError::BadMessage.errno(), 77);
+        assert_eq!(GreatError::DeviceOrResourceBusy.errno(), 16);
+        assert_eq!(GreatError::OperationNowInProgress.errno(), 119);
+        assert_eq!(GreatError::GcpVerbNotFound(ClassId::core, 1).errno(), 22);
+        assert_eq!(GreatError::GcpUnknownVerbDescriptor(7).errno(), 22);
+        assert_eq!(GreatError::Message("oops").errno(), 22);
+
+        for errno in 0..128 {
+            if let Some(error) = GreatError::from_errno(errno) {
+                assert_eq!(error.errno(), errno);
+            }
+        }
+        for error in GreatError::ERRNO_VARIANTS {
+            assert_eq!(
+                GreatError::from_errno(error.errno()).unwrap().errno(),
+                error.errno()
+            );
+        }
+        assert!(matches!(
+            GreatError::from_errno(105),
+            Some(GreatError::NoBufferSpaceAvailable)
+        ));
+        assert!(GreatError::from_errno(0).is_none());
+        assert!(GreatError::from_errno(0xdeaddead).is_none());
+    }
+
     // #[test]
     // fn test_great_error_trait() {
     //     match result_custom(31) {
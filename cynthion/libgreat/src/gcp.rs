@@ -1,9 +1,20 @@
 #![allow(dead_code, unused_imports, unused_variables)] // TODO
 
 ///! Great Communications Protocol
+#[macro_use]
+mod macros;
+pub mod assembler;
+pub mod buffers;
+pub mod bulk;
 pub mod class;
 pub mod class_core;
+pub mod response;
+pub mod serial;
+pub mod signature;
+pub use assembler::*;
+pub use buffers::*;
 pub use class::*;
+pub use response::*;
 
 use zerocopy::{
     AsBytes, BigEndian, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified, LittleEndian,
@@ -55,46 +66,13 @@ where
     }
 }
 
-// - helpers ------------------------------------------------------------------
-
-// TODO get rid of this
-pub const GCP_MAX_RESPONSE_LENGTH: usize = 512;
-
-pub type GcpResponse<'a> = core::iter::Take<core::array::IntoIter<u8, GCP_MAX_RESPONSE_LENGTH>>;
-//type GcpResponse<'a> = iter::Take<core::slice::IterMut<'a, u8>>;
-
-// TODO an ugly hack to tide us over while I try abstain from digging a deeper hole
-pub unsafe fn iter_to_response<'a>(
-    iter: impl Iterator<Item = u8>,
-    mut response: [u8; GCP_MAX_RESPONSE_LENGTH],
-) -> GcpResponse<'a> {
-    let mut length = 0;
-    for (ret, src) in response.iter_mut().zip(iter) {
-        *ret = src;
-        length += 1;
-    }
-    response.into_iter().take(length)
-}
-/*
-unsafe fn iter_ref_to_response<'a>(
-    iter: impl Iterator<Item = &'a u8>,
-    _response: &mut [u8; GCP_MAX_RESPONSE_LENGTH],
-) -> GcpResponse {
-    let mut response: [u8; GCP_MAX_RESPONSE_LENGTH] = [0; GCP_MAX_RESPONSE_LENGTH];
-    let mut length = 0;
-    for (ret, src) in response.iter_mut().zip(iter) {
-        *ret = *src;
-        length += 1;
-    }
-    response.into_iter().take(length)
-}*/
-
 // - tests --------------------------------------------------------------------
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::error::{GreatError, GreatResult};
     use crate::firmware::BoardInformation;
 
     use core::array;
@@ -134,14 +112,30 @@ mod tests {
         0x02, //                         transfer_type   = 2 (USB_TRANSFER_TYPE_BULK)
     ];
 
-    static CLASS_CORE: Class = Class {
-        id: ClassId::core,
-        name: "core",
-        docs: class_core::CLASS_DOCS,
-        verbs: &class_core::VERBS,
+    static CLASS_FIRMWARE: Class = Class {
+        id: ClassId::firmware,
+        name: "firmware\0",
+        docs: "\0",
+        verbs: &[],
     };
 
-    static SUPPORTED_CLASSES: [Class; 1] = [CLASS_CORE];
+    /// A class that answers every verb with its verb number
+    struct Echo;
+
+    impl GcpClass for Echo {
+        fn class(&self) -> &'static Class {
+            &CLASS_FIRMWARE
+        }
+
+        fn dispatch(
+            &mut self,
+            verb_number: u32,
+            _arguments: &[u8],
+            response: &mut GcpResponseWriter,
+        ) -> GreatResult<()> {
+            response.write(&verb_number.to_le_bytes())
+        }
+    }
 
     pub const BOARD_INFORMATION: BoardInformation = BoardInformation {
         board_id: [0x00, 0x00, 0x00, 0x00],
@@ -281,43 +275,98 @@ mod tests {
 
     #[test]
     fn test_dispatch_read_board_id() {
-        let classes = Classes(&SUPPORTED_CLASSES);
-        let core = class_core::Core::new(classes, BOARD_INFORMATION);
+        let mut classes: [&mut dyn GcpClass; 0] = [];
+        let mut classes = Classes::new(BOARD_INFORMATION, &mut classes);
 
         let command = Command::parse(&COMMAND_READ_BOARD_ID[..]).expect("failed parsing command");
         println!("\ntest_dispatch_read_board_id: {:?}", command);
 
-        let response_buffer = [0_u8; GCP_MAX_RESPONSE_LENGTH];
-        let response = core
-            .dispatch(command.verb_number(), &command.arguments, response_buffer)
+        let mut response_buffer = [0_u8; GCP_MAX_RESPONSE_LENGTH];
+        let mut response = GcpResponseWriter::new(&mut response_buffer);
+        classes
+            .dispatch(
+                command.class_id(),
+                command.verb_number(),
+                command.arguments,
+                &mut response,
+            )
             .expect("failed dispatch");
-        println!("  -> {:?}", response);
+        println!("  -> {:?}", response.as_slice());
 
         let expected: [u8; 4] = [0x00, 0x00, 0x00, 0x00];
 
         assert_eq!(response.len(), expected.len());
-        assert!(response.eq(expected.iter().copied()));
+        assert_eq!(response.as_slice(), &expected);
     }
 
     #[test]
     fn test_dispatch_get_verb_descriptor() {
-        let classes = Classes(&SUPPORTED_CLASSES);
-        let core = class_core::Core::new(classes, BOARD_INFORMATION);
+        let mut classes: [&mut dyn GcpClass; 0] = [];
+        let mut classes = Classes::new(BOARD_INFORMATION, &mut classes);
 
         let command =
             Command::parse(&COMMAND_GET_VERB_DESCRIPTOR[..]).expect("failed parsing command");
         println!("\ntest_dispatch_get_verb_descriptor: {:?}", command);
 
-        let response_buffer = [0_u8; GCP_MAX_RESPONSE_LENGTH];
-        let response = core
-            .dispatch(command.verb_number(), &command.arguments, response_buffer)
+        let mut response_buffer = [0_u8; GCP_MAX_RESPONSE_LENGTH];
+        let mut response = GcpResponseWriter::new(&mut response_buffer);
+        classes
+            .dispatch(
+                command.class_id(),
+                command.verb_number(),
+                command.arguments,
+                &mut response,
+            )
             .expect("failed dispatch");
-        println!("  -> {:?}", response);
+        println!("  -> {:?}", response.as_slice());
 
-        let expected: [u8; 5] = [60, 73, 73, 73, 0];
+        let expected: [u8; 5] = [60, 73, 73, 66, 0];
 
         assert_eq!(response.len(), expected.len());
-        assert!(response.eq(expected.iter().copied()));
+        assert_eq!(response.as_slice(), &expected);
+    }
+
+    #[test]
+    fn test_dispatch_registry() {
+        let mut echo = Echo;
+        let mut classes: [&mut dyn GcpClass; 1] = [&mut echo];
+        let mut classes = Classes::new(BOARD_INFORMATION, &mut classes);
+
+        let mut response_buffer = [0_u8; GCP_MAX_RESPONSE_LENGTH];
+        let mut response = GcpResponseWriter::new(&mut response_buffer);
+
+        // commands are routed to the registered class
+        classes
+            .dispatch(ClassId::firmware, 0x7, &[], &mut response)
+            .expect("failed dispatch");
+        assert_eq!(response.as_slice(), &[0x07, 0x00, 0x00, 0x00]);
+
+        // core introspection is answered from the registry
+        response.clear();
+        classes
+            .dispatch(ClassId::core, 0x4, &[], &mut response)
+            .expect("failed dispatch");
+        assert_eq!(
+            response.as_slice(),
+            &[0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+
+        response.clear();
+        classes
+            .dispatch(
+                ClassId::core,
+                0x8,
+                &COMMAND_GET_CLASS_NAME[8..],
+                &mut response,
+            )
+            .expect("failed dispatch");
+        assert_eq!(response.as_slice(), b"firmware\0");
+
+        let result = classes.dispatch(ClassId::gpio, 0x0, &[], &mut response);
+        assert!(matches!(
+            result,
+            Err(GreatError::GcpClassNotFound(ClassId::gpio))
+        ));
     }
 
     // - test_introspection --
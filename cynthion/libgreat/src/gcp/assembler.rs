@@ -0,0 +1,196 @@
+//! GCP command reassembly
+//!
+//! The host sends each command in the data stage of a control
+//! request, which arrives in packets of up to the control endpoint's
+//! maximum packet size. A [`CommandAssembler`] accumulates the packets
+//! in a command buffer, usually that of [`super::GcpBuffers`], until it
+//! holds the `wLength` bytes announced by the setup packet:
+//!
+//! ```ignore
+//! // host is starting a new command sequence
+//! assembler.start(setup_packet.length as usize)?;
+//!
+//! // ... then, for every packet received on the control endpoint:
+//! if assembler.receive(buffers.command_mut(), packet)? {
+//!     let command = assembler.command(buffers.command()).ok_or(GreatError::BadMessage)?;
+//!     ...
+//!     assembler.reset();
+//! }
+//! ```
+
+use super::Command;
+
+use crate::error::{GreatError, GreatResult};
+
+/// Largest command the host may send, matches the largest response.
+pub const GCP_MAX_COMMAND_LENGTH: usize = 4096;
+
+/// Size of the class and verb numbers at the start of every command
+const GCP_COMMAND_PRELUDE_LENGTH: usize = 8;
+
+// - CommandAssembler ---------------------------------------------------------
+
+/// Reassembles a command sent over multiple packets
+#[derive(Default)]
+pub struct CommandAssembler {
+    /// Length of the command being received, zero if idle
+    expected: usize,
+    length: usize,
+}
+
+impl CommandAssembler {
+    pub const fn new() -> Self {
+        Self {
+            expected: 0,
+            length: 0,
+        }
+    }
+
+    /// Start receiving a command of `length` bytes, discarding any
+    /// partially received command.
+    ///
+    /// Fails with [`GreatError::ArgumentListTooLong`] if the command
+    /// is larger than [`GCP_MAX_COMMAND_LENGTH`].
+    pub fn start(&mut self, length: usize) -> GreatResult<()> {
+        self.reset();
+        if length < GCP_COMMAND_PRELUDE_LENGTH {
+            return Err(GreatError::BadMessage);
+        } else if length > GCP_MAX_COMMAND_LENGTH {
+            return Err(GreatError::ArgumentListTooLong);
+        }
+        self.expected = length;
+        Ok(())
+    }
+
+    /// Returns true while a command is being received.
+    pub fn is_receiving(&self) -> bool {
+        self.expected > 0 && self.length < self.expected
+    }
+
+    /// Returns true once the whole command has been received.
+    pub fn is_complete(&self) -> bool {
+        self.expected > 0 && self.length == self.expected
+    }
+
+    /// Number of bytes received so far.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Append a packet to the command held by `buffer`.
+    ///
+    /// Returns true once the whole command has been received. Packets
+    /// that are not expected or that overrun the announced length, or
+    /// `buffer`, fail with [`GreatError::BadMessage`] and discard the
+    /// command.
+    pub fn receive(&mut self, buffer: &mut [u8], packet: &[u8]) -> GreatResult<bool> {
+        let end = self.length + packet.len();
+        if !self.is_receiving() || end > self.expected || end > buffer.len() {
+            self.reset();
+            return Err(GreatError::BadMessage);
+        }
+        buffer[self.length..end].copy_from_slice(packet);
+        self.length = end;
+        Ok(self.is_complete())
+    }
+
+    /// The command held by `buffer`, once complete.
+    pub fn command<'b>(&self, buffer: &'b [u8]) -> Option<Command<&'b [u8]>> {
+        if !self.is_complete() {
+            return None;
+        }
+        Command::parse(&buffer[..self.length])
+    }
+
+    /// Discard the command.
+    pub fn reset(&mut self) {
+        self.expected = 0;
+        self.length = 0;
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // - fixtures -------------------------------------------------------------
+
+    // firmware.write_page(0x100, [0xaa; 20])
+    fn write_page_command() -> [u8; 32] {
+        let mut command = [0xaa; 32];
+        command[0..4].copy_from_slice(&0x1_u32.to_le_bytes());
+        command[4..8].copy_from_slice(&0x3_u32.to_le_bytes());
+        command[8..12].copy_from_slice(&0x100_u32.to_le_bytes());
+        command
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_reassembly() {
+        let bytes = write_page_command();
+        let mut buffer = [0; 64];
+        let mut assembler = CommandAssembler::new();
+        assembler.start(bytes.len()).unwrap();
+
+        for packet in bytes[..24].chunks(8) {
+            assert!(!assembler.receive(&mut buffer, packet).unwrap());
+            assert!(assembler.command(&buffer).is_none());
+        }
+        assert!(assembler.receive(&mut buffer, &bytes[24..]).unwrap());
+
+        let command = assembler.command(&buffer).unwrap();
+        assert_eq!(command.class_number(), 0x1);
+        assert_eq!(command.verb_number(), 0x3);
+        assert_eq!(command.arguments, &bytes[8..]);
+
+        assembler.reset();
+        assert!(!assembler.is_receiving());
+        assert!(assembler.command(&buffer).is_none());
+    }
+
+    #[test]
+    fn test_reassembly_errors() {
+        let bytes = write_page_command();
+        let mut buffer = [0; 16];
+        let mut assembler = CommandAssembler::new();
+
+        // commands must fit the largest command
+        assert!(matches!(
+            assembler.start(GCP_MAX_COMMAND_LENGTH + 1),
+            Err(GreatError::ArgumentListTooLong)
+        ));
+
+        // and hold at least a class and verb number
+        assert!(matches!(assembler.start(4), Err(GreatError::BadMessage)));
+
+        // data must not overrun the announced length
+        assembler.start(12).unwrap();
+        assert!(!assembler.receive(&mut buffer, &bytes[..8]).unwrap());
+        assert!(matches!(
+            assembler.receive(&mut buffer, &bytes[8..16]),
+            Err(GreatError::BadMessage)
+        ));
+        assert!(!assembler.is_receiving());
+
+        // or the buffer
+        assembler.start(bytes.len()).unwrap();
+        assert!(!assembler.receive(&mut buffer, &bytes[..16]).unwrap());
+        assert!(matches!(
+            assembler.receive(&mut buffer, &bytes[16..24]),
+            Err(GreatError::BadMessage)
+        ));
+
+        // or arrive without a command being started
+        assert!(matches!(
+            assembler.receive(&mut buffer, &bytes[..8]),
+            Err(GreatError::BadMessage)
+        ));
+    }
+}
@@ -0,0 +1,183 @@
+//! Buffers shared by the GCP transports
+//!
+//! Commands and their responses are large enough that firmware can't
+//! afford a pair of buffers for every transport it serves. The
+//! transports instead share a single [`GcpBuffers`], usually a static,
+//! that they borrow for the duration of each call.
+//!
+//! Only one transport serves a command at a time. A transport claims
+//! the buffers when it starts receiving a command, abandoning the
+//! command in progress on any other transport, which discards its
+//! state the next time it is called:
+//!
+//! ```ignore
+//! static mut GCP_BUFFERS: GcpBuffers = GcpBuffers::new();
+//!
+//! let buffers = unsafe { &mut GCP_BUFFERS };
+//! buffers.claim(Transport::Bulk, &mut classes);
+//! buffers.command_mut()[..packet.len()].copy_from_slice(packet);
+//! let length = buffers.dispatch(0..packet.len(), &mut classes)?;
+//! let response = &buffers.response(0)[..length];
+//! ```
+//!
+//! Responses are written after [`GCP_RESPONSE_HEADROOM`] bytes of the
+//! response buffer, leaving room for transports to prepend a header.
+
+use super::{ClassId, Classes, Command, GcpResponseWriter};
+use super::{GCP_MAX_COMMAND_LENGTH, GCP_MAX_RESPONSE_LENGTH};
+
+use crate::error::{GreatError, GreatResult};
+
+use core::ops::Range;
+
+use log::debug;
+
+/// Space in front of each response for a transport's header
+pub const GCP_RESPONSE_HEADROOM: usize = 12;
+
+/// Transports sharing the buffers
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Transport {
+    Control,
+    Bulk,
+    Serial,
+}
+
+// - GcpBuffers ---------------------------------------------------------------
+
+/// Command and response buffers shared by the GCP transports
+pub struct GcpBuffers {
+    command: [u8; GCP_MAX_COMMAND_LENGTH],
+    response: [u8; GCP_RESPONSE_HEADROOM + GCP_MAX_RESPONSE_LENGTH],
+    /// Transport the buffers are currently held by
+    owner: Option<Transport>,
+    /// Class of the command in progress, if any
+    pending: Option<ClassId>,
+}
+
+impl GcpBuffers {
+    pub const fn new() -> Self {
+        Self {
+            command: [0; GCP_MAX_COMMAND_LENGTH],
+            response: [0; GCP_RESPONSE_HEADROOM + GCP_MAX_RESPONSE_LENGTH],
+            owner: None,
+            pending: None,
+        }
+    }
+
+    /// Claim the buffers for `transport`, abandoning the command in
+    /// progress if they are held by another transport.
+    pub fn claim(&mut self, transport: Transport, classes: &mut Classes) {
+        if self.owner == Some(transport) {
+            return;
+        }
+        if let Some(owner) = self.owner {
+            debug!(
+                "GCP {:?} transport taking buffers from {:?}",
+                transport, owner
+            );
+        }
+        self.cancel(classes);
+        self.owner = Some(transport);
+    }
+
+    /// Returns true if the buffers are held by `transport`.
+    pub fn is_held_by(&self, transport: Transport) -> bool {
+        self.owner == Some(transport)
+    }
+
+    /// Returns true if `transport` holds the buffers and its command
+    /// is still in progress.
+    pub fn is_pending(&self, transport: Transport) -> bool {
+        self.is_held_by(transport) && self.pending.is_some()
+    }
+
+    /// Release the buffers, abandoning the command in progress.
+    pub fn release(&mut self, classes: &mut Classes) {
+        self.cancel(classes);
+        self.owner = None;
+    }
+
+    /// Abandon the command in progress, if any.
+    pub fn cancel(&mut self, classes: &mut Classes) {
+        if let Some(class_id) = self.pending.take() {
+            debug!("GCP cancelling command in progress");
+            classes.cancel(class_id);
+        }
+    }
+
+    /// The command buffer.
+    pub fn command(&self) -> &[u8] {
+        &self.command
+    }
+
+    pub fn command_mut(&mut self) -> &mut [u8] {
+        &mut self.command
+    }
+
+    /// The response buffer, starting with `header_length` bytes for a
+    /// transport's header.
+    pub fn response(&self, header_length: usize) -> &[u8] {
+        &self.response[GCP_RESPONSE_HEADROOM - header_length..]
+    }
+
+    /// The `header_length` bytes in front of the response.
+    pub fn response_header_mut(&mut self, header_length: usize) -> &mut [u8] {
+        &mut self.response[GCP_RESPONSE_HEADROOM - header_length..GCP_RESPONSE_HEADROOM]
+    }
+
+    /// Dispatch the command held by `command` bytes of the command
+    /// buffer to `classes`.
+    ///
+    /// Returns the length of the response, or
+    /// [`GreatError::OperationNowInProgress`] if the command is still
+    /// in progress and must be polled.
+    pub fn dispatch(&mut self, command: Range<usize>, classes: &mut Classes) -> GreatResult<usize> {
+        self.cancel(classes);
+
+        let mut response = GcpResponseWriter::new(&mut self.response[GCP_RESPONSE_HEADROOM..]);
+        let (class_id, result) = match Command::parse(&self.command[command]) {
+            Some(command) => (
+                command.class_id(),
+                classes.dispatch(
+                    command.class_id(),
+                    command.verb_number(),
+                    command.arguments,
+                    &mut response,
+                ),
+            ),
+            None => (ClassId::core, Err(GreatError::BadMessage)),
+        };
+        let result = result.map(|()| response.len());
+
+        if let Err(GreatError::OperationNowInProgress) = result {
+            self.pending = Some(class_id);
+        }
+        result
+    }
+
+    /// Continue the command in progress.
+    ///
+    /// Returns the length of the response once it completes, or `None`
+    /// if no command is in progress.
+    pub fn poll(&mut self, classes: &mut Classes) -> Option<GreatResult<usize>> {
+        let class_id = self.pending?;
+
+        let mut response = GcpResponseWriter::new(&mut self.response[GCP_RESPONSE_HEADROOM..]);
+        let result = classes
+            .poll(class_id, &mut response)
+            .map(|()| response.len());
+
+        match result {
+            Err(GreatError::OperationNowInProgress) => (),
+            _ => self.pending = None,
+        }
+        Some(result)
+    }
+}
+
+impl Default for GcpBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,737 @@
+//! GCP transport over a pair of bulk endpoints
+//!
+//! Each frame is sent as a single bulk transfer, terminated by a short
+//! packet, and starts with a header holding a tag chosen by the host
+//! and the length of the payload that follows:
+//!
+//! ```text
+//! host to device: | tag: u32 | kind: u32   | length: u32 | command  |
+//! device to host: | tag: u32 | status: u32 | length: u32 | response |
+//! ```
+//!
+//! The response to a command carries the tag of the command and a
+//! status of zero, or the errno of the error the command failed with
+//! and no payload.
+//!
+//! Commands that are still in progress once dispatched, see
+//! [`crate::gcp::GcpClass::poll`], are answered once they complete.
+//!
+//! A cancel frame abandons the command with the given tag if it is still
+//! in progress, or stops its response after the packets that have
+//! already been sent, and is acknowledged with an empty response
+//! carrying its own tag. Commands that arrive while a command is in
+//! progress or a response is still being sent are answered with `EBUSY`.
+//! A command received on another transport abandons the command in
+//! progress, and ends its response after the packets already sent.
+//!
+//! Commands are dispatched through the same [`Classes`] registry, and
+//! share the same [`GcpBuffers`], as the other transports:
+//!
+//! ```ignore
+//! // for every packet received on the bulk OUT endpoint:
+//! transport.receive(packet, &mut buffers, &mut classes);
+//!
+//! // ... and on every iteration of the main loop:
+//! transport.poll(&mut buffers, &mut classes);
+//!
+//! // ... then, and every time the host has read a packet:
+//! if let Some(packet) = transport.next_packet(&buffers) {
+//!     if hal_driver.try_write(BULK_IN_ENDPOINT, packet.iter()).is_err() {
+//!         transport.requeue();
+//!     }
+//! }
+//! ```
+//!
+//! A packet that was never read by the host, because the write failed
+//! or the packet was discarded from the endpoint's FIFO, is returned
+//! again by the next call to [`BulkTransport::next_packet`] once the
+//! transport has been told with [`BulkTransport::requeue`].
+
+use super::{Classes, CommandAssembler, GcpBuffers, GcpResponse, Transport};
+
+use crate::error::{GreatError, GreatResult};
+
+use core::ops::Range;
+
+use log::{error, warn};
+use zerocopy::{AsBytes, FromBytes, LittleEndian, Unaligned, U32};
+
+/// Size of the header at the start of every frame
+pub const GCP_BULK_HEADER_LENGTH: usize = 12;
+
+/// Number of empty responses that can wait behind the current response
+const GCP_BULK_MAX_STATUS: usize = 4;
+
+// - frames -------------------------------------------------------------------
+
+/// Kind of frame sent by the host
+#[repr(u32)]
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum FrameKind {
+    Command = 0,
+    Cancel = 1,
+    Unknown(u32),
+}
+
+impl core::convert::From<u32> for FrameKind {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => FrameKind::Command,
+            1 => FrameKind::Cancel,
+            _ => FrameKind::Unknown(value),
+        }
+    }
+}
+
+/// Header of a frame sent by the host
+#[repr(C)]
+#[derive(Debug, FromBytes, AsBytes, Unaligned)]
+pub struct RequestHeader {
+    pub tag: U32<LittleEndian>,
+    pub kind: U32<LittleEndian>,
+    pub length: U32<LittleEndian>,
+}
+
+/// Header of a frame sent by the device
+#[repr(C)]
+#[derive(Debug, FromBytes, AsBytes, Unaligned)]
+pub struct ResponseHeader {
+    pub tag: U32<LittleEndian>,
+    pub status: U32<LittleEndian>,
+    pub length: U32<LittleEndian>,
+}
+
+impl ResponseHeader {
+    fn new(tag: u32, status: u32, length: usize) -> Self {
+        Self {
+            tag: tag.into(),
+            status: status.into(),
+            length: (length as u32).into(),
+        }
+    }
+}
+
+// - BulkTransport ------------------------------------------------------------
+
+/// Last packet returned by [`BulkTransport::next_packet`]
+#[derive(Debug, PartialEq, Clone)]
+enum Packet {
+    /// Bytes of the response buffer
+    Response(Range<usize>),
+    /// The status packet
+    Status,
+}
+
+/// Serves GCP commands received on a bulk OUT endpoint and sends their
+/// responses on a bulk IN endpoint
+pub struct BulkTransport {
+    max_packet_size: usize,
+
+    // receive
+    command: CommandAssembler,
+    /// Tag of the command being received
+    command_tag: u32,
+    /// Skip the remaining packets of the current transfer
+    discarding: bool,
+
+    // send
+    response: Option<GcpResponse>,
+    /// Tag of the command the response belongs to
+    response_tag: u32,
+    /// Empty responses waiting to be sent after the current one
+    status: [[u8; GCP_BULK_HEADER_LENGTH]; GCP_BULK_MAX_STATUS],
+    status_count: usize,
+    status_packet: [u8; GCP_BULK_HEADER_LENGTH],
+    /// A packet has been written but not yet read by the host
+    in_flight: bool,
+    last_packet: Option<Packet>,
+    /// The last packet must be written again
+    requeued: bool,
+}
+
+impl BulkTransport {
+    pub const fn new(max_packet_size: usize) -> Self {
+        Self {
+            max_packet_size,
+            command: CommandAssembler::new(),
+            command_tag: 0,
+            discarding: false,
+            response: None,
+            response_tag: 0,
+            status: [[0; GCP_BULK_HEADER_LENGTH]; GCP_BULK_MAX_STATUS],
+            status_count: 0,
+            status_packet: [0; GCP_BULK_HEADER_LENGTH],
+            in_flight: false,
+            last_packet: None,
+            requeued: false,
+        }
+    }
+
+    /// Discard any command being received and any response being sent.
+    pub fn reset(&mut self, buffers: &mut GcpBuffers, classes: &mut Classes) {
+        if buffers.is_held_by(Transport::Bulk) {
+            buffers.release(classes);
+        }
+        self.command.reset();
+        self.discarding = false;
+        self.response = None;
+        self.status_count = 0;
+        self.in_flight = false;
+        self.last_packet = None;
+        self.requeued = false;
+    }
+
+    /// Returns true while a response is waiting to be sent.
+    pub fn is_sending(&self) -> bool {
+        self.response.is_some() || self.status_count > 0
+    }
+
+    /// Handle a packet received on the bulk OUT endpoint, dispatching
+    /// each command to `classes` once it has been received.
+    pub fn receive(&mut self, packet: &[u8], buffers: &mut GcpBuffers, classes: &mut Classes) {
+        self.sync(buffers);
+        let end_of_transfer = packet.len() < self.max_packet_size;
+
+        if self.discarding {
+            self.discarding = !end_of_transfer;
+            return;
+        }
+
+        let result = if self.command.is_receiving() {
+            self.command.receive(buffers.command_mut(), packet)
+        } else {
+            self.start_frame(packet, buffers, classes)
+        };
+
+        match result {
+            Ok(true) => {
+                // swallow the zero length packet ending the transfer
+                self.discarding = !end_of_transfer;
+                self.dispatch(buffers, classes);
+            }
+            Ok(false) if end_of_transfer && self.command.is_receiving() => {
+                warn!(
+                    "GCP bulk command truncated after {} bytes",
+                    self.command.len()
+                );
+                self.command.reset();
+                self.queue_status(self.command_tag, GreatError::BadMessage.errno());
+            }
+            Ok(false) => (),
+            Err(e) => {
+                error!("GCP bulk error: failed to receive frame {}", e);
+                self.discarding = !end_of_transfer;
+                self.queue_status(self.command_tag, e.errno());
+            }
+        }
+    }
+
+    /// Continue the command in progress, if any.
+    pub fn poll(&mut self, buffers: &mut GcpBuffers, classes: &mut Classes) {
+        self.sync(buffers);
+        if !buffers.is_pending(Transport::Bulk) {
+            return;
+        }
+        if let Some(result) = buffers.poll(classes) {
+            self.complete(self.response_tag, result, buffers);
+        }
+    }
+
+    /// Returns the next packet to write to the bulk IN endpoint, if the
+    /// host has read the previous one.
+    pub fn next_packet<'b>(&'b mut self, buffers: &'b GcpBuffers) -> Option<&'b [u8]> {
+        self.sync(buffers);
+        if self.in_flight {
+            return None;
+        }
+
+        let buffer = buffers.response(GCP_BULK_HEADER_LENGTH);
+        if self.requeued {
+            self.requeued = false;
+            match &self.last_packet {
+                Some(Packet::Response(range)) => {
+                    self.in_flight = true;
+                    return Some(&buffer[range.clone()]);
+                }
+                Some(Packet::Status) => {
+                    self.in_flight = true;
+                    return Some(&self.status_packet);
+                }
+                None => (),
+            }
+        }
+
+        if let Some(response) = &mut self.response {
+            let offset = response.bytes_sent();
+            if let Some(packet) = response.next_packet(buffer, self.max_packet_size) {
+                self.in_flight = true;
+                self.last_packet = Some(Packet::Response(offset..offset + packet.len()));
+                return Some(packet);
+            }
+            self.response = None;
+        }
+
+        if self.status_count > 0 {
+            self.in_flight = true;
+            self.last_packet = Some(Packet::Status);
+            self.status_packet = self.status[0];
+            self.status.rotate_left(1);
+            self.status_count -= 1;
+            return Some(&self.status_packet);
+        }
+
+        None
+    }
+
+    /// Call once the host has read the last packet returned by
+    /// [`BulkTransport::next_packet`].
+    pub fn handle_transfer_complete(&mut self) {
+        self.in_flight = false;
+        self.last_packet = None;
+    }
+
+    /// Call if the last packet returned by [`BulkTransport::next_packet`]
+    /// could not be written, or was discarded before the host read it,
+    /// to have it returned again.
+    pub fn requeue(&mut self) {
+        if self.in_flight {
+            self.in_flight = false;
+            self.requeued = true;
+        }
+    }
+
+    /// Discard the state of a command whose buffers have been claimed
+    /// by another transport.
+    fn sync(&mut self, buffers: &GcpBuffers) {
+        if buffers.is_held_by(Transport::Bulk) {
+            return;
+        }
+        if self.requeued && matches!(self.last_packet, Some(Packet::Response(_))) {
+            warn!("GCP bulk dropping response packet lost with its buffers");
+            self.requeued = false;
+            self.last_packet = None;
+        }
+        if self.command.is_receiving() {
+            warn!("GCP bulk command abandoned for another transport");
+            self.command.reset();
+            self.discarding = true;
+            self.queue_status(self.command_tag, GreatError::DeviceOrResourceBusy.errno());
+        }
+        match &mut self.response {
+            Some(response) if response.bytes_sent() < response.len() => {
+                response.cancel(self.max_packet_size);
+            }
+            _ => (),
+        }
+    }
+
+    /// Parse the header at the start of a transfer.
+    fn start_frame(
+        &mut self,
+        packet: &[u8],
+        buffers: &mut GcpBuffers,
+        classes: &mut Classes,
+    ) -> GreatResult<bool> {
+        let header = match RequestHeader::read_from_prefix(packet) {
+            Some(header) => header,
+            None => {
+                self.command_tag = 0;
+                return Err(GreatError::BadMessage);
+            }
+        };
+        let payload = &packet[GCP_BULK_HEADER_LENGTH..];
+        self.command_tag = header.tag.get();
+
+        match FrameKind::from(header.kind.get()) {
+            FrameKind::Command => {
+                self.command.start(header.length.get() as usize)?;
+                buffers.claim(Transport::Bulk, classes);
+                self.command.receive(buffers.command_mut(), payload)
+            }
+            FrameKind::Cancel => {
+                self.cancel(header.tag.get(), buffers, classes);
+                Ok(false)
+            }
+            FrameKind::Unknown(_) => Err(GreatError::InvalidArgument),
+        }
+    }
+
+    /// Returns true while a command is in progress or its response is
+    /// being sent.
+    fn is_busy(&self, buffers: &GcpBuffers) -> bool {
+        self.response.is_some() || buffers.is_pending(Transport::Bulk)
+    }
+
+    /// Abandon the command with the given tag or stop sending its
+    /// response.
+    fn cancel(&mut self, tag: u32, buffers: &mut GcpBuffers, classes: &mut Classes) {
+        if buffers.is_pending(Transport::Bulk) && self.response_tag == tag {
+            buffers.cancel(classes);
+        }
+        if let Some(response) = &mut self.response {
+            if self.response_tag == tag {
+                response.cancel(self.max_packet_size);
+            }
+        }
+        self.queue_status(tag, 0);
+    }
+
+    fn dispatch(&mut self, buffers: &mut GcpBuffers, classes: &mut Classes) {
+        let tag = self.command_tag;
+        let length = self.command.len();
+        self.command.reset();
+
+        // the host must read a response before sending the next command
+        if self.is_busy(buffers) {
+            warn!("GCP bulk command received while busy with the last one");
+            self.queue_status(tag, GreatError::DeviceOrResourceBusy.errno());
+            return;
+        }
+
+        let result = buffers.dispatch(0..length, classes);
+        self.complete(tag, result, buffers);
+    }
+
+    /// Queue the response to a command, or keep polling it while it is
+    /// in progress.
+    fn complete(&mut self, tag: u32, result: GreatResult<usize>, buffers: &mut GcpBuffers) {
+        match result {
+            Ok(length) => {
+                ResponseHeader::new(tag, 0, length)
+                    .write_to(buffers.response_header_mut(GCP_BULK_HEADER_LENGTH));
+                let mut response = GcpResponse::new(GCP_BULK_HEADER_LENGTH + length);
+                response.truncate(usize::MAX, self.max_packet_size);
+                self.response = Some(response);
+                self.response_tag = tag;
+            }
+            Err(GreatError::OperationNowInProgress) => {
+                self.response_tag = tag;
+            }
+            Err(e) => {
+                error!("GCP bulk error: failed to dispatch command {}", e);
+                self.queue_status(tag, e.errno());
+            }
+        }
+    }
+
+    fn queue_status(&mut self, tag: u32, status: u32) {
+        if self.status_count == GCP_BULK_MAX_STATUS {
+            warn!("GCP bulk dropping status {} for frame {}", status, tag);
+            return;
+        }
+        let frame = &mut self.status[self.status_count];
+        ResponseHeader::new(tag, status, 0).write_to(&mut frame[..]);
+        self.status_count += 1;
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::firmware::BoardInformation;
+    use crate::gcp::{Class, ClassId, GcpClass, GcpResponseWriter};
+
+    use core::cell::Cell;
+
+    // - fixtures -------------------------------------------------------------
+
+    const BOARD_INFORMATION: BoardInformation = BoardInformation {
+        board_id: [0x01, 0x02, 0x03, 0x04],
+        version_string: "v2023.0.1\0",
+        part_id: [0; 8],
+        serial_number: [0; 16],
+    };
+
+    fn frame(tag: u32, kind: u32, payload: &[u8]) -> ([u8; 64], usize) {
+        let mut frame = [0; 64];
+        frame[0..4].copy_from_slice(&tag.to_le_bytes());
+        frame[4..8].copy_from_slice(&kind.to_le_bytes());
+        frame[8..12].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame[12..12 + payload.len()].copy_from_slice(payload);
+        (frame, 12 + payload.len())
+    }
+
+    /// core.read_board_id()
+    const COMMAND_READ_BOARD_ID: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
+
+    static CLASS_FIRMWARE: Class = Class {
+        id: ClassId::firmware,
+        name: "firmware\0",
+        docs: "",
+        verbs: &[],
+    };
+
+    /// A class whose verbs complete once `ready` is set
+    struct Waiter<'a> {
+        ready: &'a Cell<bool>,
+        canceled: &'a Cell<bool>,
+    }
+
+    impl GcpClass for Waiter<'_> {
+        fn class(&self) -> &'static Class {
+            &CLASS_FIRMWARE
+        }
+
+        fn dispatch(
+            &mut self,
+            _verb_number: u32,
+            _arguments: &[u8],
+            response: &mut GcpResponseWriter,
+        ) -> GreatResult<()> {
+            self.poll(response)
+        }
+
+        fn poll(&mut self, response: &mut GcpResponseWriter) -> GreatResult<()> {
+            if self.ready.get() {
+                response.write(b"done")
+            } else {
+                Err(GreatError::OperationNowInProgress)
+            }
+        }
+
+        fn cancel(&mut self) {
+            self.canceled.set(true);
+        }
+    }
+
+    /// firmware.wait()
+    const COMMAND_WAIT: [u8; 8] = [1, 0, 0, 0, 0, 0, 0, 0];
+
+    fn header(packet: &[u8]) -> (u32, u32, u32) {
+        let header = ResponseHeader::read_from_prefix(packet).unwrap();
+        (header.tag.get(), header.status.get(), header.length.get())
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_bulk_command() {
+        let mut classes: [&mut dyn GcpClass; 0] = [];
+        let mut classes = Classes::new(BOARD_INFORMATION, &mut classes);
+        let mut transport: BulkTransport = BulkTransport::new(16);
+        let mut buffers = GcpBuffers::new();
+
+        // commands may span several packets
+        let (bytes, length) = frame(7, 0, &COMMAND_READ_BOARD_ID);
+        transport.receive(&bytes[..16], &mut buffers, &mut classes);
+        assert!(transport.next_packet(&buffers).is_none());
+        transport.receive(&bytes[16..length], &mut buffers, &mut classes);
+
+        // the response is sent in packets
+        let packet = transport.next_packet(&buffers).unwrap();
+        assert_eq!(header(packet), (7, 0, 4));
+        assert_eq!(&packet[12..], &[0x01, 0x02, 0x03, 0x04]);
+        assert!(transport.next_packet(&buffers).is_none());
+
+        // and ends with a zero length packet as it fills the last one
+        transport.handle_transfer_complete();
+        assert_eq!(transport.next_packet(&buffers), Some(&[][..]));
+        transport.handle_transfer_complete();
+        assert!(transport.next_packet(&buffers).is_none());
+        assert!(!transport.is_sending());
+    }
+
+    #[test]
+    fn test_bulk_errors() {
+        let mut classes: [&mut dyn GcpClass; 0] = [];
+        let mut classes = Classes::new(BOARD_INFORMATION, &mut classes);
+        let mut transport: BulkTransport = BulkTransport::new(64);
+        let mut buffers = GcpBuffers::new();
+
+        // errors are reported as the errno of the response
+        let (bytes, length) = frame(1, 0, &[0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        transport.receive(&bytes[..length], &mut buffers, &mut classes);
+        let errno = GreatError::GcpClassNotFound(0x21.into()).errno();
+        assert_eq!(
+            header(transport.next_packet(&buffers).unwrap()),
+            (1, errno, 0)
+        );
+        transport.handle_transfer_complete();
+
+        // as are truncated commands
+        let (mut bytes, length) = frame(2, 0, &COMMAND_READ_BOARD_ID);
+        bytes[8] = 12;
+        transport.receive(&bytes[..length], &mut buffers, &mut classes);
+        assert_eq!(header(transport.next_packet(&buffers).unwrap()), (2, 77, 0));
+        transport.handle_transfer_complete();
+
+        // and unknown frames
+        let (bytes, length) = frame(3, 9, &[]);
+        transport.receive(&bytes[..length], &mut buffers, &mut classes);
+        assert_eq!(header(transport.next_packet(&buffers).unwrap()), (3, 22, 0));
+    }
+
+    #[test]
+    fn test_bulk_cancel() {
+        let mut classes: [&mut dyn GcpClass; 0] = [];
+        let mut classes = Classes::new(BOARD_INFORMATION, &mut classes);
+        let mut transport: BulkTransport = BulkTransport::new(16);
+        let mut buffers = GcpBuffers::new();
+
+        // core.get_class_name(0)
+        let (bytes, length) = frame(5, 0, &[0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0]);
+        transport.receive(&bytes[..16], &mut buffers, &mut classes);
+        transport.receive(&bytes[16..length], &mut buffers, &mut classes);
+        assert_eq!(header(transport.next_packet(&buffers).unwrap()), (5, 0, 5));
+
+        // commands sent before the response has been read are refused
+        let (bytes, length) = frame(6, 0, &COMMAND_READ_BOARD_ID);
+        transport.receive(&bytes[..16], &mut buffers, &mut classes);
+        transport.receive(&bytes[16..length], &mut buffers, &mut classes);
+
+        // cancelling ends the response after the packets already sent
+        let (bytes, length) = frame(5, 1, &[]);
+        transport.receive(&bytes[..length], &mut buffers, &mut classes);
+        transport.handle_transfer_complete();
+        assert_eq!(transport.next_packet(&buffers), Some(&[][..]));
+        transport.handle_transfer_complete();
+
+        // followed by the refusal and the acknowledgement of the cancel
+        let busy = GreatError::DeviceOrResourceBusy.errno();
+        assert_eq!(
+            header(transport.next_packet(&buffers).unwrap()),
+            (6, busy, 0)
+        );
+        transport.handle_transfer_complete();
+        assert_eq!(header(transport.next_packet(&buffers).unwrap()), (5, 0, 0));
+        transport.handle_transfer_complete();
+        assert!(transport.next_packet(&buffers).is_none());
+    }
+
+    #[test]
+    fn test_bulk_pending() {
+        let (ready, canceled) = (Cell::new(false), Cell::new(false));
+        let mut waiter = Waiter {
+            ready: &ready,
+            canceled: &canceled,
+        };
+        let mut classes: [&mut dyn GcpClass; 1] = [&mut waiter];
+        let mut classes = Classes::new(BOARD_INFORMATION, &mut classes);
+        let mut transport: BulkTransport = BulkTransport::new(64);
+        let mut buffers = GcpBuffers::new();
+
+        // commands in progress are answered once they complete
+        let (bytes, length) = frame(1, 0, &COMMAND_WAIT);
+        transport.receive(&bytes[..length], &mut buffers, &mut classes);
+        transport.poll(&mut buffers, &mut classes);
+        assert!(transport.next_packet(&buffers).is_none());
+
+        // while refusing any other command
+        let (bytes, length) = frame(2, 0, &COMMAND_READ_BOARD_ID);
+        transport.receive(&bytes[..length], &mut buffers, &mut classes);
+        let busy = GreatError::DeviceOrResourceBusy.errno();
+        assert_eq!(
+            header(transport.next_packet(&buffers).unwrap()),
+            (2, busy, 0)
+        );
+        transport.handle_transfer_complete();
+
+        ready.set(true);
+        transport.poll(&mut buffers, &mut classes);
+        let packet = transport.next_packet(&buffers).unwrap();
+        assert_eq!(header(packet), (1, 0, 4));
+        assert_eq!(&packet[12..], b"done");
+        transport.handle_transfer_complete();
+        assert!(transport.next_packet(&buffers).is_none());
+
+        // or can be cancelled
+        ready.set(false);
+        let (bytes, length) = frame(3, 0, &COMMAND_WAIT);
+        transport.receive(&bytes[..length], &mut buffers, &mut classes);
+        let (bytes, length) = frame(3, 1, &[]);
+        transport.receive(&bytes[..length], &mut buffers, &mut classes);
+        assert!(canceled.get());
+        assert_eq!(header(transport.next_packet(&buffers).unwrap()), (3, 0, 0));
+        transport.handle_transfer_complete();
+
+        ready.set(true);
+        transport.poll(&mut buffers, &mut classes);
+        assert!(transport.next_packet(&buffers).is_none());
+    }
+
+    #[test]
+    fn test_bulk_shared_buffers() {
+        let (ready, canceled) = (Cell::new(false), Cell::new(false));
+        let mut waiter = Waiter {
+            ready: &ready,
+            canceled: &canceled,
+        };
+        let mut classes: [&mut dyn GcpClass; 1] = [&mut waiter];
+        let mut classes = Classes::new(BOARD_INFORMATION, &mut classes);
+        let mut transport: BulkTransport = BulkTransport::new(16);
+        let mut buffers = GcpBuffers::new();
+
+        // another transport claiming the buffers abandons the command in progress
+        let (bytes, length) = frame(1, 0, &COMMAND_WAIT);
+        for packet in bytes[..length].chunks(16) {
+            transport.receive(packet, &mut buffers, &mut classes);
+        }
+        buffers.claim(Transport::Serial, &mut classes);
+        assert!(canceled.get());
+
+        ready.set(true);
+        transport.poll(&mut buffers, &mut classes);
+        assert!(transport.next_packet(&buffers).is_none());
+
+        // and ends the response being sent after the packets already sent
+        let (bytes, length) = frame(2, 0, &COMMAND_READ_BOARD_ID);
+        for packet in bytes[..length].chunks(16) {
+            transport.receive(packet, &mut buffers, &mut classes);
+        }
+        assert_eq!(header(transport.next_packet(&buffers).unwrap()), (2, 0, 4));
+        transport.handle_transfer_complete();
+
+        buffers.claim(Transport::Serial, &mut classes);
+        assert_eq!(transport.next_packet(&buffers), Some(&[][..]));
+        transport.handle_transfer_complete();
+        assert!(transport.next_packet(&buffers).is_none());
+        assert!(!transport.is_sending());
+    }
+
+    #[test]
+    fn test_bulk_requeue() {
+        let mut classes = Classes::new(BOARD_INFORMATION, &mut []);
+        let mut transport: BulkTransport = BulkTransport::new(16);
+        let mut buffers = GcpBuffers::new();
+
+        // a packet the host never read is returned again
+        let (bytes, length) = frame(1, 0, &COMMAND_READ_BOARD_ID);
+        for packet in bytes[..length].chunks(16) {
+            transport.receive(packet, &mut buffers, &mut classes);
+        }
+        let mut packet = [0; 16];
+        packet.copy_from_slice(transport.next_packet(&buffers).unwrap());
+        assert_eq!(header(&packet), (1, 0, 4));
+        transport.requeue();
+        assert_eq!(transport.next_packet(&buffers), Some(&packet[..]));
+        transport.handle_transfer_complete();
+
+        // including the zero length packet ending the response
+        assert_eq!(transport.next_packet(&buffers), Some(&[][..]));
+        transport.requeue();
+        assert_eq!(transport.next_packet(&buffers), Some(&[][..]));
+        transport.handle_transfer_complete();
+
+        // ... and status packets
+        let (bytes, length) = frame(2, 7, &[]);
+        transport.receive(&bytes[..length], &mut buffers, &mut classes);
+        let invalid = GreatError::InvalidArgument.errno();
+        assert_eq!(
+            header(transport.next_packet(&buffers).unwrap()),
+            (2, invalid, 0)
+        );
+        transport.requeue();
+        assert_eq!(
+            header(transport.next_packet(&buffers).unwrap()),
+            (2, invalid, 0)
+        );
+        transport.handle_transfer_complete();
+
+        // requeuing once the host has read the packet does nothing
+        transport.requeue();
+        assert!(transport.next_packet(&buffers).is_none());
+    }
+}
@@ -1,9 +1,10 @@
 ///! Great Communications Protocol Class Registry
 ///!
 use crate::error::{GreatError, GreatResult};
+use crate::firmware::BoardInformation;
 
 use super::class_core;
-use super::{Command, CommandPrelude};
+use super::{Command, CommandPrelude, GcpResponseWriter};
 
 use log::{debug, error};
 use zerocopy::{AsBytes, BigEndian, FromBytes, LittleEndian, Unaligned, U32};
@@ -11,26 +12,136 @@ use zerocopy::{AsBytes, BigEndian, FromBytes, LittleEndian, Unaligned, U32};
 use core::any::Any;
 use core::slice;
 
+// - GcpClass -----------------------------------------------------------------
+
+/// A GCP class implementation
+///
+/// Classes declared with [`crate::gcp_class!`] implement this trait by
+/// forwarding to their generated `CLASS` and `dispatch`.
+///
+/// Verbs that need to wait for an event, e.g. on the target bus, can
+/// return [`GreatError::OperationNowInProgress`] instead of blocking the
+/// main loop. The transport then calls [`GcpClass::poll`] until the
+/// verb completes or [`GcpClass::cancel`] if the host gives up on it.
+pub trait GcpClass {
+    /// Returns the metadata of the class.
+    fn class(&self) -> &'static Class;
+
+    /// Dispatch a command to the verb with the given number.
+    fn dispatch(
+        &mut self,
+        verb_number: u32,
+        arguments: &[u8],
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()>;
+
+    /// Continue the verb in progress, writing its response once it
+    /// completes or returning `OperationNowInProgress` again.
+    fn poll(&mut self, _response: &mut GcpResponseWriter) -> GreatResult<()> {
+        Err(GreatError::NoMessageOfType)
+    }
+
+    /// Abandon the verb in progress.
+    fn cancel(&mut self) {}
+}
+
 // - Classes ------------------------------------------------------------------
 
-/// Classes
-#[derive(Copy, Clone)]
-pub struct Classes(pub &'static [Class]);
+/// Registry of the classes supported by a device
+///
+/// The core class is always present and answers its introspection
+/// verbs from the registered classes:
+///
+/// ```ignore
+/// let mut firmware = moondancer::gcp::firmware::Firmware;
+/// let mut classes: [&mut dyn GcpClass; 2] = [&mut firmware, &mut self.moondancer];
+/// let mut classes = Classes::new(BOARD_INFORMATION, &mut classes);
+/// classes.dispatch(command.class_id(), command.verb_number(), command.arguments, &mut response)?;
+/// ```
+pub struct Classes<'a> {
+    board_information: BoardInformation,
+    classes: &'a mut [&'a mut dyn GcpClass],
+}
+
+impl<'a> Classes<'a> {
+    pub fn new(
+        board_information: BoardInformation,
+        classes: &'a mut [&'a mut dyn GcpClass],
+    ) -> Self {
+        Self {
+            board_information,
+            classes,
+        }
+    }
+
+    /// Returns the metadata of the class with the given id.
+    pub fn class(&self, id: ClassId) -> Option<&'static Class> {
+        self.iter().find(|class| class.id == id)
+    }
+
+    /// Returns the metadata of all classes, starting with the core class.
+    pub fn iter(&self) -> ClassIter<'_, 'a> {
+        ClassIter {
+            core: Some(&class_core::CLASS),
+            classes: self.classes.iter(),
+        }
+    }
+
+    /// Dispatch a command to the class with the given id.
+    pub fn dispatch(
+        &mut self,
+        class_id: ClassId,
+        verb_number: u32,
+        arguments: &[u8],
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        if class_id == ClassId::core {
+            let core = class_core::Core::new(self, &self.board_information);
+            return core.dispatch(verb_number, arguments, response);
+        }
 
-impl Classes {
-    pub fn class(&self, id: ClassId) -> Option<&Class> {
-        self.0.iter().find(|&class| class.id == id)
+        match self.find(class_id) {
+            Some(class) => class.dispatch(verb_number, arguments, response),
+            None => Err(GreatError::GcpClassNotFound(class_id)),
+        }
     }
 
-    pub fn new() -> Self {
-        Self(&[])
+    /// Continue the verb in progress on the class with the given id.
+    pub fn poll(&mut self, class_id: ClassId, response: &mut GcpResponseWriter) -> GreatResult<()> {
+        match self.find(class_id) {
+            Some(class) => class.poll(response),
+            None => Err(GreatError::GcpClassNotFound(class_id)),
+        }
+    }
+
+    /// Abandon the verb in progress on the class with the given id.
+    pub fn cancel(&mut self, class_id: ClassId) {
+        if let Some(class) = self.find(class_id) {
+            class.cancel();
+        }
+    }
+
+    fn find(&mut self, class_id: ClassId) -> Option<&mut (dyn GcpClass + 'a)> {
+        self.classes
+            .iter_mut()
+            .find(|class| class.class().id == class_id)
+            .map(|class| &mut **class)
     }
 }
 
-impl core::ops::Deref for Classes {
-    type Target = &'static [Class];
-    fn deref(&self) -> &Self::Target {
-        &self.0
+/// Iterator over the metadata of the classes in a registry
+pub struct ClassIter<'r, 'a> {
+    core: Option<&'static Class>,
+    classes: slice::Iter<'r, &'a mut dyn GcpClass>,
+}
+
+impl Iterator for ClassIter<'_, '_> {
+    type Item = &'static Class;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.core
+            .take()
+            .or_else(|| self.classes.next().map(|class| class.class()))
     }
 }
 
@@ -101,6 +212,7 @@ pub enum ClassId {
     gpio = 0x0103,
     greatdancer = 0x0104,
     moondancer = 0x0120,
+    personality = 0x0121,
     unsupported(u32),
 }
 
@@ -112,6 +224,7 @@ impl core::convert::From<u32> for ClassId {
             0x0103 => ClassId::gpio,
             0x0104 => ClassId::greatdancer,
             0x0120 => ClassId::moondancer,
+            0x0121 => ClassId::personality,
             _ => ClassId::unsupported(value),
         }
     }
@@ -125,6 +238,7 @@ impl ClassId {
             ClassId::gpio => 0x0103,
             ClassId::greatdancer => 0x0104,
             ClassId::moondancer => 0x0120,
+            ClassId::personality => 0x0121,
             ClassId::unsupported(value) => *value,
         }
     }
@@ -2,7 +2,7 @@ use super::{ClassId, Command, Verb, VerbDescriptor};
 
 use crate::error::{GreatError, GreatResult};
 use crate::firmware::BoardInformation;
-use crate::gcp::{self, Classes};
+use crate::gcp::{self, Classes, GcpResponseWriter};
 
 use log::{error, trace};
 use zerocopy::{AsBytes, BigEndian, ByteSlice, FromBytes, LittleEndian, Unaligned, U32};
@@ -10,129 +10,81 @@ use zerocopy::{AsBytes, BigEndian, ByteSlice, FromBytes, LittleEndian, Unaligned
 use core::any::Any;
 use core::slice;
 
-pub static CLASS: gcp::Class = gcp::Class {
-    id: gcp::ClassId::core,
-    name: "core",
-    docs: CLASS_DOCS,
-    verbs: &VERBS,
-};
-
-pub static CLASS_DOCS: &str = "Core API\0"; // used to query information about the device, and perform a few standard functions.\0";
-
-/// Fields are `"\0"`  where C implementation has `""`
-/// Fields are `"*\0"` where C implementation has `NULL`
-pub static VERBS: [Verb; 10] = [
-    Verb {
-        id: 0x0,
-        name: "read_board_id\0",
-        doc: "*\0",
-        in_signature: "*\0",
-        in_param_names: "*\0",
-        out_signature: "*\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0x1,
-        name: "read_version_string\0",
-        doc: "*\0",
-        in_signature: "*\0",
-        in_param_names: "*\0",
-        out_signature: "*\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0x2,
-        name: "read_part_id\0",
-        doc: "*\0",
-        in_signature: "*\0",
-        in_param_names: "*\0",
-        out_signature: "*\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0x3,
-        name: "read_serial_number\0",
-        doc: "*\0",
-        in_signature: "*\0",
-        in_param_names: "*\0",
-        out_signature: "*\0",
-        out_param_names: "*\0",
-    },
-    // - api introspection --
-    Verb {
-        id: 0x4,
-        name: "get_available_classes\0",
-        doc: "*\0",
-        in_signature: "*\0",
-        in_param_names: "*\0",
-        out_signature: "*\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0x5,
-        name: "get_available_verbs\0",
-        doc: "*\0",
-        in_signature: "<I\0",
-        in_param_names: "class_number\0",
-        out_signature: "*\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0x6,
-        name: "get_verb_name\0",
-        doc: "*\0",
-        in_signature: "<II\0",
-        in_param_names: "class_number, verb_number\0",
-        out_signature: "*\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0x7,
-        name: "get_verb_descriptor\0",
-        doc: "*\0",
-        in_signature: "<III\0",
-        in_param_names: "class_number, verb_number, descriptor_number\0",
-        out_signature: "*\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0x8,
-        name: "get_class_name\0",
-        doc: "*\0",
-        in_signature: "<I\0",
-        in_param_names: "class_number\0",
-        out_signature: "*\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0x9,
-        name: "get_class_docs\0",
-        doc: "*\0",
-        in_signature: "<I\0",
-        in_param_names: "class_number\0",
-        out_signature: "*\0",
-        out_param_names: "*\0",
-    },
-    /*Verb {
-        id: 0x20,
-        name: "*\0", // request_reset
-        doc: "*\0",
-        in_signature: "*\0",
-        in_param_names: "*\0",
-        out_signature: "*\0",
-        out_param_names: "*\0",
-    },*/
-];
+crate::gcp_class! {
+    class: core,
+    docs: "Core API", // used to query information about the device, and perform a few standard functions.
+    context: &Core,
+    verbs: [
+        0x0 => read_board_id {
+            in: "*" (),
+            out: "*" (),
+            handler: Core::read_board_id,
+        },
+        0x1 => read_version_string {
+            in: "*" (),
+            out: "*" (),
+            handler: Core::read_version_string,
+        },
+        0x2 => read_part_id {
+            in: "*" (),
+            out: "*" (),
+            handler: Core::read_part_id,
+        },
+        0x3 => read_serial_number {
+            in: "*" (),
+            out: "*" (),
+            handler: Core::read_serial_number,
+        },
+        // - api introspection --
+        0x4 => get_available_classes {
+            in: "*" (),
+            out: "*" (),
+            handler: Core::get_available_classes,
+        },
+        0x5 => get_available_verbs {
+            in: "<I" (class_number: U32<LittleEndian>),
+            out: "*" (),
+            handler: Core::get_available_verbs,
+        },
+        0x6 => get_verb_name {
+            in: "<II" (class_number: U32<LittleEndian>, verb_number: U32<LittleEndian>),
+            out: "*" (),
+            handler: Core::get_verb_name,
+        },
+        0x7 => get_verb_descriptor {
+            in: "<IIB" (
+                class_number: U32<LittleEndian>,
+                verb_number: U32<LittleEndian>,
+                descriptor_number: u8
+            ),
+            out: "*" (),
+            handler: Core::get_verb_descriptor,
+        },
+        0x8 => get_class_name {
+            in: "<I" (class_number: U32<LittleEndian>),
+            out: "*" (),
+            handler: Core::get_class_name,
+        },
+        0x9 => get_class_docs {
+            in: "<I" (class_number: U32<LittleEndian>),
+            out: "*" (),
+            handler: Core::get_class_docs,
+        },
+        // 0x20 => request_reset
+    ]
+}
 
 // - Core ---------------------------------------------------------------------
 
-pub struct Core {
-    classes: Classes,
-    board_information: BoardInformation,
+/// Context of the core class verbs, created by [`Classes::dispatch`]
+/// for the duration of a command.
+pub struct Core<'r, 'a> {
+    classes: &'r Classes<'a>,
+    board_information: &'r BoardInformation,
 }
 
-impl Core {
-    pub fn new(classes: Classes, board_information: BoardInformation) -> Self {
+impl<'r, 'a> Core<'r, 'a> {
+    pub fn new(classes: &'r Classes<'a>, board_information: &'r BoardInformation) -> Self {
         Self {
             classes,
             board_information,
@@ -142,227 +94,132 @@ impl Core {
 
 // - verb implementations: board ----------------------------------------------
 
-impl Core {
-    pub fn read_board_id(&self, _arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+impl Core<'_, '_> {
+    pub fn read_board_id(&self, response: &mut GcpResponseWriter) -> GreatResult<()> {
         let board_id = self.board_information.board_id;
         trace!("  sending board id: {:?}", board_id);
-        Ok(board_id.into_iter())
+        response.write(&board_id)
     }
 
-    pub fn read_version_string(&self, _arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+    pub fn read_version_string(&self, response: &mut GcpResponseWriter) -> GreatResult<()> {
         let version_string = self.board_information.version_string;
         trace!("  sending version string: {:?}", version_string);
-        Ok(version_string.as_bytes().into_iter().copied())
+        response.write(version_string.as_bytes())
     }
 
-    pub fn read_part_id(&self, _arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+    pub fn read_part_id(&self, response: &mut GcpResponseWriter) -> GreatResult<()> {
         let part_id = self.board_information.part_id;
         trace!("  sending part id: {:?}", part_id);
-        Ok(part_id.into_iter())
+        response.write(&part_id)
     }
 
-    pub fn read_serial_number(&self, _arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+    pub fn read_serial_number(&self, response: &mut GcpResponseWriter) -> GreatResult<()> {
         let serial_number = self.board_information.serial_number;
         trace!("  sending serial number: {:?}", serial_number);
-        Ok(serial_number.into_iter())
+        response.write(&serial_number)
     }
 }
 
 // - verb implementations: introspection --------------------------------------
 
-impl Core {
-    pub fn get_available_classes(
-        &self,
-        _arguments: &[u8],
-    ) -> GreatResult<impl Iterator<Item = u8>> {
-        let classes = self
-            .classes
-            .iter()
-            .flat_map(|class| class.id.into_u32().to_le_bytes());
-        Ok(classes)
+impl Core<'_, '_> {
+    pub fn get_available_classes(&self, response: &mut GcpResponseWriter) -> GreatResult<()> {
+        for class in self.classes.iter() {
+            response.write(&class.id.into_u32().to_le_bytes())?;
+        }
+        Ok(())
     }
 
-    pub fn get_available_verbs(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            class_number: U32<LittleEndian>,
+    pub fn get_available_verbs(
+        &self,
+        class_number: U32<LittleEndian>,
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        let class = self.class(class_number)?;
+        for verb in class.verbs.iter() {
+            response.write(&verb.id.to_le_bytes())?;
         }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
-        let class_id = args.class_number.into();
-        let class = self
-            .classes
-            .class(class_id)
-            .ok_or(GreatError::GcpClassNotFound(class_id))?;
-        let verbs = class.verbs.iter().flat_map(|verb| verb.id.to_le_bytes());
-        Ok(verbs)
+        Ok(())
     }
 
-    pub fn get_verb_name(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            class_number: U32<LittleEndian>,
-            verb_number: U32<LittleEndian>,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
-        let class_id = args.class_number.into();
-        let class = self
-            .classes
-            .class(class_id)
-            .ok_or(GreatError::GcpClassNotFound(class_id))?;
-        let verb = class
-            .verb(args.verb_number.into())
-            .ok_or(GreatError::GcpVerbNotFound(
-                class_id,
-                args.verb_number.into(),
-            ))?;
-        Ok(verb.name.as_bytes().into_iter().copied())
+    pub fn get_verb_name(
+        &self,
+        class_number: U32<LittleEndian>,
+        verb_number: U32<LittleEndian>,
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        let verb = self.verb(class_number, verb_number)?;
+        response.write(verb.name.as_bytes())
     }
 
-    pub fn get_verb_descriptor(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(Debug, FromBytes, Unaligned)]
-        struct Args {
-            class_number: U32<LittleEndian>,
-            verb_number: U32<LittleEndian>,
-            descriptor: u8,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
-        let class_id = args.class_number.into();
-        let class = self
-            .classes
-            .class(class_id)
-            .ok_or(GreatError::GcpClassNotFound(class_id))?;
-        let verb = class
-            .verb(args.verb_number.into())
-            .ok_or(GreatError::GcpVerbNotFound(
-                class_id,
-                args.verb_number.into(),
-            ))?;
-        match args.descriptor.into() {
-            VerbDescriptor::InSignature => Ok(verb.in_signature.as_bytes().into_iter().copied()),
-            VerbDescriptor::InParamNames => Ok(verb.in_param_names.as_bytes().into_iter().copied()),
-            VerbDescriptor::OutSignature => Ok(verb.out_signature.as_bytes().into_iter().copied()),
-            VerbDescriptor::OutParamNames => {
-                Ok(verb.out_param_names.as_bytes().into_iter().copied())
+    pub fn get_verb_descriptor(
+        &self,
+        class_number: U32<LittleEndian>,
+        verb_number: U32<LittleEndian>,
+        descriptor_number: u8,
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        let verb = self.verb(class_number, verb_number)?;
+        let descriptor = match descriptor_number.into() {
+            VerbDescriptor::InSignature => verb.in_signature,
+            VerbDescriptor::InParamNames => verb.in_param_names,
+            VerbDescriptor::OutSignature => verb.out_signature,
+            VerbDescriptor::OutParamNames => verb.out_param_names,
+            VerbDescriptor::Doc => verb.doc,
+            VerbDescriptor::Unknown(value) => {
+                return Err(GreatError::GcpUnknownVerbDescriptor(value))
             }
-            VerbDescriptor::Doc => Ok(verb.doc.as_bytes().into_iter().copied()),
-            VerbDescriptor::Unknown(value) => Err(GreatError::GcpUnknownVerbDescriptor(value)),
-        }
+        };
+        response.write(descriptor.as_bytes())
     }
 
-    pub fn get_class_name(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
-        trace!("  get_class_name: {:?}", arguments);
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            class_number: U32<LittleEndian>,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
-        let class_id = args.class_number.into();
-        let class = self
-            .classes
-            .class(class_id)
-            .ok_or(GreatError::GcpClassNotFound(class_id))?;
-        Ok(class.name.as_bytes().iter().copied())
+    pub fn get_class_name(
+        &self,
+        class_number: U32<LittleEndian>,
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        trace!("  get_class_name: {}", class_number);
+        let class = self.class(class_number)?;
+        response.write(class.name.as_bytes())
     }
 
-    pub fn get_class_docs(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            class_number: U32<LittleEndian>,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
-        let class_id = args.class_number.into();
-        let class = self
-            .classes
+    pub fn get_class_docs(
+        &self,
+        class_number: U32<LittleEndian>,
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        let class = self.class(class_number)?;
+        response.write(class.docs.as_bytes())
+    }
+
+    fn class(&self, class_number: U32<LittleEndian>) -> GreatResult<&'static gcp::Class> {
+        let class_id = class_number.into();
+        self.classes
             .class(class_id)
-            .ok_or(GreatError::GcpClassNotFound(class_id))?;
-        Ok(class.docs.as_bytes().into_iter().copied())
+            .ok_or(GreatError::GcpClassNotFound(class_id))
+    }
+
+    fn verb(
+        &self,
+        class_number: U32<LittleEndian>,
+        verb_number: U32<LittleEndian>,
+    ) -> GreatResult<&'static Verb> {
+        let class = self.class(class_number)?;
+        class
+            .verb(verb_number.get())
+            .ok_or(GreatError::GcpVerbNotFound(class.id, verb_number.get()))
     }
 }
 
 // - dispatch -----------------------------------------------------------------
 
-use crate::gcp::{iter_to_response, GcpResponse, GCP_MAX_RESPONSE_LENGTH};
-
-use core::{array, iter};
-
-impl Core {
+impl Core<'_, '_> {
     pub fn dispatch(
         &self,
         verb_number: u32,
         arguments: &[u8],
-        response_buffer: [u8; GCP_MAX_RESPONSE_LENGTH],
-    ) -> GreatResult<GcpResponse> {
-        match verb_number {
-            0x0 => {
-                // core::read_board_id
-                let iter = self.read_board_id(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x1 => {
-                // core::read_version_string
-                let iter = self.read_version_string(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x2 => {
-                // core::read_part_id
-                let iter = self.read_part_id(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x3 => {
-                // core::read_serial_number
-                let iter = self.read_serial_number(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x4 => {
-                // core::get_available_classes
-                let iter = self.get_available_classes(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x5 => {
-                // core::get_available_verbs
-                let iter = self.get_available_verbs(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x6 => {
-                // core::get_verb_name
-                let iter = self.get_verb_name(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x7 => {
-                // core::get_verb_descriptor
-                let iter = self.get_verb_descriptor(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x8 => {
-                // core::get_class_name
-                let iter = self.get_class_name(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x9 => {
-                // core::get_class_docs
-                let iter = self.get_class_docs(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-
-            verb_number => Err(GreatError::GcpVerbNotFound(
-                gcp::class::ClassId::core,
-                verb_number,
-            )),
-        }
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        dispatch(self, verb_number, arguments, response)
     }
 }
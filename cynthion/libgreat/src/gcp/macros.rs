@@ -0,0 +1,464 @@
+//! Declarative definition of GCP classes
+
+/// Define a GCP class, its verb table and dispatch function together
+///
+/// Each verb is declared once with its id, name, signatures, parameter
+/// names and handler. The macro generates:
+///
+/// * `CLASS`: the [`crate::gcp::Class`] metadata
+/// * `CLASS_DOCS`: the class documentation
+/// * `VERBS`: the [`crate::gcp::Verb`] table
+/// * `dispatch`: decodes the arguments of a verb and calls its handler
+///
+/// The argument types of each verb are checked against its `in`
+/// signature when the class is compiled, so a mismatched verb fails to
+/// build:
+///
+/// ```compile_fail
+/// use zerocopy::{LittleEndian, U16};
+///
+/// libgreat::gcp_class! {
+///     class: gpio,
+///     docs: "",
+///     verbs: [
+///         0x0 => set {
+///             in: "<BH" (value: U16<LittleEndian>),
+///             out: "" (),
+///             handler: set,
+///         },
+///     ]
+/// }
+///
+/// fn set(
+///     _value: U16<LittleEndian>,
+///     _response: &mut libgreat::gcp::GcpResponseWriter,
+/// ) -> libgreat::error::GreatResult<()> {
+///     Ok(())
+/// }
+/// ```
+///
+/// All strings are NUL terminated by the macro. Verb docs and parameter
+/// names that are left empty are sent as `"*"`, matching the `NULL` of
+/// the C implementation.
+///
+/// Verb arguments are decoded into a `zerocopy` struct made up of the
+/// given fields, which are then passed to the handler in order,
+/// followed by the [`crate::gcp::GcpResponseWriter`] the handler
+/// serialises its response into. An
+/// argument such as `..data` after the field list passes any bytes
+/// remaining after the fields to the handler as a `&[u8]`. Arguments
+/// that do not match the verb's `in` signature are rejected with
+/// [`crate::error::GreatError::BadMessage`] before they are decoded:
+///
+/// ```ignore
+/// libgreat::gcp_class! {
+///     class: firmware,
+///     docs: "Common API for updating firmware on a libgreat device.",
+///     verbs: [
+///         0x0 => initialize {
+///             in: "" (),
+///             out: "<II" (page_size, total_size),
+///             handler: initialize,
+///         },
+///         0x3 => write_page {
+///             doc: "Write the provided data to a single firmware flash page.",
+///             in: "<I*X" (address: U32<LittleEndian>) ..data,
+///             out: "" (),
+///             handler: write_page,
+///         },
+///     ]
+/// }
+///
+/// fn initialize(response: &mut GcpResponseWriter) -> GreatResult<()> { ... }
+/// fn write_page(address: U32<LittleEndian>, data: &[u8], response: &mut GcpResponseWriter) -> GreatResult<()> { ... }
+/// ```
+///
+/// If the class declares a `context: <type>,` after its docs the
+/// generated dispatch function takes the context as its first argument
+/// and passes it on to each handler, e.g. `context: &mut Moondancer,`
+/// together with handlers declared as `handler: Moondancer::connect,`.
+#[macro_export]
+macro_rules! gcp_class {
+    (
+        class: $class:ident,
+        docs: $docs:literal,
+        $(context: $context:ty,)?
+        verbs: [
+            $(
+                $id:literal => $name:ident {
+                    $(doc: $doc:literal,)?
+                    in: $in_signature:literal ($($in_field:ident : $in_type:ty),*) $(..$in_rest:ident)?,
+                    out: $out_signature:literal ($($out_field:ident),*),
+                    handler: $handler:path,
+                }
+            ),* $(,)?
+        ] $(,)?
+    ) => {
+        pub static CLASS: $crate::gcp::Class = $crate::gcp::Class {
+            id: $crate::gcp::ClassId::$class,
+            name: concat!(stringify!($class), "\0"),
+            docs: CLASS_DOCS,
+            verbs: &VERBS,
+        };
+
+        pub static CLASS_DOCS: &str = concat!($docs, "\0");
+
+        pub static VERBS: [$crate::gcp::Verb; $crate::gcp_class!(@count $($name)*)] = [
+            $(
+                $crate::gcp::Verb {
+                    id: $id,
+                    name: concat!(stringify!($name), "\0"),
+                    doc: $crate::gcp_class!(@doc $($doc)?),
+                    in_signature: concat!($in_signature, "\0"),
+                    in_param_names: $crate::gcp_class!(@names $($in_field)* $($in_rest)?),
+                    out_signature: concat!($out_signature, "\0"),
+                    out_param_names: $crate::gcp_class!(@names $($out_field)*),
+                },
+            )*
+        ];
+
+        // check that the argument types of each verb agree with its
+        // declared in_signature when the class is compiled
+        $(
+            const _: () = assert!(
+                $crate::gcp::signature::check_signature_arguments(
+                    $in_signature,
+                    &[$(<$in_type as $crate::gcp::signature::SignatureFormat>::FORMAT),*],
+                    $crate::gcp_class!(@has_rest $($in_rest)?),
+                ),
+                concat!("signature does not match arguments of verb: ", stringify!($name))
+            );
+        )*
+
+        $crate::gcp_class!(
+            @dispatch $class [$($context)?]
+            $($id => $handler, $in_signature ($($in_field: $in_type),*) [$($in_rest)?]);*
+        );
+    };
+
+    // - verb table --
+
+    (@count) => { 0 };
+    (@count $head:ident $($tail:ident)*) => { 1 + $crate::gcp_class!(@count $($tail)*) };
+
+    (@doc) => { "*\0" };
+    (@doc $doc:literal) => { concat!($doc, "\0") };
+
+    (@names) => { "*\0" };
+    (@names $first:ident $($rest:ident)*) => {
+        concat!(stringify!($first), $(", ", stringify!($rest),)* "\0")
+    };
+
+    (@has_rest) => { false };
+    (@has_rest $rest:ident) => { true };
+
+    // - dispatch --
+
+    (
+        @dispatch $class:ident []
+        $($id:literal => $handler:path, $signature:literal ($($field:ident : $type:ty),*) [$($rest:ident)?]);*
+    ) => {
+        pub fn dispatch(
+            verb_number: u32,
+            arguments: &[u8],
+            response: &mut $crate::gcp::GcpResponseWriter,
+        ) -> $crate::error::GreatResult<()> {
+            match verb_number {
+                $(
+                    $id => {
+                        $crate::gcp_class!(@validate $signature arguments);
+                        let (_args, _rest) =
+                            $crate::gcp_class!(@arguments arguments ($($field: $type),*) [$($rest)?]);
+                        $(let $rest = _rest;)?
+                        $handler($(_args.$field,)* $($rest,)? response)
+                    }
+                )*
+                verb_number => Err($crate::error::GreatError::GcpVerbNotFound(
+                    $crate::gcp::ClassId::$class,
+                    verb_number,
+                )),
+            }
+        }
+    };
+
+    (
+        @dispatch $class:ident [$context:ty]
+        $($id:literal => $handler:path, $signature:literal ($($field:ident : $type:ty),*) [$($rest:ident)?]);*
+    ) => {
+        pub fn dispatch(
+            context: $context,
+            verb_number: u32,
+            arguments: &[u8],
+            response: &mut $crate::gcp::GcpResponseWriter,
+        ) -> $crate::error::GreatResult<()> {
+            match verb_number {
+                $(
+                    $id => {
+                        $crate::gcp_class!(@validate $signature arguments);
+                        let (_args, _rest) =
+                            $crate::gcp_class!(@arguments arguments ($($field: $type),*) [$($rest)?]);
+                        $(let $rest = _rest;)?
+                        $handler(context, $(_args.$field,)* $($rest,)? response)
+                    }
+                )*
+                verb_number => Err($crate::error::GreatError::GcpVerbNotFound(
+                    $crate::gcp::ClassId::$class,
+                    verb_number,
+                )),
+            }
+        }
+    };
+
+    // - argument decoding --
+
+    // reject arguments that do not match the verb's signature
+    (@validate $signature:literal $arguments:ident) => {
+        let signature = $crate::gcp::signature::Signature::parse($signature)?;
+        signature.validate($arguments)?;
+    };
+
+    // no arguments
+    (@arguments $arguments:ident () []) => {
+        ((), $arguments)
+    };
+
+    // remaining bytes only
+    (@arguments $arguments:ident () [$rest:ident]) => {
+        ((), $arguments)
+    };
+
+    // fields only, the arguments must match their size exactly
+    (@arguments $arguments:ident ($($field:ident : $type:ty),+) []) => {{
+        #[repr(C)]
+        #[derive(::zerocopy::FromBytes, ::zerocopy::Unaligned)]
+        struct Args {
+            $($field: $type),+
+        }
+        let args = <Args as ::zerocopy::FromBytes>::read_from($arguments)
+            .ok_or($crate::error::GreatError::BadMessage)?;
+        (args, &$arguments[..0])
+    }};
+
+    // fields followed by the remaining bytes
+    (@arguments $arguments:ident ($($field:ident : $type:ty),+) [$rest:ident]) => {{
+        #[repr(C)]
+        #[derive(::zerocopy::FromBytes, ::zerocopy::Unaligned)]
+        struct Args {
+            $($field: $type),+
+        }
+        let length = ::core::mem::size_of::<Args>();
+        if $arguments.len() < length {
+            return Err($crate::error::GreatError::BadMessage);
+        }
+        let (head, rest) = $arguments.split_at(length);
+        let args = <Args as ::zerocopy::FromBytes>::read_from(head)
+            .ok_or($crate::error::GreatError::BadMessage)?;
+        (args, rest)
+    }};
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{GreatError, GreatResult};
+    use crate::gcp::{ClassId, GcpResponseWriter, GCP_MAX_RESPONSE_LENGTH};
+
+    use zerocopy::{LittleEndian, U16};
+
+    // - fixtures -------------------------------------------------------------
+
+    #[derive(Default)]
+    struct Counter {
+        count: u16,
+    }
+
+    impl Counter {
+        fn get(&self, response: &mut GcpResponseWriter) -> GreatResult<()> {
+            response.write(&self.count.to_le_bytes())
+        }
+
+        fn add(
+            &mut self,
+            amount: U16<LittleEndian>,
+            times: u8,
+            _response: &mut GcpResponseWriter,
+        ) -> GreatResult<()> {
+            self.count += amount.get() * u16::from(times);
+            Ok(())
+        }
+
+        fn add_all(&mut self, amounts: &[u8], response: &mut GcpResponseWriter) -> GreatResult<()> {
+            self.count += amounts.iter().map(|&amount| u16::from(amount)).sum::<u16>();
+            response.write(amounts)
+        }
+
+        fn echo(
+            &mut self,
+            offset: u8,
+            data: &[u8],
+            response: &mut GcpResponseWriter,
+        ) -> GreatResult<()> {
+            response.extend(data.iter().skip(offset.into()).copied())
+        }
+    }
+
+    mod counter {
+        use super::Counter;
+        use zerocopy::{LittleEndian, U16};
+
+        crate::gcp_class! {
+            class: firmware,
+            docs: "Counts things.",
+            context: &mut Counter,
+            verbs: [
+                0x0 => get {
+                    doc: "Return the count.",
+                    in: "" (),
+                    out: "<H" (count),
+                    handler: Counter::get,
+                },
+                0x1 => add {
+                    in: "<HB" (amount: U16<LittleEndian>, times: u8),
+                    out: "" (),
+                    handler: Counter::add,
+                },
+                0x2 => add_all {
+                    in: "<*B" () ..amounts,
+                    out: "<*B" (amounts),
+                    handler: Counter::add_all,
+                },
+                0x4 => echo {
+                    in: "<B*X" (offset: u8) ..data,
+                    out: "<*X" (data),
+                    handler: Counter::echo,
+                },
+            ]
+        }
+    }
+
+    mod constant {
+        use crate::error::GreatResult;
+        use crate::gcp::GcpResponseWriter;
+
+        crate::gcp_class! {
+            class: gpio,
+            docs: "",
+            verbs: [
+                0x0 => get {
+                    in: "" (),
+                    out: "<B" (value),
+                    handler: get,
+                },
+            ]
+        }
+
+        fn get(response: &mut GcpResponseWriter) -> GreatResult<()> {
+            response.write(&[0x2a])
+        }
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_class_definition() {
+        assert_eq!(counter::CLASS.id, ClassId::firmware);
+        assert_eq!(counter::CLASS.name, "firmware\0");
+        assert_eq!(counter::CLASS_DOCS, "Counts things.\0");
+        assert_eq!(counter::CLASS.verbs.len(), 4);
+
+        let get = counter::CLASS.verb(0x0).unwrap();
+        assert_eq!(get.name, "get\0");
+        assert_eq!(get.doc, "Return the count.\0");
+        assert_eq!(get.in_signature, "\0");
+        assert_eq!(get.in_param_names, "*\0");
+        assert_eq!(get.out_signature, "<H\0");
+        assert_eq!(get.out_param_names, "count\0");
+
+        let add = counter::CLASS.verb(0x1).unwrap();
+        assert_eq!(add.doc, "*\0");
+        assert_eq!(add.in_param_names, "amount, times\0");
+        assert_eq!(add.out_param_names, "*\0");
+
+        let echo = counter::CLASS.verb(0x4).unwrap();
+        assert_eq!(echo.in_signature, "<B*X\0");
+        assert_eq!(echo.in_param_names, "offset, data\0");
+        assert!(counter::CLASS.verb(0x3).is_none());
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let mut context = Counter::default();
+        let mut response_buffer = [0_u8; GCP_MAX_RESPONSE_LENGTH];
+        let mut response = GcpResponseWriter::new(&mut response_buffer);
+
+        counter::dispatch(&mut context, 0x1, &[0x02, 0x01, 0x03], &mut response).unwrap();
+        assert_eq!(response.len(), 0);
+        assert_eq!(context.count, 0x0306);
+
+        counter::dispatch(&mut context, 0x2, &[1, 2], &mut response).unwrap();
+        assert_eq!(response.as_slice(), &[1, 2]);
+
+        response.clear();
+        counter::dispatch(&mut context, 0x0, &[], &mut response).unwrap();
+        assert_eq!(response.as_slice(), &0x0309_u16.to_le_bytes());
+
+        response.clear();
+        counter::dispatch(&mut context, 0x4, &[1, 10, 11, 12], &mut response).unwrap();
+        assert_eq!(response.as_slice(), &[11, 12]);
+    }
+
+    #[test]
+    fn test_dispatch_errors() {
+        let mut context = Counter::default();
+        let mut response_buffer = [0_u8; GCP_MAX_RESPONSE_LENGTH];
+        let mut response = GcpResponseWriter::new(&mut response_buffer);
+
+        // fixed size arguments must match exactly
+        let result = counter::dispatch(&mut context, 0x1, &[0x02, 0x01], &mut response);
+        assert!(matches!(result, Err(GreatError::BadMessage)));
+        let result = counter::dispatch(&mut context, 0x1, &[0x02, 0x01, 0x03, 0x04], &mut response);
+        assert!(matches!(result, Err(GreatError::BadMessage)));
+
+        // arguments followed by data must at least hold the fields
+        let result = counter::dispatch(&mut context, 0x4, &[], &mut response);
+        assert!(matches!(result, Err(GreatError::BadMessage)));
+
+        let result = counter::dispatch(&mut context, 0x3, &[], &mut response);
+        assert!(matches!(
+            result,
+            Err(GreatError::GcpVerbNotFound(ClassId::firmware, 0x3))
+        ));
+
+        // responses that do not fit are an error
+        let mut response_buffer = [0_u8; 2];
+        let mut response = GcpResponseWriter::new(&mut response_buffer);
+        let result = counter::dispatch(&mut context, 0x4, &[0, 1, 2, 3], &mut response);
+        assert!(matches!(result, Err(GreatError::NoBufferSpaceAvailable)));
+    }
+
+    #[test]
+    fn test_dispatch_validates_signature() {
+        let mut context = Counter::default();
+        let mut response_buffer = [0_u8; GCP_MAX_RESPONSE_LENGTH];
+        let mut response = GcpResponseWriter::new(&mut response_buffer);
+
+        // verbs without arguments reject any
+        let result = counter::dispatch(&mut context, 0x0, &[0x01], &mut response);
+        assert!(matches!(result, Err(GreatError::BadMessage)));
+    }
+
+    #[test]
+    fn test_dispatch_without_context() {
+        let mut response_buffer = [0_u8; GCP_MAX_RESPONSE_LENGTH];
+        let mut response = GcpResponseWriter::new(&mut response_buffer);
+
+        constant::dispatch(0x0, &[], &mut response).unwrap();
+        assert_eq!(response.as_slice(), &[0x2a]);
+        let result = constant::dispatch(0x1, &[], &mut response);
+        assert!(matches!(
+            result,
+            Err(GreatError::GcpVerbNotFound(ClassId::gpio, 0x1))
+        ));
+    }
+}
@@ -0,0 +1,273 @@
+//! GCP responses
+//!
+//! Verbs serialise their response directly into a caller-owned buffer
+//! through a [`GcpResponseWriter`]. Once the verb has returned, the
+//! response is sent to the host in packets, tracked by a
+//! [`GcpResponse`]:
+//!
+//! ```ignore
+//! let mut writer = GcpResponseWriter::new(&mut response_buffer);
+//! core.dispatch(verb_number, arguments, &mut writer)?;
+//! let mut response = GcpResponse::new(writer.len());
+//!
+//! // host is ready to receive the response
+//! response.truncate(setup_packet.length as usize, EP_MAX_PACKET_SIZE);
+//! if let Some(packet) = response.next_packet(&response_buffer, EP_MAX_PACKET_SIZE) {
+//!     hal_driver.write(0, packet.iter())?;
+//! }
+//!
+//! // ... then, every time the host has read a packet:
+//! if let Some(packet) = response.next_packet(&response_buffer, EP_MAX_PACKET_SIZE) {
+//!     hal_driver.write(0, packet.iter())?;
+//! }
+//! ```
+
+use crate::error::{GreatError, GreatResult};
+
+use zerocopy::AsBytes;
+
+/// Largest response a verb may return, matches the length pygreat
+/// requests for the response data stage.
+pub const GCP_MAX_RESPONSE_LENGTH: usize = 4096;
+
+// - GcpResponseWriter --------------------------------------------------------
+
+/// Writes a verb's response into a caller-owned buffer
+///
+/// Writes that do not fit into the remaining space fail with
+/// [`GreatError::NoBufferSpaceAvailable`] and leave the response
+/// unchanged.
+pub struct GcpResponseWriter<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+}
+
+impl<'a> GcpResponseWriter<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, length: 0 }
+    }
+
+    /// Number of bytes written.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Number of bytes that can still be written.
+    pub fn remaining(&self) -> usize {
+        self.buffer.len() - self.length
+    }
+
+    /// The bytes written so far.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer[..self.length]
+    }
+
+    pub fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> GreatResult<()> {
+        if bytes.len() > self.remaining() {
+            return Err(GreatError::NoBufferSpaceAvailable);
+        }
+        let end = self.length + bytes.len();
+        self.buffer[self.length..end].copy_from_slice(bytes);
+        self.length = end;
+        Ok(())
+    }
+
+    /// Write the in-memory representation of a value, e.g. a
+    /// `U32<LittleEndian>` or a `#[repr(C)]` struct.
+    pub fn write_as_bytes<T>(&mut self, value: &T) -> GreatResult<()>
+    where
+        T: AsBytes + ?Sized,
+    {
+        self.write(value.as_bytes())
+    }
+
+    /// Write all bytes of an iterator.
+    pub fn extend<I>(&mut self, iter: I) -> GreatResult<()>
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        let start = self.length;
+        for byte in iter {
+            if self.length == self.buffer.len() {
+                self.length = start;
+                return Err(GreatError::NoBufferSpaceAvailable);
+            }
+            self.buffer[self.length] = byte;
+            self.length += 1;
+        }
+        Ok(())
+    }
+}
+
+// - GcpResponse --------------------------------------------------------------
+
+/// Progress of a response being sent to the host
+///
+/// The response is split into packets of up to `max_packet_size`
+/// bytes. If it is shorter than the length requested by the host and
+/// a multiple of `max_packet_size` it is terminated by a zero length
+/// packet.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GcpResponse {
+    length: usize,
+    offset: usize,
+    zlp_pending: bool,
+}
+
+impl GcpResponse {
+    /// A response of `length` bytes.
+    pub fn new(length: usize) -> Self {
+        Self {
+            length,
+            offset: 0,
+            zlp_pending: length == 0,
+        }
+    }
+
+    /// Length of the response.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Number of bytes sent so far.
+    pub fn bytes_sent(&self) -> usize {
+        self.offset
+    }
+
+    /// Limit the response to the length requested by the host.
+    pub fn truncate(&mut self, requested: usize, max_packet_size: usize) {
+        let max_packet_size = usize::max(max_packet_size, 1);
+        self.length = usize::min(self.length, requested);
+        self.zlp_pending =
+            self.length == 0 || (self.length < requested && self.length % max_packet_size == 0);
+    }
+
+    /// Returns true once every packet of the response has been
+    /// returned by [`GcpResponse::next_packet`].
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.length && !self.zlp_pending
+    }
+
+    /// Stop sending the response after the bytes sent so far, ending
+    /// it with a zero length packet if the last packet was full.
+    pub fn cancel(&mut self, max_packet_size: usize) {
+        let max_packet_size = usize::max(max_packet_size, 1);
+        self.length = self.offset;
+        self.zlp_pending = self.offset > 0 && self.offset % max_packet_size == 0;
+    }
+
+    /// Returns the next packet of the response held by `buffer`.
+    pub fn next_packet<'b>(
+        &mut self,
+        buffer: &'b [u8],
+        max_packet_size: usize,
+    ) -> Option<&'b [u8]> {
+        let max_packet_size = usize::max(max_packet_size, 1);
+        if self.offset < self.length {
+            let end = usize::min(self.offset + max_packet_size, self.length);
+            let packet = &buffer[self.offset..end];
+            self.offset = end;
+            Some(packet)
+        } else if self.zlp_pending {
+            self.zlp_pending = false;
+            Some(&buffer[..0])
+        } else {
+            None
+        }
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use zerocopy::{LittleEndian, U32};
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_writer() {
+        let mut buffer = [0; 8];
+        let mut writer = GcpResponseWriter::new(&mut buffer);
+        writer.write(&[1, 2]).unwrap();
+        writer
+            .write_as_bytes(&U32::<LittleEndian>::new(0x06050403))
+            .unwrap();
+        assert_eq!(writer.as_slice(), &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(writer.remaining(), 2);
+
+        // overflow leaves the response unchanged
+        assert!(matches!(
+            writer.write(&[7, 8, 9]),
+            Err(GreatError::NoBufferSpaceAvailable)
+        ));
+        assert!(matches!(
+            writer.extend([7, 8, 9]),
+            Err(GreatError::NoBufferSpaceAvailable)
+        ));
+        assert_eq!(writer.len(), 6);
+
+        writer.extend([7, 8]).unwrap();
+        assert_eq!(writer.as_slice(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_response_packets() {
+        let buffer: [u8; 10] = core::array::from_fn(|n| n as u8);
+
+        let mut response = GcpResponse::new(10);
+        response.truncate(4096, 4);
+        assert_eq!(response.next_packet(&buffer, 4), Some(&buffer[0..4]));
+        assert_eq!(response.next_packet(&buffer, 4), Some(&buffer[4..8]));
+        assert!(!response.is_complete());
+        assert_eq!(response.next_packet(&buffer, 4), Some(&buffer[8..10]));
+        assert!(response.is_complete());
+        assert_eq!(response.next_packet(&buffer, 4), None);
+
+        // responses shorter than requested end with a short packet
+        let mut response = GcpResponse::new(8);
+        response.truncate(4096, 4);
+        assert_eq!(response.next_packet(&buffer, 4), Some(&buffer[0..4]));
+        assert_eq!(response.next_packet(&buffer, 4), Some(&buffer[4..8]));
+        assert_eq!(response.next_packet(&buffer, 4), Some(&buffer[..0]));
+        assert!(response.is_complete());
+
+        // but not if the host asked for exactly that many bytes
+        let mut response = GcpResponse::new(10);
+        response.truncate(8, 4);
+        assert_eq!(response.len(), 8);
+        assert_eq!(response.next_packet(&buffer, 4), Some(&buffer[0..4]));
+        assert_eq!(response.next_packet(&buffer, 4), Some(&buffer[4..8]));
+        assert_eq!(response.next_packet(&buffer, 4), None);
+
+        let mut response = GcpResponse::new(0);
+        assert_eq!(response.next_packet(&buffer, 4), Some(&buffer[..0]));
+        assert!(response.is_complete());
+
+        // cancelled responses end after the packets already sent
+        let mut response = GcpResponse::new(10);
+        response.truncate(4096, 4);
+        assert_eq!(response.next_packet(&buffer, 4), Some(&buffer[0..4]));
+        response.cancel(4);
+        assert_eq!(response.next_packet(&buffer, 4), Some(&buffer[..0]));
+        assert!(response.is_complete());
+    }
+}
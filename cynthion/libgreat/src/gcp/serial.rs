@@ -0,0 +1,700 @@
+//! GCP transport over a serial port
+//!
+//! Frames are COBS encoded and delimited by a zero byte. Once decoded
+//! a frame starts with the channel it belongs to and a sequence
+//! number, and ends with a CRC-16 of everything before it:
+//!
+//! ```text
+//! | channel: u8 | sequence: u8 | payload | crc: u16 |
+//! ```
+//!
+//! Log output and GCP traffic share the wire on separate channels.
+//! The payload of a GCP command is the command itself. Its response
+//! carries the sequence number of the command and a status of zero
+//! followed by the response, or the errno of the error the command
+//! failed with:
+//!
+//! ```text
+//! command:  | class: u32 | verb: u32 | arguments |
+//! response: | status: u32 | response |
+//! ```
+//!
+//! Frames that fail their CRC, or are too large to receive, are
+//! answered with an empty frame on the NAK channel carrying the
+//! sequence number of the last command received. The host should
+//! retransmit its command on a NAK, or if it did not receive a response
+//! at all. A command with the sequence number of the previous one is a
+//! retransmit, and is answered with the previous response without being
+//! dispatched again.
+//!
+//! Commands that are still in progress once dispatched, see
+//! [`crate::gcp::GcpClass::poll`], are answered once they complete. The
+//! next command, on this or any other transport sharing the same
+//! [`GcpBuffers`], abandons the command in progress.
+//!
+//! ```ignore
+//! // for every byte received on the serial port, and on every
+//! // iteration of the main loop:
+//! if transport.receive(byte, &mut buffers, &mut classes)
+//!     || transport.poll(&mut buffers, &mut classes)
+//! {
+//!     let response = transport.response(&buffers).unwrap();
+//!     response.write(|byte| serial.write(byte));
+//! }
+//! ```
+
+use super::{Classes, GcpBuffers, Transport};
+
+use crate::error::{GreatError, GreatResult};
+
+use log::{trace, warn};
+
+/// Size of the channel, sequence number and CRC of every frame
+const FRAME_OVERHEAD: usize = 4;
+
+/// Size of the status at the start of every response
+const STATUS_LENGTH: usize = 4;
+
+// - channels -----------------------------------------------------------------
+
+/// Channel a frame belongs to
+#[repr(u8)]
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Channel {
+    Log = 0,
+    Gcp = 1,
+    Nak = 2,
+    Unknown(u8),
+}
+
+impl core::convert::From<u8> for Channel {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Channel::Log,
+            1 => Channel::Gcp,
+            2 => Channel::Nak,
+            _ => Channel::Unknown(value),
+        }
+    }
+}
+
+impl Channel {
+    pub const fn into_u8(&self) -> u8 {
+        match self {
+            Channel::Log => 0,
+            Channel::Gcp => 1,
+            Channel::Nak => 2,
+            Channel::Unknown(value) => *value,
+        }
+    }
+}
+
+// - CRC-16 -------------------------------------------------------------------
+
+/// Initial value of a CRC-16
+pub const CRC16_INIT: u16 = 0xffff;
+
+/// Update a CRC-16/CCITT-FALSE with the given bytes.
+pub fn crc16_update(mut crc: u16, bytes: &[u8]) -> u16 {
+    for &byte in bytes {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Returns the CRC-16/CCITT-FALSE of the given bytes.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    crc16_update(CRC16_INIT, bytes)
+}
+
+// - COBS ---------------------------------------------------------------------
+
+/// Streaming COBS encoder
+///
+/// Bytes are passed on to `write` a block of up to 254 bytes at a
+/// time, [`CobsEncoder::finish`] writes the last block and the frame
+/// delimiter.
+pub struct CobsEncoder<F>
+where
+    F: FnMut(u8),
+{
+    block: [u8; 254],
+    length: usize,
+    write: F,
+}
+
+impl<F> CobsEncoder<F>
+where
+    F: FnMut(u8),
+{
+    pub fn new(write: F) -> Self {
+        Self {
+            block: [0; 254],
+            length: 0,
+            write,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if byte == 0 {
+            self.flush();
+        } else {
+            self.block[self.length] = byte;
+            self.length += 1;
+            if self.length == self.block.len() {
+                self.flush();
+            }
+        }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(byte);
+        }
+    }
+
+    /// Write the last block followed by the frame delimiter.
+    pub fn finish(mut self) {
+        self.flush();
+        (self.write)(0);
+    }
+
+    fn flush(&mut self) {
+        (self.write)(self.length as u8 + 1);
+        for &byte in &self.block[..self.length] {
+            (self.write)(byte);
+        }
+        self.length = 0;
+    }
+}
+
+/// Decode a COBS encoded frame, without its delimiter, in place.
+///
+/// Returns the length of the decoded frame.
+pub fn cobs_decode(buffer: &mut [u8]) -> GreatResult<usize> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < buffer.len() {
+        let code = usize::from(buffer[read]);
+        if code == 0 || read + code > buffer.len() {
+            return Err(GreatError::BadMessage);
+        }
+        buffer.copy_within(read + 1..read + code, write);
+        write += code - 1;
+        read += code;
+        if code < 0xff && read < buffer.len() {
+            buffer[write] = 0;
+            write += 1;
+        }
+    }
+    Ok(write)
+}
+
+/// Write a frame holding the concatenation of `payload`.
+pub fn write_frame<F>(channel: Channel, sequence: u8, payload: &[&[u8]], write: F)
+where
+    F: FnMut(u8),
+{
+    let header = [channel.into_u8(), sequence];
+    let mut crc = crc16_update(CRC16_INIT, &header);
+    let mut encoder = CobsEncoder::new(write);
+    encoder.extend(&header);
+    for part in payload {
+        crc = crc16_update(crc, part);
+        encoder.extend(part);
+    }
+    encoder.extend(&crc.to_le_bytes());
+    encoder.finish();
+}
+
+// - SerialTransport ----------------------------------------------------------
+
+/// Serves GCP commands received on a serial port
+///
+/// Encoded commands must fit into the command buffer of the
+/// [`GcpBuffers`] they are received into.
+#[derive(Default)]
+pub struct SerialTransport {
+    receive_length: usize,
+    /// Skip bytes up to the next delimiter
+    discarding: bool,
+
+    /// Length of the last response, kept to answer retransmits
+    response_length: usize,
+    /// Sequence number of the last command
+    sequence: Option<u8>,
+    /// The last frame received was dropped
+    nak: bool,
+}
+
+impl SerialTransport {
+    pub const fn new() -> Self {
+        Self {
+            receive_length: 0,
+            discarding: false,
+            response_length: 0,
+            sequence: None,
+            nak: false,
+        }
+    }
+
+    /// Discard any partially received frame and forget the last
+    /// response.
+    pub fn reset(&mut self, buffers: &mut GcpBuffers, classes: &mut Classes) {
+        if buffers.is_held_by(Transport::Serial) {
+            buffers.release(classes);
+        }
+        self.receive_length = 0;
+        self.discarding = false;
+        self.sequence = None;
+        self.nak = false;
+    }
+
+    /// Handle a byte received on the serial port, dispatching each
+    /// command to `classes` once it has been received.
+    ///
+    /// Returns true once a command has been received and its response
+    /// is ready to be sent, or a frame has been dropped and a NAK is
+    /// ready to be sent.
+    pub fn receive(&mut self, byte: u8, buffers: &mut GcpBuffers, classes: &mut Classes) -> bool {
+        self.nak = false;
+        self.sync(buffers);
+        if byte != 0 {
+            if self.discarding {
+                return false;
+            } else if self.receive_length == 0 {
+                buffers.claim(Transport::Serial, classes);
+            }
+            let buffer = buffers.command_mut();
+            if self.receive_length == buffer.len() {
+                // frame does not fit, drop it
+                warn!(
+                    "GCP serial dropping frame larger than {} bytes",
+                    buffer.len()
+                );
+                self.receive_length = 0;
+                self.discarding = true;
+            } else {
+                buffer[self.receive_length] = byte;
+                self.receive_length += 1;
+            }
+            return false;
+        }
+
+        let length = self.receive_length;
+        let discarding = self.discarding;
+        self.receive_length = 0;
+        self.discarding = false;
+
+        if discarding {
+            self.nak = true;
+            return true;
+        } else if length == 0 {
+            return false;
+        }
+
+        let length = match Self::decode(&mut buffers.command_mut()[..length]) {
+            Ok(length) => length,
+            Err(e) => {
+                warn!("GCP serial dropping frame: {}", e);
+                self.nak = true;
+                return true;
+            }
+        };
+
+        let frame = &buffers.command()[..length];
+        let (channel, sequence) = (Channel::from(frame[0]), frame[1]);
+        if channel != Channel::Gcp {
+            trace!("GCP serial ignoring frame on channel {:?}", channel);
+            return false;
+        }
+
+        // a retransmit of the last command is answered with its response
+        if self.sequence == Some(sequence) {
+            return !buffers.is_pending(Transport::Serial);
+        }
+
+        // dispatching abandons the command in progress, if any
+        let result = buffers.dispatch(2..length, classes);
+        self.sequence = Some(sequence);
+
+        self.complete(result, buffers)
+    }
+
+    /// Continue the command in progress, if any.
+    ///
+    /// Returns true once it has completed and its response is ready to
+    /// be sent.
+    pub fn poll(&mut self, buffers: &mut GcpBuffers, classes: &mut Classes) -> bool {
+        self.nak = false;
+        self.sync(buffers);
+        if !buffers.is_pending(Transport::Serial) {
+            return false;
+        }
+
+        match buffers.poll(classes) {
+            Some(result) => self.complete(result, buffers),
+            None => false,
+        }
+    }
+
+    /// The response to the last command, or a NAK if the last frame
+    /// was dropped.
+    pub fn response<'b>(&self, buffers: &'b GcpBuffers) -> Option<SerialResponse<'b>> {
+        if self.nak {
+            return Some(SerialResponse {
+                channel: Channel::Nak,
+                sequence: self.sequence.unwrap_or(0),
+                payload: &[],
+            });
+        } else if !buffers.is_held_by(Transport::Serial) || buffers.is_pending(Transport::Serial) {
+            return None;
+        }
+        self.sequence.map(|sequence| SerialResponse {
+            channel: Channel::Gcp,
+            sequence,
+            payload: &buffers.response(STATUS_LENGTH)[..self.response_length],
+        })
+    }
+
+    /// Drop the frame being received, and forget the last response,
+    /// once another transport has claimed the buffers.
+    fn sync(&mut self, buffers: &GcpBuffers) {
+        if buffers.is_held_by(Transport::Serial) {
+            return;
+        }
+        if self.receive_length > 0 {
+            warn!("GCP serial frame abandoned for another transport");
+            self.receive_length = 0;
+            self.discarding = true;
+        }
+        self.sequence = None;
+    }
+
+    /// Write the status of a command, unless it is still in progress.
+    fn complete(&mut self, result: GreatResult<usize>, buffers: &mut GcpBuffers) -> bool {
+        let status = match result {
+            Ok(length) => {
+                self.response_length = STATUS_LENGTH + length;
+                0
+            }
+            Err(GreatError::OperationNowInProgress) => return false,
+            Err(e) => {
+                warn!("GCP serial error: failed to dispatch command {}", e);
+                self.response_length = STATUS_LENGTH;
+                e.errno()
+            }
+        };
+        buffers
+            .response_header_mut(STATUS_LENGTH)
+            .copy_from_slice(&status.to_le_bytes());
+
+        true
+    }
+
+    /// Decode and check the frame in `buffer`.
+    fn decode(buffer: &mut [u8]) -> GreatResult<usize> {
+        let length = cobs_decode(buffer)?;
+        if length < FRAME_OVERHEAD {
+            return Err(GreatError::BadMessage);
+        }
+        let (frame, crc) = buffer[..length].split_at(length - 2);
+        if crc16(frame).to_le_bytes() != crc {
+            return Err(GreatError::BadMessage);
+        }
+        Ok(length - 2)
+    }
+}
+
+/// Response to be sent in a frame on the GCP or NAK channel
+pub struct SerialResponse<'a> {
+    pub channel: Channel,
+    pub sequence: u8,
+    pub payload: &'a [u8],
+}
+
+impl SerialResponse<'_> {
+    pub fn write<F>(&self, write: F)
+    where
+        F: FnMut(u8),
+    {
+        write_frame(self.channel, self.sequence, &[self.payload], write);
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::firmware::BoardInformation;
+    use crate::gcp::{GcpClass, GCP_MAX_COMMAND_LENGTH};
+
+    // - fixtures -------------------------------------------------------------
+
+    const BOARD_INFORMATION: BoardInformation = BoardInformation {
+        board_id: [0x01, 0x00, 0x03, 0x04],
+        version_string: "v2023.0.1\0",
+        part_id: [0; 8],
+        serial_number: [0; 16],
+    };
+
+    /// core.read_board_id()
+    const COMMAND_READ_BOARD_ID: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
+
+    /// Large enough for a frame that does not fit the command buffer
+    const WIRE_LENGTH: usize = GCP_MAX_COMMAND_LENGTH + 64;
+
+    struct Wire {
+        bytes: [u8; WIRE_LENGTH],
+        length: usize,
+    }
+
+    impl Wire {
+        fn new() -> Self {
+            Self {
+                bytes: [0; WIRE_LENGTH],
+                length: 0,
+            }
+        }
+
+        fn write(&mut self, byte: u8) {
+            self.bytes[self.length] = byte;
+            self.length += 1;
+        }
+
+        /// Decode the frame on the wire, without its CRC.
+        fn frame(&mut self) -> &[u8] {
+            assert_eq!(self.bytes[self.length - 1], 0);
+            let length = cobs_decode(&mut self.bytes[..self.length - 1]).unwrap();
+            let (frame, crc) = self.bytes[..length].split_at(length - 2);
+            assert_eq!(crc16(frame).to_le_bytes(), crc);
+            frame
+        }
+    }
+
+    /// Send the frame on the `command` wire, writing any response to the
+    /// `response` wire.
+    fn send(
+        transport: &mut SerialTransport,
+        buffers: &mut GcpBuffers,
+        classes: &mut Classes,
+        command: &Wire,
+        response: &mut Wire,
+    ) {
+        for &byte in &command.bytes[..command.length] {
+            if transport.receive(byte, buffers, classes) {
+                let frame = transport.response(buffers).unwrap();
+                frame.write(|byte| response.write(byte));
+            }
+        }
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+        assert_eq!(crc16(&[]), CRC16_INIT);
+    }
+
+    #[test]
+    fn test_cobs() {
+        let mut payload = [0x11_u8; 300];
+        payload[0] = 0;
+        payload[10] = 0;
+        payload[299] = 0;
+
+        for length in [0, 1, 2, 11, 254, 255, 300] {
+            let mut wire = [0_u8; 310];
+            let mut count = 0;
+            let mut encoder = CobsEncoder::new(|byte| {
+                wire[count] = byte;
+                count += 1;
+            });
+            encoder.extend(&payload[..length]);
+            encoder.finish();
+
+            assert_eq!(wire[count - 1], 0);
+            assert!(wire[..count - 1].iter().all(|&byte| byte != 0));
+            let decoded = cobs_decode(&mut wire[..count - 1]).unwrap();
+            assert_eq!(&wire[..decoded], &payload[..length]);
+        }
+
+        assert!(matches!(
+            cobs_decode(&mut [0x05, 0x01]),
+            Err(GreatError::BadMessage)
+        ));
+    }
+
+    #[test]
+    fn test_serial_command() {
+        let mut classes: [&mut dyn GcpClass; 0] = [];
+        let mut classes = Classes::new(BOARD_INFORMATION, &mut classes);
+        let mut transport = SerialTransport::new();
+        let mut buffers = GcpBuffers::new();
+
+        let mut command = Wire::new();
+        write_frame(Channel::Gcp, 3, &[&COMMAND_READ_BOARD_ID], |byte| {
+            command.write(byte)
+        });
+
+        let mut response = Wire::new();
+        send(
+            &mut transport,
+            &mut buffers,
+            &mut classes,
+            &command,
+            &mut response,
+        );
+
+        // retransmits are answered with the previous response
+        let mut retransmit = Wire::new();
+        send(
+            &mut transport,
+            &mut buffers,
+            &mut classes,
+            &command,
+            &mut retransmit,
+        );
+        assert_eq!(
+            &retransmit.bytes[..retransmit.length],
+            &response.bytes[..response.length]
+        );
+
+        assert_eq!(
+            response.frame(),
+            &[1, 3, 0, 0, 0, 0, 0x01, 0x00, 0x03, 0x04]
+        );
+    }
+
+    #[test]
+    fn test_serial_errors() {
+        let mut classes: [&mut dyn GcpClass; 0] = [];
+        let mut classes = Classes::new(BOARD_INFORMATION, &mut classes);
+        let mut transport = SerialTransport::new();
+        let mut buffers = GcpBuffers::new();
+
+        // frames with a bad CRC are answered with a NAK
+        let mut command = Wire::new();
+        write_frame(Channel::Gcp, 1, &[&COMMAND_READ_BOARD_ID], |byte| {
+            command.write(byte)
+        });
+        command.bytes[4] ^= 0x40;
+        let mut response = Wire::new();
+        send(
+            &mut transport,
+            &mut buffers,
+            &mut classes,
+            &command,
+            &mut response,
+        );
+        assert_eq!(response.frame(), &[2, 0]);
+
+        // as are frames too large to receive
+        let mut oversize = Wire::new();
+        write_frame(
+            Channel::Gcp,
+            1,
+            &[&[0x11; GCP_MAX_COMMAND_LENGTH]],
+            |byte| oversize.write(byte),
+        );
+        let mut response = Wire::new();
+        send(
+            &mut transport,
+            &mut buffers,
+            &mut classes,
+            &oversize,
+            &mut response,
+        );
+        assert_eq!(response.frame(), &[2, 0]);
+
+        // frames on other channels are ignored
+        let mut log = Wire::new();
+        write_frame(Channel::Log, 2, &[b"hello"], |byte| log.write(byte));
+        let mut response = Wire::new();
+        send(
+            &mut transport,
+            &mut buffers,
+            &mut classes,
+            &log,
+            &mut response,
+        );
+        assert_eq!(response.length, 0);
+
+        // errors are reported as the status of the response
+        let mut command = Wire::new();
+        write_frame(Channel::Gcp, 3, &[&[0, 0, 0, 0, 0x42, 0, 0, 0]], |byte| {
+            command.write(byte)
+        });
+        send(
+            &mut transport,
+            &mut buffers,
+            &mut classes,
+            &command,
+            &mut response,
+        );
+        let errno = GreatError::GcpVerbNotFound(0.into(), 0x42).errno();
+        let mut expected = [1, 3, 0, 0, 0, 0];
+        expected[2..].copy_from_slice(&errno.to_le_bytes());
+        assert_eq!(response.frame(), &expected);
+    }
+
+    #[test]
+    fn test_serial_shared_buffers() {
+        let mut classes: [&mut dyn GcpClass; 0] = [];
+        let mut classes = Classes::new(BOARD_INFORMATION, &mut classes);
+        let mut transport = SerialTransport::new();
+        let mut buffers = GcpBuffers::new();
+
+        let mut command = Wire::new();
+        write_frame(Channel::Gcp, 5, &[&COMMAND_READ_BOARD_ID], |byte| {
+            command.write(byte)
+        });
+
+        // frames interrupted by another transport are answered with a NAK
+        for &byte in &command.bytes[..4] {
+            assert!(!transport.receive(byte, &mut buffers, &mut classes));
+        }
+        buffers.claim(Transport::Bulk, &mut classes);
+        for &byte in &command.bytes[4..command.length - 1] {
+            assert!(!transport.receive(byte, &mut buffers, &mut classes));
+        }
+        assert!(transport.receive(0, &mut buffers, &mut classes));
+        assert_eq!(transport.response(&buffers).unwrap().channel, Channel::Nak);
+
+        let mut response = Wire::new();
+        send(
+            &mut transport,
+            &mut buffers,
+            &mut classes,
+            &command,
+            &mut response,
+        );
+        assert_eq!(response.frame()[..2], [1, 5]);
+
+        // and the last response is forgotten, retransmits are dispatched again
+        buffers.claim(Transport::Bulk, &mut classes);
+        assert!(transport.response(&buffers).is_none());
+        let mut response = Wire::new();
+        send(
+            &mut transport,
+            &mut buffers,
+            &mut classes,
+            &command,
+            &mut response,
+        );
+        assert_eq!(
+            response.frame(),
+            &[1, 5, 0, 0, 0, 0, 0x01, 0x00, 0x03, 0x04]
+        );
+    }
+}
@@ -0,0 +1,1087 @@
+//! GCP verb signatures
+//!
+//! Verb signatures use pygreat's struct-format language, which extends
+//! the format strings of Python's `struct` module with:
+//!
+//! * `*` for an element repeated until the end of the message, e.g. `<*B`
+//! * `X` for a blob of bytes, e.g. `<8X`, where `X` or `*X` take all
+//!   remaining bytes
+//! * `S` for a NUL terminated string
+//! * `(...)` for tuple groups, e.g. `<*(BHB)`
+//!
+//! A signature of `"*"` is left unspecified and matches any message.
+//!
+//! ```ignore
+//! let signature = Signature::parse("<B*X\0")?;
+//! signature.validate(arguments)?;
+//!
+//! for value in signature.decode(arguments) {
+//!     match value? {
+//!         Value::U8(endpoint_number) => ...,
+//!         Value::Bytes(data) => ...,
+//!         _ => ...,
+//!     }
+//! }
+//! ```
+
+use crate::error::{GreatError, GreatResult};
+
+use zerocopy::{LittleEndian, I16, I32, I64, U16, U32, U64};
+
+// - Signature ----------------------------------------------------------------
+
+/// Byte order of a signature
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+/// Format of a signature element
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format<'a> {
+    /// `x`
+    Pad,
+    /// `c`
+    Char,
+    /// `?`
+    Bool,
+    /// `b`
+    I8,
+    /// `B`
+    U8,
+    /// `h`
+    I16,
+    /// `H`
+    U16,
+    /// `i` or `l`
+    I32,
+    /// `I` or `L`
+    U32,
+    /// `q`
+    I64,
+    /// `Q`
+    U64,
+    /// `f`
+    F32,
+    /// `d`
+    F64,
+    /// `X`
+    Bytes,
+    /// `S`
+    String,
+    /// `(...)`, holds the signature of the tuple's elements
+    Tuple(&'a str),
+}
+
+impl<'a> Format<'a> {
+    /// Returns the size in bytes of the format, or `None` if the size
+    /// depends on the message.
+    pub fn size(&self, byte_order: ByteOrder) -> Option<usize> {
+        use Format::*;
+        match self {
+            Pad | Char | Bool | I8 | U8 | Bytes => Some(1),
+            I16 | U16 => Some(2),
+            I32 | U32 | F32 => Some(4),
+            I64 | U64 | F64 => Some(8),
+            String => None,
+            Tuple(elements) => Signature::with_byte_order(elements, byte_order).fixed_size(),
+        }
+    }
+
+    const fn from_code(code: u8) -> GreatResult<Self> {
+        use Format::*;
+        match code {
+            b'x' => Ok(Pad),
+            b'c' => Ok(Char),
+            b'?' => Ok(Bool),
+            b'b' => Ok(I8),
+            b'B' => Ok(U8),
+            b'h' => Ok(I16),
+            b'H' => Ok(U16),
+            b'i' | b'l' => Ok(I32),
+            b'I' | b'L' => Ok(U32),
+            b'q' => Ok(I64),
+            b'Q' => Ok(U64),
+            b'f' => Ok(F32),
+            b'd' => Ok(F64),
+            b'X' => Ok(Bytes),
+            b'S' => Ok(String),
+            _ => Err(GreatError::InvalidArgument),
+        }
+    }
+
+    /// Returns the canonical format code, `(` for tuples.
+    const fn code(&self) -> u8 {
+        use Format::*;
+        match self {
+            Pad => b'x',
+            Char => b'c',
+            Bool => b'?',
+            I8 => b'b',
+            U8 => b'B',
+            I16 => b'h',
+            U16 => b'H',
+            I32 => b'i',
+            U32 => b'I',
+            I64 => b'q',
+            U64 => b'Q',
+            F32 => b'f',
+            F64 => b'd',
+            Bytes => b'X',
+            String => b'S',
+            Tuple(_) => b'(',
+        }
+    }
+}
+
+/// Number of times a signature element repeats
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Repeat {
+    Count(usize),
+    /// Repeats until the end of the message
+    Variable,
+}
+
+/// A single element of a signature, e.g. `2H` or `*(BHB)`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Element<'a> {
+    pub repeat: Repeat,
+    pub format: Format<'a>,
+}
+
+/// A parsed verb signature
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Signature<'a> {
+    byte_order: ByteOrder,
+    elements: &'a str,
+    unspecified: bool,
+}
+
+impl<'a> Signature<'a> {
+    /// Parse a signature, which may be NUL terminated.
+    pub fn parse(signature: &'a str) -> GreatResult<Self> {
+        let signature = signature.trim_end_matches('\0');
+        if signature == "*" {
+            return Ok(Self {
+                byte_order: ByteOrder::Little,
+                elements: "",
+                unspecified: true,
+            });
+        }
+
+        let (byte_order, elements) = match signature.as_bytes().first() {
+            Some(b'<' | b'=' | b'@') => (ByteOrder::Little, &signature[1..]),
+            Some(b'>' | b'!') => (ByteOrder::Big, &signature[1..]),
+            _ => (ByteOrder::Little, signature),
+        };
+        let signature = Self::with_byte_order(elements, byte_order);
+        signature.check_syntax()?;
+        Ok(signature)
+    }
+
+    fn with_byte_order(elements: &'a str, byte_order: ByteOrder) -> Self {
+        Self {
+            byte_order,
+            elements,
+            unspecified: false,
+        }
+    }
+
+    fn check_syntax(&self) -> GreatResult<()> {
+        let mut elements = self.elements();
+        while let Some(element) = elements.next_element()? {
+            // only the last element may take the remainder of the message
+            if element.repeat == Repeat::Variable && !elements.remaining.trim().is_empty() {
+                return Err(GreatError::InvalidArgument);
+            }
+            if let Format::Tuple(elements) = element.format {
+                Self::with_byte_order(elements, self.byte_order).check_syntax()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+
+    /// Returns true if the signature is `"*"`.
+    pub fn is_unspecified(&self) -> bool {
+        self.unspecified
+    }
+
+    pub fn elements(&self) -> Elements<'a> {
+        Elements {
+            remaining: self.elements,
+        }
+    }
+
+    /// Returns the size in bytes of messages matching the signature,
+    /// or `None` if the size depends on the message.
+    pub fn fixed_size(&self) -> Option<usize> {
+        if self.unspecified {
+            return None;
+        }
+        let mut size = 0;
+        for element in self.elements() {
+            match element.repeat {
+                Repeat::Count(count) => size += count * element.format.size(self.byte_order)?,
+                Repeat::Variable => return None,
+            }
+        }
+        Some(size)
+    }
+
+    /// Returns an iterator over the values of a message.
+    pub fn decode(&self, bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder {
+            signature: *self,
+            elements: self.elements(),
+            current: None,
+            bytes,
+            done: self.unspecified,
+        }
+    }
+
+    /// Check that a message matches the signature.
+    pub fn validate(&self, bytes: &[u8]) -> GreatResult<()> {
+        for value in self.decode(bytes) {
+            if let Value::Tuple(tuple) = value? {
+                for value in tuple {
+                    value?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns an encoder for writing a message into `buffer`.
+    pub fn encoder<'b>(&self, buffer: &'b mut [u8]) -> Encoder<'a, 'b> {
+        Encoder {
+            signature: *self,
+            elements: self.elements(),
+            current: None,
+            buffer,
+            length: 0,
+        }
+    }
+
+    /// Check that the given argument formats agree with the signature.
+    ///
+    /// `rest` is true if the arguments end with the remaining bytes of
+    /// the message. Arguments are decoded as little endian, so
+    /// multi-byte formats in big endian signatures are rejected. A
+    /// string can only be taken by the remaining bytes and must be the
+    /// last element of the signature.
+    pub fn check_arguments(&self, formats: &[Format], rest: bool) -> GreatResult<()> {
+        if self.unspecified {
+            return Ok(());
+        }
+        let elements = self.elements.as_bytes();
+        match check_formats(elements, 0, elements.len(), self.byte_order, formats, 0) {
+            Some((checked, rest_matched)) if checked == formats.len() && rest == rest_matched => {
+                Ok(())
+            }
+            _ => Err(GreatError::InvalidArgument),
+        }
+    }
+}
+
+/// Check that the given argument formats agree with a signature.
+///
+/// Equivalent to parsing the signature and calling
+/// [`Signature::check_arguments`], but can be evaluated at compile
+/// time. Used by [`gcp_class!`](crate::gcp_class) to reject verbs
+/// whose arguments do not match their signature.
+pub const fn check_signature_arguments(signature: &str, formats: &[Format], rest: bool) -> bool {
+    let bytes = signature.as_bytes();
+    let mut end = bytes.len();
+    while end > 0 && bytes[end - 1] == 0 {
+        end -= 1;
+    }
+    if end == 1 && bytes[0] == b'*' {
+        return true;
+    }
+
+    let (start, byte_order) = if end == 0 {
+        (0, ByteOrder::Little)
+    } else {
+        match bytes[0] {
+            b'<' | b'=' | b'@' => (1, ByteOrder::Little),
+            b'>' | b'!' => (1, ByteOrder::Big),
+            _ => (0, ByteOrder::Little),
+        }
+    };
+    match check_formats(bytes, start, end, byte_order, formats, 0) {
+        Some((checked, rest_matched)) => checked == formats.len() && rest == rest_matched,
+        None => false,
+    }
+}
+
+/// Match the signature elements in `bytes[start..end]` against
+/// `formats`, starting at `formats[checked]`.
+///
+/// Returns the number of formats checked so far and whether the last
+/// element takes the remaining bytes of the message, or `None` if the
+/// formats do not match or the signature is malformed.
+const fn check_formats(
+    bytes: &[u8],
+    start: usize,
+    end: usize,
+    byte_order: ByteOrder,
+    formats: &[Format],
+    mut checked: usize,
+) -> Option<(usize, bool)> {
+    let mut position = start;
+    loop {
+        position = skip_whitespace(bytes, position, end);
+        if position == end {
+            return Some((checked, false));
+        }
+
+        // repeat
+        let mut count = 0;
+        let mut counted = false;
+        let mut variable = false;
+        if bytes[position] == b'*' {
+            variable = true;
+            position += 1;
+        } else {
+            while position < end && bytes[position].is_ascii_digit() {
+                count = count * 10 + (bytes[position] - b'0') as usize;
+                counted = true;
+                position += 1;
+            }
+        }
+        if position == end {
+            return None;
+        }
+
+        // format
+        let code = bytes[position];
+        let (format, tuple_start, tuple_end) = if code == b'(' {
+            let mut depth = 0;
+            let mut close = position;
+            while close < end {
+                match bytes[close] {
+                    b'(' => depth += 1,
+                    b')' => depth -= 1,
+                    _ => (),
+                }
+                if depth == 0 {
+                    break;
+                }
+                close += 1;
+            }
+            if close == end {
+                return None;
+            }
+            let tuple = (Format::Tuple(""), position + 1, close);
+            position = close + 1;
+            tuple
+        } else {
+            position += 1;
+            match Format::from_code(code) {
+                Ok(format) => (format, 0, 0),
+                Err(_) => return None,
+            }
+        };
+
+        // a blob without a length takes all remaining bytes
+        if !counted && !variable {
+            match format {
+                Format::Bytes => variable = true,
+                _ => count = 1,
+            }
+        }
+
+        // only the last element may take the remainder of the message
+        if variable || (count == 1 && matches!(format, Format::String)) {
+            return if skip_whitespace(bytes, position, end) == end {
+                Some((checked, true))
+            } else {
+                None
+            };
+        }
+        if matches!(format, Format::String | Format::Bytes) {
+            return None;
+        }
+
+        let mut index = 0;
+        while index < count {
+            match format {
+                Format::Tuple(_) => {
+                    match check_formats(bytes, tuple_start, tuple_end, byte_order, formats, checked)
+                    {
+                        Some((tuple_checked, false)) => checked = tuple_checked,
+                        _ => return None,
+                    }
+                }
+                // arguments are decoded as little endian
+                Format::Pad | Format::Char | Format::Bool | Format::I8 | Format::U8 => {
+                    if checked == formats.len() || formats[checked].code() != format.code() {
+                        return None;
+                    }
+                    checked += 1;
+                }
+                _ => {
+                    if matches!(byte_order, ByteOrder::Big)
+                        || checked == formats.len()
+                        || formats[checked].code() != format.code()
+                    {
+                        return None;
+                    }
+                    checked += 1;
+                }
+            }
+            index += 1;
+        }
+    }
+}
+
+const fn skip_whitespace(bytes: &[u8], mut position: usize, end: usize) -> usize {
+    while position < end && bytes[position].is_ascii_whitespace() {
+        position += 1;
+    }
+    position
+}
+
+// - Elements -----------------------------------------------------------------
+
+/// Iterator over the elements of a signature
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Elements<'a> {
+    remaining: &'a str,
+}
+
+impl<'a> Elements<'a> {
+    fn next_element(&mut self) -> GreatResult<Option<Element<'a>>> {
+        let remaining = self.remaining.trim_start();
+        let bytes = remaining.as_bytes();
+        if bytes.is_empty() {
+            self.remaining = remaining;
+            return Ok(None);
+        }
+
+        // repeat
+        let mut position = 0;
+        let repeat = if bytes[0] == b'*' {
+            position += 1;
+            Some(Repeat::Variable)
+        } else {
+            while position < bytes.len() && bytes[position].is_ascii_digit() {
+                position += 1;
+            }
+            match remaining[..position].parse() {
+                Ok(count) => Some(Repeat::Count(count)),
+                Err(_) => None,
+            }
+        };
+
+        // format
+        let code = *bytes.get(position).ok_or(GreatError::InvalidArgument)?;
+        let format = if code == b'(' {
+            let mut depth = 0;
+            let close = bytes[position..]
+                .iter()
+                .position(|&byte| {
+                    match byte {
+                        b'(' => depth += 1,
+                        b')' => depth -= 1,
+                        _ => (),
+                    }
+                    depth == 0
+                })
+                .ok_or(GreatError::InvalidArgument)?;
+            let tuple = Format::Tuple(&remaining[position + 1..position + close]);
+            position += close + 1;
+            tuple
+        } else {
+            position += 1;
+            Format::from_code(code)?
+        };
+
+        // a blob without a length takes all remaining bytes
+        let repeat = match (repeat, format) {
+            (Some(repeat), _) => repeat,
+            (None, Format::Bytes) => Repeat::Variable,
+            (None, _) => Repeat::Count(1),
+        };
+
+        self.remaining = &remaining[position..];
+        Ok(Some(Element { repeat, format }))
+    }
+}
+
+impl<'a> Iterator for Elements<'a> {
+    type Item = Element<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_element().ok().flatten()
+    }
+}
+
+// - Value --------------------------------------------------------------------
+
+/// A value decoded from, or to be encoded into, a message
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Value<'a> {
+    Pad,
+    Char(u8),
+    Bool(bool),
+    I8(i8),
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Bytes(&'a [u8]),
+    String(&'a str),
+    Tuple(Decoder<'a>),
+}
+
+// - Decoder ------------------------------------------------------------------
+
+macro_rules! from_bytes {
+    ($type:ty, $byte_order:expr, $bytes:expr) => {
+        match $byte_order {
+            ByteOrder::Little => <$type>::from_le_bytes($bytes),
+            ByteOrder::Big => <$type>::from_be_bytes($bytes),
+        }
+    };
+}
+
+/// Iterator over the values of a message
+///
+/// Yields `Err(GreatError::BadMessage)` and stops if the message does
+/// not match the signature.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Decoder<'a> {
+    signature: Signature<'a>,
+    elements: Elements<'a>,
+    current: Option<(Element<'a>, usize)>,
+    bytes: &'a [u8],
+    done: bool,
+}
+
+impl<'a> Decoder<'a> {
+    /// The bytes that have not been decoded yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    fn take<const N: usize>(&mut self) -> GreatResult<[u8; N]> {
+        let bytes = self.take_slice(N)?;
+        let mut array = [0; N];
+        array.copy_from_slice(bytes);
+        Ok(array)
+    }
+
+    fn take_slice(&mut self, length: usize) -> GreatResult<&'a [u8]> {
+        if length > self.bytes.len() {
+            return Err(GreatError::BadMessage);
+        }
+        let (bytes, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn decode_value(&mut self, element: Element<'a>) -> GreatResult<Value<'a>> {
+        let byte_order = self.signature.byte_order;
+        let value = match element.format {
+            Format::Pad => {
+                self.take::<1>()?;
+                Value::Pad
+            }
+            Format::Char => Value::Char(self.take::<1>()?[0]),
+            Format::Bool => Value::Bool(self.take::<1>()?[0] != 0),
+            Format::I8 => Value::I8(self.take::<1>()?[0] as i8),
+            Format::U8 => Value::U8(self.take::<1>()?[0]),
+            Format::I16 => Value::I16(from_bytes!(i16, byte_order, self.take()?)),
+            Format::U16 => Value::U16(from_bytes!(u16, byte_order, self.take()?)),
+            Format::I32 => Value::I32(from_bytes!(i32, byte_order, self.take()?)),
+            Format::U32 => Value::U32(from_bytes!(u32, byte_order, self.take()?)),
+            Format::I64 => Value::I64(from_bytes!(i64, byte_order, self.take()?)),
+            Format::U64 => Value::U64(from_bytes!(u64, byte_order, self.take()?)),
+            Format::F32 => Value::F32(from_bytes!(f32, byte_order, self.take()?)),
+            Format::F64 => Value::F64(from_bytes!(f64, byte_order, self.take()?)),
+            Format::Bytes => {
+                let length = match element.repeat {
+                    Repeat::Count(count) => count,
+                    Repeat::Variable => self.bytes.len(),
+                };
+                Value::Bytes(self.take_slice(length)?)
+            }
+            Format::String => {
+                let length = self
+                    .bytes
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .ok_or(GreatError::BadMessage)?;
+                let string = self.take_slice(length + 1)?;
+                let string =
+                    core::str::from_utf8(&string[..length]).map_err(|_| GreatError::BadMessage)?;
+                Value::String(string)
+            }
+            Format::Tuple(elements) => {
+                let tuple = Signature::with_byte_order(elements, byte_order);
+                let size = tuple.fixed_size().ok_or(GreatError::InvalidArgument)?;
+                let bytes = self.take_slice(size)?;
+                Value::Tuple(tuple.decode(bytes))
+            }
+        };
+        Ok(value)
+    }
+
+    fn next_value(&mut self) -> GreatResult<Option<Value<'a>>> {
+        loop {
+            let (element, remaining) = match self.current.take() {
+                Some(current) => current,
+                None => match self.elements.next() {
+                    Some(element) => match (element.repeat, element.format) {
+                        // a blob is a single value
+                        (_, Format::Bytes) => (element, 1),
+                        (Repeat::Count(count), _) => (element, count),
+                        (Repeat::Variable, _) => (element, usize::MAX),
+                    },
+                    None if self.bytes.is_empty() => return Ok(None),
+                    None => return Err(GreatError::BadMessage),
+                },
+            };
+
+            if remaining == 0 || (element.repeat == Repeat::Variable && self.bytes.is_empty()) {
+                continue;
+            }
+            if element.repeat != Repeat::Variable || element.format == Format::Bytes {
+                self.current = Some((element, remaining - 1));
+            } else {
+                self.current = Some((element, remaining));
+            }
+
+            return self.decode_value(element).map(Some);
+        }
+    }
+}
+
+impl<'a> Iterator for Decoder<'a> {
+    type Item = GreatResult<Value<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_value() {
+            Ok(Some(value)) => Some(Ok(value)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// - Encoder ------------------------------------------------------------------
+
+macro_rules! to_bytes {
+    ($value:expr, $byte_order:expr) => {
+        match $byte_order {
+            ByteOrder::Little => $value.to_le_bytes(),
+            ByteOrder::Big => $value.to_be_bytes(),
+        }
+    };
+}
+
+/// Writes values into a message according to a signature
+///
+/// Values are pushed in signature order, tuples are written with
+/// [`Encoder::push_tuple`].
+pub struct Encoder<'a, 'b> {
+    signature: Signature<'a>,
+    elements: Elements<'a>,
+    current: Option<(Element<'a>, usize)>,
+    buffer: &'b mut [u8],
+    length: usize,
+}
+
+impl<'a, 'b> Encoder<'a, 'b> {
+    /// Append a value to the message.
+    pub fn push(&mut self, value: Value) -> GreatResult<()> {
+        let element = self.next_element()?;
+        let byte_order = self.signature.byte_order;
+        match (element.format, value) {
+            (Format::Pad, Value::Pad) => self.write(&[0]),
+            (Format::Char, Value::Char(value)) => self.write(&[value]),
+            (Format::Bool, Value::Bool(value)) => self.write(&[value.into()]),
+            (Format::I8, Value::I8(value)) => self.write(&value.to_le_bytes()),
+            (Format::U8, Value::U8(value)) => self.write(&[value]),
+            (Format::I16, Value::I16(value)) => self.write(&to_bytes!(value, byte_order)),
+            (Format::U16, Value::U16(value)) => self.write(&to_bytes!(value, byte_order)),
+            (Format::I32, Value::I32(value)) => self.write(&to_bytes!(value, byte_order)),
+            (Format::U32, Value::U32(value)) => self.write(&to_bytes!(value, byte_order)),
+            (Format::I64, Value::I64(value)) => self.write(&to_bytes!(value, byte_order)),
+            (Format::U64, Value::U64(value)) => self.write(&to_bytes!(value, byte_order)),
+            (Format::F32, Value::F32(value)) => self.write(&to_bytes!(value, byte_order)),
+            (Format::F64, Value::F64(value)) => self.write(&to_bytes!(value, byte_order)),
+            (Format::Bytes, Value::Bytes(bytes)) => match element.repeat {
+                Repeat::Count(count) if count != bytes.len() => Err(GreatError::InvalidArgument),
+                _ => self.write(bytes),
+            },
+            (Format::String, Value::String(string)) => {
+                self.write(string.as_bytes())?;
+                self.write(&[0])
+            }
+            _ => Err(GreatError::InvalidArgument),
+        }
+    }
+
+    /// Append a tuple to the message, `f` pushes the tuple's values.
+    pub fn push_tuple<F>(&mut self, f: F) -> GreatResult<()>
+    where
+        F: FnOnce(&mut Encoder) -> GreatResult<()>,
+    {
+        let elements = match self.next_element()?.format {
+            Format::Tuple(elements) => elements,
+            _ => return Err(GreatError::InvalidArgument),
+        };
+        let tuple = Signature::with_byte_order(elements, self.signature.byte_order);
+        let mut encoder = tuple.encoder(&mut self.buffer[self.length..]);
+        f(&mut encoder)?;
+        self.length += encoder.finish()?;
+        Ok(())
+    }
+
+    /// Returns the length of the message.
+    ///
+    /// Fails if values are missing.
+    pub fn finish(mut self) -> GreatResult<usize> {
+        if let Some((element, remaining)) = self.current.take() {
+            if element.repeat != Repeat::Variable && remaining > 0 {
+                return Err(GreatError::InvalidArgument);
+            }
+        }
+        for element in self.elements {
+            if element.repeat != Repeat::Variable && element.repeat != Repeat::Count(0) {
+                return Err(GreatError::InvalidArgument);
+            }
+        }
+        Ok(self.length)
+    }
+
+    fn next_element(&mut self) -> GreatResult<Element<'a>> {
+        loop {
+            let (element, remaining) = match self.current.take() {
+                Some(current) => current,
+                None => match self.elements.next() {
+                    Some(element) => match (element.repeat, element.format) {
+                        (Repeat::Count(_), Format::Bytes) => (element, 1),
+                        (Repeat::Count(count), _) => (element, count),
+                        (Repeat::Variable, _) => (element, usize::MAX),
+                    },
+                    None => return Err(GreatError::ArgumentListTooLong),
+                },
+            };
+            if remaining == 0 {
+                continue;
+            }
+            match element.repeat {
+                Repeat::Count(_) => self.current = Some((element, remaining - 1)),
+                Repeat::Variable => self.current = Some((element, remaining)),
+            }
+            return Ok(element);
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> GreatResult<()> {
+        let end = self.length + bytes.len();
+        if end > self.buffer.len() {
+            return Err(GreatError::NoBufferSpaceAvailable);
+        }
+        self.buffer[self.length..end].copy_from_slice(bytes);
+        self.length = end;
+        Ok(())
+    }
+}
+
+// - SignatureFormat ----------------------------------------------------------
+
+/// Signature format of a verb argument type
+///
+/// Used to check that the argument types of a verb agree with its
+/// declared signature, see [`Signature::check_arguments`].
+pub trait SignatureFormat {
+    const FORMAT: Format<'static>;
+}
+
+macro_rules! impl_signature_format {
+    ($($type:ty => $format:ident),*) => {
+        $(
+            impl SignatureFormat for $type {
+                const FORMAT: Format<'static> = Format::$format;
+            }
+        )*
+    };
+}
+
+impl_signature_format! {
+    bool => Bool,
+    i8 => I8,
+    u8 => U8,
+    I16<LittleEndian> => I16,
+    U16<LittleEndian> => U16,
+    I32<LittleEndian> => I32,
+    U32<LittleEndian> => U32,
+    I64<LittleEndian> => I64,
+    U64<LittleEndian> => U64
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // - fixtures -------------------------------------------------------------
+
+    fn next<'a>(values: &mut Decoder<'a>) -> Option<Value<'a>> {
+        values
+            .next()
+            .map(|value| value.expect("failed decoding value"))
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_parse() {
+        let signature = Signature::parse("<B*X\0").unwrap();
+        assert_eq!(signature.byte_order(), ByteOrder::Little);
+        let mut elements = signature.elements();
+        assert_eq!(
+            elements.next(),
+            Some(Element {
+                repeat: Repeat::Count(1),
+                format: Format::U8
+            })
+        );
+        assert_eq!(
+            elements.next(),
+            Some(Element {
+                repeat: Repeat::Variable,
+                format: Format::Bytes
+            })
+        );
+        assert_eq!(elements.next(), None);
+
+        let signature = Signature::parse(">*(BHB)").unwrap();
+        assert_eq!(signature.byte_order(), ByteOrder::Big);
+        assert_eq!(
+            signature.elements().next(),
+            Some(Element {
+                repeat: Repeat::Variable,
+                format: Format::Tuple("BHB")
+            })
+        );
+
+        assert_eq!(Signature::parse("<8X").unwrap().fixed_size(), Some(8));
+        assert_eq!(Signature::parse("<2HI").unwrap().fixed_size(), Some(8));
+        assert_eq!(Signature::parse("<BS").unwrap().fixed_size(), None);
+        assert!(Signature::parse("*\0").unwrap().is_unspecified());
+
+        assert!(Signature::parse("<Z").is_err());
+        assert!(Signature::parse("<(BH").is_err());
+        assert!(Signature::parse("<*BH").is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        let signature = Signature::parse("<HB*X").unwrap();
+        let mut values = signature.decode(&[0x34, 0x12, 0x01, 0xaa, 0xbb]);
+        assert_eq!(next(&mut values), Some(Value::U16(0x1234)));
+        assert_eq!(next(&mut values), Some(Value::U8(0x01)));
+        assert_eq!(next(&mut values), Some(Value::Bytes(&[0xaa, 0xbb])));
+        assert_eq!(next(&mut values), None);
+
+        let signature = Signature::parse(">IS").unwrap();
+        let mut values = signature.decode(&[0x00, 0x00, 0x01, 0x00, b'h', b'i', 0x00]);
+        assert_eq!(next(&mut values), Some(Value::U32(0x100)));
+        assert_eq!(next(&mut values), Some(Value::String("hi")));
+        assert_eq!(next(&mut values), None);
+    }
+
+    #[test]
+    fn test_decode_tuples() {
+        let signature = Signature::parse("<*(BHB)").unwrap();
+        let bytes = [0x01, 0x40, 0x00, 0x00, 0x82, 0x00, 0x02, 0x02];
+        let mut endpoints = signature.decode(&bytes);
+
+        let Some(Value::Tuple(mut endpoint)) = next(&mut endpoints) else {
+            panic!("expected a tuple");
+        };
+        assert_eq!(next(&mut endpoint), Some(Value::U8(0x01)));
+        assert_eq!(next(&mut endpoint), Some(Value::U16(64)));
+        assert_eq!(next(&mut endpoint), Some(Value::U8(0)));
+        assert_eq!(next(&mut endpoint), None);
+
+        let Some(Value::Tuple(endpoint)) = next(&mut endpoints) else {
+            panic!("expected a tuple");
+        };
+        assert!(endpoint
+            .map(Result::unwrap)
+            .eq([Value::U8(0x82), Value::U16(512), Value::U8(2)]));
+        assert_eq!(next(&mut endpoints), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let signature = Signature::parse("<HH").unwrap();
+        assert!(signature.validate(&[0, 0, 0, 0]).is_ok());
+        assert!(matches!(
+            signature.validate(&[0, 0, 0]),
+            Err(GreatError::BadMessage)
+        ));
+        assert!(matches!(
+            signature.validate(&[0, 0, 0, 0, 0]),
+            Err(GreatError::BadMessage)
+        ));
+
+        let signature = Signature::parse("<*(BHB)").unwrap();
+        assert!(signature.validate(&[]).is_ok());
+        assert!(signature.validate(&[0, 0, 0, 0, 0, 0, 0, 0]).is_ok());
+        assert!(signature.validate(&[0, 0, 0, 0, 0, 0]).is_err());
+
+        assert!(Signature::parse("\0").unwrap().validate(&[]).is_ok());
+        assert!(Signature::parse("\0").unwrap().validate(&[0]).is_err());
+        assert!(Signature::parse("*\0")
+            .unwrap()
+            .validate(&[0, 1, 2])
+            .is_ok());
+        assert!(Signature::parse("<S").unwrap().validate(b"abc").is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut buffer = [0; 16];
+        let signature = Signature::parse("<H*(BH)").unwrap();
+        let mut encoder = signature.encoder(&mut buffer);
+        encoder.push(Value::U16(0x1234)).unwrap();
+        for value in [1, 2] {
+            encoder
+                .push_tuple(|tuple| {
+                    tuple.push(Value::U8(value))?;
+                    tuple.push(Value::U16(0x100))
+                })
+                .unwrap();
+        }
+        assert!(encoder.push(Value::U8(0)).is_err());
+        let length = encoder.finish().unwrap();
+        assert_eq!(buffer[..length], [0x34, 0x12, 1, 0x00, 0x01, 2, 0x00, 0x01]);
+
+        // the message must be complete
+        let signature = Signature::parse(">IS").unwrap();
+        let mut encoder = signature.encoder(&mut buffer);
+        encoder.push(Value::U32(1)).unwrap();
+        assert!(encoder.finish().is_err());
+
+        // values must match the signature
+        let mut encoder = signature.encoder(&mut buffer);
+        assert!(encoder.push(Value::U16(1)).is_err());
+
+        // and fit in the buffer
+        let mut buffer = [0; 4];
+        let signature = Signature::parse("<*X").unwrap();
+        let mut encoder = signature.encoder(&mut buffer);
+        assert!(matches!(
+            encoder.push(Value::Bytes(&[0; 5])),
+            Err(GreatError::NoBufferSpaceAvailable)
+        ));
+    }
+
+    #[test]
+    fn test_check_arguments() {
+        let signature = Signature::parse("<HB").unwrap();
+        assert!(signature
+            .check_arguments(&[Format::U16, Format::U8], false)
+            .is_ok());
+        assert!(signature.check_arguments(&[Format::U16], false).is_err());
+        assert!(signature
+            .check_arguments(&[Format::U8, Format::U16], false)
+            .is_err());
+        assert!(signature
+            .check_arguments(&[Format::U16, Format::U8], true)
+            .is_err());
+
+        let signature = Signature::parse("<2B*X").unwrap();
+        assert!(signature
+            .check_arguments(&[Format::U8, Format::U8], true)
+            .is_ok());
+        assert!(signature
+            .check_arguments(&[Format::U8, Format::U8], false)
+            .is_err());
+
+        let signature = Signature::parse("<*(BHB)").unwrap();
+        assert!(signature.check_arguments(&[], true).is_ok());
+
+        let signature = Signature::parse("<I(BH)").unwrap();
+        assert!(signature
+            .check_arguments(&[Format::U32, Format::U8, Format::U16], false)
+            .is_ok());
+
+        // arguments are little endian
+        let signature = Signature::parse(">H").unwrap();
+        assert!(signature.check_arguments(&[Format::U16], false).is_err());
+        let signature = Signature::parse("!BB").unwrap();
+        assert!(signature
+            .check_arguments(&[Format::U8, Format::U8], false)
+            .is_ok());
+
+        // strings are only taken by the remaining bytes
+        let signature = Signature::parse("<BS").unwrap();
+        assert!(signature.check_arguments(&[Format::U8], true).is_ok());
+        assert!(signature.check_arguments(&[Format::U8], false).is_err());
+        let signature = Signature::parse("<SB").unwrap();
+        assert!(signature.check_arguments(&[], true).is_err());
+        assert!(signature.check_arguments(&[Format::U8], true).is_err());
+        let signature = Signature::parse("<2S").unwrap();
+        assert!(signature.check_arguments(&[], true).is_err());
+    }
+
+    #[test]
+    fn test_check_signature_arguments() {
+        // evaluated at compile time
+        const _: () = assert!(check_signature_arguments("<I*X\0", &[Format::U32], true));
+
+        assert!(check_signature_arguments("*", &[Format::U8], false));
+        assert!(check_signature_arguments("", &[], false));
+        assert!(check_signature_arguments("<*(BHB)", &[], true));
+        assert!(check_signature_arguments(
+            "<I(BH)",
+            &[Format::U32, Format::U8, Format::U16],
+            false
+        ));
+        assert!(check_signature_arguments("<L", &[Format::U32], false));
+
+        assert!(!check_signature_arguments("<BH", &[Format::U16], false));
+        assert!(!check_signature_arguments("<HB", &[Format::U16], false));
+        assert!(!check_signature_arguments(
+            "<HB",
+            &[Format::U16, Format::U8],
+            true
+        ));
+        assert!(!check_signature_arguments(">H", &[Format::U16], false));
+        assert!(!check_signature_arguments("<*BB", &[], true));
+        assert!(!check_signature_arguments(
+            "<(BH",
+            &[Format::U8, Format::U16],
+            false
+        ));
+        assert!(!check_signature_arguments("<Z", &[], false));
+    }
+}
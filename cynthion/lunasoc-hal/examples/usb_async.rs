@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+
+//! Bulk loopback on usb0 written as two independent async tasks
+//!
+//! Uses the gadget zero loopback descriptors so that the device can
+//! be tested with the Linux `usbtest` driver.
+
+use panic_halt as _;
+use riscv_rt::entry;
+
+use lunasoc_hal as hal;
+use lunasoc_pac as pac;
+
+use hal::smolusb::class::gadget_zero;
+use hal::smolusb::device::UsbDevice;
+use hal::usb::asynch::{self, AsyncUsbDriver};
+
+#[entry]
+fn main() -> ! {
+    let peripherals = pac::Peripherals::take().unwrap();
+
+    let usb0 = UsbDevice::new(
+        hal::Usb0::new(
+            peripherals.USB0,
+            peripherals.USB0_EP_CONTROL,
+            peripherals.USB0_EP_IN,
+            peripherals.USB0_EP_OUT,
+        ),
+        &gadget_zero::DEVICE_DESCRIPTOR,
+        &gadget_zero::CONFIGURATION_DESCRIPTOR_LOOPBACK,
+        &gadget_zero::USB_STRING_DESCRIPTOR_0,
+        gadget_zero::USB_STRING_DESCRIPTORS,
+    );
+    usb0.connect();
+
+    // enable interrupts
+    unsafe {
+        // set mstatus register: interrupt enable
+        riscv::interrupt::enable();
+
+        // set mie register: machine external interrupts enable
+        riscv::register::mie::set_mext();
+
+        // write csr: enable usb0 interrupts and events
+        pac::csr::interrupt::enable(pac::Interrupt::USB0);
+        pac::csr::interrupt::enable(pac::Interrupt::USB0_EP_CONTROL);
+        pac::csr::interrupt::enable(pac::Interrupt::USB0_EP_IN);
+        pac::csr::interrupt::enable(pac::Interrupt::USB0_EP_OUT);
+        usb0.hal_driver.enable_interrupts();
+    }
+
+    // answer control requests
+    let control = async {
+        let control = usb0.hal_driver.control();
+        loop {
+            // a bus reset simply restarts the wait
+            if let Ok(setup_packet) = control.wait_setup().await {
+                let _ = usb0.handle_setup_request(&setup_packet);
+            }
+        }
+    };
+
+    // return every packet received on endpoint 1
+    let loopback = async {
+        let endpoint_out = usb0.hal_driver.endpoint_out(1);
+        let endpoint_in = usb0.hal_driver.endpoint_in(1);
+        let mut buffer = [0; 512];
+        loop {
+            if let Ok(length) = endpoint_out.read_packet(&mut buffer).await {
+                let _ = endpoint_in.write_packet(&buffer[..length]).await;
+            }
+        }
+    };
+
+    asynch::block_on(asynch::join(control, loopback));
+
+    panic!("usb tasks exited");
+}
+
+// interrupt handler
+#[allow(non_snake_case)]
+#[no_mangle]
+fn MachineExternal() {
+    let usb0 = unsafe { hal::Usb0::summon() };
+    usb0.handle_interrupt();
+}
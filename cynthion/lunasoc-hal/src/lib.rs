@@ -1,5 +1,7 @@
 #![cfg_attr(feature = "nightly", feature(error_in_core))]
 #![cfg_attr(feature = "nightly", feature(panic_info_message))]
+#![cfg_attr(feature = "embassy", feature(async_fn_in_trait))]
+#![cfg_attr(feature = "embassy", allow(incomplete_features))]
 #![no_std]
 
 pub mod gpio;
@@ -20,6 +22,6 @@ pub use lunasoc_pac as pac;
 
 pub use embedded_hal as hal;
 pub use embedded_hal_0 as hal_0;
-pub(crate) use embedded_hal_nb as hal_nb;
+pub use embedded_hal_nb as hal_nb;
 
 pub use nb;
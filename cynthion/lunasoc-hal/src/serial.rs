@@ -79,6 +79,25 @@ macro_rules! impl_serial {
                     }
                 }
 
+                // trait: hal_nb::serial::Read
+                impl $crate::hal_nb::serial::Read<u8> for $SERIALX {
+                    fn read(&mut self) -> $crate::nb::Result<u8, Self::Error> {
+                        // rx_err: overflow, frame, parity
+                        let rx_err = self.registers.rx_err.read().rx_err().bits();
+                        if rx_err & 0b001 != 0 {
+                            Err($crate::nb::Error::Other($crate::serial::Error::Overrun))
+                        } else if rx_err & 0b010 != 0 {
+                            Err($crate::nb::Error::Other($crate::serial::Error::FrameFormat))
+                        } else if rx_err & 0b100 != 0 {
+                            Err($crate::nb::Error::Other($crate::serial::Error::Parity))
+                        } else if !self.registers.rx_rdy.read().rx_rdy().bit() {
+                            Err($crate::nb::Error::WouldBlock)
+                        } else {
+                            Ok(self.registers.rx_data.read().rx_data().bits())
+                        }
+                    }
+                }
+
                 // trait: hal_nb::serial::Write
                 impl $crate::hal_nb::serial::Write<u8> for $SERIALX {
                     fn write(&mut self, word: u8) -> $crate::nb::Result<(), Self::Error> {
@@ -3,10 +3,19 @@
 mod error;
 pub use error::ErrorKind;
 
+#[cfg(feature = "async")]
+pub mod asynch;
+#[cfg(feature = "embassy")]
+pub mod embassy;
+#[cfg(feature = "usb-device")]
+pub mod usbd;
+
 use smolusb::control::*;
+use smolusb::error::{SmolError, SmolResult};
+use smolusb::event::UsbEvent;
 use smolusb::traits::{
-    ControlRead, EndpointRead, EndpointWrite, EndpointWriteRef, UnsafeUsbDriverOperations,
-    UsbDriver, UsbDriverOperations,
+    ControlRead, EndpointRead, EndpointWrite, UnsafeUsbDriverOperations, UsbDriver,
+    UsbDriverOperations,
 };
 
 use crate::pac;
@@ -14,6 +23,28 @@ use pac::interrupt::Interrupt;
 
 use log::{trace, warn};
 
+use core::borrow::Borrow;
+use core::iter;
+
+/// Number of polls to wait for the IN FIFO to drain before a write
+/// gives up with [`SmolError::FifoBusy`]
+const WRITE_TIMEOUT: usize = 100_000;
+
+/// Highest endpoint number supported by the device controller
+const MAX_ENDPOINT_NUMBER: usize = 15;
+
+// - EndpointFifo -------------------------------------------------------------
+
+/// Operations on the endpoint FIFOs shared by the async and
+/// `usb-device` drivers
+pub trait EndpointFifo {
+    /// Returns true while the shared IN FIFO holds a packet.
+    fn is_in_fifo_busy(&self) -> bool;
+
+    /// Prepare endpoint to receive a single OUT packet.
+    fn prime_receive(&self, endpoint: u8);
+}
+
 /// Macro to generate hal wrappers for pac::USBx peripherals
 ///
 /// For example:
@@ -108,6 +139,28 @@ macro_rules! impl_usb {
                     self.disable_interrupt(Interrupt::$USBX_EP_OUT);
                 }
 
+                /// Returns the endpoint of the packet a write to the
+                /// control endpoint discarded from the shared IN FIFO
+                /// since the last call, if any.
+                ///
+                /// The host never reads a discarded packet, it must be
+                /// written again.
+                pub fn take_discarded_in_endpoint(&self) -> Option<u8> {
+                    #[cfg(not(target_has_atomic))]
+                    let endpoint = riscv::interrupt::free(|| unsafe {
+                        core::mem::replace(&mut $USBX_CONTROLLER::DISCARDED_IN_ENDPOINT, 0)
+                    });
+                    #[cfg(target_has_atomic)]
+                    let endpoint = {
+                        use core::sync::atomic::Ordering;
+                        $USBX_CONTROLLER::DISCARDED_IN_ENDPOINT.swap(0, Ordering::Relaxed)
+                    };
+                    match endpoint {
+                        0 => None,
+                        endpoint => Some(endpoint),
+                    }
+                }
+
                 #[inline(always)]
                 pub fn is_pending(&self, interrupt: Interrupt) -> bool {
                     pac::csr::interrupt::pending(interrupt)
@@ -207,6 +260,76 @@ macro_rules! impl_usb {
                 pub fn ep_control_address(&self) -> u8 {
                     self.ep_control.address.read().address().bits()
                 }
+
+                /// Drain the next pending device controller event.
+                ///
+                /// Events are returned in the same order of priority used by
+                /// the interrupt handlers: bus reset, SETUP packet, OUT packet
+                /// and IN transfer complete. Bus resets are handled before
+                /// they are returned. OUT packets are read into `buffer` and
+                /// their endpoint is left unprimed.
+                ///
+                /// The device controller does not report bus suspend or
+                /// resume.
+                pub fn poll_event(&self, buffer: &mut [u8]) -> Option<UsbEvent> {
+                    let event = self.next_event(|endpoint| {
+                        self.read(endpoint, buffer).unwrap_or_else(|_| {
+                            warn!("Packet on endpoint {} overflowed buffer", endpoint);
+                            buffer.len()
+                        })
+                    });
+                    if let Some(UsbEvent::BusReset) = event {
+                        self.bus_reset();
+                    }
+                    event
+                }
+
+                /// Decode the next pending device controller event, e.g. from
+                /// an interrupt handler.
+                ///
+                /// Unlike [`Self::poll_event`] bus resets are left to the
+                /// caller and OUT packets are passed to `read`, which returns
+                /// the length of the packet. `read` may also leave the packet
+                /// in the FIFO to be read later.
+                pub fn next_event<F>(&self, read: F) -> Option<UsbEvent>
+                where
+                    F: FnOnce(u8) -> usize,
+                {
+                    if self.controller.ev_pending.read().pending().bit() {
+                        self.clear_pending(Interrupt::$USBX_CONTROLLER);
+                        Some(UsbEvent::BusReset)
+
+                    } else if self.ep_control.ev_pending.read().pending().bit() {
+                        let mut setup_packet_buffer = [0_u8; 8];
+                        self.read_control(&mut setup_packet_buffer);
+                        self.clear_pending(Interrupt::$USBX_EP_CONTROL);
+                        match SetupPacket::try_from(setup_packet_buffer) {
+                            Ok(setup_packet) => Some(UsbEvent::Setup(setup_packet)),
+                            Err(_) => {
+                                warn!("Ignoring invalid setup packet: {:?}", setup_packet_buffer);
+                                self.next_event(read)
+                            }
+                        }
+
+                    } else if self.ep_out.ev_pending.read().pending().bit() {
+                        let endpoint = self.ep_out.data_ep.read().bits() as u8;
+                        let length = read(endpoint);
+                        self.clear_pending(Interrupt::$USBX_EP_OUT);
+                        Some(UsbEvent::OutPacket { endpoint, length })
+
+                    } else if self.ep_in.ev_pending.read().pending().bit() {
+                        let endpoint = self.ep_in.epno.read().bits() as u8;
+                        self.clear_pending(Interrupt::$USBX_EP_IN);
+                        // TODO something a little bit safer would be nice
+                        unsafe {
+                            self.clear_tx_ack_active();
+                        }
+                        Some(UsbEvent::InComplete { endpoint })
+
+                    } else {
+                        None
+                    }
+                }
             }
 
             // - trait: UsbDriverOperations -----------------------------------
@@ -267,6 +390,9 @@ macro_rules! impl_usb {
                     self.ep_in.reset.write(|w| w.reset().bit(true));
                     self.ep_out.reset.write(|w| w.reset().bit(true));
 
+                    // a reset clears any halted endpoints
+                    self.clear_halted_endpoints();
+
                     self.enable_interrupts();
 
                     // 0: High, 1: Full, 2: Low, 3:SuperSpeed (incl SuperSpeed+)
@@ -290,6 +416,9 @@ macro_rules! impl_usb {
                     self.ep_in.reset.write(|w| w.reset().bit(true));
                     self.ep_out.reset.write(|w| w.reset().bit(true));
 
+                    // a reset clears any halted endpoints
+                    self.clear_halted_endpoints();
+
                     // clear any pending interrupts
                     self.controller.ev_pending.write(|w| w.pending().bit(true));
                     self.ep_control.ev_pending.write(|w| w.pending().bit(true));
@@ -314,7 +443,9 @@ macro_rules! impl_usb {
                         // If this is an IN request, read a zero-length packet (ZLP) from the host..
                         Direction::DeviceToHost => self.ep_out_prime_receive(0),
                         // ... otherwise, send a ZLP.
-                        Direction::HostToDevice => self.write(0, [].into_iter()),
+                        Direction::HostToDevice => {
+                            self.write(0, iter::empty::<u8>()).ok();
+                        }
                     }
                 }
 
@@ -323,7 +454,11 @@ macro_rules! impl_usb {
                         // If this is an IN request, read a zero-length packet (ZLP) from the host..
                         Direction::DeviceToHost => self.ep_out_prime_receive(endpoint),
                         // ... otherwise, send a ZLP.
-                        Direction::HostToDevice => self.write(endpoint, [].into_iter()),
+                        Direction::HostToDevice => {
+                            if let Err(e) = self.write(endpoint, iter::empty::<u8>()) {
+                                warn!("Failed to send ZLP on endpoint {}: {:?}", endpoint, e);
+                            }
+                        }
                     }
                 }
 
@@ -350,6 +485,7 @@ macro_rules! impl_usb {
                 /// for direction. It may be more consistent to actually pass
                 /// in the direction and peripheral address separately
                 fn stall_endpoint_address(&self, endpoint_address: u8, state: bool) {
+                    self.set_endpoint_halted(endpoint_address, state);
                     match Direction::from(endpoint_address) {
                         Direction::HostToDevice => {
                             self.ep_out
@@ -399,13 +535,16 @@ macro_rules! impl_usb {
                 /// Also see: https://github.com/greatscottgadgets/luna/issues/166
                 fn clear_feature_endpoint_halt(&self, endpoint_address: u8) {
                     let endpoint_number = endpoint_address & 0xf;
+                    self.set_endpoint_halted(endpoint_address, false);
 
                     if (endpoint_address & 0x80) == 0 {  // HostToDevice
                         self.ep_out.epno.write(|w| unsafe { w.epno().bits(endpoint_number) });
+                        self.ep_out.stall.write(|w| w.stall().bit(false));
                         self.ep_out.pid.write(|w| w.pid().bit(false));
 
                     } else { // DeviceToHost
                         self.ep_in.epno.write(|w| unsafe { w.epno().bits(endpoint_number) });
+                        self.ep_in.stall.write(|w| w.stall().bit(false));
                         self.ep_in.pid.write(|w| w.pid().bit(false));
                     }
 
@@ -413,6 +552,53 @@ macro_rules! impl_usb {
                     // this smacks of a deeper problem ...
                     log::debug!("  usb::clear_feature_endpoint_halt: 0x{:x}", endpoint_address);
                 }
+
+                /// Only `TestSe0Nak` and `TestPacket` are supported.
+                ///
+                /// `TestJ` and `TestK` need the PHY's line state to be
+                /// driven directly, but the eptri gateware only exposes
+                /// its `connect`, `speed` and event registers and keeps
+                /// the UTMI transceiver and operating mode to itself, so
+                /// there is no register the driver could use to hold the
+                /// bus in either state. `TestForceEnable` only applies to
+                /// the downstream ports of hubs. Requests for these modes
+                /// are stalled.
+                fn supports_test_mode(&self, mode: TestMode) -> bool {
+                    matches!(mode, TestMode::TestSe0Nak | TestMode::TestPacket)
+                }
+
+                fn enter_test_mode(&self, mode: TestMode) -> SmolResult<()> {
+                    if !self.supports_test_mode(mode) {
+                        warn!("usb::enter_test_mode: unsupported test mode {:?}", mode);
+                        return Err(SmolError::Unsupported);
+                    }
+
+                    // disable endpoint events and empty the FIFOs, the
+                    // device controller will NAK any IN tokens from here on
+                    self.disable_interrupt(Interrupt::$USBX_EP_CONTROL);
+                    self.disable_interrupt(Interrupt::$USBX_EP_IN);
+                    self.disable_interrupt(Interrupt::$USBX_EP_OUT);
+                    self.ep_control.reset.write(|w| w.reset().bit(true));
+                    self.ep_in.reset.write(|w| w.reset().bit(true));
+                    self.ep_out.reset.write(|w| w.reset().bit(true));
+
+                    if mode == TestMode::TestSe0Nak {
+                        return Ok(());
+                    }
+
+                    // queue the test packet on endpoint zero, with a DATA0
+                    // pid, until the device is power cycled. The device
+                    // controller has no means of transmitting without
+                    // being polled, so the packet is only sent in answer
+                    // to IN tokens from the host or test fixture.
+                    loop {
+                        if !self.ep_in.have.read().have().bit() {
+                            self.ep_in.epno.write(|w| unsafe { w.epno().bits(0) });
+                            self.ep_in.pid.write(|w| w.pid().bit(false));
+                            self.write_packet(0, TEST_PACKET.iter());
+                        }
+                    }
+                }
             }
 
             // - trait: UnsafeUsbDriverOperations -----------------------------
@@ -429,6 +615,138 @@ macro_rules! impl_usb {
                 #[cfg(target_has_atomic)]
                 pub static TX_ACK_ACTIVE: core::sync::atomic::AtomicBool =
                     core::sync::atomic::AtomicBool::new(false);
+
+                /// Endpoint of the last packet discarded from the IN
+                /// FIFO by a control transfer, zero if none
+                #[cfg(not(target_has_atomic))]
+                pub static mut DISCARDED_IN_ENDPOINT: u8 = 0;
+                #[cfg(target_has_atomic)]
+                pub static DISCARDED_IN_ENDPOINT: core::sync::atomic::AtomicU8 =
+                    core::sync::atomic::AtomicU8::new(0);
+
+                /// Halted endpoints, OUT endpoints in bits 0-15 and IN
+                /// endpoints in bits 16-31
+                #[cfg(not(target_has_atomic))]
+                pub static mut HALTED_ENDPOINTS: u32 = 0;
+                #[cfg(target_has_atomic)]
+                pub static HALTED_ENDPOINTS: core::sync::atomic::AtomicU32 =
+                    core::sync::atomic::AtomicU32::new(0);
+
+                #[cfg(feature = "async")]
+                pub static SIGNALS: super::asynch::Signals = super::asynch::Signals::new();
+            }
+
+            // - trait: EndpointFifo ------------------------------------------
+
+            impl EndpointFifo for $USBX {
+                fn is_in_fifo_busy(&self) -> bool {
+                    self.ep_in.have.read().have().bit()
+                }
+
+                fn prime_receive(&self, endpoint: u8) {
+                    self.ep_out_prime_receive(endpoint);
+                }
+            }
+
+            // - trait: AsyncUsbDriver ----------------------------------------
+
+            #[cfg(feature = "async")]
+            impl asynch::AsyncUsbDriver for $USBX {
+                fn signals(&self) -> &'static asynch::Signals {
+                    &$USBX_CONTROLLER::SIGNALS
+                }
+            }
+
+            #[cfg(feature = "async")]
+            impl $USBX {
+                /// Handle any pending USBx interrupts and wake the
+                /// endpoint futures waiting on them.
+                ///
+                /// Returns false if none of this peripheral's interrupts
+                /// were pending.
+                pub fn handle_interrupt(&self) -> bool {
+                    let signals = &$USBX_CONTROLLER::SIGNALS;
+
+                    if self.is_pending(Interrupt::$USBX_CONTROLLER) {
+                        self.clear_pending(Interrupt::$USBX_CONTROLLER);
+                        self.bus_reset();
+                        signals.bus_reset();
+
+                    } else if self.is_pending(Interrupt::$USBX_EP_CONTROL) {
+                        let mut setup_packet_buffer = [0_u8; 8];
+                        self.read_control(&mut setup_packet_buffer);
+                        self.clear_pending(Interrupt::$USBX_EP_CONTROL);
+                        match SetupPacket::try_from(setup_packet_buffer) {
+                            Ok(setup_packet) => signals.setup(setup_packet),
+                            Err(_) => warn!("Ignoring invalid setup packet: {:?}", setup_packet_buffer),
+                        }
+
+                    } else if self.is_pending(Interrupt::$USBX_EP_OUT) {
+                        // the packet stays in the fifo until the endpoint future reads it
+                        let endpoint = self.ep_out.data_ep.read().bits() as u8;
+                        self.clear_pending(Interrupt::$USBX_EP_OUT);
+                        signals.out_ready(endpoint);
+
+                    } else if self.is_pending(Interrupt::$USBX_EP_IN) {
+                        let endpoint = self.ep_in.epno.read().bits() as u8;
+                        self.clear_pending(Interrupt::$USBX_EP_IN);
+                        // TODO something a little bit safer would be nice
+                        unsafe {
+                            self.clear_tx_ack_active();
+                        }
+                        signals.in_complete(endpoint);
+
+                    } else {
+                        return false;
+                    }
+
+                    true
+                }
+            }
+
+            // - trait: UsbBusDriver ------------------------------------------
+
+            #[cfg(feature = "usb-device")]
+            impl usbd::UsbBusDriver for $USBX {
+                fn take_bus_reset(&self) -> bool {
+                    if self.controller.ev_pending.read().pending().bit() {
+                        self.clear_pending(Interrupt::$USBX_CONTROLLER);
+                        self.bus_reset();
+                        true
+                    } else {
+                        false
+                    }
+                }
+
+                fn take_setup(&self, buffer: &mut [u8; 8]) -> bool {
+                    if self.ep_control.ev_pending.read().pending().bit() {
+                        self.read_control(buffer);
+                        self.clear_pending(Interrupt::$USBX_EP_CONTROL);
+                        true
+                    } else {
+                        false
+                    }
+                }
+
+                fn take_out_ready(&self) -> Option<u8> {
+                    if self.ep_out.ev_pending.read().pending().bit() {
+                        let endpoint = self.ep_out.data_ep.read().bits() as u8;
+                        self.clear_pending(Interrupt::$USBX_EP_OUT);
+                        Some(endpoint)
+                    } else {
+                        None
+                    }
+                }
+
+                fn take_in_complete(&self) -> Option<u8> {
+                    if self.ep_in.ev_pending.read().pending().bit() {
+                        let endpoint = self.ep_in.epno.read().bits() as u8;
+                        self.clear_pending(Interrupt::$USBX_EP_IN);
+                        Some(endpoint)
+                    } else {
+                        None
+                    }
+                }
             }
 
             impl UnsafeUsbDriverOperations for $USBX {
@@ -502,7 +820,11 @@ macro_rules! impl_usb {
 
             impl EndpointRead for $USBX {
                 #[inline(always)]
-                fn read(&self, endpoint: u8, buffer: &mut [u8]) -> usize {
+                fn read(&self, endpoint: u8, buffer: &mut [u8]) -> SmolResult<usize> {
+                    if endpoint & 0xf != 0 && self.is_endpoint_halted(endpoint & 0xf) {
+                        return Err(SmolError::Stalled);
+                    }
+
                     /*let mut bytes_read = 0;
                     let mut overflow = 0;
                     while self.ep_out.have.read().have().bit() {
@@ -538,56 +860,146 @@ macro_rules! impl_usb {
 
                     trace!("  RX OUT{} {} bytes read + {} bytes overflow", endpoint, bytes_read, overflow);
 
-                    bytes_read
+                    if overflow > 0 {
+                        return Err(SmolError::Overflow);
+                    }
+
+                    Ok(bytes_read)
                 }
             }
 
             impl EndpointWrite for $USBX {
                 #[inline(always)]
-                fn write<I>(&self, endpoint: u8, iter: I)
+                fn write<I, B>(&self, endpoint: u8, iter: I) -> SmolResult<usize>
                 where
-                    I: Iterator<Item = u8>,
+                    I: Iterator<Item = B>,
+                    B: Borrow<u8>,
                 {
-                    // reset output fifo if needed
-                    // TODO rather return an error
-                    if self.ep_in.have.read().have().bit() {
-                        trace!("  clear tx");
-                        self.ep_in.reset.write(|w| w.reset().bit(true));
+                    if endpoint & 0xf != 0 && self.is_endpoint_halted(endpoint | 0x80) {
+                        return Err(SmolError::Stalled);
                     }
 
-                    // write data
-                    let mut bytes_written: usize = 0;
-                    for byte in iter {
-                        self.ep_in.data.write(|w| unsafe { w.data().bits(byte) });
-                        bytes_written += 1;
+                    if endpoint & 0xf == 0 {
+                        // a control transfer supersedes any packet still
+                        // waiting in the shared fifo
+                        if self.ep_in.have.read().have().bit() {
+                            trace!("  clear tx");
+                            let discarded = self.ep_in.epno.read().bits() as u8;
+                            self.ep_in.reset.write(|w| w.reset().bit(true));
+                            if discarded != 0 {
+                                self.set_discarded_in_endpoint(discarded);
+                            }
+                        }
+                    } else {
+                        // wait for the previous packet to be sent
+                        let mut timeout = WRITE_TIMEOUT;
+                        while self.ep_in.have.read().have().bit() {
+                            timeout -= 1;
+                            if timeout == 0 {
+                                return Err(SmolError::FifoBusy);
+                            }
+                        }
                     }
 
-                    // finally, prime IN endpoint
-                    self.ep_in
-                        .epno
-                        .write(|w| unsafe { w.epno().bits(endpoint & 0xf) });
-
-                    trace!("  TX {} bytes", bytes_written);
+                    Ok(self.write_packet(endpoint, iter))
                 }
-            }
 
-            impl EndpointWriteRef for $USBX {
                 #[inline(always)]
-                fn write_ref<'a, I>(&self, endpoint: u8, iter: I)
+                fn try_write<I, B>(&self, endpoint: u8, iter: I) -> nb::Result<usize, SmolError>
                 where
-                    I: Iterator<Item = &'a u8>,
+                    I: Iterator<Item = B>,
+                    B: Borrow<u8>,
                 {
-                    // reset output fifo if needed
-                    // TODO rather return an error
+                    if endpoint & 0xf != 0 && self.is_endpoint_halted(endpoint | 0x80) {
+                        return Err(nb::Error::Other(SmolError::Stalled));
+                    }
                     if self.ep_in.have.read().have().bit() {
-                        trace!("  clear tx");
-                        self.ep_in.reset.write(|w| w.reset().bit(true));
+                        return Err(nb::Error::WouldBlock);
+                    }
+
+                    Ok(self.write_packet(endpoint, iter))
+                }
+            }
+
+            impl $USBX {
+                /// Bit of the given endpoint address in `HALTED_ENDPOINTS`
+                fn halted_endpoint_bit(endpoint_address: u8) -> u32 {
+                    match Direction::from(endpoint_address) {
+                        Direction::HostToDevice => 1 << (endpoint_address & 0xf),
+                        Direction::DeviceToHost => 1 << (16 + (endpoint_address & 0xf)),
+                    }
+                }
+
+                /// Record the halt state of the given endpoint address.
+                fn set_endpoint_halted(&self, endpoint_address: u8, halted: bool) {
+                    let bit = Self::halted_endpoint_bit(endpoint_address);
+                    #[cfg(not(target_has_atomic))]
+                    riscv::interrupt::free(|| unsafe {
+                        if halted {
+                            $USBX_CONTROLLER::HALTED_ENDPOINTS |= bit;
+                        } else {
+                            $USBX_CONTROLLER::HALTED_ENDPOINTS &= !bit;
+                        }
+                    });
+                    #[cfg(target_has_atomic)]
+                    {
+                        use core::sync::atomic::Ordering;
+                        if halted {
+                            $USBX_CONTROLLER::HALTED_ENDPOINTS.fetch_or(bit, Ordering::Relaxed);
+                        } else {
+                            $USBX_CONTROLLER::HALTED_ENDPOINTS.fetch_and(!bit, Ordering::Relaxed);
+                        }
                     }
+                }
 
+                /// Returns true if the given endpoint address is halted.
+                fn is_endpoint_halted(&self, endpoint_address: u8) -> bool {
+                    #[cfg(not(target_has_atomic))]
+                    let halted = riscv::interrupt::free(|| unsafe {
+                        $USBX_CONTROLLER::HALTED_ENDPOINTS
+                    });
+                    #[cfg(target_has_atomic)]
+                    let halted = {
+                        use core::sync::atomic::Ordering;
+                        $USBX_CONTROLLER::HALTED_ENDPOINTS.load(Ordering::Relaxed)
+                    };
+                    halted & Self::halted_endpoint_bit(endpoint_address) != 0
+                }
+
+                fn clear_halted_endpoints(&self) {
+                    #[cfg(not(target_has_atomic))]
+                    riscv::interrupt::free(|| unsafe {
+                        $USBX_CONTROLLER::HALTED_ENDPOINTS = 0;
+                    });
+                    #[cfg(target_has_atomic)]
+                    {
+                        use core::sync::atomic::Ordering;
+                        $USBX_CONTROLLER::HALTED_ENDPOINTS.store(0, Ordering::Relaxed);
+                    }
+                }
+
+                fn set_discarded_in_endpoint(&self, endpoint: u8) {
+                    #[cfg(not(target_has_atomic))]
+                    riscv::interrupt::free(|| unsafe {
+                        $USBX_CONTROLLER::DISCARDED_IN_ENDPOINT = endpoint;
+                    });
+                    #[cfg(target_has_atomic)]
+                    {
+                        use core::sync::atomic::Ordering;
+                        $USBX_CONTROLLER::DISCARDED_IN_ENDPOINT.store(endpoint, Ordering::Relaxed);
+                    }
+                }
+
+                #[inline(always)]
+                fn write_packet<I, B>(&self, endpoint: u8, iter: I) -> usize
+                where
+                    I: Iterator<Item = B>,
+                    B: Borrow<u8>,
+                {
                     // write data
                     let mut bytes_written: usize = 0;
                     for byte in iter {
-                        self.ep_in.data.write(|w| unsafe { w.data().bits(*byte) });
+                        self.ep_in.data.write(|w| unsafe { w.data().bits(*byte.borrow()) });
                         bytes_written += 1;
                     }
 
@@ -597,6 +1009,8 @@ macro_rules! impl_usb {
                         .write(|w| unsafe { w.epno().bits(endpoint & 0xf) });
 
                     trace!("  TX {} bytes", bytes_written);
+
+                    bytes_written
                 }
             }
 
@@ -0,0 +1,478 @@
+//! Async endpoint API
+//!
+//! Provides futures for waiting on SETUP packets, reading OUT packets
+//! and writing IN packets. The futures are woken from the USBx
+//! interrupt handler, which firmware installs by calling
+//! `UsbX::handle_interrupt()` from `MachineExternal`:
+//!
+//!     #[no_mangle]
+//!     fn MachineExternal() {
+//!         let usb0 = unsafe { hal::Usb0::summon() };
+//!         usb0.handle_interrupt();
+//!     }
+//!
+//! Shared state lives in a per-peripheral [`Signals`] instance and is
+//! only accessed from within critical sections so that it works on
+//! cores without atomics.
+//!
+//! A bus reset fails any outstanding futures with
+//! [`ErrorKind::BusReset`].
+
+use smolusb::control::SetupPacket;
+use smolusb::traits::{
+    ControlRead, EndpointRead, EndpointWrite, UnsafeUsbDriverOperations, UsbDriverOperations,
+};
+
+use super::{EndpointFifo, ErrorKind};
+
+use core::cell::UnsafeCell;
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+/// Number of endpoints supported by the device controller
+pub const ENDPOINT_COUNT: usize = super::MAX_ENDPOINT_NUMBER + 1;
+
+// - Signals ------------------------------------------------------------------
+
+/// Events shared between a USB peripheral's interrupt handler and
+/// its endpoint futures
+pub struct Signals {
+    inner: UnsafeCell<Inner>,
+}
+
+struct Inner {
+    /// Incremented on each bus reset
+    epoch: u32,
+    setup_packet: Option<SetupPacket>,
+    setup_waker: Option<Waker>,
+    /// Bitmap of IN endpoints whose packet has been read by the host
+    in_complete: u16,
+    /// Bitmap of OUT endpoints holding a received packet
+    out_ready: u16,
+    in_wakers: [Option<Waker>; ENDPOINT_COUNT],
+    out_wakers: [Option<Waker>; ENDPOINT_COUNT],
+    /// Woken whenever the shared IN FIFO drains
+    fifo_waker: Option<Waker>,
+    /// Woken on bus reset
+    reset_waker: Option<Waker>,
+    /// Bitmap of enabled endpoints, OUT in bits 0..16 and IN in bits 16..32
+    enabled: u32,
+}
+
+// Safety: `inner` is only ever accessed within a critical section.
+unsafe impl Sync for Signals {}
+
+const NO_WAKER: Option<Waker> = None;
+
+impl Signals {
+    pub const fn new() -> Self {
+        Self {
+            inner: UnsafeCell::new(Inner {
+                epoch: 0,
+                setup_packet: None,
+                setup_waker: None,
+                in_complete: 0,
+                out_ready: 0,
+                in_wakers: [NO_WAKER; ENDPOINT_COUNT],
+                out_wakers: [NO_WAKER; ENDPOINT_COUNT],
+                fifo_waker: None,
+                reset_waker: None,
+                enabled: 0,
+            }),
+        }
+    }
+
+    #[inline(always)]
+    fn with<R>(&self, f: impl FnOnce(&mut Inner) -> R) -> R {
+        riscv::interrupt::free(|| f(unsafe { &mut *self.inner.get() }))
+    }
+
+    /// Returns the number of bus resets seen since power on.
+    pub fn epoch(&self) -> u32 {
+        self.with(|inner| inner.epoch)
+    }
+
+    /// Wait for the next bus reset after the given epoch, returning the
+    /// new epoch.
+    pub async fn wait_bus_reset(&self, epoch: u32) -> u32 {
+        poll_fn(|cx| {
+            self.with(|inner| {
+                if inner.epoch != epoch {
+                    Poll::Ready(inner.epoch)
+                } else {
+                    register(&mut inner.reset_waker, cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    /// Enable or disable an endpoint, waking any futures waiting on it.
+    pub fn set_endpoint_enabled(&self, endpoint_address: u8, enabled: bool) {
+        let index = endpoint_address as usize & 0xf;
+        let bit = enabled_bit(endpoint_address);
+        self.with(|inner| {
+            if enabled {
+                inner.enabled |= bit;
+            } else {
+                inner.enabled &= !bit;
+            }
+            if endpoint_address & 0x80 == 0 {
+                wake(&mut inner.out_wakers[index]);
+            } else {
+                wake(&mut inner.in_wakers[index]);
+            }
+        });
+    }
+
+    /// Returns true if the endpoint has been enabled.
+    pub fn is_endpoint_enabled(&self, endpoint_address: u8) -> bool {
+        let bit = enabled_bit(endpoint_address);
+        self.with(|inner| inner.enabled & bit != 0)
+    }
+
+    /// Wait for an endpoint to be enabled.
+    pub async fn wait_endpoint_enabled(&self, endpoint_address: u8) {
+        let index = endpoint_address as usize & 0xf;
+        let bit = enabled_bit(endpoint_address);
+        poll_fn(|cx| {
+            self.with(|inner| {
+                if inner.enabled & bit != 0 {
+                    Poll::Ready(())
+                } else if endpoint_address & 0x80 == 0 {
+                    register(&mut inner.out_wakers[index], cx.waker());
+                    Poll::Pending
+                } else {
+                    register(&mut inner.in_wakers[index], cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    // - interrupt handler side --
+
+    /// Signal a bus reset, failing all outstanding futures.
+    pub fn bus_reset(&self) {
+        self.with(|inner| {
+            inner.epoch = inner.epoch.wrapping_add(1);
+            inner.setup_packet = None;
+            inner.in_complete = 0;
+            inner.out_ready = 0;
+            inner.enabled = 0;
+            wake(&mut inner.setup_waker);
+            wake(&mut inner.reset_waker);
+            wake(&mut inner.fifo_waker);
+            inner.in_wakers.iter_mut().for_each(wake);
+            inner.out_wakers.iter_mut().for_each(wake);
+        });
+    }
+
+    /// Signal receipt of a SETUP packet.
+    ///
+    /// A new SETUP packet replaces any that has not yet been taken.
+    pub fn setup(&self, setup_packet: SetupPacket) {
+        self.with(|inner| {
+            inner.setup_packet = Some(setup_packet);
+            wake(&mut inner.setup_waker);
+        });
+    }
+
+    /// Signal that the host has read the packet written to an IN endpoint.
+    pub fn in_complete(&self, endpoint: u8) {
+        let index = endpoint as usize & 0xf;
+        self.with(|inner| {
+            inner.in_complete |= 1 << index;
+            wake(&mut inner.in_wakers[index]);
+            wake(&mut inner.fifo_waker);
+        });
+    }
+
+    /// Signal that a packet has been received on an OUT endpoint.
+    pub fn out_ready(&self, endpoint: u8) {
+        let index = endpoint as usize & 0xf;
+        self.with(|inner| {
+            inner.out_ready |= 1 << index;
+            wake(&mut inner.out_wakers[index]);
+        });
+    }
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn enabled_bit(endpoint_address: u8) -> u32 {
+    let index = endpoint_address as u32 & 0xf;
+    if endpoint_address & 0x80 == 0 {
+        1 << index
+    } else {
+        1 << (index + 16)
+    }
+}
+
+fn wake(slot: &mut Option<Waker>) {
+    if let Some(waker) = slot.take() {
+        waker.wake();
+    }
+}
+
+fn register(slot: &mut Option<Waker>, waker: &Waker) {
+    match slot {
+        Some(registered) if registered.will_wake(waker) => (),
+        _ => *slot = Some(waker.clone()),
+    }
+}
+
+// - AsyncUsbDriver -----------------------------------------------------------
+
+/// A USB peripheral supporting the async endpoint API
+pub trait AsyncUsbDriver:
+    ControlRead
+    + EndpointRead
+    + EndpointWrite
+    + EndpointFifo
+    + UsbDriverOperations
+    + UnsafeUsbDriverOperations
+{
+    /// Returns the signals shared with the peripheral's interrupt handler.
+    fn signals(&self) -> &'static Signals;
+
+    /// Returns a handle to the control endpoint.
+    fn control(&self) -> Control<'_, Self>
+    where
+        Self: Sized,
+    {
+        Control { driver: self }
+    }
+
+    /// Returns a handle to the given IN endpoint.
+    fn endpoint_in(&self, number: u8) -> EndpointIn<'_, Self>
+    where
+        Self: Sized,
+    {
+        EndpointIn {
+            driver: self,
+            number: number & 0xf,
+        }
+    }
+
+    /// Returns a handle to the given OUT endpoint.
+    fn endpoint_out(&self, number: u8) -> EndpointOut<'_, Self>
+    where
+        Self: Sized,
+    {
+        EndpointOut {
+            driver: self,
+            number: number & 0xf,
+        }
+    }
+}
+
+// - endpoint handles ---------------------------------------------------------
+
+/// Control endpoint handle
+pub struct Control<'a, D> {
+    driver: &'a D,
+}
+
+impl<'a, D: AsyncUsbDriver> Control<'a, D> {
+    /// Wait for the next SETUP packet.
+    pub async fn wait_setup(&self) -> Result<SetupPacket, ErrorKind> {
+        let signals = self.driver.signals();
+        let epoch = signals.epoch();
+        poll_fn(|cx| {
+            signals.with(|inner| {
+                if inner.epoch != epoch {
+                    return Poll::Ready(Err(ErrorKind::BusReset));
+                }
+                match inner.setup_packet.take() {
+                    Some(setup_packet) => Poll::Ready(Ok(setup_packet)),
+                    None => {
+                        register(&mut inner.setup_waker, cx.waker());
+                        Poll::Pending
+                    }
+                }
+            })
+        })
+        .await
+    }
+}
+
+/// IN endpoint handle
+pub struct EndpointIn<'a, D> {
+    driver: &'a D,
+    number: u8,
+}
+
+impl<'a, D: AsyncUsbDriver> EndpointIn<'a, D> {
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    /// Write a single packet and wait for the host to read it.
+    ///
+    /// The IN FIFO is shared by all endpoints so this first waits for
+    /// any packet already in the FIFO to be read.
+    pub async fn write_packet(&self, data: &[u8]) -> Result<(), ErrorKind> {
+        let signals = self.driver.signals();
+        let epoch = signals.epoch();
+        let bit = 1 << self.number;
+
+        // wait for the FIFO to drain
+        poll_fn(|cx| {
+            signals.with(|inner| {
+                if inner.epoch != epoch {
+                    Poll::Ready(Err(ErrorKind::BusReset))
+                } else if self.driver.is_in_fifo_busy() {
+                    register(&mut inner.fifo_waker, cx.waker());
+                    Poll::Pending
+                } else {
+                    inner.in_complete &= !bit;
+                    Poll::Ready(Ok(()))
+                }
+            })
+        })
+        .await?;
+
+        self.driver.write(self.number, data.iter())?;
+
+        // wait for the host to read the packet
+        poll_fn(|cx| {
+            signals.with(|inner| {
+                if inner.epoch != epoch {
+                    Poll::Ready(Err(ErrorKind::BusReset))
+                } else if inner.in_complete & bit != 0 {
+                    inner.in_complete &= !bit;
+                    Poll::Ready(Ok(()))
+                } else {
+                    register(&mut inner.in_wakers[self.number as usize], cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+/// OUT endpoint handle
+pub struct EndpointOut<'a, D> {
+    driver: &'a D,
+    number: u8,
+}
+
+impl<'a, D: AsyncUsbDriver> EndpointOut<'a, D> {
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    /// Prime the endpoint and wait for a packet, returning the number
+    /// of bytes read into `buffer`.
+    pub async fn read_packet(&self, buffer: &mut [u8]) -> Result<usize, ErrorKind> {
+        let signals = self.driver.signals();
+        let epoch = signals.epoch();
+        let bit = 1 << self.number;
+
+        signals.with(|inner| inner.out_ready &= !bit);
+        self.driver.prime_receive(self.number);
+
+        poll_fn(|cx| {
+            signals.with(|inner| {
+                if inner.epoch != epoch {
+                    Poll::Ready(Err(ErrorKind::BusReset))
+                } else if inner.out_ready & bit != 0 {
+                    inner.out_ready &= !bit;
+                    Poll::Ready(Ok(()))
+                } else {
+                    register(&mut inner.out_wakers[self.number as usize], cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await?;
+
+        Ok(self.driver.read(self.number, buffer)?)
+    }
+}
+
+// - join ---------------------------------------------------------------------
+
+/// Run two futures concurrently, returning both outputs once they
+/// have completed.
+pub async fn join<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    let mut a_output = None;
+    let mut b_output = None;
+
+    poll_fn(|cx| {
+        if a_output.is_none() {
+            if let Poll::Ready(output) = a.as_mut().poll(cx) {
+                a_output = Some(output);
+            }
+        }
+        if b_output.is_none() {
+            if let Poll::Ready(output) = b.as_mut().poll(cx) {
+                b_output = Some(output);
+            }
+        }
+        match (a_output.take(), b_output.take()) {
+            (Some(a), Some(b)) => Poll::Ready((a, b)),
+            (a, b) => {
+                a_output = a;
+                b_output = b;
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+// - block_on -----------------------------------------------------------------
+
+static mut WOKEN: bool = false;
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(waker_clone, waker_wake, waker_wake, waker_drop);
+
+unsafe fn waker_clone(_: *const ()) -> RawWaker {
+    RawWaker::new(core::ptr::null(), &VTABLE)
+}
+
+unsafe fn waker_wake(_: *const ()) {
+    riscv::interrupt::free(|| WOKEN = true);
+}
+
+unsafe fn waker_drop(_: *const ()) {}
+
+/// Run a future to completion, sleeping until the next interrupt
+/// whenever it is pending.
+///
+/// Several tasks can be run concurrently by combining them with
+/// [`join`], or by using a full executor instead.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) };
+    let mut context = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+
+        // wfi returns on any pending interrupt, even while they are
+        // disabled, so the flag can't be set between check and sleep
+        unsafe {
+            riscv::interrupt::disable();
+            if !WOKEN {
+                riscv::asm::wfi();
+            }
+            WOKEN = false;
+            riscv::interrupt::enable();
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! `embassy-usb-driver` implementation
+//!
+//! Allows the embassy-usb device stack and its classes to run on any
+//! of the USBx peripherals. The driver is built on the async endpoint
+//! API so firmware must forward interrupts to
+//! `UsbX::handle_interrupt()` as described in [`super::asynch`].
+//!
+//! For example:
+//!
+//!     let usb0 = hal::Usb0::new(...);
+//!     let driver = hal::usb::embassy::Driver::new(&usb0);
+//!     let mut builder = embassy_usb::Builder::new(driver, config, ...);
+//!
+//! The device controller does not report suspend, resume or VBUS so
+//! the bus only ever emits [`Event::PowerDetected`] followed by
+//! [`Event::Reset`] events.
+
+use embassy_usb_driver as driver;
+use driver::{
+    Direction, EndpointAddress, EndpointAllocError, EndpointError, EndpointInfo, EndpointType,
+    Event, Unsupported,
+};
+
+use smolusb::control::{Direction as UsbDirection, SetupPacket};
+use smolusb::error::SmolError;
+
+use super::asynch::{self, AsyncUsbDriver};
+use super::{ErrorKind, MAX_ENDPOINT_NUMBER};
+
+use log::{trace, warn};
+
+// - Driver -------------------------------------------------------------------
+
+/// embassy-usb driver for a USBx peripheral
+pub struct Driver<'d, D> {
+    usb: &'d D,
+    allocated_in: u16,
+    allocated_out: u16,
+}
+
+impl<'d, D: AsyncUsbDriver> Driver<'d, D> {
+    pub fn new(usb: &'d D) -> Self {
+        Self {
+            usb,
+            // endpoint zero belongs to the control pipe
+            allocated_in: 1,
+            allocated_out: 1,
+        }
+    }
+
+    fn alloc_endpoint(
+        &mut self,
+        direction: Direction,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<EndpointInfo, EndpointAllocError> {
+        let allocated = match direction {
+            Direction::In => &mut self.allocated_in,
+            Direction::Out => &mut self.allocated_out,
+        };
+
+        let index = (1..=MAX_ENDPOINT_NUMBER)
+            .find(|index| *allocated & (1 << index) == 0)
+            .ok_or(EndpointAllocError)?;
+        *allocated |= 1 << index;
+
+        trace!("embassy::alloc_endpoint {:?} {} -> {}", direction, max_packet_size, index);
+
+        Ok(EndpointInfo {
+            addr: EndpointAddress::from_parts(index, direction),
+            ep_type,
+            max_packet_size,
+            interval_ms,
+        })
+    }
+}
+
+impl<'a, 'd: 'a, D: AsyncUsbDriver> driver::Driver<'a> for Driver<'d, D> {
+    type EndpointOut = EndpointOut<'d, D>;
+    type EndpointIn = EndpointIn<'d, D>;
+    type ControlPipe = ControlPipe<'d, D>;
+    type Bus = Bus<'d, D>;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        let info = self.alloc_endpoint(Direction::Out, ep_type, max_packet_size, interval_ms)?;
+        Ok(EndpointOut {
+            endpoint: self.usb.endpoint_out(info.addr.index() as u8),
+            signals: self.usb.signals(),
+            info,
+        })
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        let info = self.alloc_endpoint(Direction::In, ep_type, max_packet_size, interval_ms)?;
+        Ok(EndpointIn {
+            endpoint: self.usb.endpoint_in(info.addr.index() as u8),
+            signals: self.usb.signals(),
+            info,
+        })
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        let bus = Bus {
+            usb: self.usb,
+            epoch: self.usb.signals().epoch(),
+            powered: false,
+            stalled: 0,
+        };
+        let control_pipe = ControlPipe {
+            usb: self.usb,
+            max_packet_size: control_max_packet_size as usize,
+        };
+        (bus, control_pipe)
+    }
+}
+
+// - Bus ----------------------------------------------------------------------
+
+/// embassy-usb bus
+pub struct Bus<'d, D> {
+    usb: &'d D,
+    epoch: u32,
+    powered: bool,
+    /// Bitmap of stalled endpoints, OUT in bits 0..16 and IN in bits 16..32
+    stalled: u32,
+}
+
+fn stalled_bit(ep_addr: EndpointAddress) -> u32 {
+    if ep_addr.is_in() {
+        1 << (ep_addr.index() + 16)
+    } else {
+        1 << ep_addr.index()
+    }
+}
+
+impl<'d, D: AsyncUsbDriver> driver::Bus for Bus<'d, D> {
+    async fn enable(&mut self) {
+        self.usb.connect();
+        // re-enables all device controller events
+        self.usb.bus_reset();
+        self.epoch = self.usb.signals().epoch();
+    }
+
+    async fn disable(&mut self) {
+        self.usb.disconnect();
+    }
+
+    async fn poll(&mut self) -> Event {
+        // without VBUS detection the bus is always powered
+        if !self.powered {
+            self.powered = true;
+            return Event::PowerDetected;
+        }
+
+        self.epoch = self.usb.signals().wait_bus_reset(self.epoch).await;
+        self.stalled = 0;
+        Event::Reset
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        trace!("embassy::endpoint_set_enabled {:?} {}", ep_addr, enabled);
+        self.usb
+            .signals()
+            .set_endpoint_enabled(u8::from(ep_addr), enabled);
+    }
+
+    fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        self.usb
+            .stall_endpoint_address(u8::from(ep_addr), stalled);
+        if stalled {
+            self.stalled |= stalled_bit(ep_addr);
+        } else {
+            self.usb.clear_feature_endpoint_halt(u8::from(ep_addr));
+            self.stalled &= !stalled_bit(ep_addr);
+        }
+    }
+
+    fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
+        self.stalled & stalled_bit(ep_addr) != 0
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
+}
+
+// - Endpoints ----------------------------------------------------------------
+
+/// embassy-usb IN endpoint
+pub struct EndpointIn<'d, D> {
+    endpoint: asynch::EndpointIn<'d, D>,
+    signals: &'static asynch::Signals,
+    info: EndpointInfo,
+}
+
+impl<'d, D: AsyncUsbDriver> driver::Endpoint for EndpointIn<'d, D> {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        self.signals
+            .wait_endpoint_enabled(u8::from(self.info.addr))
+            .await
+    }
+}
+
+impl<'d, D: AsyncUsbDriver> driver::EndpointIn for EndpointIn<'d, D> {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        if buf.len() > self.info.max_packet_size as usize {
+            return Err(EndpointError::BufferOverflow);
+        }
+        if !self.signals.is_endpoint_enabled(u8::from(self.info.addr)) {
+            return Err(EndpointError::Disabled);
+        }
+        self.endpoint.write_packet(buf).await.map_err(endpoint_error)
+    }
+}
+
+/// embassy-usb OUT endpoint
+pub struct EndpointOut<'d, D> {
+    endpoint: asynch::EndpointOut<'d, D>,
+    signals: &'static asynch::Signals,
+    info: EndpointInfo,
+}
+
+impl<'d, D: AsyncUsbDriver> driver::Endpoint for EndpointOut<'d, D> {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        self.signals
+            .wait_endpoint_enabled(u8::from(self.info.addr))
+            .await
+    }
+}
+
+impl<'d, D: AsyncUsbDriver> driver::EndpointOut for EndpointOut<'d, D> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        if buf.len() < self.info.max_packet_size as usize {
+            return Err(EndpointError::BufferOverflow);
+        }
+        if !self.signals.is_endpoint_enabled(u8::from(self.info.addr)) {
+            return Err(EndpointError::Disabled);
+        }
+        self.endpoint.read_packet(buf).await.map_err(endpoint_error)
+    }
+}
+
+fn endpoint_error(error: ErrorKind) -> EndpointError {
+    match error {
+        ErrorKind::Driver(SmolError::Overflow) => EndpointError::BufferOverflow,
+        _ => EndpointError::Disabled,
+    }
+}
+
+// - ControlPipe --------------------------------------------------------------
+
+/// embassy-usb control pipe
+pub struct ControlPipe<'d, D> {
+    usb: &'d D,
+    max_packet_size: usize,
+}
+
+impl<'d, D: AsyncUsbDriver> ControlPipe<'d, D> {
+    /// Send a zero length status packet and wait for the host to read it.
+    async fn send_status(&self) {
+        if let Err(e) = self.usb.endpoint_in(0).write_packet(&[]).await {
+            warn!("embassy::ControlPipe failed to send status: {:?}", e);
+        }
+    }
+}
+
+impl<'d, D: AsyncUsbDriver> driver::ControlPipe for ControlPipe<'d, D> {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        let control = self.usb.control();
+        loop {
+            // a bus reset simply restarts the wait
+            if let Ok(setup_packet) = control.wait_setup().await {
+                return SetupPacket::as_bytes(setup_packet);
+            }
+        }
+    }
+
+    async fn data_out(&mut self, buf: &mut [u8], _first: bool, _last: bool) -> Result<usize, EndpointError> {
+        self.usb
+            .endpoint_out(0)
+            .read_packet(buf)
+            .await
+            .map_err(endpoint_error)
+    }
+
+    async fn data_in(&mut self, data: &[u8], _first: bool, last: bool) -> Result<(), EndpointError> {
+        if data.len() > self.max_packet_size {
+            return Err(EndpointError::BufferOverflow);
+        }
+        self.usb
+            .endpoint_in(0)
+            .write_packet(data)
+            .await
+            .map_err(endpoint_error)?;
+
+        // receive the host's zero length status packet
+        if last {
+            self.usb.ack(0, UsbDirection::DeviceToHost);
+        }
+
+        Ok(())
+    }
+
+    async fn accept(&mut self) {
+        self.send_status().await;
+    }
+
+    async fn reject(&mut self) {
+        self.usb.stall_request();
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        // The device address may only change once the status stage
+        // has completed. Marking the ack as active lets anything else
+        // polling the controller know the address is pending.
+        unsafe {
+            self.usb.set_tx_ack_active();
+        }
+        self.send_status().await;
+        self.usb.set_address(addr);
+        trace!("embassy::accept_set_address {}", addr);
+    }
+}
@@ -1,7 +1,20 @@
+use smolusb::error::SmolError;
+
 /// USB Error type
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum ErrorKind {
     Timeout,
+    /// The operation was interrupted by a USB bus reset
+    BusReset,
+    /// Error reported by the device controller driver
+    Driver(SmolError),
+}
+
+// trait: core::convert::From<SmolError>
+impl core::convert::From<SmolError> for ErrorKind {
+    fn from(error: SmolError) -> Self {
+        ErrorKind::Driver(error)
+    }
 }
 
 // trait:: core::fmt::Display
@@ -27,6 +40,8 @@ impl core::error::Error for ErrorKind {
         use ErrorKind::*;
         match self {
             Timeout => "Blocking operation timed-out",
+            BusReset => "Operation interrupted by bus reset",
+            Driver(_) => "Device controller driver error",
         }
     }
 }
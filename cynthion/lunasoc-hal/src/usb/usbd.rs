@@ -0,0 +1,307 @@
+//! `usb-device` bus implementation
+//!
+//! Allows gadgets written against the `usb-device` crate to run on any
+//! of the USBx peripherals:
+//!
+//!     let usb_bus = UsbBusAllocator::new(hal::usb::usbd::UsbBus::new(usb0));
+//!     let mut serial = usbd_serial::SerialPort::new(&usb_bus);
+//!     let mut device = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1209, 0x0001)).build();
+//!
+//!     loop {
+//!         if device.poll(&mut [&mut serial]) {
+//!             ...
+//!         }
+//!     }
+//!
+//! The bus is polled directly from the device controller's event
+//! registers so interrupts do not need to be enabled.
+//!
+//! All OUT endpoints share a single receive FIFO, so a packet must be
+//! read before any further OUT packets can be received. Likewise all
+//! IN endpoints share a single transmit FIFO and writes will return
+//! [`UsbError::WouldBlock`] until the previous packet has been sent.
+//!
+//! The device controller does not report suspend or resume.
+
+use usb_device::bus::PollResult;
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{Result, UsbDirection, UsbError};
+
+use smolusb::error::SmolError;
+use smolusb::traits::UsbDriver;
+
+use super::{EndpointFifo, MAX_ENDPOINT_NUMBER};
+
+use core::cell::UnsafeCell;
+
+use log::trace;
+
+// - UsbBusDriver -------------------------------------------------------------
+
+/// Register level operations required by [`UsbBus`]
+pub trait UsbBusDriver: UsbDriver + EndpointFifo {
+    /// Returns true and resets the device controller if a bus reset
+    /// is pending.
+    fn take_bus_reset(&self) -> bool;
+
+    /// Returns true and reads the SETUP packet into `buffer` if one
+    /// has been received.
+    fn take_setup(&self, buffer: &mut [u8; 8]) -> bool;
+
+    /// Returns the endpoint number of a received OUT packet, leaving
+    /// the packet in the receive FIFO.
+    fn take_out_ready(&self) -> Option<u8>;
+
+    /// Returns the endpoint number of a completed IN transfer.
+    fn take_in_complete(&self) -> Option<u8>;
+}
+
+// - UsbBus -------------------------------------------------------------------
+
+/// `usb-device` bus for a USBx peripheral
+pub struct UsbBus<D> {
+    usb: D,
+    state: UnsafeCell<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Bitmaps of allocated endpoints
+    allocated_in: u16,
+    allocated_out: u16,
+    /// Maximum packet size of each allocated endpoint
+    max_packet_size_in: [u16; MAX_ENDPOINT_NUMBER + 1],
+    max_packet_size_out: [u16; MAX_ENDPOINT_NUMBER + 1],
+    /// Bitmaps of stalled endpoints
+    stalled_in: u16,
+    stalled_out: u16,
+    /// Bitmap of IN endpoints with a packet waiting to be sent
+    in_busy: u16,
+    /// Endpoint with a packet waiting in the receive FIFO
+    out_ready: Option<u8>,
+    /// SETUP packet waiting to be read from endpoint zero
+    setup_packet: Option<[u8; 8]>,
+}
+
+// Safety: the peripheral is only used from a single hart and `state`
+// is only ever accessed within a critical section.
+unsafe impl<D> Sync for UsbBus<D> {}
+
+impl<D: UsbBusDriver> UsbBus<D> {
+    pub fn new(usb: D) -> Self {
+        Self {
+            usb,
+            state: UnsafeCell::new(State::default()),
+        }
+    }
+
+    /// Release the peripheral and consume self.
+    pub fn free(self) -> D {
+        self.usb
+    }
+
+    #[inline(always)]
+    fn with<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        riscv::interrupt::free(|| f(unsafe { &mut *self.state.get() }))
+    }
+}
+
+impl<D: UsbBusDriver> usb_device::bus::UsbBus for UsbBus<D> {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        if max_packet_size > 512 {
+            return Err(UsbError::EndpointMemoryOverflow);
+        }
+
+        let state = self.state.get_mut();
+        let (allocated, max_packet_sizes) = match ep_dir {
+            UsbDirection::In => (&mut state.allocated_in, &mut state.max_packet_size_in),
+            UsbDirection::Out => (&mut state.allocated_out, &mut state.max_packet_size_out),
+        };
+
+        let index = match ep_addr {
+            Some(ep_addr) if ep_addr.index() > MAX_ENDPOINT_NUMBER => {
+                return Err(UsbError::InvalidEndpoint);
+            }
+            Some(ep_addr) if *allocated & (1 << ep_addr.index()) != 0 => {
+                return Err(UsbError::InvalidEndpoint);
+            }
+            Some(ep_addr) => ep_addr.index(),
+            None => (1..=MAX_ENDPOINT_NUMBER)
+                .find(|index| *allocated & (1 << index) == 0)
+                .ok_or(UsbError::EndpointOverflow)?,
+        };
+
+        *allocated |= 1 << index;
+        max_packet_sizes[index] = max_packet_size;
+
+        trace!("usbd::alloc_ep {:?} {} -> {}", ep_dir, max_packet_size, index);
+
+        Ok(EndpointAddress::from_parts(index, ep_dir))
+    }
+
+    fn enable(&mut self) {
+        self.usb.connect();
+        self.usb.bus_reset();
+    }
+
+    fn reset(&self) {
+        let allocated_out = self.with(|state| {
+            state.stalled_in = 0;
+            state.stalled_out = 0;
+            state.in_busy = 0;
+            state.out_ready = None;
+            state.setup_packet = None;
+            state.allocated_out
+        });
+
+        // OUT endpoints are kept primed until their packet is read
+        for endpoint in (0..=MAX_ENDPOINT_NUMBER as u8).filter(|n| allocated_out & (1 << n) != 0) {
+            self.usb.prime_receive(endpoint);
+        }
+    }
+
+    fn set_device_address(&self, addr: u8) {
+        // usb-device only calls this once the status stage has completed
+        self.usb.set_address(addr);
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let index = ep_addr.index();
+        let max_packet_size = self.with(|state| {
+            if state.allocated_in & (1 << index) == 0 {
+                Err(UsbError::InvalidEndpoint)
+            } else if state.in_busy != 0 || self.usb.is_in_fifo_busy() {
+                Err(UsbError::WouldBlock)
+            } else {
+                Ok(state.max_packet_size_in[index])
+            }
+        })?;
+
+        if buf.len() > max_packet_size as usize {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        let bytes_written = match self.usb.try_write(index as u8, buf.iter()) {
+            Ok(bytes_written) => bytes_written,
+            Err(nb::Error::WouldBlock) => return Err(UsbError::WouldBlock),
+            Err(nb::Error::Other(_)) => return Err(UsbError::InvalidState),
+        };
+        self.with(|state| state.in_busy |= 1 << index);
+
+        Ok(bytes_written)
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        let index = ep_addr.index();
+
+        // SETUP packets are read from endpoint zero ahead of any data
+        if index == 0 {
+            if let Some(setup_packet) = self.with(|state| state.setup_packet.take()) {
+                if buf.len() < setup_packet.len() {
+                    return Err(UsbError::BufferOverflow);
+                }
+                buf[..setup_packet.len()].copy_from_slice(&setup_packet);
+                return Ok(setup_packet.len());
+            }
+        }
+
+        let ready = self.with(|state| {
+            if state.out_ready == Some(index as u8) {
+                state.out_ready = None;
+                true
+            } else {
+                false
+            }
+        });
+        if !ready {
+            return Err(UsbError::WouldBlock);
+        }
+
+        let result = self.usb.read(index as u8, buf);
+        self.usb.prime_receive(index as u8);
+
+        result.map_err(|e| match e {
+            SmolError::Stalled => UsbError::InvalidState,
+            _ => UsbError::BufferOverflow,
+        })
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let bit = 1 << ep_addr.index();
+        self.with(|state| {
+            let bitmap = match ep_addr.direction() {
+                UsbDirection::In => &mut state.stalled_in,
+                UsbDirection::Out => &mut state.stalled_out,
+            };
+            if stalled {
+                *bitmap |= bit;
+            } else {
+                *bitmap &= !bit;
+            }
+        });
+
+        self.usb.stall_endpoint_address(u8::from(ep_addr), stalled);
+        if !stalled {
+            self.usb.clear_feature_endpoint_halt(u8::from(ep_addr));
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        let bit = 1 << ep_addr.index();
+        self.with(|state| match ep_addr.direction() {
+            UsbDirection::In => state.stalled_in & bit != 0,
+            UsbDirection::Out => state.stalled_out & bit != 0,
+        })
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        if self.usb.take_bus_reset() {
+            return PollResult::Reset;
+        }
+
+        let mut setup_packet = [0; 8];
+        let setup = self.usb.take_setup(&mut setup_packet);
+        let out_ready = self.usb.take_out_ready();
+        let in_complete = self.usb.take_in_complete();
+
+        self.with(|state| {
+            if setup {
+                state.setup_packet = Some(setup_packet);
+            }
+            if out_ready.is_some() {
+                state.out_ready = out_ready;
+            }
+            if let Some(endpoint) = in_complete {
+                state.in_busy &= !(1 << endpoint);
+            }
+
+            let mut ep_out = 0;
+            if let Some(endpoint) = state.out_ready {
+                ep_out |= 1 << endpoint;
+            }
+            let ep_setup = if state.setup_packet.is_some() { 1 } else { 0 };
+            let ep_in_complete = in_complete.map(|endpoint| 1 << endpoint).unwrap_or(0);
+
+            if ep_out | ep_setup | ep_in_complete == 0 {
+                PollResult::None
+            } else {
+                PollResult::Data {
+                    ep_out: ep_out | ep_setup,
+                    ep_in_complete,
+                    ep_setup,
+                }
+            }
+        })
+    }
+}
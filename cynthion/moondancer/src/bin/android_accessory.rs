@@ -0,0 +1,275 @@
+#![no_std]
+#![no_main]
+
+//! Android Open Accessory compatible device on usb0 (target)
+//!
+//! Emulates an Android phone so that accessory host software can be
+//! tested against it. Once the host sends `START` the device
+//! re-enumerates in accessory mode and echoes any data received on
+//! the accessory bulk endpoint.
+
+use moondancer::{hal, pac, Message};
+
+use smolusb::class::aoa::{self, Accessory, AudioMode, Event, StringIndex};
+use smolusb::control::RequestType;
+use smolusb::descriptor::DeviceDescriptor;
+use smolusb::device::UsbDevice;
+use smolusb::event::UsbEvent;
+use smolusb::traits::{EndpointRead, EndpointWrite, UsbDriverOperations};
+
+use libgreat::{GreatError, GreatResult};
+
+use heapless::mpmc::MpMcQueue as Queue;
+
+use log::{debug, error, info, warn};
+
+// - configuration ------------------------------------------------------------
+
+const ENDPOINT_IN: u8 = 0x81;
+const ENDPOINT_OUT: u8 = 0x01;
+
+/// Capacity of the identification strings and control data buffer
+const ACCESSORY_BUFFER_SIZE: usize = 256;
+
+// - global static state ------------------------------------------------------
+
+static MESSAGE_QUEUE: Queue<Message, 32> = Queue::new();
+
+#[inline(always)]
+fn dispatch_message(message: Message) {
+    match MESSAGE_QUEUE.enqueue(message) {
+        Ok(()) => (),
+        Err(_) => {
+            error!("MachineExternal - message queue overflow");
+            panic!("MachineExternal - message queue overflow");
+        }
+    }
+}
+
+// - MachineExternal interrupt handler ----------------------------------------
+
+#[allow(non_snake_case)]
+#[no_mangle]
+fn MachineExternal() {
+    use moondancer::UsbInterface::Target;
+
+    let usb0 = unsafe { hal::Usb0::summon() };
+
+    // - usb0 interrupts - "target_phy" --
+    //
+    // OUT packets are left in the FIFO and read from the main loop.
+    if let Some(event) = usb0.next_event(|_endpoint| 0) {
+        if let UsbEvent::BusReset = event {
+            usb0.bus_reset();
+        }
+        dispatch_message(Message::from_usb_event(Target, event));
+
+    // - Unknown Interrupt --
+    } else {
+        let pending = pac::csr::interrupt::reg_pending();
+        dispatch_message(Message::HandleUnknownInterrupt(pending));
+    }
+}
+
+// - main entry point ---------------------------------------------------------
+
+#[cfg(feature = "vexriscv")]
+#[riscv_rt::pre_init]
+unsafe fn pre_main() {
+    pac::cpu::vexriscv::flush_icache();
+    #[cfg(feature = "vexriscv_dcache")]
+    pac::cpu::vexriscv::flush_dcache();
+}
+
+#[riscv_rt::entry]
+fn main() -> ! {
+    match main_loop() {
+        Ok(()) => {
+            error!("Firmware exited unexpectedly in main loop");
+            panic!("Firmware exited unexpectedly in main loop")
+        }
+        Err(e) => {
+            error!("Fatal error in firmware main loop: {}", e);
+            panic!("Fatal error in firmware main loop: {}", e)
+        }
+    }
+}
+
+// - main loop ----------------------------------------------------------------
+
+fn main_loop() -> GreatResult<()> {
+    let peripherals = pac::Peripherals::take().unwrap();
+
+    // initialize logging
+    moondancer::log::init(hal::Serial::new(peripherals.UART));
+    info!("Logging initialized");
+
+    // usb0: Target
+    let mut usb0 = new_device(
+        hal::Usb0::new(
+            peripherals.USB0,
+            peripherals.USB0_EP_CONTROL,
+            peripherals.USB0_EP_IN,
+            peripherals.USB0_EP_OUT,
+        ),
+        &aoa::DEVICE_DESCRIPTOR,
+    );
+    let speed = usb0.connect();
+    debug!("Connected usb0 device: {:?}", speed);
+
+    let mut accessory: Accessory<ACCESSORY_BUFFER_SIZE> = Accessory::new();
+
+    // enable interrupts
+    unsafe {
+        // set mstatus register: interrupt enable
+        riscv::interrupt::enable();
+
+        // set mie register: machine external interrupts enable
+        riscv::register::mie::set_mext();
+
+        // write csr: enable usb0 interrupts and events
+        pac::csr::interrupt::enable(pac::Interrupt::USB0);
+        pac::csr::interrupt::enable(pac::Interrupt::USB0_EP_CONTROL);
+        pac::csr::interrupt::enable(pac::Interrupt::USB0_EP_IN);
+        pac::csr::interrupt::enable(pac::Interrupt::USB0_EP_OUT);
+        usb0.hal_driver.enable_interrupts();
+    }
+
+    info!("Peripherals initialized, entering main loop.");
+
+    let mut rx_buffer: [u8; moondancer::EP_MAX_PACKET_SIZE] = [0; moondancer::EP_MAX_PACKET_SIZE];
+
+    loop {
+        let mut start = false;
+
+        while let Some(message) = MESSAGE_QUEUE.dequeue() {
+            use moondancer::{Message::*, UsbInterface::Target};
+
+            match message {
+                // - usb0 message handlers --
+
+                // Usb0 received USB bus reset
+                UsbBusReset(Target) => {
+                    accessory.handle_bus_reset();
+                    usb0.poll(&UsbEvent::BusReset, &[], &mut [])
+                        .map_err(|_| GreatError::BadMessage)?;
+                }
+
+                // Usb0 received setup packet
+                UsbReceiveSetupPacket(Target, setup_packet) => {
+                    if setup_packet.request_type() == RequestType::Vendor {
+                        if let Some(event) = accessory.handle_setup_packet(&setup_packet) {
+                            start |= event == Event::Start;
+                            log_event(&event);
+                        }
+                    }
+                    usb0.poll(&UsbEvent::Setup(setup_packet), &[], &mut [])
+                        .map_err(|_| GreatError::BadMessage)?;
+                }
+
+                // Usb0 received data on control endpoint
+                UsbReceivePacket(Target, 0, _) => {
+                    let bytes_read = usb0
+                        .hal_driver
+                        .read(0, &mut rx_buffer)
+                        .map_err(|_| GreatError::NoBufferSpaceAvailable)?;
+                    if let Some(event) = accessory.handle_receive_control_data(&rx_buffer[..bytes_read]) {
+                        log_event(&event);
+                    }
+                    usb0.hal_driver.ep_out_prime_receive(0);
+                }
+
+                // Usb0 received packet on the accessory endpoint
+                UsbReceivePacket(Target, ENDPOINT_OUT, _) => {
+                    let bytes_read = usb0
+                        .hal_driver
+                        .read(ENDPOINT_OUT, &mut rx_buffer)
+                        .map_err(|_| GreatError::NoBufferSpaceAvailable)?;
+                    debug!("AOA received {} bytes", bytes_read);
+                    if let Err(e) = usb0.hal_driver.write(ENDPOINT_IN, rx_buffer[..bytes_read].iter()) {
+                        warn!("AOA failed to echo {} bytes: {:?}", bytes_read, e);
+                    }
+                    usb0.hal_driver.ep_out_prime_receive(ENDPOINT_OUT);
+                }
+
+                // Usb0 received packet
+                UsbReceivePacket(Target, endpoint, _) => {
+                    let bytes_read = usb0
+                        .hal_driver
+                        .read(endpoint, &mut rx_buffer)
+                        .map_err(|_| GreatError::NoBufferSpaceAvailable)?;
+                    warn!("Received {} bytes on unknown endpoint {}", bytes_read, endpoint);
+                    usb0.hal_driver.ep_out_prime_receive(endpoint);
+                }
+
+                // Usb0 transfer complete
+                UsbTransferComplete(Target, endpoint) => {
+                    usb0.poll(&UsbEvent::InComplete { endpoint }, &[], &mut [])
+                        .map_err(|_| GreatError::BadMessage)?;
+                }
+
+                // Error Message
+                ErrorMessage(message) => {
+                    error!("MachineExternal Error - {}", message);
+                }
+
+                // Unhandled message
+                _ => {
+                    error!("Unhandled message: {:?}", message);
+                }
+            }
+        }
+
+        // re-enumerate in accessory mode once the status stage of
+        // START has been sent
+        if start {
+            info!(
+                "AOA starting accessory mode for: {} {} {}",
+                accessory.string(StringIndex::Manufacturer).unwrap_or(""),
+                accessory.string(StringIndex::Model).unwrap_or(""),
+                accessory.string(StringIndex::Version).unwrap_or(""),
+            );
+            if accessory.audio_mode() != AudioMode::None {
+                warn!("AOA audio interfaces are not supported, enumerating without audio");
+            }
+
+            usb0.disconnect();
+            unsafe { riscv::asm::delay(pac::clock::sysclk() / 10) };
+
+            usb0 = new_device(usb0.hal_driver, &aoa::ACCESSORY_DEVICE_DESCRIPTOR);
+            let speed = usb0.connect();
+            debug!("Reconnected usb0 device in accessory mode: {:?}", speed);
+        }
+    }
+}
+
+// - helpers ------------------------------------------------------------------
+
+fn new_device(
+    hal_driver: hal::Usb0,
+    device_descriptor: &'static DeviceDescriptor,
+) -> UsbDevice<'static, hal::Usb0> {
+    let mut device = UsbDevice::new(
+        hal_driver,
+        device_descriptor,
+        &aoa::CONFIGURATION_DESCRIPTOR_0,
+        &aoa::USB_STRING_DESCRIPTOR_0,
+        aoa::USB_STRING_DESCRIPTORS,
+    );
+    device.device_qualifier_descriptor = Some(&aoa::DEVICE_QUALIFIER_DESCRIPTOR);
+    device.other_speed_configuration_descriptor = Some(aoa::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0);
+    device.cb_vendor_request = Some(aoa::handle_vendor_request::<_, ACCESSORY_BUFFER_SIZE>);
+    device
+}
+
+fn log_event(event: &Event) {
+    match event {
+        Event::HidReportDescriptor { id, offset, data } => {
+            debug!("AOA hid {} report descriptor @{}: {:?}", id, offset, data);
+        }
+        Event::HidEvent { id, report } => {
+            info!("AOA hid {} event: {:?}", id, report);
+        }
+        _ => info!("AOA {:?}", event),
+    }
+}
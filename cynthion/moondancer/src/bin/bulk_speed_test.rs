@@ -3,10 +3,10 @@
 
 use moondancer::{hal, pac, Message};
 
-use smolusb::control::SetupPacket;
 use smolusb::descriptor::*;
 use smolusb::device::UsbDevice;
-use smolusb::traits::{ControlRead, EndpointRead, UnsafeUsbDriverOperations, UsbDriverOperations};
+use smolusb::event::UsbEvent;
+use smolusb::traits::{EndpointRead, UsbDriverOperations};
 
 use libgreat::{GreatError, GreatResult};
 
@@ -39,57 +39,13 @@ fn MachineExternal() {
     let usb0 = unsafe { hal::Usb0::summon() };
 
     // - usb0 interrupts - "host_phy" / "aux_phy" --
-
-    // USB0 UsbBusReset
-    if usb0.is_pending(pac::Interrupt::USB0) {
-        usb0.clear_pending(pac::Interrupt::USB0);
-        usb0.bus_reset();
-        dispatch_message(Message::UsbBusReset(Target))
-
-    // USB0_EP_CONTROL UsbReceiveSetupPacket
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_CONTROL) {
-        let mut setup_packet_buffer = [0_u8; 8];
-        usb0.read_control(&mut setup_packet_buffer);
-        usb0.clear_pending(pac::Interrupt::USB0_EP_CONTROL);
-
-        let message = match SetupPacket::try_from(setup_packet_buffer) {
-            Ok(setup_packet) => Message::UsbReceiveSetupPacket(Target, setup_packet),
-            Err(_e) => Message::ErrorMessage("USB0_EP_CONTROL failed to read setup packet"),
-        };
-        dispatch_message(message);
-
-    // USB0_EP_OUT UsbReceiveData
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_OUT) {
-        let endpoint = usb0.ep_out.data_ep.read().bits() as u8;
-
-        // discard packets from Bulk OUT transfer endpoint
-        /*if endpoint == 1 {
-            /*while usb0.ep_out.have.read().have().bit() {
-                let _b = usb0.ep_out.data.read().data().bits();
-            }*/
-            usb0.ep_out_prime_receive(1);
-            usb0.clear_pending(pac::Interrupt::USB0_EP_OUT);
-            return;
-        }*/
-
-        usb0.clear_pending(pac::Interrupt::USB0_EP_OUT);
-        dispatch_message(Message::UsbReceivePacket(
-            moondancer::UsbInterface::Target,
-            endpoint,
-            0,
-        ));
-
-    // USB0_EP_IN UsbTransferComplete
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_IN) {
-        let endpoint = usb0.ep_in.epno.read().bits() as u8;
-        usb0.clear_pending(pac::Interrupt::USB0_EP_IN);
-
-        // TODO something a little bit safer would be nice
-        unsafe {
-            usb0.clear_tx_ack_active();
+    //
+    // OUT packets are left in the FIFO and read from the main loop.
+    if let Some(event) = usb0.next_event(|_endpoint| 0) {
+        if let UsbEvent::BusReset = event {
+            usb0.bus_reset();
         }
-
-        dispatch_message(Message::UsbTransferComplete(Target, endpoint));
+        dispatch_message(Message::from_usb_event(Target, event));
 
     // - Unknown Interrupt --
     } else {
@@ -200,18 +156,24 @@ fn main_loop() -> GreatResult<()> {
                 // - usb0 message handlers --
 
                 // Usb0 received USB bus reset
-                UsbBusReset(Target) => (),
+                UsbBusReset(Target) => {
+                    usb0.poll(&UsbEvent::BusReset, &[], &mut [])
+                        .map_err(|_| GreatError::BadMessage)?;
+                }
 
                 // Usb0 received setup packet
                 UsbReceiveSetupPacket(Target, setup_packet) => {
                     test_command = TestCommand::Stop;
-                    usb0.handle_setup_request(&setup_packet)
+                    usb0.poll(&UsbEvent::Setup(setup_packet), &[], &mut [])
                         .map_err(|_| GreatError::BadMessage)?;
                 }
 
                 // Usb0 received packet
                 UsbReceivePacket(Target, endpoint, _) => {
-                    let bytes_read = usb0.hal_driver.read(endpoint, &mut rx_buffer);
+                    let bytes_read = usb0
+                        .hal_driver
+                        .read(endpoint, &mut rx_buffer)
+                        .map_err(|_| GreatError::NoBufferSpaceAvailable)?;
                     if endpoint == 1 {
                         leds.output.write(|w| unsafe { w.output().bits(0b11_1000) });
                         if counter % 100 == 0 {
@@ -261,8 +223,10 @@ fn main_loop() -> GreatResult<()> {
                 }
 
                 // Usb0 transfer complete
-                UsbTransferComplete(Target, _endpoint) => {
+                UsbTransferComplete(Target, endpoint) => {
                     leds.output.write(|w| unsafe { w.output().bits(0b00_0111) });
+                    usb0.poll(&UsbEvent::InComplete { endpoint }, &[], &mut [])
+                        .map_err(|_| GreatError::BadMessage)?;
                 }
 
                 // Error Message
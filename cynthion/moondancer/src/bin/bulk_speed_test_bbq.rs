@@ -3,10 +3,10 @@
 
 use moondancer::{hal, pac, Message};
 
-use smolusb::control::SetupPacket;
 use smolusb::descriptor::*;
 use smolusb::device::UsbDevice;
-use smolusb::traits::{ControlRead, UnsafeUsbDriverOperations, UsbDriverOperations};
+use smolusb::event::UsbEvent;
+use smolusb::traits::{EndpointRead, UsbDriverOperations};
 
 use libgreat::{GreatError, GreatResult};
 
@@ -35,76 +35,47 @@ fn MachineExternal() {
 
     // - usb0 interrupts - "host_phy" / "aux_phy" --
 
-    // USB0 UsbBusReset
-    let message = if usb0.is_pending(pac::Interrupt::USB0) {
-        usb0.clear_pending(pac::Interrupt::USB0);
-        usb0.bus_reset();
-        Message::UsbBusReset(Target)
-
-    // USB0_EP_CONTROL UsbReceiveSetupPacket
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_CONTROL) {
-        let mut setup_packet_buffer = [0_u8; 8];
-        usb0.read_control(&mut setup_packet_buffer);
-        usb0.clear_pending(pac::Interrupt::USB0_EP_CONTROL);
-
-        match SetupPacket::try_from(setup_packet_buffer) {
-            Ok(setup_packet) => Message::UsbReceiveSetupPacket(Target, setup_packet),
-            Err(_ee) => Message::ErrorMessage("USB0_EP_CONTROL failed to read setup packet"),
+    // OUT packets are read into the bbqueue, packets that don't fit are
+    // dropped
+    let event = usb0.next_event(|endpoint| {
+        let producer = match unsafe { USB_RECEIVE_BUFFER_PRODUCER.as_mut() } {
+            Some(producer) => producer,
+            None => {
+                error!("MachineExternal - no bbqueue");
+                let _ = usb0.read(endpoint, &mut []);
+                return 0;
+            }
+        };
+        match producer.grant_exact(moondancer::EP_MAX_PACKET_SIZE) {
+            Ok(mut grant) => {
+                let bytes_read = usb0.read(endpoint, grant.buf()).unwrap_or_else(|_| {
+                    leds.output.write(|w| unsafe { w.output().bits(0b10_0001) });
+                    moondancer::EP_MAX_PACKET_SIZE
+                });
+                grant.commit(bytes_read);
+                bytes_read
+            }
+            Err(_e) => {
+                error!("MachineExternal - no space in bbqueue");
+                let _ = usb0.read(endpoint, &mut []);
+                0
+            }
         }
+    });
 
-    // USB0_EP_OUT UsbReceiveData
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_OUT) {
-        let endpoint = usb0.ep_out.data_ep.read().bits() as u8;
-
-        // discard packets from Bulk OUT transfer endpoint
-        /*if endpoint == 1 {
-            /*while usb0.ep_out.have.read().have().bit() {
-                let _b = usb0.ep_out.data.read().data().bits();
-            }*/
-            usb0.ep_out_prime_receive(1);
-            usb0.clear_pending(pac::Interrupt::USB0_EP_OUT);
-            return;
-        }*/
-
-        if let Some(producer) = unsafe { USB_RECEIVE_BUFFER_PRODUCER.as_mut() } {
-            match producer.grant_exact(moondancer::EP_MAX_PACKET_SIZE) {
-                Ok(mut grant) => {
-                    let mut bytes_read = 0;
-                    while usb0.ep_out.have.read().have().bit() {
-                        let b = usb0.ep_out.data.read().data().bits();
-                        if bytes_read < moondancer::EP_MAX_PACKET_SIZE {
-                            grant.buf()[bytes_read] = b;
-                        } else {
-                            leds.output.write(|w| unsafe { w.output().bits(0b10_0001) });
-                        }
-                        bytes_read += 1;
-                    }
-                    grant.commit(bytes_read);
-                    usb0.clear_pending(pac::Interrupt::USB0_EP_OUT);
-                    Message::UsbReceivePacket(Target, endpoint, bytes_read)
-                }
-                Err(_e) => Message::ErrorMessage("no space in bbqueue"),
+    let message = match event {
+        Some(event) => {
+            if let UsbEvent::BusReset = event {
+                usb0.bus_reset();
             }
-        } else {
-            Message::ErrorMessage("no bbqueue")
+            Message::from_usb_event(Target, event)
         }
 
-    // USB0_EP_IN UsbTransferComplete
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_IN) {
-        usb0.clear_pending(pac::Interrupt::USB0_EP_IN);
-        let endpoint = usb0.ep_in.epno.read().bits() as u8;
-
-        // TODO something a little bit safer would be nice
-        unsafe {
-            usb0.clear_tx_ack_active();
+        // - Unknown Interrupt --
+        None => {
+            let pending = pac::csr::interrupt::reg_pending();
+            Message::HandleUnknownInterrupt(pending)
         }
-
-        Message::UsbTransferComplete(Target, endpoint)
-
-    // - Unknown Interrupt --
-    } else {
-        let pending = pac::csr::interrupt::reg_pending();
-        Message::HandleUnknownInterrupt(pending)
     };
 
     match MESSAGE_QUEUE.enqueue(message) {
@@ -217,13 +188,10 @@ fn main_loop() -> GreatResult<()> {
             match message {
                 // - usb0 message handlers --
 
-                // Usb0 received USB bus reset
-                UsbBusReset(Target) => (),
-
                 // Usb0 received setup packet
                 UsbReceiveSetupPacket(Target, setup_packet) => {
                     test_command = TestCommand::Stop;
-                    usb0.handle_setup_request(&setup_packet)
+                    usb0.poll(&UsbEvent::Setup(setup_packet), &[], &mut [])
                         .map_err(|_| GreatError::BadMessage)?;
                 }
 
@@ -303,8 +271,13 @@ fn main_loop() -> GreatResult<()> {
                     usb0.hal_driver.ep_out_prime_receive(2);
                 }
 
-                // Usb0 transfer complete
-                UsbTransferComplete(Target, _endpoint) => {}
+                // Usb0 received USB bus reset or transfer complete
+                UsbBusReset(Target) | UsbTransferComplete(Target, _) => {
+                    if let Some((_, event)) = message.into_usb_event() {
+                        usb0.poll(&event, &[], &mut [])
+                            .map_err(|_| GreatError::BadMessage)?;
+                    }
+                }
 
                 // Error Message
                 ErrorMessage(message) => {
@@ -3,10 +3,10 @@
 
 use moondancer::{hal, pac, Message, UsbDataPacket};
 
-use smolusb::control::SetupPacket;
 use smolusb::descriptor::*;
 use smolusb::device::UsbDevice;
-use smolusb::traits::{ControlRead, EndpointRead, UnsafeUsbDriverOperations, UsbDriverOperations};
+use smolusb::event::UsbEvent;
+use smolusb::traits::{EndpointRead, UsbDriverOperations};
 
 use libgreat::{GreatError, GreatResult};
 
@@ -54,74 +54,37 @@ fn MachineExternal() {
 
     // - usb0 interrupts - "host_phy" / "aux_phy" --
 
-    // USB0_EP_OUT UsbReceiveData
-    if usb0.is_pending(pac::Interrupt::USB0_EP_OUT) {
-        let endpoint = usb0.ep_out.data_ep.read().bits() as u8;
-
-        // discard packets from Bulk OUT transfer endpoint
-        /*if endpoint == 1 {
-            /*while usb0.ep_out.have.read().have().bit() {
-                let _b = usb0.ep_out.data.read().data().bits();
-            }*/
-            usb0.ep_out_prime_receive(1);
-            usb0.clear_pending(pac::Interrupt::USB0_EP_OUT);
-            return;
-        }*/
-
-        // read data from endpoint
-        let mut receive_packet = UsbDataPacket {
-            interface: moondancer::UsbInterface::Target,
+    // OUT packets are read into a UsbDataPacket for the main loop
+    let mut receive_packet = None;
+    let event = usb0.next_event(|endpoint| {
+        let packet = receive_packet.insert(UsbDataPacket {
+            interface: Target,
             endpoint,
             bytes_read: 0,
             buffer: [0_u8; moondancer::EP_MAX_PACKET_SIZE],
-        };
-        receive_packet.bytes_read = usb0.read(endpoint, &mut receive_packet.buffer);
-
-        // clear interrupt
-        usb0.clear_pending(pac::Interrupt::USB0_EP_OUT);
-
+        });
+        // packets larger than the buffer are truncated
+        packet.bytes_read = usb0
+            .read(endpoint, &mut packet.buffer)
+            .unwrap_or(moondancer::EP_MAX_PACKET_SIZE);
+        packet.bytes_read
+    });
+
+    match (event, receive_packet) {
         // dispatch packet to main loop
-        dispatch_receive_packet(receive_packet);
-
-        // prime for immediate reception if we have space in our receive queue
-        //if unsafe { USB_RECEIVE_PACKET_QUEUE.len() } < unsafe { USB_RECEIVE_PACKET_QUEUE.capacity() } {
-        //    usb0.ep_out_prime_receive(endpoint);
-        //}
-
-        // USB0 UsbBusReset
-    } else if usb0.is_pending(pac::Interrupt::USB0) {
-        usb0.clear_pending(pac::Interrupt::USB0);
-        usb0.bus_reset();
-        dispatch_message(Message::UsbBusReset(Target))
-
-    // USB0_EP_CONTROL UsbReceiveSetupPacket
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_CONTROL) {
-        let mut setup_packet_buffer = [0_u8; 8];
-        usb0.read_control(&mut setup_packet_buffer);
-        usb0.clear_pending(pac::Interrupt::USB0_EP_CONTROL);
-
-        let message = match SetupPacket::try_from(setup_packet_buffer) {
-            Ok(setup_packet) => Message::UsbReceiveSetupPacket(Target, setup_packet),
-            Err(_e) => Message::ErrorMessage("USB0_EP_CONTROL failed to read setup packet"),
-        };
-        dispatch_message(message);
-
-    // USB0_EP_IN UsbTransferComplete
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_IN) {
-        usb0.clear_pending(pac::Interrupt::USB0_EP_IN);
-        let endpoint = usb0.ep_in.epno.read().bits() as u8;
-
-        // TODO something a little bit safer would be nice
-        unsafe {
-            usb0.clear_tx_ack_active();
+        (Some(_), Some(receive_packet)) => dispatch_receive_packet(receive_packet),
+        (Some(event), None) => {
+            if let UsbEvent::BusReset = event {
+                usb0.bus_reset();
+            }
+            dispatch_message(Message::from_usb_event(Target, event));
         }
 
-        dispatch_message(Message::UsbTransferComplete(Target, endpoint));
-
-    // - Unknown Interrupt --
-    } else {
-        let pending = pac::csr::interrupt::reg_pending();
-        dispatch_message(Message::HandleUnknownInterrupt(pending));
+        // - Unknown Interrupt --
+        (None, _) => {
+            let pending = pac::csr::interrupt::reg_pending();
+            dispatch_message(Message::HandleUnknownInterrupt(pending));
+        }
     }
 }
 
@@ -290,24 +253,26 @@ fn main_loop() -> GreatResult<()> {
             match message {
                 // - usb0 message handlers --
 
-                // Usb0 received USB bus reset
-                UsbBusReset(Target) => (),
-
                 // Usb0 received setup packet
                 UsbReceiveSetupPacket(Target, setup_packet) => {
                     test_command = TestCommand::Stop;
-                    usb0.handle_setup_request(&setup_packet)
+                    usb0.poll(&UsbEvent::Setup(setup_packet), &[], &mut [])
                         .map_err(|_| GreatError::BadMessage)?;
                 }
 
-                // Usb0 transfer complete
-                UsbTransferComplete(Target, _endpoint) => {}
-
                 // Error Message
                 ErrorMessage(message) => {
                     error!("MachineExternal Error - {}", message);
                 }
 
+                // Usb0 received USB bus reset or transfer complete
+                UsbBusReset(Target) | UsbTransferComplete(Target, _) => {
+                    if let Some((_, event)) = message.into_usb_event() {
+                        usb0.poll(&event, &[], &mut [])
+                            .map_err(|_| GreatError::BadMessage)?;
+                    }
+                }
+
                 // Unhandled message
                 _ => {
                     error!("Unhandled message: {:?}", message);
@@ -9,10 +9,8 @@ use moondancer::hal;
 use smolusb::class::cdc;
 use smolusb::control::SetupPacket;
 use smolusb::device::{Speed, UsbDevice};
-use smolusb::traits::{
-    ControlRead, EndpointRead, EndpointWrite, EndpointWriteRef, UnsafeUsbDriverOperations,
-    UsbDriverOperations,
-};
+use smolusb::event::UsbEvent;
+use smolusb::traits::{ControlRead, EndpointRead, EndpointWrite, UsbDriverOperations};
 
 use log::{debug, error, info, trace};
 
@@ -63,91 +61,58 @@ fn MachineExternal() {
     leds.output
         .write(|w| unsafe { w.output().bits(pending as u8) });
 
+    // OUT packets are read into a UsbDataPacket for the main loop
+    let mut receive_packet = None;
+
     // - Usb0 (Target) interrupts --
-    if usb0.is_pending(pac::Interrupt::USB0) {
-        usb0.clear_pending(pac::Interrupt::USB0);
-        usb0.bus_reset();
-        dispatch_message(Message::HandleInterrupt(pac::Interrupt::USB0));
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_CONTROL) {
-        let mut buffer = [0_u8; 8];
-        usb0.read_control(&mut buffer);
-        let setup_packet = match SetupPacket::try_from(buffer) {
-            Ok(packet) => packet,
-            Err(e) => {
-                error!("MachineExternal USB0_EP_CONTROL - {:?}", e);
-                return;
-            }
-        };
-        usb0.clear_pending(pac::Interrupt::USB0_EP_CONTROL);
-        dispatch_message(Message::UsbReceiveSetupPacket(Target, setup_packet));
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_IN) {
-        usb0.clear_pending(pac::Interrupt::USB0_EP_IN);
-        // TODO something a little bit safer would be nice
-        unsafe {
-            usb0.clear_tx_ack_active();
-        }
-        dispatch_message(Message::HandleInterrupt(pac::Interrupt::USB0_EP_IN));
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_OUT) {
-        // read data from endpoint
-        let endpoint = usb0.ep_out.data_ep.read().bits() as u8;
-        let mut receive_packet = UsbDataPacket {
+    let event = if let Some(event) = usb0.next_event(|endpoint| {
+        let packet = receive_packet.insert(UsbDataPacket {
             interface: Target,
             endpoint,
             bytes_read: 0,
             buffer: [0_u8; moondancer::EP_MAX_PACKET_SIZE],
-        };
-        receive_packet.bytes_read = usb0.read(endpoint, &mut receive_packet.buffer);
-
-        // clear pending IRQ after data is read
-        usb0.clear_pending(pac::Interrupt::USB0_EP_OUT);
-
-        // dispatch packet to main loop
-        dispatch_receive_packet(receive_packet);
+        });
+        // packets larger than the buffer are truncated
+        packet.bytes_read = usb0
+            .read(endpoint, &mut packet.buffer)
+            .unwrap_or(moondancer::EP_MAX_PACKET_SIZE);
+        packet.bytes_read
+    }) {
+        if let UsbEvent::BusReset = event {
+            usb0.bus_reset();
+        }
+        Some((Target, event))
 
     // - Usb1 (Aux) interrupts --
-    } else if usb1.is_pending(pac::Interrupt::USB1) {
-        usb1.clear_pending(pac::Interrupt::USB1);
-        usb1.bus_reset();
-        dispatch_message(Message::HandleInterrupt(pac::Interrupt::USB1));
-    } else if usb1.is_pending(pac::Interrupt::USB1_EP_CONTROL) {
-        let mut buffer = [0_u8; 8];
-        usb1.read_control(&mut buffer);
-        let setup_packet = match SetupPacket::try_from(buffer) {
-            Ok(packet) => packet,
-            Err(e) => {
-                error!("MachineExternal USB1_EP_CONTROL - {:?}", e);
-                return;
-            }
-        };
-        usb1.clear_pending(pac::Interrupt::USB1_EP_CONTROL);
-        dispatch_message(Message::UsbReceiveSetupPacket(Aux, setup_packet));
-    } else if usb1.is_pending(pac::Interrupt::USB1_EP_IN) {
-        usb1.clear_pending(pac::Interrupt::USB1_EP_IN);
-        // TODO something a little bit safer would be nice
-        unsafe {
-            usb1.clear_tx_ack_active();
-        }
-        dispatch_message(Message::HandleInterrupt(pac::Interrupt::USB1_EP_IN));
-    } else if usb1.is_pending(pac::Interrupt::USB1_EP_OUT) {
-        // read data from endpoint
-        let endpoint = usb1.ep_out.data_ep.read().bits() as u8;
-        let mut receive_packet = UsbDataPacket {
+    } else if let Some(event) = usb1.next_event(|endpoint| {
+        let packet = receive_packet.insert(UsbDataPacket {
             interface: Aux,
             endpoint,
             bytes_read: 0,
             buffer: [0_u8; moondancer::EP_MAX_PACKET_SIZE],
-        };
-        receive_packet.bytes_read = usb1.read(endpoint, &mut receive_packet.buffer);
-
-        // clear pending IRQ after data is read
-        usb1.clear_pending(pac::Interrupt::USB1_EP_OUT);
+        });
+        // packets larger than the buffer are truncated
+        packet.bytes_read = usb1
+            .read(endpoint, &mut packet.buffer)
+            .unwrap_or(moondancer::EP_MAX_PACKET_SIZE);
+        packet.bytes_read
+    }) {
+        if let UsbEvent::BusReset = event {
+            usb1.bus_reset();
+        }
+        Some((Aux, event))
+    } else {
+        None
+    };
 
+    match (event, receive_packet) {
         // dispatch packet to main loop
-        dispatch_receive_packet(receive_packet);
-
-    // - Unknown Interrupt --
-    } else {
-        dispatch_message(Message::HandleUnknownInterrupt(pending));
+        (Some(_), Some(receive_packet)) => dispatch_receive_packet(receive_packet),
+        (Some((interface, event)), None) => {
+            dispatch_message(Message::from_usb_event(interface, event))
+        }
+        // - Unknown Interrupt --
+        (None, _) => dispatch_message(Message::HandleUnknownInterrupt(pending)),
     }
 }
 
@@ -261,9 +226,12 @@ fn main() -> ! {
                             endpoint,
                             &buffer[0..8],
                         );
-                        usb1.hal_driver
-                            .write_ref(endpoint, buffer.iter().take(bytes_read).into_iter());
-                        info!("Sent {} bytes to usb1 endpoint: {}", bytes_read, endpoint);
+                        match usb1.hal_driver.write(endpoint, buffer.iter().take(bytes_read)) {
+                            Ok(bytes_written) => {
+                                info!("Sent {} bytes to usb1 endpoint: {}", bytes_written, endpoint)
+                            }
+                            Err(e) => error!("Failed to send to usb1 endpoint {}: {:?}", endpoint, e),
+                        }
                     }
                     usb0.hal_driver.ep_out_prime_receive(endpoint);
                 }
@@ -277,9 +245,12 @@ fn main() -> ! {
                             endpoint,
                             &buffer[0..8],
                         );
-                        usb0.hal_driver
-                            .write_ref(endpoint, buffer.iter().take(bytes_read).into_iter());
-                        info!("Sent {} bytes to usb0 endpoint: {}", bytes_read, endpoint);
+                        match usb0.hal_driver.write(endpoint, buffer.iter().take(bytes_read)) {
+                            Ok(bytes_written) => {
+                                info!("Sent {} bytes to usb0 endpoint: {}", bytes_written, endpoint)
+                            }
+                            Err(e) => error!("Failed to send to usb0 endpoint {}: {:?}", endpoint, e),
+                        }
                     }
                     usb1.hal_driver.ep_out_prime_receive(endpoint);
                 }
@@ -292,33 +263,13 @@ fn main() -> ! {
         if let Some(message) = MESSAGE_QUEUE.dequeue() {
             use moondancer::UsbInterface::{Aux, Target};
 
-            match message {
-                // usb0 message handlers
-                Message::UsbReceiveSetupPacket(Target, packet) => {
-                    match usb0.handle_setup_request(&packet) {
-                        Ok(()) => (),
-                        Err(e) => error!("  usb0 handle_setup_request: {:?}: {:?}", e, packet),
-                    }
-                }
-                // usb1 message handlers
-                Message::UsbReceiveSetupPacket(Aux, packet) => {
-                    match usb1.handle_setup_request(&packet) {
-                        Ok(()) => (),
-                        Err(e) => error!("  usb1 handle_setup_request: {:?}: {:?}", e, packet),
-                    }
-                }
-
-                // usb0 interrupts
-                Message::HandleInterrupt(pac::Interrupt::USB0) => {
-                    trace!("MachineExternal - USB0");
-                }
-                // usb1 interrupts
-                Message::HandleInterrupt(pac::Interrupt::USB1) => {
-                    trace!("MachineExternal - USB1");
-                }
-
-                // unhandled
-                _ => (),
+            let result = match message.into_usb_event() {
+                Some((Target, event)) => usb0.poll(&event, &[], &mut []),
+                Some((Aux, event)) => usb1.poll(&event, &[], &mut []),
+                _ => Ok(true),
+            };
+            if let Err(e) = result {
+                error!("  usb poll: {:?}", e);
             }
         }
     }
@@ -328,21 +279,21 @@ fn main() -> ! {
 
 fn handle_vendor_request<'a, D>(device: &UsbDevice<'a, D>, _setup_packet: &SetupPacket, request: u8)
 where
-    D: ControlRead + EndpointRead + EndpointWrite + EndpointWriteRef + UsbDriverOperations,
+    D: ControlRead + EndpointRead + EndpointWrite + UsbDriverOperations,
 {
     let request = cdc::ch34x::VendorRequest::from(request);
     debug!("  CDC-SERIAL vendor_request: {:?}", request);
 
     // we can just spoof these
-    device.hal_driver.write(0, [0, 0].into_iter());
+    device.hal_driver.write(0, [0, 0].into_iter()).ok();
 }
 
 fn handle_string_request<'a, D>(device: &UsbDevice<'a, D>, _setup_packet: &SetupPacket, index: u8)
 where
-    D: ControlRead + EndpointRead + EndpointWrite + EndpointWriteRef + UsbDriverOperations,
+    D: ControlRead + EndpointRead + EndpointWrite + UsbDriverOperations,
 {
     debug!("  CDC-SERIAL string_request: {}", index);
 
     // we can just spoof this too
-    device.hal_driver.write(0, [].into_iter());
+    device.hal_driver.write(0, core::iter::empty::<u8>()).ok();
 }
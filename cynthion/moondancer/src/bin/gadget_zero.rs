@@ -0,0 +1,140 @@
+#![no_std]
+#![no_main]
+
+//! Linux Gadget Zero compatible test device on usb0 (target)
+//!
+//! Run the standard `usbtest` battery from a Linux host with:
+//!
+//! ```text
+//! sudo modprobe usbtest
+//! sudo testusb -a
+//! ```
+//!
+//! Select the loopback function by setting `FUNCTION` below.
+//!
+//! Device controller events are polled from the main loop and
+//! dispatched by `UsbDevice::poll`.
+
+use moondancer::{hal, pac};
+
+use smolusb::class::gadget_zero::{self, Function, GadgetZero, Pattern};
+use smolusb::device::{Speed, UsbDevice};
+use smolusb::event::UsbEvent;
+
+use libgreat::{GreatError, GreatResult};
+
+use log::{debug, error, info, warn};
+
+// - configuration ------------------------------------------------------------
+
+const FUNCTION: Function = Function::SourceSink;
+const PATTERN: Pattern = Pattern::Zero;
+
+const ENDPOINT_IN: u8 = 0x81;
+const ENDPOINT_OUT: u8 = 0x01;
+
+/// Size of the buffer used by the control write and read tests
+const CONTROL_BUFFER_SIZE: usize = 512;
+
+// - main entry point ---------------------------------------------------------
+
+#[cfg(feature = "vexriscv")]
+#[riscv_rt::pre_init]
+unsafe fn pre_main() {
+    pac::cpu::vexriscv::flush_icache();
+    #[cfg(feature = "vexriscv_dcache")]
+    pac::cpu::vexriscv::flush_dcache();
+}
+
+#[riscv_rt::entry]
+fn main() -> ! {
+    match main_loop() {
+        Ok(()) => {
+            error!("Firmware exited unexpectedly in main loop");
+            panic!("Firmware exited unexpectedly in main loop")
+        }
+        Err(e) => {
+            error!("Fatal error in firmware main loop: {}", e);
+            panic!("Fatal error in firmware main loop: {}", e)
+        }
+    }
+}
+
+// - main loop ----------------------------------------------------------------
+
+fn main_loop() -> GreatResult<()> {
+    let peripherals = pac::Peripherals::take().unwrap();
+
+    // initialize logging
+    moondancer::log::init(hal::Serial::new(peripherals.UART));
+    info!("Logging initialized");
+
+    let (configuration_descriptor, other_speed_configuration_descriptor) = match FUNCTION {
+        Function::SourceSink => (
+            &gadget_zero::CONFIGURATION_DESCRIPTOR_SOURCE_SINK,
+            gadget_zero::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_SOURCE_SINK,
+        ),
+        Function::Loopback => (
+            &gadget_zero::CONFIGURATION_DESCRIPTOR_LOOPBACK,
+            gadget_zero::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_LOOPBACK,
+        ),
+    };
+
+    // usb0: Target
+    let mut usb0 = UsbDevice::new(
+        hal::Usb0::new(
+            peripherals.USB0,
+            peripherals.USB0_EP_CONTROL,
+            peripherals.USB0_EP_IN,
+            peripherals.USB0_EP_OUT,
+        ),
+        &gadget_zero::DEVICE_DESCRIPTOR,
+        configuration_descriptor,
+        &gadget_zero::USB_STRING_DESCRIPTOR_0,
+        gadget_zero::USB_STRING_DESCRIPTORS,
+    );
+    usb0.device_qualifier_descriptor = Some(&gadget_zero::DEVICE_QUALIFIER_DESCRIPTOR);
+    usb0.other_speed_configuration_descriptor = Some(other_speed_configuration_descriptor);
+
+    let speed = usb0.connect();
+    debug!("Connected usb0 device: {:?}", speed);
+
+    let max_packet_size = match speed {
+        Speed::High => 512,
+        _ => 64,
+    };
+    let mut gadget: GadgetZero<CONTROL_BUFFER_SIZE> =
+        GadgetZero::new(FUNCTION, PATTERN, ENDPOINT_IN, ENDPOINT_OUT, max_packet_size);
+
+    // enable usb0 events, they are polled rather than handled by
+    // an interrupt handler
+    usb0.hal_driver.enable_interrupts();
+
+    info!("Peripherals initialized, entering main loop.");
+
+    let mut rx_buffer: [u8; moondancer::EP_MAX_PACKET_SIZE] = [0; moondancer::EP_MAX_PACKET_SIZE];
+
+    loop {
+        while let Some(event) = usb0.hal_driver.poll_event(&mut rx_buffer) {
+            if let UsbEvent::BusReset = event {
+                if gadget.packets_in > 0 || gadget.packets_out > 0 {
+                    info!(
+                        "GADGET_ZERO in: {} out: {} errors: {}",
+                        gadget.packets_in, gadget.packets_out, gadget.errors
+                    );
+                }
+            }
+
+            let handled = usb0
+                .poll(&event, &rx_buffer, &mut [&mut gadget])
+                .map_err(|_| GreatError::BadMessage)?;
+
+            if let (false, UsbEvent::OutPacket { endpoint, length }) = (handled, &event) {
+                if *endpoint != 0 {
+                    warn!("Received {} bytes on unknown endpoint {}", length, endpoint);
+                    usb0.hal_driver.ep_out_prime_receive(*endpoint);
+                }
+            }
+        }
+    }
+}
@@ -9,13 +9,17 @@ use pac::csr::interrupt;
 
 use smolusb::class;
 use smolusb::control::{Direction, RequestType, SetupPacket};
-use smolusb::device::{Speed, UsbDevice};
+use smolusb::device::{DeviceState, Speed, UsbDevice};
+use smolusb::event::UsbEvent;
 use smolusb::traits::{
-    ControlRead, EndpointRead, EndpointWrite, EndpointWriteRef, UnsafeUsbDriverOperations,
-    UsbDriverOperations,
+    ControlRead, EndpointRead, EndpointWrite, UnsafeUsbDriverOperations, UsbDriverOperations,
 };
 
-use libgreat::gcp::{iter_to_response, GcpResponse, GCP_MAX_RESPONSE_LENGTH};
+use moondancer::gcp::control::ControlTransport;
+
+use libgreat::gcp::bulk::BulkTransport;
+use libgreat::gcp::serial::{Channel, SerialResponse, SerialTransport};
+use libgreat::gcp::{Classes, GcpBuffers, GcpClass};
 use libgreat::{GreatError, GreatResult};
 
 use heapless::mpmc::MpMcQueue as Queue;
@@ -28,6 +32,9 @@ use core::{array, iter, slice};
 
 static MESSAGE_QUEUE: Queue<Message, 128> = Queue::new();
 
+/// Command and response buffers shared by the gcp transports
+static mut GCP_BUFFERS: GcpBuffers = GcpBuffers::new();
+
 #[inline(always)]
 fn dispatch_message(message: Message) {
     match MESSAGE_QUEUE.enqueue(message) {
@@ -52,87 +59,27 @@ fn MachineExternal() {
     use moondancer::UsbInterface::{Aux, Target};
 
     // peripherals
-    let peripherals = unsafe { pac::Peripherals::steal() };
     let usb0 = unsafe { hal::Usb0::summon() };
     let usb1 = unsafe { hal::Usb1::summon() };
 
-    let pending = interrupt::reg_pending();
-
     // - usb1 interrupts - "aux_phy" (host on r0.4) --
-
-    // USB1 UsbBusReset
-    if usb1.is_pending(pac::Interrupt::USB1) {
-        usb1.clear_pending(pac::Interrupt::USB1);
-        usb1.bus_reset();
-        dispatch_message(Message::UsbBusReset(Aux));
-
-    // USB1_EP_CONTROL UsbReceiveSetupPacket
-    } else if usb1.is_pending(pac::Interrupt::USB1_EP_CONTROL) {
-        let mut setup_packet_buffer = [0_u8; 8];
-        usb1.read_control(&mut setup_packet_buffer);
-        usb1.clear_pending(pac::Interrupt::USB1_EP_CONTROL);
-        let message = match SetupPacket::try_from(setup_packet_buffer) {
-            Ok(setup_packet) => Message::UsbReceiveSetupPacket(Aux, setup_packet),
-            Err(e) => Message::ErrorMessage("USB1_EP_CONTROL failed to read setup packet"),
-        };
-        dispatch_message(message);
-
-    // USB1_EP_OUT UsbReceiveData
-    } else if usb1.is_pending(pac::Interrupt::USB1_EP_OUT) {
-        let endpoint = usb1.ep_out.data_ep.read().bits() as u8;
-        usb1.clear_pending(pac::Interrupt::USB1_EP_OUT);
-        dispatch_message(Message::UsbReceivePacket(Aux, endpoint, 0));
-
-    // USB1_EP_IN UsbTransferComplete
-    } else if usb1.is_pending(pac::Interrupt::USB1_EP_IN) {
-        let endpoint = usb1.ep_in.epno.read().bits() as u8;
-        usb1.clear_pending(pac::Interrupt::USB1_EP_IN);
-
-        // TODO something a little bit safer would be nice
-        unsafe {
-            usb1.clear_tx_ack_active();
+    //
+    // OUT packets are left in the FIFO and read from the main loop.
+    if let Some(event) = usb1.next_event(|_endpoint| 0) {
+        if let UsbEvent::BusReset = event {
+            usb1.bus_reset();
         }
-
-        dispatch_message(Message::UsbTransferComplete(Aux, endpoint));
+        dispatch_message(Message::from_usb_event(Aux, event));
 
     // - usb0 interrupts - "target_phy" --
-
-    // USB0 UsbBusReset
-    } else if usb0.is_pending(pac::Interrupt::USB0) {
-        usb0.clear_pending(pac::Interrupt::USB0);
-        dispatch_message(Message::UsbBusReset(Target));
-
-    // USB0_EP_CONTROL UsbReceiveSetupPacket
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_CONTROL) {
-        let mut setup_packet_buffer = [0_u8; 8];
-        usb0.read_control(&mut setup_packet_buffer);
-        usb0.clear_pending(pac::Interrupt::USB0_EP_CONTROL);
-        let message = match SetupPacket::try_from(setup_packet_buffer) {
-            Ok(setup_packet) => Message::UsbReceiveSetupPacket(Target, setup_packet),
-            Err(e) => Message::ErrorMessage("USB0_EP_CONTROL failed to read setup packet"),
-        };
-        dispatch_message(message);
-
-    // USB0_EP_OUT UsbReceiveData
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_OUT) {
-        let endpoint = usb0.ep_out.data_ep.read().bits() as u8;
-        usb0.clear_pending(pac::Interrupt::USB0_EP_OUT);
-        dispatch_message(Message::UsbReceivePacket(Target, endpoint, 0));
-
-    // USB0_EP_IN UsbTransferComplete
-    } else if usb0.is_pending(pac::Interrupt::USB0_EP_IN) {
-        let endpoint = usb0.ep_in.epno.read().bits() as u8;
-        usb0.clear_pending(pac::Interrupt::USB0_EP_IN);
-
-        // TODO something a little bit safer would be nice
-        unsafe {
-            usb0.clear_tx_ack_active();
-        }
-
-        dispatch_message(Message::UsbTransferComplete(Target, endpoint));
+    //
+    // Bus resets are left to the facedancer host.
+    } else if let Some(event) = usb0.next_event(|_endpoint| 0) {
+        dispatch_message(Message::from_usb_event(Target, event));
 
     // - Unknown Interrupt --
     } else {
+        let pending = interrupt::reg_pending();
         dispatch_message(Message::HandleUnknownInterrupt(pending));
     }
 }
@@ -184,12 +131,15 @@ struct Firmware<'a> {
     // peripherals
     leds: pac::LEDS,
     usb1: UsbDevice<'a, hal::Usb1>,
+    uart: hal::Serial,
 
     // state
-    gcp_response: Option<GcpResponse<'a>>,
+    gcp_buffers: &'a mut GcpBuffers,
+    gcp_control: ControlTransport,
+    gcp_bulk: BulkTransport,
+    gcp_serial: SerialTransport,
 
     // classes
-    core: libgreat::gcp::class_core::Core,
     moondancer: moondancer::gcp::moondancer::Moondancer,
 }
 
@@ -199,6 +149,9 @@ impl<'a> Firmware<'a> {
         moondancer::log::init(hal::Serial::new(peripherals.UART));
         info!("Logging initialized");
 
+        // uart: the logger owns the transmit side, we receive gcp commands
+        let uart = unsafe { hal::Serial::summon() };
+
         // usb1: aux (host on r0.4)
         let mut usb1 = UsbDevice::new(
             hal::Usb1::new(
@@ -224,23 +177,20 @@ impl<'a> Firmware<'a> {
             peripherals.USB0_EP_OUT,
         );
 
-        // initialize class registry
-        static CLASSES: [libgreat::gcp::Class; 3] = [
-            libgreat::gcp::class_core::CLASS,
-            moondancer::gcp::firmware::CLASS,
-            moondancer::gcp::moondancer::CLASS,
-        ];
-        let classes = libgreat::gcp::Classes(&CLASSES);
-
         // initialize classes
-        let core = libgreat::gcp::class_core::Core::new(classes, moondancer::BOARD_INFORMATION);
         let moondancer = moondancer::gcp::moondancer::Moondancer::new(usb0);
 
         Self {
             leds: peripherals.LEDS,
             usb1,
-            gcp_response: None,
-            core,
+            uart,
+            // safety: the buffers are only referenced by the firmware
+            gcp_buffers: unsafe { &mut GCP_BUFFERS },
+            gcp_control: ControlTransport::new(
+                moondancer::usb::DEVICE_DESCRIPTOR.max_packet_size as usize,
+            ),
+            gcp_bulk: BulkTransport::new(moondancer::EP_MAX_PACKET_SIZE),
+            gcp_serial: SerialTransport::new(),
             moondancer,
         }
     }
@@ -255,6 +205,11 @@ impl<'a> Firmware<'a> {
         let speed = self.usb1.connect();
         info!("Connected usb1 device: {:?}", speed);
 
+        // prime the gcp bulk transport
+        self.usb1
+            .hal_driver
+            .ep_out_prime_receive(moondancer::usb::GCP_BULK_OUT_ENDPOINT);
+
         // enable interrupts
         unsafe {
             // set mstatus register: interrupt enable
@@ -316,6 +271,13 @@ impl<'a> Firmware<'a> {
                     UsbBusReset(Aux) => {
                         // handled in MachineExternal
                         //warn!("ME Usb1BusReset");
+                        let (gcp_control, gcp_bulk) = (&mut self.gcp_control, &mut self.gcp_bulk);
+                        let gcp_buffers = &mut *self.gcp_buffers;
+                        with_gcp_classes(&mut self.moondancer, |classes| {
+                            gcp_control.reset(gcp_buffers, classes);
+                            gcp_bulk.reset(gcp_buffers, classes);
+                        });
+                        self.moondancer.reset_events();
                     }
 
                     // Usb1 received setup packet
@@ -328,16 +290,24 @@ impl<'a> Firmware<'a> {
                     // Usb1 received data on control endpoint
                     UsbReceivePacket(Aux, 0, _) => {
                         //warn!("ME Usb1ReceivePacket 0");
-                        let bytes_read = self.usb1.hal_driver.read(0, &mut rx_buffer);
-                        self.handle_receive_control_data(bytes_read, rx_buffer)?;
+                        match self.usb1.hal_driver.read(0, &mut rx_buffer) {
+                            Ok(bytes_read) => {
+                                self.handle_receive_control_data(bytes_read, rx_buffer)?
+                            }
+                            Err(e) => warn!("Usb1 failed to read control data: {:?}", e),
+                        }
                         self.usb1.hal_driver.ep_out_prime_receive(0);
                     }
 
                     // Usb1 received data on endpoint - shouldn't ever be called
                     UsbReceivePacket(Aux, endpoint, _) => {
                         //warn!("ME Usb1ReceivePacket {}", endpoint);
-                        let bytes_read = self.usb1.hal_driver.read(endpoint, &mut rx_buffer);
-                        self.handle_receive_data(endpoint, bytes_read, rx_buffer)?;
+                        match self.usb1.hal_driver.read(endpoint, &mut rx_buffer) {
+                            Ok(bytes_read) => {
+                                self.handle_receive_data(endpoint, bytes_read, rx_buffer)?
+                            }
+                            Err(e) => warn!("Usb1 failed to read endpoint {}: {:?}", endpoint, e),
+                        }
                         self.usb1.hal_driver.ep_out_prime_receive(endpoint);
                     }
 
@@ -364,18 +334,24 @@ impl<'a> Firmware<'a> {
                     // Usb0 received data on control endpoint
                     UsbReceivePacket(Target, 0, _) => {
                         warn!("ME Usb0ReceivePacket 0");
-                        let bytes_read = self.moondancer.usb0.read(0, &mut rx_buffer);
-                        self.moondancer
-                            .handle_receive_control_data(bytes_read, rx_buffer)?;
+                        match self.moondancer.usb0.read(0, &mut rx_buffer) {
+                            Ok(bytes_read) => self
+                                .moondancer
+                                .handle_receive_control_data(bytes_read, rx_buffer)?,
+                            Err(e) => warn!("Usb0 failed to read control data: {:?}", e),
+                        }
                         self.moondancer.usb0.ep_out_prime_receive(0);
                     }
 
                     // Usb0 received data on endpoint
                     UsbReceivePacket(Target, endpoint, _) => {
                         warn!("ME Usb0ReceivePacket {}", endpoint);
-                        let bytes_read = self.moondancer.usb0.read(endpoint, &mut rx_buffer);
-                        self.moondancer
-                            .handle_receive_data(endpoint, bytes_read, rx_buffer)?;
+                        match self.moondancer.usb0.read(endpoint, &mut rx_buffer) {
+                            Ok(bytes_read) => self
+                                .moondancer
+                                .handle_receive_data(endpoint, bytes_read, rx_buffer)?,
+                            Err(e) => warn!("Usb0 failed to read endpoint {}: {:?}", endpoint, e),
+                        }
                         self.moondancer.usb0.ep_out_prime_receive(endpoint);
                     }
 
@@ -396,6 +372,22 @@ impl<'a> Firmware<'a> {
                     }
                 }
             }
+
+            // continue any gcp commands in progress
+            let (hal_driver, gcp_control) = (&self.usb1.hal_driver, &mut self.gcp_control);
+            let (gcp_bulk, gcp_buffers) = (&mut self.gcp_bulk, &mut *self.gcp_buffers);
+            if let Err(e) = with_gcp_classes(&mut self.moondancer, |classes| {
+                gcp_bulk.poll(gcp_buffers, classes);
+                gcp_control.poll(hal_driver, gcp_buffers, classes)
+            }) {
+                warn!("GCP failed to write response: {:?}", e);
+            }
+            self.write_gcp_bulk_packet();
+
+            self.poll_gcp_serial();
+
+            // let the host know about any target status changes
+            self.write_moondancer_event();
         }
 
         #[allow(unreachable_code)] // TODO
@@ -426,10 +418,21 @@ impl<'a> Firmware<'a> {
             setup_packet
         );
 
-        match (&request_type, &vendor_request) {
-            (RequestType::Vendor, VendorRequest::UsbCommandRequest) => {
-                self.handle_vendor_request(&setup_packet)?;
+        // gcp requests
+        let (hal_driver, gcp_control) = (&self.usb1.hal_driver, &mut self.gcp_control);
+        let gcp_buffers = &mut *self.gcp_buffers;
+        match with_gcp_classes(&mut self.moondancer, |classes| {
+            gcp_control.handle_setup_request(hal_driver, &setup_packet, gcp_buffers, classes)
+        }) {
+            Ok(true) => return Ok(()),
+            Ok(false) => (),
+            Err(e) => {
+                warn!("GCP failed to handle vendor request: {:?}", e);
+                return Ok(());
             }
+        }
+
+        match (&request_type, &vendor_request) {
             (RequestType::Vendor, VendorRequest::Unknown(vendor_request)) => {
                 error!("GCP Unknown vendor request '{}'", vendor_request);
                 // TODO how to handle? should it be handled?
@@ -477,50 +480,6 @@ impl<'a> Firmware<'a> {
         Ok(())
     }
 
-    /// Usb1: gcp vendor request handler
-    fn handle_vendor_request(&mut self, setup_packet: &SetupPacket) -> GreatResult<()> {
-        let direction = setup_packet.direction();
-        let request = VendorRequest::from(setup_packet.request);
-        let value = VendorValue::from(setup_packet.value);
-        let length = setup_packet.length as usize;
-
-        trace!(
-            "GCP vendor_request: {:?} dir:{:?} value:{:?} length:{} index:{}",
-            request,
-            direction,
-            value,
-            length,
-            setup_packet.index
-        );
-
-        match (&direction, &request, &value) {
-            // host is starting a new command sequence
-            (Direction::HostToDevice, VendorRequest::UsbCommandRequest, VendorValue::Execute) => {
-                self.usb1.hal_driver.ack_status_stage(setup_packet);
-            }
-
-            // host is ready to receive a response
-            (Direction::DeviceToHost, VendorRequest::UsbCommandRequest, VendorValue::Execute) => {
-                self.dispatch_gcp_response(setup_packet)?;
-            }
-
-            // host would like to abort the current command sequence
-            (Direction::DeviceToHost, VendorRequest::UsbCommandRequest, VendorValue::Cancel) => {
-                self.dispatch_gcp_abort(setup_packet)?;
-            }
-
-            _ => {
-                error!(
-                    "GCP stall: unknown vendor request and/or value: {:?} {:?} {:?}",
-                    direction, request, value
-                );
-                self.usb1.hal_driver.stall_endpoint_address(0, true);
-            }
-        }
-
-        Ok(())
-    }
-
     fn handle_receive_control_data(
         &mut self,
         bytes_read: usize,
@@ -528,125 +487,201 @@ impl<'a> Firmware<'a> {
     ) -> GreatResult<()> {
         trace!("Received {} bytes on usb1 control endpoint", bytes_read,);
 
-        if bytes_read >= 8 {
-            // it's gcp request data, dispatch it
-            self.dispatch_gcp_request(&buffer[0..bytes_read])?;
-        } else {
-            // it's an ack for the last gcp response we sent, ignore it
-        }
+        // it's gcp request data, or an ack for the last gcp response we
+        // sent which we can ignore
+        let (gcp_control, gcp_buffers) = (&mut self.gcp_control, &mut *self.gcp_buffers);
+        with_gcp_classes(&mut self.moondancer, |classes| {
+            gcp_control.handle_receive_packet(&buffer[..bytes_read], gcp_buffers, classes)
+        });
 
         Ok(())
     }
 
-    /// This shouldn't ever be called
     fn handle_receive_data(
         &mut self,
         endpoint: u8,
         bytes_read: usize,
         buffer: [u8; moondancer::EP_MAX_PACKET_SIZE],
     ) -> GreatResult<()> {
-        warn!(
-            "Usb1 received {} bytes on endpoint: {}",
-            endpoint, bytes_read,
-        );
+        if endpoint != moondancer::usb::GCP_BULK_OUT_ENDPOINT {
+            warn!(
+                "Usb1 received {} bytes on endpoint: {}",
+                endpoint, bytes_read,
+            );
+            return Ok(());
+        }
+
+        // it's gcp bulk transport data, dispatch any completed command
+        let (gcp_bulk, gcp_buffers) = (&mut self.gcp_bulk, &mut *self.gcp_buffers);
+        with_gcp_classes(&mut self.moondancer, |classes| {
+            gcp_bulk.receive(&buffer[..bytes_read], gcp_buffers, classes)
+        });
+        self.write_gcp_bulk_packet();
+
         Ok(())
     }
 
     /// TODO we should probably take this into account for state handling
     pub fn handle_transfer_complete(&mut self, endpoint: u8) -> GreatResult<()> {
+        // enter any test mode requested by SET_FEATURE(TEST_MODE) now
+        // its status stage has completed
+        if endpoint == 0
+            && self
+                .usb1
+                .enter_pending_test_mode()
+                .map_err(|_| GreatError::InvalidArgument)?
+        {
+            return Ok(());
+        }
+
+        // host has read a packet of the gcp response, send the next one
+        if endpoint == 0 {
+            self.gcp_control
+                .handle_transfer_complete(&self.usb1.hal_driver, self.gcp_buffers);
+        } else if endpoint == moondancer::usb::GCP_BULK_IN_ENDPOINT {
+            self.gcp_bulk.handle_transfer_complete();
+            self.write_gcp_bulk_packet();
+        } else if endpoint == moondancer::usb::MOONDANCER_EVENT_ENDPOINT {
+            self.moondancer.handle_event_complete();
+            self.write_moondancer_event();
+        }
         Ok(())
     }
 }
 
-// - gcp command dispatch -----------------------------------------------------
+// - gcp transports -----------------------------------------------------------
 
 impl<'a> Firmware<'a> {
-    fn dispatch_gcp_request(&mut self, command_buffer: &[u8]) -> GreatResult<()> {
-        // parse command
-        let (class_id, verb_number, arguments) = match libgreat::gcp::Command::parse(command_buffer)
-        {
-            Some(command) => (command.class_id(), command.verb_number(), command.arguments),
-            None => {
-                // TODO some kind of error handling
-                error!("Failed to parse GCP command");
-                return Ok(());
-            }
-        };
-
-        //debug!("GCP dispatch request {:?}.{}", class_id, verb_number);
+    /// Dispatch any gcp commands received on the serial port.
+    fn poll_gcp_serial(&mut self) {
+        use hal::hal_nb::serial::Read;
+
+        // continue the command in progress, if any
+        let (gcp_serial, gcp_buffers) = (&mut self.gcp_serial, &mut *self.gcp_buffers);
+        if with_gcp_classes(&mut self.moondancer, |classes| {
+            gcp_serial.poll(gcp_buffers, classes)
+        }) {
+            self.write_gcp_serial_response();
+        }
 
-        // dispatch command
-        let response_buffer: [u8; GCP_MAX_RESPONSE_LENGTH] = [0; GCP_MAX_RESPONSE_LENGTH];
-        let response = match class_id {
-            // class: core
-            libgreat::gcp::ClassId::core => {
-                self.core.dispatch(verb_number, arguments, response_buffer)
-            }
-            // class: firmware
-            libgreat::gcp::ClassId::firmware => {
-                moondancer::gcp::firmware::dispatch(verb_number, arguments, response_buffer)
-            }
-            // class: moondancer
-            libgreat::gcp::ClassId::moondancer => {
-                self.moondancer
-                    .dispatch(verb_number, arguments, response_buffer)
-            }
-            // class: unsupported
-            _ => Err(GreatError::Message("class or verb not found")),
-        };
-
-        // queue response
-        match response {
-            Ok(response) => {
-                // TODO we really need a better way to get this to the vendor request
-                // NEXT so what's happening with greatfet info is that we queue
-                //      the response but the host errors out before we get the
-                //      vendor_request telling us we can send it ???
-                //debug!("GCP queueing response");
-                self.gcp_response = Some(response);
+        loop {
+            let byte = match self.uart.read() {
+                Ok(byte) => byte,
+                Err(hal::nb::Error::WouldBlock) => return,
+                Err(hal::nb::Error::Other(e)) => {
+                    warn!("GCP serial receive error: {:?}", e);
+                    return;
+                }
+            };
+
+            let (gcp_serial, gcp_buffers) = (&mut self.gcp_serial, &mut *self.gcp_buffers);
+            let received = with_gcp_classes(&mut self.moondancer, |classes| {
+                gcp_serial.receive(byte, gcp_buffers, classes)
+            });
+            if !received {
+                continue;
             }
-            Err(e) => {
-                error!("GCP error: failed to dispatch command {}", e);
-                // TODO set a proper errno
-                self.usb1
-                    .hal_driver
-                    .write(0, [0xde, 0xad, 0xde, 0xad].into_iter());
+
+            // don't NAK line noise until the host has spoken gcp
+            let nak = matches!(
+                self.gcp_serial.response(self.gcp_buffers),
+                Some(SerialResponse {
+                    channel: Channel::Nak,
+                    ..
+                })
+            );
+            if nak && !moondancer::log::is_framed() {
+                continue;
             }
+
+            // the host speaks gcp, switch log output to frames
+            moondancer::log::set_framed(true);
+            self.write_gcp_serial_response();
         }
+    }
 
-        Ok(())
+    /// Write the response to the last gcp command received on the serial port.
+    fn write_gcp_serial_response(&self) {
+        if let Some(response) = self.gcp_serial.response(self.gcp_buffers) {
+            moondancer::log::write_serial_frame(
+                response.channel,
+                response.sequence,
+                &[response.payload],
+            );
+        }
     }
 
-    fn dispatch_gcp_response(&mut self, setup_packet: &SetupPacket) -> GreatResult<()> {
-        // do we have a response ready?
-        if let Some(response) = &mut self.gcp_response {
-            // send it
-            // debug!("GCP dispatch response: {} bytes", response.len());
-            // TODO handle long writes -> setup_packet.length as usize is 4096
-            self.usb1
+    /// Write the next packet of the gcp bulk transport, if any.
+    ///
+    /// The IN FIFO is shared by every endpoint, a packet that can't be
+    /// written yet is retried from the main loop.
+    fn write_gcp_bulk_packet(&mut self) {
+        self.requeue_discarded_packets();
+
+        if let Some(packet) = self.gcp_bulk.next_packet(self.gcp_buffers) {
+            match self
+                .usb1
                 .hal_driver
-                .write(0, response.take(moondancer::EP_MAX_PACKET_SIZE));
-            self.gcp_response = None;
-        } else {
-            // TODO figure out what to do if we don't have a response
-            error!("GCP stall: gcp response requested but no response queued");
-            self.usb1.hal_driver.stall_endpoint_in(0);
+                .try_write(moondancer::usb::GCP_BULK_IN_ENDPOINT, packet.iter())
+            {
+                Ok(_) => (),
+                Err(hal::nb::Error::WouldBlock) => self.gcp_bulk.requeue(),
+                Err(hal::nb::Error::Other(e)) => {
+                    warn!("GCP bulk failed to write packet: {:?}", e);
+                    self.gcp_bulk.requeue();
+                }
+            }
         }
-
-        Ok(())
     }
 
-    fn dispatch_gcp_abort(&mut self, setup_packet: &SetupPacket) -> GreatResult<()> {
-        debug!("GCP dispatch abort");
-
-        // cancel any queued response
-        self.gcp_response = None;
+    /// Requeue the packet a control transfer discarded from the shared
+    /// IN FIFO before the host could read it, if any.
+    fn requeue_discarded_packets(&mut self) {
+        match self.usb1.hal_driver.take_discarded_in_endpoint() {
+            Some(moondancer::usb::GCP_BULK_IN_ENDPOINT) => self.gcp_bulk.requeue(),
+            Some(moondancer::usb::MOONDANCER_EVENT_ENDPOINT) => self.moondancer.requeue_event(),
+            Some(endpoint) => warn!("Usb1 discarded packet on endpoint: {}", endpoint),
+            None => (),
+        }
+    }
 
-        // TODO figure out what response host is expecting
-        self.usb1
-            .hal_driver
-            .write(0, [0xde, 0xad, 0xde, 0xad].into_iter());
+    /// Write a record of the target status changes since the last one,
+    /// if the host has asked for records and read the last one.
+    ///
+    /// Changes that happen while a record is in flight, or that can't
+    /// be written yet, are merged into the next one.
+    fn write_moondancer_event(&mut self) {
+        self.requeue_discarded_packets();
+
+        // the event endpoint only exists once the host has configured usb1
+        if self.usb1.state() != DeviceState::Configured {
+            return;
+        }
 
-        Ok(())
+        if let Some(event) = self.moondancer.take_event() {
+            match self.usb1.hal_driver.try_write(
+                moondancer::usb::MOONDANCER_EVENT_ENDPOINT,
+                event.to_bytes().into_iter(),
+            ) {
+                Ok(_) => (),
+                Err(hal::nb::Error::WouldBlock) => self.moondancer.requeue_event(),
+                Err(hal::nb::Error::Other(e)) => {
+                    warn!("Moondancer failed to write event: {:?}", e);
+                    self.moondancer.requeue_event();
+                }
+            }
+        }
     }
 }
+
+/// Calls `f` with the registry of the classes served by the firmware,
+/// which is shared by the control and bulk transports.
+fn with_gcp_classes<R>(
+    target: &mut moondancer::gcp::moondancer::Moondancer,
+    f: impl FnOnce(&mut Classes) -> R,
+) -> R {
+    let mut firmware = moondancer::gcp::firmware::Firmware;
+    let mut classes: [&mut dyn GcpClass; 2] = [&mut firmware, target];
+    let mut classes = Classes::new(moondancer::BOARD_INFORMATION, &mut classes);
+    f(&mut classes)
+}
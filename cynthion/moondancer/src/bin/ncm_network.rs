@@ -0,0 +1,349 @@
+#![no_std]
+#![no_main]
+
+//! USB network adapter serving DHCP and HTTP on usb1 (aux)
+//!
+//! The host is leased 10.0.0.2 and can query the board with:
+//!
+//! ```text
+//! curl http://10.0.0.1/status
+//! curl --data-binary @command.bin http://10.0.0.1/gcp
+//! ```
+//!
+//! Responses are sent as single-datagram NTBs that fit in one bulk
+//! packet, which limits HTTP responses to roughly 400 bytes.
+
+use moondancer::net::{self, http};
+use moondancer::{hal, pac, Message};
+
+use pac::csr::interrupt;
+
+use smolusb::class::ncm;
+use smolusb::control::{Direction, Recipient, Request, RequestType, SetupPacket};
+use smolusb::device::UsbDevice;
+use smolusb::event::UsbEvent;
+use smolusb::traits::{EndpointRead, UsbDriverOperations};
+
+use libgreat::gcp::{Classes, Command, GcpClass, GcpResponseWriter};
+use libgreat::GreatError;
+
+use heapless::mpmc::MpMcQueue as Queue;
+use log::{debug, error, info, trace, warn};
+
+// - configuration ------------------------------------------------------------
+
+const CONTROL_INTERFACE: u8 = 0;
+const DATA_INTERFACE: u8 = 1;
+const ENDPOINT_NOTIFY: u8 = 0x82;
+const ENDPOINT_IN: u8 = 0x81;
+const ENDPOINT_OUT: u8 = 0x01;
+
+/// Size of the NTBs we send, one bulk packet
+const NTB_OUT_SIZE: usize = moondancer::EP_MAX_PACKET_SIZE;
+
+// - global static state ------------------------------------------------------
+
+static MESSAGE_QUEUE: Queue<Message, 64> = Queue::new();
+
+#[inline(always)]
+fn dispatch_message(message: Message) {
+    match MESSAGE_QUEUE.enqueue(message) {
+        Ok(()) => (),
+        Err(_) => {
+            error!("MachineExternal - message queue overflow");
+        }
+    }
+}
+
+// - MachineExternal interrupt handler ----------------------------------------
+
+#[allow(non_snake_case)]
+#[no_mangle]
+fn MachineExternal() {
+    use moondancer::UsbInterface::Aux;
+
+    let usb1 = unsafe { hal::Usb1::summon() };
+
+    // OUT packets are left in the FIFO and read from the main loop.
+    if let Some(event) = usb1.next_event(|_endpoint| 0) {
+        if let UsbEvent::BusReset = event {
+            usb1.bus_reset();
+        }
+        dispatch_message(Message::from_usb_event(Aux, event));
+
+    // - Unknown Interrupt --
+    } else {
+        let pending = interrupt::reg_pending();
+        dispatch_message(Message::HandleUnknownInterrupt(pending));
+    }
+}
+
+// - main entry point ---------------------------------------------------------
+
+#[cfg(feature = "vexriscv")]
+#[riscv_rt::pre_init]
+unsafe fn pre_main() {
+    pac::cpu::vexriscv::flush_icache();
+    #[cfg(feature = "vexriscv_dcache")]
+    pac::cpu::vexriscv::flush_dcache();
+}
+
+#[riscv_rt::entry]
+fn main() -> ! {
+    let peripherals = pac::Peripherals::take().unwrap();
+    let leds = &peripherals.LEDS;
+    leds.output.write(|w| unsafe { w.output().bits(0x0) });
+
+    // initialize logging
+    moondancer::log::init(hal::Serial::new(peripherals.UART));
+    info!("Logging initialized");
+
+    // usb1: aux
+    let mut usb1 = UsbDevice::new(
+        hal::Usb1::new(
+            peripherals.USB1,
+            peripherals.USB1_EP_CONTROL,
+            peripherals.USB1_EP_IN,
+            peripherals.USB1_EP_OUT,
+        ),
+        &ncm::DEVICE_DESCRIPTOR,
+        &ncm::CONFIGURATION_DESCRIPTOR_0,
+        &ncm::USB_STRING_DESCRIPTOR_0,
+        ncm::USB_STRING_DESCRIPTORS,
+    );
+    usb1.device_qualifier_descriptor = Some(&ncm::DEVICE_QUALIFIER_DESCRIPTOR);
+    usb1.other_speed_configuration_descriptor = Some(ncm::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0);
+    let speed = usb1.connect();
+    info!("Connected usb1 device: {:?}", speed);
+
+    // gcp classes
+    let mut firmware = moondancer::gcp::firmware::Firmware;
+    let mut classes: [&mut dyn GcpClass; 1] = [&mut firmware];
+    let mut classes = Classes::new(moondancer::BOARD_INFORMATION, &mut classes);
+
+    // network function
+    let mut ncm = ncm::Ncm::new(CONTROL_INTERFACE, DATA_INTERFACE, ENDPOINT_NOTIFY, ENDPOINT_IN);
+    let mut receiver: ncm::NtbReceiver<{ ncm::NTB_MAX_SIZE as usize }> = ncm::NtbReceiver::new();
+    let mut interface = net::Interface::new(net::Config::new());
+
+    // enable interrupts
+    unsafe {
+        // set mstatus register: interrupt enable
+        riscv::interrupt::enable();
+
+        // set mie register: machine external interrupts enable
+        riscv::register::mie::set_mext();
+
+        // write csr: enable usb1 interrupts and events
+        interrupt::enable(pac::Interrupt::USB1);
+        interrupt::enable(pac::Interrupt::USB1_EP_CONTROL);
+        interrupt::enable(pac::Interrupt::USB1_EP_IN);
+        interrupt::enable(pac::Interrupt::USB1_EP_OUT);
+        usb1.hal_driver.enable_interrupts();
+    }
+
+    // prime the usb OUT endpoints we'll be using
+    usb1.hal_driver.ep_out_prime_receive(0);
+    usb1.hal_driver.ep_out_prime_receive(ENDPOINT_OUT);
+
+    info!("Peripherals initialized, entering main loop");
+
+    let mut rx_buffer = [0_u8; moondancer::EP_MAX_PACKET_SIZE];
+    let mut control_request: Option<SetupPacket> = None;
+
+    loop {
+        let message = match MESSAGE_QUEUE.dequeue() {
+            Some(message) => message,
+            None => continue,
+        };
+
+        use moondancer::UsbInterface::Aux;
+
+        match message {
+            Message::UsbBusReset(Aux) => {
+                ncm.handle_bus_reset();
+                receiver.reset();
+                control_request = None;
+                usb1.hal_driver.ep_out_prime_receive(ENDPOINT_OUT);
+            }
+
+            Message::UsbReceiveSetupPacket(Aux, setup_packet) => {
+                let result = match (
+                    setup_packet.request_type(),
+                    setup_packet.recipient(),
+                    setup_packet.request(),
+                ) {
+                    (RequestType::Class, Recipient::Interface, _)
+                        if setup_packet.index as u8 == CONTROL_INTERFACE =>
+                    {
+                        if setup_packet.length > 0
+                            && setup_packet.direction() == Direction::HostToDevice
+                        {
+                            // data stage follows on endpoint 0
+                            control_request = Some(setup_packet.clone());
+                        }
+                        ncm.handle_setup_request(&usb1.hal_driver, &setup_packet)
+                    }
+                    (RequestType::Standard, _, Request::SetInterface) => {
+                        usb1.handle_setup_request(&setup_packet).and_then(|()| {
+                            receiver.reset();
+                            ncm.handle_set_interface(&usb1.hal_driver, &setup_packet)
+                        })
+                    }
+                    _ => usb1.handle_setup_request(&setup_packet),
+                };
+                if let Err(e) = result {
+                    error!("  handle_setup_request: {:?}: {:?}", e, setup_packet);
+                }
+            }
+
+            Message::UsbReceivePacket(Aux, 0, _) => {
+                match usb1.hal_driver.read(0, &mut rx_buffer) {
+                    Ok(bytes_read) => {
+                        if let Some(setup_packet) = control_request.take() {
+                            ncm.handle_receive_control_data(&setup_packet, &rx_buffer[..bytes_read]);
+                        }
+                    }
+                    Err(e) => error!("NCM failed to read control data: {:?}", e),
+                }
+                usb1.hal_driver.ep_out_prime_receive(0);
+            }
+
+            Message::UsbReceivePacket(Aux, endpoint, _) if endpoint == ENDPOINT_OUT => {
+                match usb1.hal_driver.read(endpoint, &mut rx_buffer) {
+                    Ok(bytes_read) => {
+                        let max_packet_size = usb1
+                            .max_packet_size(ENDPOINT_OUT)
+                            .unwrap_or(moondancer::EP_MAX_PACKET_SIZE as u16);
+                        let short_packet = bytes_read < max_packet_size as usize;
+                        if let Some(ntb) = receiver.push(&rx_buffer[..bytes_read], short_packet) {
+                            let max_packet_size = usb1
+                                .max_packet_size(ENDPOINT_IN)
+                                .unwrap_or(moondancer::EP_MAX_PACKET_SIZE as u16);
+                            handle_ntb(
+                                &usb1.hal_driver,
+                                max_packet_size as usize,
+                                &mut ncm,
+                                &mut interface,
+                                &mut classes,
+                                ntb,
+                            );
+                        }
+                    }
+                    Err(e) => error!("NCM failed to read packet: {:?}", e),
+                }
+                usb1.hal_driver.ep_out_prime_receive(endpoint);
+            }
+
+            Message::UsbTransferComplete(Aux, endpoint) => {
+                if let Err(e) = ncm.handle_transfer_complete(&usb1.hal_driver, endpoint) {
+                    error!("NCM failed to send notification: {:?}", e);
+                }
+            }
+
+            Message::ErrorMessage(message) => {
+                error!("MachineExternal Error - {}", message);
+            }
+
+            _ => {
+                trace!("Unhandled message: {:?}", message);
+            }
+        }
+    }
+}
+
+// - network ------------------------------------------------------------------
+
+/// Pass each datagram in a received NTB to the network interface and
+/// send any responses back to the host.
+///
+/// `max_packet_size` is that of the bulk IN endpoint at the current
+/// bus speed.
+fn handle_ntb(
+    hal_driver: &hal::Usb1,
+    max_packet_size: usize,
+    ncm: &mut ncm::Ncm,
+    interface: &mut net::Interface,
+    classes: &mut Classes,
+    ntb: &[u8],
+) {
+    if !ncm.is_active() {
+        warn!("NCM dropping NTB received while data interface is inactive");
+        return;
+    }
+
+    let ntb = match ncm::Ntb::parse(ntb) {
+        Ok(ntb) => ntb,
+        Err(e) => {
+            warn!("NCM failed to parse NTB: {:?}", e);
+            return;
+        }
+    };
+
+    // don't send NTBs larger than the host asked for
+    let ntb_size = usize::min(NTB_OUT_SIZE, ncm.ntb_input_size() as usize);
+
+    for frame in ntb.datagrams() {
+        let mut buffer = [0_u8; NTB_OUT_SIZE];
+        let mut builder: ncm::NtbBuilder<1> =
+            ncm::NtbBuilder::new(&mut buffer[..ntb_size], ncm.next_sequence());
+
+        let length = interface.handle_frame(frame, builder.datagram_buffer(), |request, body| {
+            handle_http_request(classes, request, body)
+        });
+
+        if let Some(length) = length {
+            if builder.commit(length).is_ok() {
+                if let Err(e) = ncm.write_ntb(hal_driver, builder.finish(), max_packet_size) {
+                    warn!("NCM failed to send NTB: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+fn handle_http_request(
+    classes: &mut Classes,
+    request: &http::Request,
+    body: &mut [u8],
+) -> Option<http::Response> {
+    use core::fmt::Write;
+
+    match (request.method, request.path) {
+        ("GET", "/status") | ("GET", "/") => {
+            let information = moondancer::BOARD_INFORMATION;
+            let mut cursor = http::Cursor::new(body);
+            let _ = write!(
+                cursor,
+                "Cynthion moondancer {}\nserial: {:02x?}\n",
+                information.version_string.trim_end_matches('\0'),
+                information.serial_number,
+            );
+            Some(http::Response::text(cursor.position))
+        }
+        ("POST", "/gcp") => {
+            let mut response = GcpResponseWriter::new(body);
+            match dispatch_gcp_request(classes, request.body, &mut response) {
+                Ok(()) => Some(http::Response::binary(response.len())),
+                Err(e) => {
+                    error!("GCP error: failed to dispatch command {}", e);
+                    let mut cursor = http::Cursor::new(body);
+                    let _ = writeln!(cursor, "{}", e);
+                    Some(http::Response::text(cursor.position))
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+fn dispatch_gcp_request(
+    classes: &mut Classes,
+    command_buffer: &[u8],
+    response: &mut GcpResponseWriter,
+) -> libgreat::GreatResult<()> {
+    let command = Command::parse(command_buffer).ok_or(GreatError::Message("invalid command"))?;
+    debug!("GCP dispatch request {:?}.{}", command.class_id(), command.verb_number());
+
+    classes.dispatch(command.class_id(), command.verb_number(), command.arguments, response)
+}
@@ -0,0 +1,440 @@
+#![no_std]
+#![no_main]
+
+//! Switch usb0 (target) between device personalities at runtime
+//!
+//! usb1 (aux) serves the firmware, personality and moondancer GCP
+//! classes. usb0 starts out as the moondancer target, driven by the
+//! facedancer host through the moondancer class, and can be switched
+//! to a CDC serial loopback or Gadget Zero loopback device with the
+//! `personality.set_personality` GCP verb or by pressing the button
+//! connected to GPIOA pin 0, which cycles through the personalities.
+//!
+//! Device controller events are polled from the main loop and
+//! dispatched by `UsbDevice::poll`, or passed on to the moondancer
+//! class while it has the target port.
+
+use moondancer::gcp::control::ControlTransport;
+use moondancer::gcp::moondancer::Moondancer;
+use moondancer::{hal, pac};
+
+use hal::hal::delay::DelayUs;
+
+use smolusb::class::gadget_zero::{self, Function, GadgetZero, Pattern};
+use smolusb::class::{cdc, UsbClass};
+use smolusb::control::{Direction, RequestType, SetupPacket};
+use smolusb::device::UsbDevice;
+use smolusb::error::SmolResult;
+use smolusb::event::UsbEvent;
+use smolusb::personality::{Personality, PersonalitySwitch};
+use smolusb::traits::{ControlRead, EndpointRead, EndpointWrite, UsbDriverOperations};
+
+use libgreat::gcp::{Classes, GcpBuffers, GcpClass};
+use libgreat::GreatResult;
+
+use log::{debug, error, info, warn};
+
+// - configuration ------------------------------------------------------------
+
+/// How long the device stays disconnected when switching personality
+const DISCONNECT_DELAY_MS: u32 = 250;
+
+/// GPIOA pin used to cycle through the personalities
+const BUTTON_PIN: u32 = 0;
+
+/// Size of the buffer used by the Gadget Zero control tests
+const CONTROL_BUFFER_SIZE: usize = 512;
+
+/// Index of the personality that leaves usb0 to the moondancer class,
+/// the others follow in the order of `personalities`
+const MOONDANCER_PERSONALITY: usize = 0;
+
+// - global static state ------------------------------------------------------
+
+/// Command and response buffers of the gcp transport
+static mut GCP_BUFFERS: GcpBuffers = GcpBuffers::new();
+
+// - main entry point ---------------------------------------------------------
+
+#[cfg(feature = "vexriscv")]
+#[riscv_rt::pre_init]
+unsafe fn pre_main() {
+    pac::cpu::vexriscv::flush_icache();
+    #[cfg(feature = "vexriscv_dcache")]
+    pac::cpu::vexriscv::flush_dcache();
+}
+
+#[riscv_rt::entry]
+fn main() -> ! {
+    match main_loop() {
+        Ok(()) => {
+            error!("Firmware exited unexpectedly in main loop");
+            panic!("Firmware exited unexpectedly in main loop")
+        }
+        Err(e) => {
+            error!("Fatal error in firmware main loop: {}", e);
+            panic!("Fatal error in firmware main loop: {}", e)
+        }
+    }
+}
+
+// - main loop ----------------------------------------------------------------
+
+fn main_loop() -> GreatResult<()> {
+    let peripherals = pac::Peripherals::take().unwrap();
+    let leds = &peripherals.LEDS;
+
+    // initialize logging
+    moondancer::log::init(hal::Serial::new(peripherals.UART));
+    info!("Logging initialized");
+
+    let mut timer = hal::Timer::new(peripherals.TIMER, pac::clock::sysclk());
+
+    // configure gpioa pins 3-0 as inputs
+    let gpioa = &peripherals.GPIOA;
+    gpioa
+        .moder
+        .write(|w| unsafe { w.moder().bits(0b0000_1111) }); // 0=output, 1=input
+
+    // personalities
+    let personalities = [
+        Personality {
+            device_qualifier_descriptor: Some(&cdc::DEVICE_QUALIFIER_DESCRIPTOR),
+            other_speed_configuration_descriptor: Some(cdc::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0),
+            cb_vendor_request: Some(handle_cdc_vendor_request),
+            cb_string_request: Some(handle_cdc_string_request),
+            ..Personality::new(
+                "cdc-serial",
+                &cdc::DEVICE_DESCRIPTOR,
+                &cdc::CONFIGURATION_DESCRIPTOR_0,
+                &cdc::USB_STRING_DESCRIPTOR_0,
+                cdc::USB_STRING_DESCRIPTORS,
+            )
+        },
+        Personality {
+            device_qualifier_descriptor: Some(&gadget_zero::DEVICE_QUALIFIER_DESCRIPTOR),
+            other_speed_configuration_descriptor: Some(
+                gadget_zero::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_LOOPBACK,
+            ),
+            ..Personality::new(
+                "gadget-zero",
+                &gadget_zero::DEVICE_DESCRIPTOR,
+                &gadget_zero::CONFIGURATION_DESCRIPTOR_LOOPBACK,
+                &gadget_zero::USB_STRING_DESCRIPTOR_0,
+                gadget_zero::USB_STRING_DESCRIPTORS,
+            )
+        },
+    ];
+
+    // usb1: aux, serves gcp
+    let mut usb1 = UsbDevice::new(
+        hal::Usb1::new(
+            peripherals.USB1,
+            peripherals.USB1_EP_CONTROL,
+            peripherals.USB1_EP_IN,
+            peripherals.USB1_EP_OUT,
+        ),
+        &moondancer::usb::DEVICE_DESCRIPTOR,
+        &moondancer::usb::CONFIGURATION_DESCRIPTOR_0,
+        &moondancer::usb::USB_STRING_DESCRIPTOR_0,
+        moondancer::usb::USB_STRING_DESCRIPTORS,
+    );
+    usb1.device_qualifier_descriptor = Some(&moondancer::usb::DEVICE_QUALIFIER_DESCRIPTOR);
+    usb1.other_speed_configuration_descriptor =
+        Some(moondancer::usb::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0);
+    let speed = usb1.connect();
+    debug!("Connected usb1 device: {:?}", speed);
+
+    // usb0: target, left to the moondancer class until another
+    // personality is selected
+    let target = Moondancer::new(hal::Usb0::new(
+        peripherals.USB0,
+        peripherals.USB0_EP_CONTROL,
+        peripherals.USB0_EP_IN,
+        peripherals.USB0_EP_OUT,
+    ));
+    let mut usb0 = UsbDevice::from_personality(unsafe { hal::Usb0::summon() }, &personalities[0]);
+
+    // class handlers for each personality
+    let mut gcp = Gcp::new(
+        PersonalitySwitch::new(personalities.len() + 1),
+        target,
+        // safety: the buffers are only referenced by the gcp transport
+        unsafe { &mut GCP_BUFFERS },
+        usb1.device_descriptor.max_packet_size.into(),
+    );
+    let mut serial = SerialLoopback::new(0x02);
+    let mut gadget: GadgetZero<CONTROL_BUFFER_SIZE> =
+        GadgetZero::new(Function::Loopback, Pattern::Zero, 0x81, 0x01, 512);
+
+    // enable usb events, they are polled rather than handled by an
+    // interrupt handler
+    usb1.hal_driver.enable_interrupts();
+    usb0.hal_driver.enable_interrupts();
+
+    info!("Peripherals initialized, entering main loop.");
+
+    let mut rx_buffer: [u8; moondancer::EP_MAX_PACKET_SIZE] = [0; moondancer::EP_MAX_PACKET_SIZE];
+    let mut button_was_pressed = false;
+
+    loop {
+        // gcp
+        while let Some(event) = usb1.hal_driver.poll_event(&mut rx_buffer) {
+            if let Err(e) = usb1.poll(&event, &rx_buffer, &mut [&mut gcp]) {
+                warn!("Usb1 failed to handle {:?}: {:?}", event, e);
+            }
+        }
+        if let Err(e) = gcp.poll(&usb1.hal_driver) {
+            warn!("GCP failed to write response: {:?}", e);
+        }
+
+        // target
+        let active = gcp.switch.active();
+        if active == MOONDANCER_PERSONALITY {
+            poll_moondancer(&mut gcp.target, &mut rx_buffer);
+        }
+        while active != MOONDANCER_PERSONALITY {
+            let event = match usb0.hal_driver.poll_event(&mut rx_buffer) {
+                Some(event) => event,
+                None => break,
+            };
+            let handled = match active {
+                1 => usb0.poll(&event, &rx_buffer, &mut [&mut serial]),
+                _ => usb0.poll(&event, &rx_buffer, &mut [&mut gadget]),
+            };
+
+            match (handled, &event) {
+                (Ok(false), UsbEvent::OutPacket { endpoint, length }) if *endpoint != 0 => {
+                    warn!("Received {} bytes on unknown endpoint {}", length, endpoint);
+                    usb0.hal_driver.ep_out_prime_receive(*endpoint);
+                }
+                (Err(e), _) => warn!("Usb0 failed to handle {:?}: {:?}", event, e),
+                _ => (),
+            }
+        }
+
+        // cycle through personalities on button release
+        let button_pressed = gpioa.idr.read().bits() & (1 << BUTTON_PIN) != 0;
+        if button_was_pressed && !button_pressed {
+            gcp.switch.request_next();
+        }
+        button_was_pressed = button_pressed;
+
+        // switch personality once any GCP response has been sent
+        if let Some(index) = gcp.take_personality_request() {
+            if index == MOONDANCER_PERSONALITY {
+                // the facedancer host connects the target when it is ready
+                usb0.disconnect();
+                info!("Left usb0 to the moondancer target");
+            } else {
+                let personality = &personalities[index - 1];
+                let speed = usb0.switch_personality(personality, || {
+                    timer.delay_ms(DISCONNECT_DELAY_MS).ok();
+                });
+                usb0.hal_driver.enable_interrupts();
+                info!("Connected usb0 device as {}: {:?}", personality.name, speed);
+            }
+
+            leds.output
+                .write(|w| unsafe { w.output().bits(1 << index as u8) });
+        }
+    }
+}
+
+/// Pass any usb0 events on to the moondancer class.
+fn poll_moondancer(target: &mut Moondancer, rx_buffer: &mut [u8; moondancer::EP_MAX_PACKET_SIZE]) {
+    // bus resets are left to the facedancer host
+    while let Some(event) = target.usb0.next_event(|_endpoint| 0) {
+        let result = match event {
+            UsbEvent::BusReset => target.handle_bus_reset(),
+            UsbEvent::Setup(setup_packet) => target.handle_receive_setup_packet(setup_packet),
+            UsbEvent::OutPacket { endpoint, .. } => {
+                let result = match target.usb0.read(endpoint, rx_buffer) {
+                    Ok(bytes_read) if endpoint == 0 => {
+                        target.handle_receive_control_data(bytes_read, *rx_buffer)
+                    }
+                    Ok(bytes_read) => target.handle_receive_data(endpoint, bytes_read, *rx_buffer),
+                    Err(e) => {
+                        warn!("Usb0 failed to read endpoint {}: {:?}", endpoint, e);
+                        Ok(())
+                    }
+                };
+                target.usb0.ep_out_prime_receive(endpoint);
+                result
+            }
+            UsbEvent::InComplete { endpoint } => target.handle_transfer_complete(endpoint),
+            UsbEvent::Suspend | UsbEvent::Resume => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("MD failed to handle usb0 event: {}", e);
+        }
+    }
+}
+
+// - Gcp ----------------------------------------------------------------------
+
+/// Serves the core, firmware, personality and moondancer GCP classes
+/// over control requests on endpoint zero.
+struct Gcp {
+    switch: PersonalitySwitch,
+    target: Moondancer,
+    buffers: &'static mut GcpBuffers,
+    control: ControlTransport,
+}
+
+impl Gcp {
+    fn new(
+        switch: PersonalitySwitch,
+        target: Moondancer,
+        buffers: &'static mut GcpBuffers,
+        max_packet_size: usize,
+    ) -> Self {
+        Self {
+            switch,
+            target,
+            buffers,
+            control: ControlTransport::new(max_packet_size),
+        }
+    }
+
+    /// Returns a requested personality once the host has collected
+    /// the response to the verb that requested it.
+    fn take_personality_request(&mut self) -> Option<usize> {
+        if self.control.is_busy(self.buffers) {
+            return None;
+        }
+        self.switch.take_request()
+    }
+
+    /// Continue the command in progress, if any.
+    fn poll<D>(&mut self, hal_driver: &D) -> SmolResult<()>
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        let (control, buffers) = (&mut self.control, &mut *self.buffers);
+        with_gcp_classes(&mut self.switch, &mut self.target, |classes| {
+            control.poll(hal_driver, buffers, classes)
+        })
+    }
+}
+
+impl<D> UsbClass<D> for Gcp
+where
+    D: EndpointWrite + UsbDriverOperations,
+{
+    fn handle_bus_reset(&mut self) {
+        let (control, buffers) = (&mut self.control, &mut *self.buffers);
+        with_gcp_classes(&mut self.switch, &mut self.target, |classes| {
+            control.reset(buffers, classes)
+        });
+    }
+
+    fn handle_setup_request(&mut self, hal_driver: &D, setup_packet: &SetupPacket) -> SmolResult<bool> {
+        let (control, buffers) = (&mut self.control, &mut *self.buffers);
+        let handled = with_gcp_classes(&mut self.switch, &mut self.target, |classes| {
+            control.handle_setup_request(hal_driver, setup_packet, buffers, classes)
+        })?;
+
+        // the greatfet board scan expects legacy requests to stall
+        if !handled && setup_packet.request_type() == RequestType::Vendor {
+            warn!("GCP Legacy vendor request '{}'", setup_packet.request);
+            hal_driver.stall_endpoint_in(0);
+            return Ok(true);
+        }
+
+        Ok(handled)
+    }
+
+    fn handle_receive_packet(&mut self, _hal_driver: &D, endpoint: u8, packet: &[u8]) -> bool {
+        if endpoint != 0 {
+            return false;
+        }
+        let (control, buffers) = (&mut self.control, &mut *self.buffers);
+        with_gcp_classes(&mut self.switch, &mut self.target, |classes| {
+            control.handle_receive_packet(packet, buffers, classes)
+        })
+    }
+
+    fn handle_transfer_complete(&mut self, hal_driver: &D, endpoint: u8) -> bool {
+        if endpoint != 0 {
+            return false;
+        }
+        self.control.handle_transfer_complete(hal_driver, self.buffers)
+    }
+}
+
+/// Calls `f` with the registry of the classes served over usb1.
+fn with_gcp_classes<R>(
+    switch: &mut PersonalitySwitch,
+    target: &mut Moondancer,
+    f: impl FnOnce(&mut Classes) -> R,
+) -> R {
+    let mut firmware = moondancer::gcp::firmware::Firmware;
+    let mut personality = moondancer::gcp::personality::Personality::new(switch);
+    let mut classes: [&mut dyn GcpClass; 3] = [&mut firmware, &mut personality, target];
+    let mut classes = Classes::new(moondancer::BOARD_INFORMATION, &mut classes);
+    f(&mut classes)
+}
+
+// - SerialLoopback -----------------------------------------------------------
+
+/// Echoes any data received on the CDC serial bulk OUT endpoint back
+/// to the host.
+struct SerialLoopback {
+    endpoint: u8,
+}
+
+impl SerialLoopback {
+    const fn new(endpoint: u8) -> Self {
+        Self {
+            endpoint: endpoint & 0x0f,
+        }
+    }
+}
+
+impl<D> UsbClass<D> for SerialLoopback
+where
+    D: EndpointWrite + UsbDriverOperations,
+{
+    fn handle_set_configuration(&mut self, hal_driver: &D, configuration: u8) {
+        if configuration == 1 {
+            hal_driver.ack(self.endpoint, Direction::DeviceToHost);
+        }
+    }
+
+    fn handle_receive_packet(&mut self, hal_driver: &D, endpoint: u8, packet: &[u8]) -> bool {
+        if endpoint != self.endpoint {
+            return false;
+        }
+
+        if let Err(e) = hal_driver.write(self.endpoint, packet.iter()) {
+            warn!("CDC-SERIAL failed to echo {} bytes: {:?}", packet.len(), e);
+        }
+        hal_driver.ack(self.endpoint, Direction::DeviceToHost);
+
+        true
+    }
+}
+
+// - cdc-serial request handlers ----------------------------------------------
+
+fn handle_cdc_vendor_request<'a, D>(device: &UsbDevice<'a, D>, _setup_packet: &SetupPacket, request: u8)
+where
+    D: ControlRead + EndpointRead + EndpointWrite + UsbDriverOperations,
+{
+    let request = cdc::ch34x::VendorRequest::from(request);
+    debug!("  CDC-SERIAL vendor_request: {:?}", request);
+
+    // we can just spoof these
+    device.hal_driver.write(0, [0, 0].into_iter()).ok();
+}
+
+fn handle_cdc_string_request<'a, D>(device: &UsbDevice<'a, D>, _setup_packet: &SetupPacket, index: u8)
+where
+    D: ControlRead + EndpointRead + EndpointWrite + UsbDriverOperations,
+{
+    debug!("  CDC-SERIAL string_request: {}", index);
+
+    // we can just spoof this too
+    device.hal_driver.write(0, core::iter::empty::<u8>()).ok();
+}
@@ -1,2 +1,4 @@
+pub mod control;
 pub mod firmware;
 pub mod moondancer;
+pub mod personality;
@@ -0,0 +1,323 @@
+//! GCP transport over vendor requests on the control endpoint
+//!
+//! The host sends each command in the data stage of a
+//! [`VendorRequest::UsbCommandRequest`] and then asks for its response
+//! with an IN request, which is answered over as many packets as it
+//! takes. The IN request stalls if the command failed, and the host
+//! then retrieves the errno with a [`VendorValue::Cancel`] request.
+//!
+//! Commands that are still in progress once dispatched, see
+//! [`libgreat::gcp::GcpClass::poll`], are answered once they complete.
+//! Commands are received into, and answered from, the [`GcpBuffers`]
+//! shared with the other transports.
+//!
+//! ```ignore
+//! // for every setup packet received on the control endpoint:
+//! transport.handle_setup_request(&hal_driver, &setup_packet, &mut buffers, &mut classes)?;
+//!
+//! // ... every packet received on the control endpoint:
+//! transport.handle_receive_packet(packet, &mut buffers, &mut classes);
+//!
+//! // ... every time the host has read a packet:
+//! transport.handle_transfer_complete(&hal_driver, &buffers);
+//!
+//! // ... and on every iteration of the main loop:
+//! transport.poll(&hal_driver, &mut buffers, &mut classes)?;
+//! ```
+
+use crate::usb::vendor::{VendorRequest, VendorValue};
+
+use libgreat::gcp::{Classes, CommandAssembler, GcpBuffers, GcpResponse, Transport};
+use libgreat::{GreatError, GreatResult};
+
+use smolusb::control::{Direction, RequestType, SetupPacket};
+use smolusb::error::SmolResult;
+use smolusb::traits::{EndpointWrite, UsbDriverOperations};
+
+use log::{debug, error, warn};
+
+// - ControlTransport ---------------------------------------------------------
+
+/// Serves GCP commands received in vendor requests on the control
+/// endpoint
+pub struct ControlTransport {
+    /// Maximum packet size of the control endpoint
+    max_packet_size: usize,
+    command: CommandAssembler,
+    /// A response is waiting for the host to ask for it
+    response: Option<GcpResponse>,
+    /// A response is being sent but has not yet been read by the host
+    response_in_flight: Option<GcpResponse>,
+    /// Length of the response the host is waiting for
+    response_requested: Option<usize>,
+    /// Error of the last command, reported to the host on cancel
+    error: Option<GreatError>,
+}
+
+impl ControlTransport {
+    pub const fn new(max_packet_size: usize) -> Self {
+        Self {
+            max_packet_size,
+            command: CommandAssembler::new(),
+            response: None,
+            response_in_flight: None,
+            response_requested: None,
+            error: None,
+        }
+    }
+
+    /// Abandon the command in progress, if any, and discard any
+    /// command being received and any response.
+    pub fn reset(&mut self, buffers: &mut GcpBuffers, classes: &mut Classes) {
+        if buffers.is_held_by(Transport::Control) {
+            buffers.release(classes);
+        }
+        self.command.reset();
+        self.response = None;
+        self.response_in_flight = None;
+        self.response_requested = None;
+        self.error = None;
+    }
+
+    /// Returns true until the host has read the response to the last
+    /// command.
+    pub fn is_busy(&self, buffers: &GcpBuffers) -> bool {
+        buffers.is_pending(Transport::Control)
+            || self.response.is_some()
+            || self.response_in_flight.is_some()
+    }
+
+    /// Handle a setup packet received on the control endpoint.
+    ///
+    /// Returns false if it is not a GCP request.
+    pub fn handle_setup_request<D>(
+        &mut self,
+        hal_driver: &D,
+        setup_packet: &SetupPacket,
+        buffers: &mut GcpBuffers,
+        classes: &mut Classes,
+    ) -> SmolResult<bool>
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        if setup_packet.request_type() != RequestType::Vendor
+            || VendorRequest::from(setup_packet.request) != VendorRequest::UsbCommandRequest
+        {
+            return Ok(false);
+        }
+        self.sync(buffers);
+
+        let direction = setup_packet.direction();
+        let value = VendorValue::from(setup_packet.value);
+        let length = setup_packet.length as usize;
+
+        match (&direction, &value) {
+            // host is starting a new command sequence
+            (Direction::HostToDevice, VendorValue::Execute) => {
+                self.reset(buffers, classes);
+                match self.command.start(length) {
+                    Ok(()) => {
+                        buffers.claim(Transport::Control, classes);
+                        hal_driver.ack_status_stage(setup_packet);
+                    }
+                    Err(e) => {
+                        error!(
+                            "GCP stall: can't receive command of {} bytes: {}",
+                            length, e
+                        );
+                        self.error = Some(e);
+                        hal_driver.stall_endpoint_address(0, true);
+                    }
+                }
+            }
+
+            // host is ready to receive a response
+            (Direction::DeviceToHost, VendorValue::Execute) => {
+                if buffers.is_pending(Transport::Control) {
+                    // the command is still in progress, respond once it completes
+                    self.response_requested = Some(length);
+                } else {
+                    self.send_response(hal_driver, length, buffers)?;
+                }
+            }
+
+            // host would like to abort the current command sequence
+            (Direction::DeviceToHost, VendorValue::Cancel) => {
+                debug!("GCP dispatch abort");
+                let error = self.error.take();
+                self.reset(buffers, classes);
+
+                // respond with the errno of the last command, if any
+                let errno = error.map(|e| e.errno()).unwrap_or(0);
+                hal_driver.write(0, errno.to_le_bytes().into_iter())?;
+            }
+
+            _ => {
+                error!(
+                    "GCP stall: unknown vendor request value: {:?} {:?}",
+                    direction, value
+                );
+                hal_driver.stall_endpoint_address(0, true);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Handle a packet received on the control endpoint, dispatching
+    /// the command to `classes` once all of it has been received.
+    ///
+    /// Returns false if no command is being received.
+    pub fn handle_receive_packet(
+        &mut self,
+        packet: &[u8],
+        buffers: &mut GcpBuffers,
+        classes: &mut Classes,
+    ) -> bool {
+        self.sync(buffers);
+        if !self.command.is_receiving() {
+            return false;
+        }
+
+        match self.command.receive(buffers.command_mut(), packet) {
+            Ok(true) => self.dispatch(buffers, classes),
+            Ok(false) => (),
+            Err(e) => {
+                error!("GCP error: failed to receive command {}", e);
+                self.error = Some(e);
+            }
+        }
+
+        true
+    }
+
+    /// Call once the host has read a packet written to the control
+    /// endpoint, to write the next packet of the response.
+    ///
+    /// Returns false if no response is being sent.
+    pub fn handle_transfer_complete<D>(&mut self, hal_driver: &D, buffers: &GcpBuffers) -> bool
+    where
+        D: EndpointWrite,
+    {
+        self.sync(buffers);
+        match &self.response_in_flight {
+            // the host has read the last packet of the response
+            Some(response) if response.is_complete() => self.response_in_flight = None,
+            Some(_) => {
+                if let Err(e) = self.write_response_packet(hal_driver, buffers) {
+                    warn!("GCP failed to write response: {:?}", e);
+                    self.response_in_flight = None;
+                }
+            }
+            None => return false,
+        }
+        true
+    }
+
+    /// Continue the command in progress, if any, and send its response
+    /// once it completes if the host is already waiting for it.
+    pub fn poll<D>(
+        &mut self,
+        hal_driver: &D,
+        buffers: &mut GcpBuffers,
+        classes: &mut Classes,
+    ) -> SmolResult<()>
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        self.sync(buffers);
+        if !buffers.is_pending(Transport::Control) {
+            return Ok(());
+        }
+
+        if let Some(result) = buffers.poll(classes) {
+            self.complete(result);
+        }
+
+        if buffers.is_pending(Transport::Control) {
+            return Ok(());
+        }
+        match self.response_requested.take() {
+            Some(length) => self.send_response(hal_driver, length, buffers),
+            None => Ok(()),
+        }
+    }
+
+    /// Discard the command being received and any response once
+    /// another transport has claimed the buffers.
+    fn sync(&mut self, buffers: &GcpBuffers) {
+        if buffers.is_held_by(Transport::Control) {
+            return;
+        }
+        if self.command.is_receiving() {
+            warn!("GCP command abandoned for another transport");
+            self.error = Some(GreatError::DeviceOrResourceBusy);
+        }
+        self.command.reset();
+        self.response = None;
+        self.response_in_flight = None;
+    }
+
+    fn dispatch(&mut self, buffers: &mut GcpBuffers, classes: &mut Classes) {
+        let length = self.command.len();
+
+        // ready for the next command
+        self.command.reset();
+
+        let result = buffers.dispatch(0..length, classes);
+        self.complete(result);
+    }
+
+    /// Queue the response to a command, unless it is still in progress.
+    fn complete(&mut self, result: GreatResult<usize>) {
+        match result {
+            Ok(length) => self.response = Some(GcpResponse::new(length)),
+            Err(GreatError::OperationNowInProgress) => (),
+            Err(e) => {
+                // the response request will stall and the host can
+                // then retrieve the error with a cancel request
+                error!("GCP error: failed to dispatch command {}", e);
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Start sending the response to the last command, the remaining
+    /// packets follow as the host reads them.
+    fn send_response<D>(
+        &mut self,
+        hal_driver: &D,
+        length: usize,
+        buffers: &GcpBuffers,
+    ) -> SmolResult<()>
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        if let Some(mut response) = self.response.take() {
+            response.truncate(length, self.max_packet_size);
+            self.response_in_flight = Some(response);
+            self.write_response_packet(hal_driver, buffers)?;
+        } else if let Some(e) = &self.error {
+            // report the error, the host will ask for the errno
+            debug!("GCP stall: command failed with errno {}", e.errno());
+            hal_driver.stall_endpoint_in(0);
+        } else {
+            error!("GCP stall: gcp response requested but no response queued");
+            hal_driver.stall_endpoint_in(0);
+        }
+        Ok(())
+    }
+
+    /// Write the next packet of the response being sent, if any.
+    fn write_response_packet<D>(&mut self, hal_driver: &D, buffers: &GcpBuffers) -> SmolResult<()>
+    where
+        D: EndpointWrite,
+    {
+        if let Some(response) = &mut self.response_in_flight {
+            if let Some(packet) = response.next_packet(buffers.response(0), self.max_packet_size) {
+                hal_driver.write(0, packet.iter())?;
+            }
+        }
+        Ok(())
+    }
+}
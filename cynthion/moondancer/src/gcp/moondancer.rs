@@ -7,12 +7,12 @@ use hal::smolusb;
 use smolusb::control::{Direction, RequestType, SetupPacket};
 use smolusb::device::{Speed, UsbDevice};
 use smolusb::traits::{
-    ControlRead, EndpointRead, EndpointWrite, EndpointWriteRef, UnsafeUsbDriverOperations,
-    UsbDriverOperations,
+    ControlRead, EndpointRead, EndpointWrite, UnsafeUsbDriverOperations, UsbDriverOperations,
 };
+use smolusb::transfer::{InTransfer, TransferStatus};
 
 use libgreat::error::{GreatError, GreatResult};
-use libgreat::gcp::{self, Verb};
+use libgreat::gcp::{Class, GcpClass, GcpResponseWriter, GCP_MAX_COMMAND_LENGTH};
 
 use log::{debug, error, trace, warn};
 use zerocopy::{AsBytes, BigEndian, FromBytes, LittleEndian, Unaligned, U16, U32};
@@ -23,145 +23,113 @@ use core::slice;
 
 // - class information --------------------------------------------------------
 
-pub static CLASS: gcp::Class = gcp::Class {
-    id: gcp::ClassId::moondancer,
-    name: "moondancer",
-    docs: CLASS_DOCS,
-    verbs: &VERBS,
-};
-
-pub static CLASS_DOCS: &str = "API for fine-grained control of the Target USB port.\0";
-
-/// Verb definitions for class: moondancer
-///
-/// Fields are `"\0"`  where C implementation has `""`
-/// Fields are `"*\0"` where C implementation has `NULL`
-pub static VERBS: [Verb; 13] = [
-    // - connection / disconnection
-    Verb {
-        id: 0x0,
-        name: "connect\0",
-        doc: "\0", //"Setup the target port to connect to a host.\nEnables the target port's USB pull-ups.\0",
-        in_signature: "<HH\0",
-        in_param_names: "ep0_max_packet_size, quirk_flags\0",
-        out_signature: "\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0x1,
-        name: "disconnect\0",
-        doc: "\0", //"Disconnect the target port from the host.\0",
-        in_signature: "\0",
-        in_param_names: "*\0",
-        out_signature: "\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0x2,
-        name: "bus_reset\0",
-        doc: "\0", //"Cause the target device to handle a bus reset.\0",
-        in_signature: "\0",
-        in_param_names: "*\0",
-        out_signature: "\0",
-        out_param_names: "*\0",
-    },
-
-    // - enumeration / setup --
-    Verb {
-        id: 0x3,
-        name: "set_address\0",
-        doc: "\0", //"Set the address of the target device.\nIf deferred is set this action won't complete until the setup phase ends.\0",
-        in_signature: "<BB\0",
-        in_param_names: "address, deferred\0",
-        out_signature: "\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0x4,
-        name: "set_up_endpoints\0",
-        doc: "\0", //"Set up all of the non-control endpoints for the device.\0",
-        in_signature: "<*(BHB)\0",
-        in_param_names: "endpoint_descriptors\0",
-        out_signature: "\0",
-        out_param_names: "*\0",
-    },
-
-    // - status & control --
-    Verb {
-        id: 0x5,
-        name: "get_status\0",
-        doc: "\0", //"Read one of the device's USB status registers.\0",
-        in_signature: "<B\0",
-        in_param_names: "register_type\0",
-        out_signature: "<I\0",
-        out_param_names: "register_value\0",
-    },
-    Verb {
-        id: 0x6,
-        name: "read_setup\0",
-        doc: "\0", //"Read any pending setup packets recieved on the given endpoint.\0",
-        in_signature: "<B\0",
-        in_param_names: "endpoint_number\0",
-        out_signature: "<8X\0",
-        out_param_names: "raw_setup_packet\0",
-    },
-    Verb {
-        id: 0x7,
-        name: "stall_endpoint\0",
-        doc: "\0", //"Stall the endpoint with the provided address.\0",
-        in_signature: "<B\0",
-        in_param_names: "endpoint_address\0",
-        out_signature: "\0",
-        out_param_names: "*\0",
-    },
-
-    // - data transfer --
-    Verb {
-        id: 0x8,
-        name: "send_on_endpoint\0",
-        doc: "\0", //"Send the provided data on the given IN endpoint.\0",
-        in_signature: "<B*X\0",
-        in_param_names: "endpoint_number, data_to_send\0",
-        out_signature: "\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0x9,
-        name: "clean_up_transfer\0",
-        doc: "\0", //"Clean up any complete transfers on the given endpoint.\0",
-        in_signature: "<B\0",
-        in_param_names: "endpoint_address\0",
-        out_signature: "\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0xa,
-        name: "start_nonblocking_read\0",
-        doc: "\0", //"Begin listening for data on the given OUT endpoint.\0",
-        in_signature: "<B\0",
-        in_param_names: "endpoint_number\0",
-        out_signature: "\0",
-        out_param_names: "*\0",
-    },
-    Verb {
-        id: 0xb,
-        name: "finish_nonblocking_read\0",
-        doc: "\0", //"Return the data read after a given non-blocking read.\0",
-        in_signature: "<B\0",
-        in_param_names: "endpoint_number\0",
-        out_signature: "<*X\0",
-        out_param_names: "read_data\0",
-    },
-    Verb {
-        id: 0xc,
-        name: "get_nonblocking_data_length\0",
-        doc: "\0", //"Return the amount of data read after a given non-blocking read.\0",
-        in_signature: "<B\0",
-        in_param_names: "endpoint_number\0",
-        out_signature: "<I\0",
-        out_param_names: "length\0",
-    },
-];
+libgreat::gcp_class! {
+    class: moondancer,
+    docs: "API for fine-grained control of the Target USB port.",
+    context: &mut Moondancer,
+    verbs: [
+        // - connection / disconnection
+        0x0 => connect {
+            // doc: "Setup the target port to connect to a host.\nEnables the target port's USB pull-ups.",
+            in: "<HH" (ep0_max_packet_size: U16<LittleEndian>, quirk_flags: U16<LittleEndian>),
+            out: "" (),
+            handler: Moondancer::connect,
+        },
+        0x1 => disconnect {
+            // doc: "Disconnect the target port from the host.",
+            in: "" (),
+            out: "" (),
+            handler: Moondancer::disconnect,
+        },
+        0x2 => bus_reset {
+            // doc: "Cause the target device to handle a bus reset.",
+            in: "" (),
+            out: "" (),
+            handler: Moondancer::bus_reset,
+        },
+
+        // - enumeration / setup --
+        0x3 => set_address {
+            // doc: "Set the address of the target device.\nIf deferred is set this action won't complete until the setup phase ends.",
+            in: "<BB" (address: u8, deferred: u8),
+            out: "" (),
+            handler: Moondancer::set_address,
+        },
+        0x4 => set_up_endpoints {
+            // doc: "Set up all of the non-control endpoints for the device.",
+            in: "<*(BHB)" () ..endpoint_descriptors,
+            out: "" (),
+            handler: Moondancer::set_up_endpoints,
+        },
+
+        // - status & control --
+        0x5 => get_status {
+            // doc: "Read one of the device's USB status registers.",
+            in: "<B" (register_type: u8),
+            out: "<I" (register_value),
+            handler: Moondancer::get_status,
+        },
+        0x6 => read_setup {
+            // doc: "Read any pending setup packets recieved on the given endpoint.",
+            in: "<B" (endpoint_number: u8),
+            out: "<8X" (raw_setup_packet),
+            handler: Moondancer::read_setup,
+        },
+        0x7 => stall_endpoint {
+            // doc: "Stall the endpoint with the provided address.",
+            in: "<B" (endpoint_address: u8),
+            out: "" (),
+            handler: Moondancer::stall_endpoint,
+        },
+
+        // - data transfer --
+        0x8 => send_on_endpoint {
+            // doc: "Send the provided data on the given IN endpoint.",
+            in: "<B*X" (endpoint_number: u8) ..data_to_send,
+            out: "" (),
+            handler: Moondancer::send_on_endpoint,
+        },
+        0x9 => clean_up_transfer {
+            // doc: "Clean up any complete transfers on the given endpoint.",
+            in: "<B" (endpoint_address: u8),
+            out: "" (),
+            handler: Moondancer::clean_up_transfer,
+        },
+        0xa => start_nonblocking_read {
+            // doc: "Begin listening for data on the given OUT endpoint.",
+            in: "<B" (endpoint_number: u8),
+            out: "" (),
+            handler: Moondancer::start_nonblocking_read,
+        },
+        0xb => finish_nonblocking_read {
+            // doc: "Return the data read after a given non-blocking read.",
+            in: "<B" (endpoint_number: u8),
+            out: "<*X" (read_data),
+            handler: Moondancer::finish_nonblocking_read,
+        },
+        0xc => get_nonblocking_data_length {
+            // doc: "Return the amount of data read after a given non-blocking read.",
+            in: "<B" (endpoint_number: u8),
+            out: "<I" (length),
+            handler: Moondancer::get_nonblocking_data_length,
+        },
+
+        // - events --
+        0xd => enable_events {
+            // doc: "Start or stop pushing status change records on the event endpoint.",
+            in: "<B" (enable: u8),
+            out: "" (),
+            handler: Moondancer::enable_events,
+        },
+
+        0xe => send_on_endpoint_and_wait {
+            // doc: "Send the provided data on the given IN endpoint, completing once the target host has read it.",
+            in: "<B*X" (endpoint_number: u8) ..data_to_send,
+            out: "" (),
+            handler: Moondancer::send_on_endpoint_and_wait,
+        },
+    ]
+}
 
 // - types --------------------------------------------------------------------
 
@@ -197,11 +165,112 @@ impl TryFrom<u8> for RegisterType {
     }
 }
 
+/// Status change record pushed to the host on the event endpoint
+///
+/// Holds the status changes since the last record, using the same
+/// encoding as the registers read by `get_status`:
+///
+/// ```text
+/// | usb_status: u32 | setup_status: u32 | endpoint_complete: u32 | endpoint_nak: u32 |
+/// ```
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct StatusEvent {
+    pub usb_status: u32,        // 0x0 USBSTS
+    pub setup_status: u32,      // 0x1 ENDPTSETUPSTAT
+    pub endpoint_complete: u32, // 0x2 ENDPTCOMPLETE
+    pub endpoint_nak: u32,      // 0x4 ENDPTNAK
+}
+
+impl StatusEvent {
+    pub const LENGTH: usize = 16;
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Add the status changes of the `newer` record.
+    pub fn merge(&mut self, newer: &StatusEvent) {
+        if newer.is_empty() {
+            return;
+        }
+        self.usb_status |= newer.usb_status;
+        self.setup_status = newer.setup_status;
+        self.endpoint_complete |= newer.endpoint_complete;
+        self.endpoint_nak |= newer.endpoint_nak;
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LENGTH] {
+        let mut bytes = [0; Self::LENGTH];
+        bytes[0..4].copy_from_slice(&self.usb_status.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.setup_status.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.endpoint_complete.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.endpoint_nak.to_le_bytes());
+        bytes
+    }
+}
+
+// - EventQueue ---------------------------------------------------------------
+
+/// Status changes waiting to be pushed to the host on the event endpoint
+///
+/// Changes are only recorded once the host has asked for them, and
+/// the changes that happen while a record is in flight are merged
+/// into the next one.
+#[derive(Debug, Default)]
+struct EventQueue {
+    /// the host has asked for status change records
+    enabled: bool,
+    /// status changes that have not been pushed to the host yet
+    pending: StatusEvent,
+    /// record written to the event endpoint but not yet read by the host
+    in_flight: Option<StatusEvent>,
+}
+
+impl EventQueue {
+    /// Start or stop recording status changes, discarding any that
+    /// have not been pushed yet.
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.pending = StatusEvent::default();
+    }
+
+    fn push(&mut self, event: StatusEvent) {
+        if self.enabled {
+            self.pending.merge(&event);
+        }
+    }
+
+    /// Returns the next record to write, if the host has read the last one.
+    fn take(&mut self) -> Option<StatusEvent> {
+        if self.in_flight.is_some() || self.pending.is_empty() {
+            return None;
+        }
+        let event = core::mem::take(&mut self.pending);
+        self.in_flight = Some(event);
+        Some(event)
+    }
+
+    /// The host has read the record in flight.
+    fn complete(&mut self) {
+        self.in_flight = None;
+    }
+
+    /// The record in flight was never read, merge it back into the next one.
+    fn requeue(&mut self) {
+        if let Some(mut event) = self.in_flight.take() {
+            event.merge(&self.pending);
+            self.pending = event;
+        }
+    }
+}
+
 // - State --------------------------------------------------------------------
 
 // TODO
 const NUM_ENDPOINTS: usize = 8;
 type ReceiveBuffer = [u8; crate::EP_MAX_PACKET_SIZE];
+/// Data of a `send_on_endpoint` transfer, which outlives the command it arrived in
+type SendBuffer = heapless::Vec<u8, GCP_MAX_COMMAND_LENGTH>;
 
 /// State
 struct State {
@@ -214,6 +283,10 @@ struct State {
     /// TODO multiple queued packets?
     usb0_setup_pending: Option<SetupPacket>, // 0x1 ENDPTSETUPSTATE
 
+    /// wLength of the last setup packet received, which ends the
+    /// data stage sent by `send_on_endpoint`
+    usb0_control_request_length: Option<u16>,
+
     /// bitmap: ndpoints that have completed a transaction
     ///
     /// 00-15  receive complete
@@ -225,6 +298,13 @@ struct State {
 
     receive_buffers: [ReceiveBuffer; NUM_ENDPOINTS],
     bytes_read: [usize; NUM_ENDPOINTS],
+
+    /// max packet size of each IN endpoint in the active configuration
+    max_packet_size_in: [u16; NUM_ENDPOINTS],
+
+    /// transfer started by `send_on_endpoint`, advanced as the host
+    /// reads each packet
+    in_transfer: Option<InTransfer<SendBuffer>>,
 }
 
 impl Default for State {
@@ -232,11 +312,14 @@ impl Default for State {
         Self {
             usb0_status_pending: 0,
             usb0_setup_pending: None,
+            usb0_control_request_length: None,
             usb0_endpoint_complete_pending: 0,
             usb0_endpoint_prime_pending: 0,
             usb0_endpoint_nak_pending: 0,
             receive_buffers: [[0; crate::EP_MAX_PACKET_SIZE]; NUM_ENDPOINTS],
             bytes_read: [0; NUM_ENDPOINTS],
+            max_packet_size_in: [crate::EP_MAX_PACKET_SIZE as u16; NUM_ENDPOINTS],
+            in_transfer: None,
         }
     }
 }
@@ -254,6 +337,19 @@ impl State {
     }
 }
 
+impl State {
+    /// Returns a record of a status change for the event endpoint.
+    fn status_event(&mut self, usb_status: u32, endpoint_complete: u32) -> StatusEvent {
+        StatusEvent {
+            usb_status,
+            setup_status: self.get_endpoint_setup_status(),
+            endpoint_complete,
+            // TODO the gateware does not report NAKs yet
+            endpoint_nak: self.usb0_endpoint_nak_pending,
+        }
+    }
+}
+
 impl State {
     // 0x0
     fn get_usb_status(&mut self) -> u32 {
@@ -323,12 +419,21 @@ pub mod UsbStatusFlag {
     pub const USBSTS_D_NAKI: u32 = 1 << 16;
 }
 
+/// Verb waiting for an event on the target bus
+#[derive(Debug, Clone, Copy)]
+enum Pending {
+    ReadSetup(u8),
+    SendOnEndpoint(u8),
+}
+
 // - Moondancer --------------------------------------------------------------
 
 /// Moondancer
 pub struct Moondancer {
     pub usb0: hal::Usb0, // TODO needs to be private
     state: State,
+    events: EventQueue,
+    pending: Option<Pending>,
     ep0_max_packet_size: u16,
     quirk_flags: u16,
 }
@@ -338,6 +443,8 @@ impl Moondancer {
         Self {
             usb0,
             state: State::default().into(),
+            events: EventQueue::default(),
+            pending: None,
             ep0_max_packet_size: 0,
             quirk_flags: 0,
         }
@@ -368,7 +475,11 @@ impl Moondancer {
     }
 
     pub fn handle_bus_reset(&mut self) -> GreatResult<()> {
+        // the transfer in progress, if any, is lost
+        self.state.in_transfer = None;
+
         self.state.usb0_status_pending |= UsbStatusFlag::USBSTS_D_URI; // URI: USB reset received
+        self.push_event(UsbStatusFlag::USBSTS_D_URI, 0);
 
         debug!(
             "MD => IRQ handle_bus_reset() -> 0b{:b}",
@@ -392,6 +503,8 @@ impl Moondancer {
 
         self.state.usb0_status_pending |= UsbStatusFlag::USBSTS_D_RECEIVE_SETUP_PACKET;
         self.state.usb0_setup_pending = Some(setup_packet.clone());
+        self.state.usb0_control_request_length = Some(setup_packet.length);
+        self.push_event(UsbStatusFlag::USBSTS_D_RECEIVE_SETUP_PACKET, 0);
 
         Ok(())
     }
@@ -403,6 +516,7 @@ impl Moondancer {
     ) -> GreatResult<()> {
         self.state.usb0_status_pending |= UsbStatusFlag::USBSTS_D_RECEIVE_CONTROL_DATA;
         self.state.usb0_endpoint_complete_pending |= 1 << 0;
+        self.push_event(UsbStatusFlag::USBSTS_D_RECEIVE_CONTROL_DATA, 1 << 0);
 
         debug!(
             "MD => IRQ handle_receive_control_data({}) -> 0b{:b} -> 0b{:b}",
@@ -423,6 +537,7 @@ impl Moondancer {
     ) -> GreatResult<()> {
         self.state.usb0_status_pending |= UsbStatusFlag::USBSTS_D_RECEIVE_DATA;
         self.state.usb0_endpoint_complete_pending |= 1 << endpoint;
+        self.push_event(UsbStatusFlag::USBSTS_D_RECEIVE_DATA, 1 << endpoint);
 
         debug!(
             "MD => IRQ handle_receive_data({}) -> 0b{:b} -> 0b{:b}",
@@ -437,8 +552,26 @@ impl Moondancer {
     }
 
     pub fn handle_transfer_complete(&mut self, endpoint: u8) -> GreatResult<()> {
+        // the host has read a packet of the transfer in progress, send the next one
+        if let Some(transfer) = &mut self.state.in_transfer {
+            match transfer.handle_in_complete(&self.usb0, endpoint) {
+                // keep the transfer until `send_on_endpoint_and_wait` has seen it complete
+                Ok(TransferStatus::Complete(_))
+                    if !matches!(self.pending, Some(Pending::SendOnEndpoint(_))) =>
+                {
+                    self.state.in_transfer = None;
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    warn!("MD failed to send on endpoint {}: {:?}", endpoint, e);
+                    self.state.in_transfer = None;
+                }
+            }
+        }
+
         self.state.usb0_status_pending |= UsbStatusFlag::USBSTS_D_SEND_COMPLETE;
         self.state.usb0_endpoint_complete_pending |= 1 << (endpoint + 16);
+        self.push_event(UsbStatusFlag::USBSTS_D_SEND_COMPLETE, 1 << (endpoint + 16));
 
         debug!(
             "MD => IRQ handle_transfer_complete({}) -> 0b{:b}",
@@ -449,54 +582,86 @@ impl Moondancer {
     }
 }
 
+impl Moondancer {
+    /// Record a status change for the event endpoint.
+    fn push_event(&mut self, usb_status: u32, endpoint_complete: u32) {
+        let event = self.state.status_event(usb_status, endpoint_complete);
+        self.events.push(event);
+    }
+
+    /// Returns the status changes since the last record, if any, to be
+    /// pushed to the host on the event endpoint.
+    ///
+    /// Returns `None` until the host has read the last record, see
+    /// [`Moondancer::handle_event_complete`].
+    pub fn take_event(&mut self) -> Option<StatusEvent> {
+        self.events.take()
+    }
+
+    /// Call once the host has read the last record returned by
+    /// [`Moondancer::take_event`].
+    pub fn handle_event_complete(&mut self) {
+        self.events.complete();
+    }
+
+    /// Call if the last record returned by [`Moondancer::take_event`]
+    /// could not be written, or was discarded before the host read it,
+    /// to have it merged into the next one.
+    pub fn requeue_event(&mut self) {
+        self.events.requeue();
+    }
+
+    /// Forget the record in flight, if any, and stop pushing records
+    /// until the host asks for them again.
+    pub fn reset_events(&mut self) {
+        self.events = EventQueue::default();
+    }
+}
+
 // - verb implementations: connection / disconnection -------------------------
 
 impl Moondancer {
     /// Connect the USB interface.
-    pub fn connect(&mut self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            ep0_max_packet_size: U16<LittleEndian>,
-            quirk_flags: U16<LittleEndian>,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
-
-        self.ep0_max_packet_size = args.ep0_max_packet_size.into();
-        self.quirk_flags = args.quirk_flags.into();
+    pub fn connect(
+        &mut self,
+        ep0_max_packet_size: U16<LittleEndian>,
+        quirk_flags: U16<LittleEndian>,
+        _response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        self.ep0_max_packet_size = ep0_max_packet_size.into();
+        self.quirk_flags = quirk_flags.into();
 
         self.state = State::default();
 
         let speed = self.usb0.connect();
         debug!(
             "MD Moondancer::connect(ep0_max_packet_size:{}, quirk_flags:{}) -> {:?}",
-            args.ep0_max_packet_size, args.quirk_flags, speed
+            ep0_max_packet_size, quirk_flags, speed
         );
 
         unsafe { self.enable_usb_interrupts() };
 
-        Ok([].into_iter())
+        Ok(())
     }
 
     /// Terminate all existing communication and disconnects the USB interface.
-    pub fn disconnect(&mut self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+    pub fn disconnect(&mut self, _response: &mut GcpResponseWriter) -> GreatResult<()> {
         debug!("MD Moondancer::disconnect()");
 
         self.state = State::default();
         self.usb0.disconnect();
 
-        let iter = [].into_iter();
-        Ok(iter)
+        Ok(())
     }
 
     /// Perform a USB bus reset.
-    pub fn bus_reset(&mut self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+    pub fn bus_reset(&mut self, _response: &mut GcpResponseWriter) -> GreatResult<()> {
         debug!("MD Moondancer::bus_reset()");
 
         self.state = State::default();
         self.usb0.bus_reset();
 
-        Ok([].into_iter())
+        Ok(())
     }
 }
 
@@ -504,28 +669,29 @@ impl Moondancer {
 
 impl Moondancer {
     // TODO move tx_ack_active flag logic to hal_driver
-    pub fn set_address(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            address: u8,
-            deferred: u8,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
+    pub fn set_address(
+        &self,
+        address: u8,
+        deferred: u8,
+        _response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
         debug!(
             "MD Moondancer::set_address(address:{}, deferred:{})",
-            args.address, args.deferred
+            address, deferred
         );
 
         // activate new address
-        let address = args.address & 0x7f;
+        let address = address & 0x7f;
         self.usb0.set_address(address);
 
-        let iter = [].into_iter();
-        Ok(iter)
+        Ok(())
     }
 
-    pub fn set_up_endpoints(&mut self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+    pub fn set_up_endpoints(
+        &mut self,
+        endpoint_descriptors: &[u8],
+        _response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
         #[repr(C)]
         #[derive(Debug, FromBytes, Unaligned)]
         struct ArgEndpoint {
@@ -535,7 +701,7 @@ impl Moondancer {
         }
 
         // while we have endpoint triplets to handle
-        let mut byte_slice = arguments;
+        let mut byte_slice = endpoint_descriptors;
         while let Some((endpoint, next)) =
             zerocopy::LayoutVerified::<_, ArgEndpoint>::new_from_prefix(byte_slice)
         {
@@ -558,11 +724,16 @@ impl Moondancer {
                 return Err(GreatError::InvalidArgument);
             }
 
+            // remember the packet size used to split IN transfers
+            let endpoint_number = (endpoint.address & 0x7f) as usize;
+            if endpoint.address & 0x80 != 0 && endpoint_number < NUM_ENDPOINTS {
+                self.state.max_packet_size_in[endpoint_number] = endpoint.max_packet_size.get();
+            }
+
             // TODO configure endpoint
         }
 
-        let iter = [].into_iter();
-        Ok(iter)
+        Ok(())
     }
 }
 
@@ -570,7 +741,9 @@ impl Moondancer {
 
 impl Moondancer {
     /// Query the Moondancer for any events that need to be processed.
-    /// FIXME: should this actually use an interrupt pipe?
+    ///
+    /// Hosts can instead wait for the [`StatusEvent`] records pushed on
+    /// the event endpoint.
     ///
     /// The index value is used to select which status section we're looking for:
     ///
@@ -580,14 +753,13 @@ impl Moondancer {
     ///	3 = endpoint primed status (ENDPTSTATUS)
     ///
     ///	Returns: register_value: u32
-    pub fn get_status(&mut self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            register_type: u8,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
-        let register_type = RegisterType::try_from(args.register_type)?;
+    pub fn get_status(
+        &mut self,
+        register_type: u8,
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        let register_type_number = register_type;
+        let register_type = RegisterType::try_from(register_type)?;
         let register_value = self.state.get(&register_type);
 
         // throttle log output some
@@ -595,10 +767,10 @@ impl Moondancer {
         unsafe {
             static mut LAST_TYPE: u8 = 0;
             static mut LAST_VALUE: u32 = 0;
-            if args.register_type == LAST_TYPE && register_value == LAST_VALUE {
+            if register_type_number == LAST_TYPE && register_value == LAST_VALUE {
                 is_repeat = true;
             }
-            LAST_TYPE = args.register_type;
+            LAST_TYPE = register_type_number;
             LAST_VALUE = register_value;
         }
 
@@ -609,8 +781,7 @@ impl Moondancer {
             );
         }
 
-        let iter = register_value.to_le_bytes().into_iter();
-        Ok(iter)
+        response.write(&register_value.to_le_bytes())
     }
 
     /// Read a setup packet from the Moondancer port and relays it to the host.
@@ -618,46 +789,60 @@ impl Moondancer {
     /// The endpoint_number parameter specifies which endpoint we should be reading from.
     ///
     /// Always transmits an 8-byte setup packet back to the host. If no setup packet
-    /// is waiting, the results of this vendor request are unspecified.
+    /// is waiting, the response is sent once one is received.
     ///
     /// Returns: raw_setup_packet: [u8; 8]
-    pub fn read_setup(&mut self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            endpoint_number: u8,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
-
+    pub fn read_setup(
+        &mut self,
+        endpoint_number: u8,
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
         // TODO handle endpoint numbers other than 0
-        let result = match self.state.usb0_setup_pending.take() {
-            Some(setup_packet) => Ok(SetupPacket::as_bytes(setup_packet).into_iter()),
-            None => Err(GreatError::NoMessageOfType),
+        let setup_packet = match self.state.usb0_setup_pending.take() {
+            Some(setup_packet) => setup_packet,
+            None => {
+                self.pending = Some(Pending::ReadSetup(endpoint_number));
+                return Err(GreatError::OperationNowInProgress);
+            }
         };
+        let result = response.write(&SetupPacket::as_bytes(setup_packet));
 
         debug!(
             "MD Moondancer::read_setup(endpoint_numger:{}) -> {:?}",
-            args.endpoint_number, result
+            endpoint_number, result
         );
 
         result
     }
 
     /// Temporarily stalls the given USB endpoint.
-    pub fn stall_endpoint(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            endpoint_number: u8,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
+    pub fn stall_endpoint(
+        &self,
+        endpoint_address: u8,
+        _response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        self.usb0.stall_endpoint_address(endpoint_address, true);
+
+        debug!("MD Moondancer::stall_endpoint({})", endpoint_address);
+
+        Ok(())
+    }
 
-        self.usb0.stall_endpoint_address(args.endpoint_number, true);
+    /// Start or stop pushing [`StatusEvent`] records on the event
+    /// endpoint.
+    ///
+    /// Records only hold the status changes that happen once they
+    /// have been enabled, `get_status` still reports any earlier ones.
+    pub fn enable_events(
+        &mut self,
+        enable: u8,
+        _response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        debug!("MD Moondancer::enable_events({})", enable);
 
-        debug!("MD Moondancer::stall_endpoint({})", args.endpoint_number);
+        self.events.set_enabled(enable != 0);
 
-        let iter = [].into_iter();
-        Ok(iter)
+        Ok(())
     }
 }
 
@@ -667,50 +852,119 @@ impl Moondancer {
     /// Read data from the GreatFET host and sends on the provided Moondancer endpoint.
     ///
     /// The OUT request should contain a data stage containing all data to be sent.
-    pub fn send_on_endpoint(&mut self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
-        struct Args<B: zerocopy::ByteSlice> {
-            endpoint_number: zerocopy::LayoutVerified<B, u8>,
-            data_to_send: B,
-        }
-        let (endpoint_number, data_to_send) =
-            zerocopy::LayoutVerified::new_unaligned_from_prefix(arguments)
-                .ok_or(GreatError::BadMessage)?;
-        let args = Args {
-            endpoint_number,
-            data_to_send,
-        };
+    ///
+    /// The first packet is written straight away and each of the
+    /// others once the target host has read the previous one. The
+    /// response is sent once the transfer has started, see
+    /// [`Moondancer::send_on_endpoint_and_wait`] to wait for the target
+    /// host to read all of it.
+    pub fn send_on_endpoint(
+        &mut self,
+        endpoint_number: u8,
+        data_to_send: &[u8],
+        _response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        self.start_in_transfer(endpoint_number, data_to_send)
+    }
+
+    /// Like [`Moondancer::send_on_endpoint`], but the response is only
+    /// sent once the target host has read the whole transfer.
+    pub fn send_on_endpoint_and_wait(
+        &mut self,
+        endpoint_number: u8,
+        data_to_send: &[u8],
+        _response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        self.start_in_transfer(endpoint_number, data_to_send)?;
+
+        self.pending = Some(Pending::SendOnEndpoint(endpoint_number & 0x7f));
+        Err(GreatError::OperationNowInProgress)
+    }
 
-        let endpoint: u8 = args.endpoint_number.read();
+    /// Start sending `data_to_send` on the given endpoint.
+    fn start_in_transfer(&mut self, endpoint_number: u8, data_to_send: &[u8]) -> GreatResult<()> {
+        let endpoint = endpoint_number & 0x7f;
 
-        let iter = args.data_to_send.into_iter();
-        self.usb0.write_ref(endpoint, iter);
+        if matches!(&self.state.in_transfer, Some(transfer) if !transfer.is_complete()) {
+            warn!(
+                "MD send_on_endpoint({}) while a transfer is in progress",
+                endpoint
+            );
+            return Err(GreatError::DeviceOrResourceBusy);
+        }
+        let data =
+            SendBuffer::from_slice(data_to_send).map_err(|_| GreatError::ArgumentListTooLong)?;
+
+        let mut transfer = if endpoint == 0 {
+            // the host stops reading once it has the length it asked for
+            let max_packet_size = match self.ep0_max_packet_size {
+                0 => crate::EP_MAX_PACKET_SIZE as u16,
+                max_packet_size => max_packet_size,
+            };
+            let transfer = InTransfer::new(endpoint, data, max_packet_size);
+            match self.state.usb0_control_request_length {
+                Some(length) => transfer.with_requested_length(length as usize),
+                None => transfer.without_zlp(),
+            }
+        } else {
+            let max_packet_size = self
+                .state
+                .max_packet_size_in
+                .get(endpoint as usize)
+                .copied()
+                .unwrap_or(crate::EP_MAX_PACKET_SIZE as u16);
+            InTransfer::new(endpoint, data, max_packet_size)
+        };
+        transfer
+            .start(&self.usb0)
+            .map_err(|_| GreatError::DeviceOrResourceBusy)?;
+        self.state.in_transfer = Some(transfer);
 
         debug!(
             "MD Moondancer::send_on_endpoint(endpoint_number:{}, data_to_send.len:{})",
             endpoint,
-            args.data_to_send.len()
+            data_to_send.len()
         );
 
-        Ok([].into_iter())
+        Ok(())
+    }
+
+    /// Complete a `send_on_endpoint_and_wait` once the target host has
+    /// read the whole transfer.
+    fn poll_send_on_endpoint(&mut self, endpoint: u8) -> GreatResult<()> {
+        match &self.state.in_transfer {
+            Some(transfer) if transfer.is_complete() => {
+                debug!(
+                    "MD Moondancer::send_on_endpoint({}) -> {} bytes",
+                    endpoint,
+                    transfer.bytes_transferred()
+                );
+                self.state.in_transfer = None;
+                Ok(())
+            }
+            Some(_) => {
+                self.pending = Some(Pending::SendOnEndpoint(endpoint));
+                Err(GreatError::OperationNowInProgress)
+            }
+            // abandoned by a bus reset or a failed write
+            None => Err(GreatError::InterruptedSystemCall),
+        }
     }
 
     /// Should be called whenever a transfer is complete; cleans up any transfer
     /// descriptors associated with that transfer.
-    pub fn clean_up_transfer(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            endpoint_address: u8,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
-        let endpoint_number = args.endpoint_address & 0x7f;
+    pub fn clean_up_transfer(
+        &self,
+        endpoint_address: u8,
+        _response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        let endpoint_number = endpoint_address & 0x7f;
         debug!(
             "MD Moondancer::clean_up_transfer({} / 0x{:x})",
-            args.endpoint_address, endpoint_number
+            endpoint_address, endpoint_number
         );
 
-        let iter = [].into_iter();
-        Ok(iter)
+        Ok(())
     }
 
     /// Prime the USB controller to recieve data on a particular endpoint.
@@ -720,23 +974,14 @@ impl Moondancer {
     /// with `finish_nonblocking_read`.
     pub fn start_nonblocking_read(
         &mut self,
-        arguments: &[u8],
-    ) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            endpoint_number: u8,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
-        debug!(
-            "MD Moondancer::start_nonblocking_read({})",
-            args.endpoint_number
-        );
+        endpoint_number: u8,
+        _response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        debug!("MD Moondancer::start_nonblocking_read({})", endpoint_number);
 
-        self.usb0.ep_out_prime_receive(args.endpoint_number);
+        self.usb0.ep_out_prime_receive(endpoint_number);
 
-        let iter = [].into_iter();
-        Ok(iter)
+        Ok(())
     }
 
     /// Finish a non-blocking read by returning the read data back to the host.
@@ -748,17 +993,10 @@ impl Moondancer {
     /// Returns: read_data: [u8]
     pub fn finish_nonblocking_read(
         &mut self,
-        arguments: &[u8],
-    ) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            endpoint_number: u8,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
-
-        let endpoint = args.endpoint_number as usize;
-        let data = self.state.receive_buffers[endpoint];
+        endpoint_number: u8,
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        let endpoint = endpoint_number as usize;
         let bytes_read = self.state.bytes_read[endpoint];
         self.state.bytes_read[endpoint] = 0;
 
@@ -767,8 +1005,7 @@ impl Moondancer {
             endpoint, bytes_read,
         );
 
-        let iter = data.into_iter().take(bytes_read);
-        Ok(iter)
+        response.write(&self.state.receive_buffers[endpoint][..bytes_read])
     }
 
     /// Query an endpoint to determine how much data is available.
@@ -783,120 +1020,160 @@ impl Moondancer {
     /// Returns: length: u32
     pub fn get_nonblocking_data_length(
         &self,
-        arguments: &[u8],
-    ) -> GreatResult<impl Iterator<Item = u8>> {
-        #[repr(C)]
-        #[derive(FromBytes, Unaligned)]
-        struct Args {
-            endpoint_number: u8,
-        }
-        let args = Args::read_from(arguments).ok_or(GreatError::BadMessage)?;
+        endpoint_number: u8,
+        _response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
         debug!(
             "MD Moondancer::get_nonblocking_data_length({})",
-            args.endpoint_number
+            endpoint_number
         );
-        let iter = [].into_iter();
-        Ok(iter)
+        Ok(())
     }
 }
 
 // - dispatch -----------------------------------------------------------------
 
-use libgreat::gcp::{iter_to_response, GcpResponse, GCP_MAX_RESPONSE_LENGTH};
-
-use core::{array, iter};
+impl GcpClass for Moondancer {
+    fn class(&self) -> &'static Class {
+        &CLASS
+    }
 
-impl Moondancer {
-    pub fn dispatch(
+    fn dispatch(
         &mut self,
         verb_number: u32,
         arguments: &[u8],
-        response_buffer: [u8; GCP_MAX_RESPONSE_LENGTH],
-    ) -> GreatResult<GcpResponse> {
-        match verb_number {
-            0x0 => {
-                // moondancer::connect
-                let iter = self.connect(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x1 => {
-                // moondancer::disconnect
-                let iter = self.disconnect(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x2 => {
-                // moondancer::bus_reset
-                let iter = self.bus_reset(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x3 => {
-                // moondancer::set_address
-                let iter = self.set_address(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x4 => {
-                // moondancer::set_up_endpoints
-                let iter = self.set_up_endpoints(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x5 => {
-                // moondancer::get_status
-                let iter = self.get_status(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x6 => {
-                // moondancer::read_setup
-                let iter = self.read_setup(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x7 => {
-                // moondancer::stall_endpoint
-                let iter = self.stall_endpoint(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x8 => {
-                // moondancer::send_on_endpoint
-                let iter = self.send_on_endpoint(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0x9 => {
-                // moondancer::clean_up_transfer
-                let iter = self.clean_up_transfer(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0xa => {
-                // moondancer::start_nonblocking_read
-                let iter = self.start_nonblocking_read(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
-            }
-            0xb => {
-                // moondancer::finish_nonblocking_read
-                let iter = self.finish_nonblocking_read(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        dispatch(self, verb_number, arguments, response)
+    }
+
+    fn poll(&mut self, response: &mut GcpResponseWriter) -> GreatResult<()> {
+        match self.pending.take() {
+            Some(Pending::ReadSetup(endpoint_number)) => self.read_setup(endpoint_number, response),
+            Some(Pending::SendOnEndpoint(endpoint)) => self.poll_send_on_endpoint(endpoint),
+            None => Err(GreatError::NoMessageOfType),
+        }
+    }
+
+    fn cancel(&mut self) {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        debug!("MD Moondancer::cancel({:?})", pending);
+
+        // transfers started by `send_on_endpoint` carry on without their command
+        if let Pending::SendOnEndpoint(_) = pending {
+            if let Some(transfer) = self.state.in_transfer.take() {
+                debug!(
+                    "MD abandoning transfer on endpoint {} after {} bytes",
+                    transfer.endpoint(),
+                    transfer.bytes_transferred()
+                );
             }
-            0xc => {
-                // moondancer::get_nonblocking_data_length
-                let iter = self.get_nonblocking_data_length(arguments)?;
-                let response = unsafe { iter_to_response(iter, response_buffer) };
-                Ok(response)
+        }
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_status_event_merge() {
+        let mut event = StatusEvent {
+            usb_status: UsbStatusFlag::USBSTS_D_RECEIVE_SETUP_PACKET,
+            setup_status: 1,
+            endpoint_complete: 1 << 0,
+            endpoint_nak: 0,
+        };
+
+        // change bits accumulate, setup status follows the newer record
+        event.merge(&StatusEvent {
+            usb_status: UsbStatusFlag::USBSTS_D_SEND_COMPLETE,
+            setup_status: 0,
+            endpoint_complete: 1 << 17,
+            endpoint_nak: 1 << 2,
+        });
+        assert_eq!(
+            event,
+            StatusEvent {
+                usb_status: UsbStatusFlag::USBSTS_D_RECEIVE_SETUP_PACKET
+                    | UsbStatusFlag::USBSTS_D_SEND_COMPLETE,
+                setup_status: 0,
+                endpoint_complete: (1 << 0) | (1 << 17),
+                endpoint_nak: 1 << 2,
             }
+        );
 
-            verb_number => Err(GreatError::GcpVerbNotFound(
-                gcp::class::ClassId::moondancer,
-                verb_number,
-            )),
-        }
+        // merging an empty record changes nothing
+        let merged = event;
+        event.merge(&StatusEvent::default());
+        assert_eq!(event, merged);
+
+        assert_eq!(
+            &event.to_bytes()[8..12],
+            &((1u32 << 0) | (1 << 17)).to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn test_event_queue() {
+        let setup = StatusEvent {
+            usb_status: UsbStatusFlag::USBSTS_D_RECEIVE_SETUP_PACKET,
+            setup_status: 1,
+            ..StatusEvent::default()
+        };
+        let complete = StatusEvent {
+            usb_status: UsbStatusFlag::USBSTS_D_SEND_COMPLETE,
+            setup_status: 1,
+            endpoint_complete: 1 << 17,
+            ..StatusEvent::default()
+        };
+        let mut events = EventQueue::default();
+
+        // nothing is recorded until the host asks for records
+        events.push(setup);
+        assert_eq!(events.take(), None);
+        events.set_enabled(true);
+        assert_eq!(events.take(), None);
+
+        // changes while a record is in flight are merged into the next one
+        events.push(setup);
+        assert_eq!(events.take(), Some(setup));
+        events.push(complete);
+        assert_eq!(events.take(), None);
+        events.complete();
+        assert_eq!(events.take(), Some(complete));
+        events.complete();
+        assert_eq!(events.take(), None);
+
+        // a record that was never read is merged back into the next one
+        events.push(setup);
+        assert_eq!(events.take(), Some(setup));
+        events.push(complete);
+        events.requeue();
+        let mut merged = setup;
+        merged.merge(&complete);
+        assert_eq!(events.take(), Some(merged));
+
+        // ... or sent again as is
+        events.requeue();
+        assert_eq!(events.take(), Some(merged));
+        events.complete();
+
+        // requeuing once the host has read the record does nothing
+        events.requeue();
+        assert_eq!(events.take(), None);
+
+        // disabling records drops the pending changes
+        events.push(setup);
+        events.set_enabled(false);
+        events.push(complete);
+        assert_eq!(events.take(), None);
     }
 }
@@ -0,0 +1,88 @@
+//! GCP class for switching device personalities
+//!
+//! See [`smolusb::personality`].
+
+use libgreat::error::{GreatError, GreatResult};
+use libgreat::gcp::{Class, GcpClass, GcpResponseWriter};
+
+use smolusb::personality::PersonalitySwitch;
+
+use log::debug;
+
+libgreat::gcp_class! {
+    class: personality,
+    docs: "API for switching between device personalities.",
+    context: &mut PersonalitySwitch,
+    verbs: [
+        0x0 => get_personality {
+            doc: "Return the active personality and the number of personalities available.",
+            in: "" (),
+            out: "<BB" (active, count),
+            handler: get_personality,
+        },
+        0x1 => set_personality {
+            doc: "Re-enumerate the device with the given personality.",
+            in: "<B" (index: u8),
+            out: "" (),
+            handler: set_personality,
+        },
+    ]
+}
+
+// - verb implementations -----------------------------------------------------
+
+pub fn get_personality(
+    switch: &PersonalitySwitch,
+    response: &mut GcpResponseWriter,
+) -> GreatResult<()> {
+    debug!(
+        "MD personality::get_personality() -> {} of {}",
+        switch.active(),
+        switch.count()
+    );
+    response.write(&[switch.active() as u8, switch.count() as u8])
+}
+
+/// The switch only happens once the response to this verb has been
+/// sent and the firmware takes the request from the switch.
+pub fn set_personality(
+    switch: &mut PersonalitySwitch,
+    index: u8,
+    _response: &mut GcpResponseWriter,
+) -> GreatResult<()> {
+    debug!("MD personality::set_personality({})", index);
+
+    if !switch.request(index.into()) {
+        return Err(GreatError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+// - dispatch -----------------------------------------------------------------
+
+/// The personality class, serving the verbs of a [`PersonalitySwitch`]
+pub struct Personality<'a> {
+    switch: &'a mut PersonalitySwitch,
+}
+
+impl<'a> Personality<'a> {
+    pub fn new(switch: &'a mut PersonalitySwitch) -> Self {
+        Self { switch }
+    }
+}
+
+impl GcpClass for Personality<'_> {
+    fn class(&self) -> &'static Class {
+        &CLASS
+    }
+
+    fn dispatch(
+        &mut self,
+        verb_number: u32,
+        arguments: &[u8],
+        response: &mut GcpResponseWriter,
+    ) -> GreatResult<()> {
+        dispatch(self.switch, verb_number, arguments, response)
+    }
+}
@@ -8,6 +8,7 @@ pub mod error;
 pub mod gcp;
 pub mod log;
 pub mod macros;
+pub mod net;
 pub mod panic_log;
 pub mod usb;
 
@@ -92,6 +93,46 @@ pub enum Message {
     DebugMessage(&'static str),
 }
 
+impl Message {
+    /// Wrap a [`smolusb::event::UsbEvent`] decoded by a `MachineExternal`
+    /// interrupt handler.
+    pub fn from_usb_event(interface: UsbInterface, event: smolusb::event::UsbEvent) -> Self {
+        use smolusb::event::UsbEvent;
+        match event {
+            UsbEvent::BusReset => Message::UsbBusReset(interface),
+            UsbEvent::Setup(setup_packet) => {
+                Message::UsbReceiveSetupPacket(interface, setup_packet)
+            }
+            UsbEvent::OutPacket { endpoint, length } => {
+                Message::UsbReceivePacket(interface, endpoint, length)
+            }
+            UsbEvent::InComplete { endpoint } => Message::UsbTransferComplete(interface, endpoint),
+            UsbEvent::Suspend | UsbEvent::Resume => {
+                Message::DebugMessage("Ignoring usb suspend/resume")
+            }
+        }
+    }
+
+    /// Unwrap a message created with [`Message::from_usb_event`] so that
+    /// it can be passed on to [`smolusb::device::UsbDevice::poll`].
+    pub fn into_usb_event(self) -> Option<(UsbInterface, smolusb::event::UsbEvent)> {
+        use smolusb::event::UsbEvent;
+        match self {
+            Message::UsbBusReset(interface) => Some((interface, UsbEvent::BusReset)),
+            Message::UsbReceiveSetupPacket(interface, setup_packet) => {
+                Some((interface, UsbEvent::Setup(setup_packet)))
+            }
+            Message::UsbReceivePacket(interface, endpoint, length) => {
+                Some((interface, UsbEvent::OutPacket { endpoint, length }))
+            }
+            Message::UsbTransferComplete(interface, endpoint) => {
+                Some((interface, UsbEvent::InComplete { endpoint }))
+            }
+            _ => None,
+        }
+    }
+}
+
 impl core::fmt::Debug for Message {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
@@ -5,9 +5,10 @@
 
 use crate::{hal, pac};
 
+use libgreat::gcp::serial::{write_frame, Channel};
 use log::{Level, LevelFilter, Metadata, Record};
 
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use core::fmt::Write;
 
 // - initialization -----------------------------------------------------------
@@ -15,6 +16,8 @@ use core::fmt::Write;
 static LOGGER: WriteLogger<hal::Serial> = WriteLogger {
     writer: RefCell::new(None),
     level: Level::Trace,
+    framed: Cell::new(false),
+    sequence: Cell::new(0),
 };
 
 pub fn init(writer: hal::Serial) {
@@ -43,6 +46,28 @@ pub fn init(writer: hal::Serial) {
     }
 }
 
+/// Send log output as frames on the log channel, allowing it to share
+/// the serial port with the GCP serial transport.
+///
+/// See: [`libgreat::gcp::serial`]
+pub fn set_framed(framed: bool) {
+    LOGGER.framed.set(framed);
+}
+
+/// Returns true if log output is sent as frames.
+pub fn is_framed() -> bool {
+    LOGGER.framed.get()
+}
+
+/// Write a frame to the serial port used for log output.
+pub fn write_serial_frame(channel: Channel, sequence: u8, payload: &[&[u8]]) {
+    LOGGER.with_writer(|writer| {
+        write_frame(channel, sequence, payload, |byte| {
+            let _ = hal::hal::serial::Write::write(writer, &[byte]);
+        });
+    });
+}
+
 // - implementation -----------------------------------------------------------
 
 /// WriteLogger
@@ -52,11 +77,58 @@ where
 {
     pub writer: RefCell<Option<W>>,
     pub level: Level,
+    framed: Cell<bool>,
+    sequence: Cell<u8>,
+}
+
+impl<W> WriteLogger<W>
+where
+    W: Write + hal::hal::serial::Write<u8> + Send,
+{
+    fn with_writer(&self, f: impl FnOnce(&mut W)) {
+        #[cfg(target_has_atomic)]
+        {
+            match self.writer.borrow_mut().as_mut() {
+                Some(writer) => f(writer),
+                None => {
+                    panic!("Logger has not been initialized");
+                }
+            }
+        }
+
+        #[cfg(not(target_has_atomic))]
+        {
+            riscv::interrupt::free(|| match self.writer.borrow_mut().as_mut() {
+                Some(writer) => f(writer),
+                None => {
+                    panic!("Logger has not been initialized");
+                }
+            });
+        }
+    }
+
+    fn write_record(&self, writer: &mut W, record: &Record) -> core::fmt::Result {
+        if !self.framed.get() {
+            return writeln!(writer, "{}\t{}", record.level(), record.args());
+        }
+
+        // lines longer than the buffer are truncated
+        let mut line = format_nostd::BufferWriter::new([0; format_nostd::SIZE]);
+        write!(line, "{}\t{}", record.level(), record.args())?;
+
+        let sequence = self.sequence.get();
+        self.sequence.set(sequence.wrapping_add(1));
+        write_frame(Channel::Log, sequence, &[line.as_bytes()], |byte| {
+            let _ = hal::hal::serial::Write::write(writer, &[byte]);
+        });
+
+        Ok(())
+    }
 }
 
 impl<W> log::Log for WriteLogger<W>
 where
-    W: Write + Send,
+    W: Write + hal::hal::serial::Write<u8> + Send,
 {
     fn enabled(&self, metadata: &Metadata) -> bool {
         metadata.level() <= self.level
@@ -70,7 +142,7 @@ where
         #[cfg(target_has_atomic)]
         {
             match self.writer.borrow_mut().as_mut() {
-                Some(writer) => match writeln!(writer, "{}\t{}", record.level(), record.args()) {
+                Some(writer) => match self.write_record(writer, record) {
                     Ok(()) => (),
                     Err(_e) => {
                         panic!("Logger failed to write to device");
@@ -85,7 +157,7 @@ where
         #[cfg(not(target_has_atomic))]
         {
             riscv::interrupt::free(|| match self.writer.borrow_mut().as_mut() {
-                Some(writer) => match writeln!(writer, "{}\t{}", record.level(), record.args()) {
+                Some(writer) => match self.write_record(writer, record) {
                     Ok(()) => (),
                     Err(_e) => {
                         panic!("Logger failed to write to device");
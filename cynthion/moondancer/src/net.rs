@@ -0,0 +1,284 @@
+//! A minimal IPv4 stack for USB network functions
+//!
+//! `Interface` answers ARP and ICMP echo requests, runs a DHCP server
+//! that leases a single address to the host, and serves HTTP requests
+//! on a single TCP port.
+//!
+//! Each received Ethernet frame produces at most one response frame,
+//! which is enough for request/response traffic that fits in a single
+//! segment.
+
+pub mod arp;
+pub mod dhcp;
+pub mod http;
+pub mod ipv4;
+pub mod tcp;
+
+use log::{debug, trace, warn};
+
+// - constants ----------------------------------------------------------------
+
+pub const ETHERNET_HEADER_LENGTH: usize = 14;
+pub const BROADCAST_MAC_ADDRESS: [u8; 6] = [0xff; 6];
+
+/// EtherType
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum EtherType {
+    Ipv4,
+    Arp,
+    Unknown(u16),
+}
+
+impl From<u16> for EtherType {
+    fn from(value: u16) -> Self {
+        match value {
+            0x0800 => EtherType::Ipv4,
+            0x0806 => EtherType::Arp,
+            _ => EtherType::Unknown(value),
+        }
+    }
+}
+
+// - Config -------------------------------------------------------------------
+
+/// Network configuration
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// Our MAC address
+    pub mac_address: [u8; 6],
+    /// Our IP address
+    pub ip_address: [u8; 4],
+    /// IP address leased to the host
+    pub host_ip_address: [u8; 4],
+    pub netmask: [u8; 4],
+    /// TCP port to serve HTTP on
+    pub http_port: u16,
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Self {
+            mac_address: [0x02, 0x00, 0x00, 0xc0, 0xff, 0xef],
+            ip_address: [10, 0, 0, 1],
+            host_ip_address: [10, 0, 0, 2],
+            netmask: [255, 255, 255, 0],
+            http_port: 80,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// - Interface ----------------------------------------------------------------
+
+/// Network interface
+pub struct Interface {
+    pub config: Config,
+    dhcp: dhcp::Server,
+    tcp: tcp::Server<{ http::MAX_REQUEST_LENGTH }>,
+}
+
+impl Interface {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            dhcp: dhcp::Server::new(),
+            tcp: tcp::Server::new(config.http_port),
+        }
+    }
+
+    /// Handle a received Ethernet frame, writing any response frame to
+    /// `response` and returning its length.
+    ///
+    /// HTTP requests are passed to `handler`, which writes the
+    /// response body and returns its content type and length.
+    pub fn handle_frame<F>(&mut self, frame: &[u8], response: &mut [u8], handler: F) -> Option<usize>
+    where
+        F: FnMut(&http::Request, &mut [u8]) -> Option<http::Response>,
+    {
+        if frame.len() < ETHERNET_HEADER_LENGTH {
+            warn!("NET dropping runt frame of {} bytes", frame.len());
+            return None;
+        }
+        let destination = &frame[0..6];
+        if destination != self.config.mac_address && destination != BROADCAST_MAC_ADDRESS {
+            return None;
+        }
+
+        let mut source = [0; 6];
+        source.copy_from_slice(&frame[6..12]);
+        let ether_type = EtherType::from(read_u16(frame, 12));
+        let payload = &frame[ETHERNET_HEADER_LENGTH..];
+
+        trace!("NET received {:?} frame of {} bytes", ether_type, frame.len());
+
+        let (ether_type, length) = match ether_type {
+            EtherType::Arp => {
+                let length = arp::handle(
+                    &self.config,
+                    payload,
+                    &mut response[ETHERNET_HEADER_LENGTH..],
+                )?;
+                (EtherType::Arp, length)
+            }
+            EtherType::Ipv4 => {
+                let length = self.handle_ipv4(
+                    payload,
+                    &mut response[ETHERNET_HEADER_LENGTH..],
+                    handler,
+                )?;
+                (EtherType::Ipv4, length)
+            }
+            EtherType::Unknown(value) => {
+                trace!("NET ignoring ethertype 0x{:04x}", value);
+                return None;
+            }
+        };
+
+        // replies to broadcast DHCP requests are themselves broadcast
+        let destination = if ether_type == EtherType::Ipv4
+            && response[ETHERNET_HEADER_LENGTH + 16..ETHERNET_HEADER_LENGTH + 20] == [0xff; 4]
+        {
+            BROADCAST_MAC_ADDRESS
+        } else {
+            source
+        };
+
+        response[0..6].copy_from_slice(&destination);
+        response[6..12].copy_from_slice(&self.config.mac_address);
+        write_u16(
+            response,
+            12,
+            match ether_type {
+                EtherType::Arp => 0x0806,
+                _ => 0x0800,
+            },
+        );
+
+        Some(ETHERNET_HEADER_LENGTH + length)
+    }
+
+    fn handle_ipv4<F>(&mut self, packet: &[u8], response: &mut [u8], handler: F) -> Option<usize>
+    where
+        F: FnMut(&http::Request, &mut [u8]) -> Option<http::Response>,
+    {
+        let header = ipv4::Header::parse(packet)?;
+        let broadcast = header.destination == [0xff; 4];
+        if header.destination != self.config.ip_address && !broadcast {
+            return None;
+        }
+        let payload = &packet[header.header_length..header.total_length];
+        let response_payload = &mut response[ipv4::HEADER_LENGTH..];
+
+        let (protocol, destination, length) = match header.protocol {
+            ipv4::Protocol::Icmp => {
+                let length = ipv4::handle_icmp(payload, response_payload)?;
+                (ipv4::Protocol::Icmp, header.source, length)
+            }
+            ipv4::Protocol::Udp => {
+                let (destination, length) = self.handle_udp(payload, response_payload)?;
+                (ipv4::Protocol::Udp, destination, length)
+            }
+            ipv4::Protocol::Tcp if !broadcast => {
+                let length = self.tcp.handle(
+                    &self.config,
+                    &header,
+                    payload,
+                    response_payload,
+                    handler,
+                )?;
+                (ipv4::Protocol::Tcp, header.source, length)
+            }
+            _ => return None,
+        };
+
+        let response_header = ipv4::Header {
+            header_length: ipv4::HEADER_LENGTH,
+            total_length: ipv4::HEADER_LENGTH + length,
+            protocol,
+            source: self.config.ip_address,
+            destination,
+        };
+        response_header.write(response);
+
+        Some(ipv4::HEADER_LENGTH + length)
+    }
+
+    fn handle_udp(&mut self, datagram: &[u8], response: &mut [u8]) -> Option<([u8; 4], usize)> {
+        if datagram.len() < ipv4::UDP_HEADER_LENGTH {
+            return None;
+        }
+        let source_port = read_u16(datagram, 0);
+        let destination_port = read_u16(datagram, 2);
+        let length = usize::min(read_u16(datagram, 4) as usize, datagram.len());
+        let payload = &datagram[ipv4::UDP_HEADER_LENGTH..length];
+
+        let (destination, length) = match destination_port {
+            dhcp::SERVER_PORT => {
+                let length = self.dhcp.handle(
+                    &self.config,
+                    payload,
+                    &mut response[ipv4::UDP_HEADER_LENGTH..],
+                )?;
+                ([0xff; 4], length)
+            }
+            port => {
+                trace!("NET ignoring udp datagram for port {}", port);
+                return None;
+            }
+        };
+
+        // checksum is optional for udp over ipv4
+        let total_length = ipv4::UDP_HEADER_LENGTH + length;
+        write_u16(response, 0, destination_port);
+        write_u16(response, 2, source_port);
+        write_u16(response, 4, total_length as u16);
+        write_u16(response, 6, 0);
+
+        debug!("NET sending udp datagram of {} bytes", total_length);
+
+        Some((destination, total_length))
+    }
+}
+
+// - helpers ------------------------------------------------------------------
+
+/// Internet checksum over `data`, continuing from a partial `sum`.
+pub fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in chunks.by_ref() {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [byte] = chunks.remainder() {
+        sum += (*byte as u32) << 8;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+pub fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+pub fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
@@ -0,0 +1,42 @@
+//! Address Resolution Protocol
+
+use super::{read_u16, write_u16, Config};
+
+use log::trace;
+
+const PACKET_LENGTH: usize = 28;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+
+/// Answer ARP requests for our IP address.
+pub fn handle(config: &Config, packet: &[u8], response: &mut [u8]) -> Option<usize> {
+    if packet.len() < PACKET_LENGTH || response.len() < PACKET_LENGTH {
+        return None;
+    }
+
+    let hardware_type = read_u16(packet, 0);
+    let protocol_type = read_u16(packet, 2);
+    let operation = read_u16(packet, 6);
+    let sender_mac_address = &packet[8..14];
+    let sender_ip_address = &packet[14..18];
+    let target_ip_address = &packet[24..28];
+
+    if hardware_type != 1
+        || protocol_type != 0x0800
+        || operation != OPERATION_REQUEST
+        || target_ip_address != config.ip_address
+    {
+        return None;
+    }
+
+    trace!("NET arp request from {:?}", sender_ip_address);
+
+    response[0..6].copy_from_slice(&packet[0..6]); // htype, ptype, hlen, plen
+    write_u16(response, 6, OPERATION_REPLY);
+    response[8..14].copy_from_slice(&config.mac_address);
+    response[14..18].copy_from_slice(&config.ip_address);
+    response[18..24].copy_from_slice(sender_mac_address);
+    response[24..28].copy_from_slice(sender_ip_address);
+
+    Some(PACKET_LENGTH)
+}
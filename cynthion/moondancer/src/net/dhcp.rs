@@ -0,0 +1,228 @@
+//! DHCP server leasing a single address to the host
+
+use super::{read_u32, write_u32, Config};
+
+use log::{debug, trace, warn};
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+/// Lease time offered to the host, in seconds
+pub const LEASE_TIME: u32 = 24 * 60 * 60;
+
+const MAGIC_COOKIE: u32 = 0x6382_5363;
+const MESSAGE_HEADER_LENGTH: usize = 236;
+const OPTIONS_OFFSET: usize = MESSAGE_HEADER_LENGTH + 4;
+
+const OP_BOOTREQUEST: u8 = 1;
+const OP_BOOTREPLY: u8 = 2;
+
+/// DHCP option codes
+#[allow(non_snake_case, non_upper_case_globals)]
+mod OptionCode {
+    pub const Pad: u8 = 0;
+    pub const SubnetMask: u8 = 1;
+    pub const RequestedIpAddress: u8 = 50;
+    pub const LeaseTime: u8 = 51;
+    pub const MessageType: u8 = 53;
+    pub const ServerIdentifier: u8 = 54;
+    pub const End: u8 = 255;
+}
+
+/// DHCP message types
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Inform,
+    Unknown(u8),
+}
+
+impl From<u8> for MessageType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            4 => MessageType::Decline,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            7 => MessageType::Release,
+            8 => MessageType::Inform,
+            _ => MessageType::Unknown(value),
+        }
+    }
+}
+
+impl From<MessageType> for u8 {
+    fn from(message_type: MessageType) -> Self {
+        match message_type {
+            MessageType::Discover => 1,
+            MessageType::Offer => 2,
+            MessageType::Request => 3,
+            MessageType::Decline => 4,
+            MessageType::Ack => 5,
+            MessageType::Nak => 6,
+            MessageType::Release => 7,
+            MessageType::Inform => 8,
+            MessageType::Unknown(value) => value,
+        }
+    }
+}
+
+// - Server -------------------------------------------------------------------
+
+/// DHCP server
+pub struct Server {
+    /// Hardware address of the client holding the lease
+    client: Option<[u8; 6]>,
+}
+
+impl Server {
+    pub const fn new() -> Self {
+        Self { client: None }
+    }
+
+    /// Handle a DHCP message, writing any reply to `response` and
+    /// returning its length.
+    pub fn handle(&mut self, config: &Config, message: &[u8], response: &mut [u8]) -> Option<usize> {
+        if message.len() < OPTIONS_OFFSET
+            || message[0] != OP_BOOTREQUEST
+            || message[1] != 1 // ethernet
+            || message[2] != 6
+            || read_u32(message, MESSAGE_HEADER_LENGTH) != MAGIC_COOKIE
+        {
+            warn!("NET invalid dhcp message");
+            return None;
+        }
+
+        let mut client = [0; 6];
+        client.copy_from_slice(&message[28..34]);
+
+        let mut message_type = None;
+        let mut requested_ip_address = None;
+        for (option, value) in Options::new(&message[OPTIONS_OFFSET..]) {
+            match (option, value.len()) {
+                (OptionCode::MessageType, 1) => message_type = Some(MessageType::from(value[0])),
+                (OptionCode::RequestedIpAddress, 4) => requested_ip_address = Some(value),
+                _ => (),
+            }
+        }
+        let ciaddr = &message[12..16];
+        let requested_ip_address = requested_ip_address.unwrap_or(ciaddr);
+
+        trace!("NET dhcp {:?} from {:02x?}", message_type, client);
+
+        let reply = match message_type? {
+            MessageType::Discover => match self.client {
+                Some(leased) if leased != client => {
+                    warn!("NET dhcp address already leased to {:02x?}", leased);
+                    return None;
+                }
+                _ => MessageType::Offer,
+            },
+            MessageType::Request => {
+                if requested_ip_address == config.host_ip_address
+                    && !matches!(self.client, Some(leased) if leased != client)
+                {
+                    self.client = Some(client);
+                    debug!("NET dhcp leased {:?} to {:02x?}", config.host_ip_address, client);
+                    MessageType::Ack
+                } else {
+                    MessageType::Nak
+                }
+            }
+            MessageType::Release => {
+                if self.client == Some(client) {
+                    self.client = None;
+                }
+                return None;
+            }
+            _ => return None,
+        };
+
+        Some(write_reply(config, message, reply, response))
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn write_reply(config: &Config, request: &[u8], reply: MessageType, response: &mut [u8]) -> usize {
+    let response = &mut response[..OPTIONS_OFFSET + 64];
+    response.fill(0);
+
+    response[0] = OP_BOOTREPLY;
+    response[1..4].copy_from_slice(&request[1..4]); // htype, hlen, hops
+    response[4..8].copy_from_slice(&request[4..8]); // xid
+    response[10..12].copy_from_slice(&request[10..12]); // flags
+    if reply != MessageType::Nak {
+        response[16..20].copy_from_slice(&config.host_ip_address); // yiaddr
+        response[20..24].copy_from_slice(&config.ip_address); // siaddr
+    }
+    response[28..44].copy_from_slice(&request[28..44]); // chaddr
+    write_u32(response, MESSAGE_HEADER_LENGTH, MAGIC_COOKIE);
+
+    let mut offset = OPTIONS_OFFSET;
+    let mut option = |code: u8, value: &[u8]| {
+        response[offset] = code;
+        response[offset + 1] = value.len() as u8;
+        response[offset + 2..offset + 2 + value.len()].copy_from_slice(value);
+        offset += 2 + value.len();
+    };
+    option(OptionCode::MessageType, &[reply.into()]);
+    option(OptionCode::ServerIdentifier, &config.ip_address);
+    if reply != MessageType::Nak {
+        option(OptionCode::LeaseTime, &LEASE_TIME.to_be_bytes());
+        option(OptionCode::SubnetMask, &config.netmask);
+    }
+    response[offset] = OptionCode::End;
+
+    // pad to the minimum BOOTP message length
+    usize::max(offset + 1, 300)
+}
+
+// - Options ------------------------------------------------------------------
+
+/// Iterator over the options of a DHCP message
+struct Options<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Options<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (&code, rest) = self.bytes.split_first()?;
+            match code {
+                OptionCode::Pad => self.bytes = rest,
+                OptionCode::End => return None,
+                _ => {
+                    let (&length, rest) = rest.split_first()?;
+                    let length = length as usize;
+                    if length > rest.len() {
+                        return None;
+                    }
+                    let (value, rest) = rest.split_at(length);
+                    self.bytes = rest;
+                    return Some((code, value));
+                }
+            }
+        }
+    }
+}
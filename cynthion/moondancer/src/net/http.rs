@@ -0,0 +1,171 @@
+//! HTTP/1.0 request parsing and response framing
+
+use log::{debug, warn};
+
+use core::fmt::Write;
+
+/// Maximum length of a request, including the body
+pub const MAX_REQUEST_LENGTH: usize = 1024;
+
+/// A parsed HTTP request
+#[derive(Debug)]
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub body: &'a [u8],
+}
+
+/// Response body written by a request handler
+#[derive(Debug, Clone, Copy)]
+pub struct Response {
+    pub content_type: &'static str,
+    pub length: usize,
+}
+
+impl Response {
+    pub const fn text(length: usize) -> Self {
+        Self {
+            content_type: "text/plain",
+            length,
+        }
+    }
+
+    pub const fn binary(length: usize) -> Self {
+        Self {
+            content_type: "application/octet-stream",
+            length,
+        }
+    }
+}
+
+/// Error returned for malformed requests
+#[derive(Debug, PartialEq)]
+pub struct InvalidRequest;
+
+impl<'a> Request<'a> {
+    /// Parse a request, returning `Ok(None)` if more data is required.
+    pub fn parse(bytes: &'a [u8]) -> Result<Option<Self>, InvalidRequest> {
+        let header_end = match find(bytes, b"\r\n\r\n") {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let header = core::str::from_utf8(&bytes[..header_end]).map_err(|_| InvalidRequest)?;
+        let mut lines = header.split("\r\n");
+
+        let mut request_line = lines.next().ok_or(InvalidRequest)?.split(' ');
+        let method = request_line.next().ok_or(InvalidRequest)?;
+        let path = request_line.next().ok_or(InvalidRequest)?;
+
+        let mut content_length = 0;
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse::<usize>().map_err(|_| InvalidRequest)?;
+                }
+            }
+        }
+
+        let body_start = header_end + 4;
+        if bytes.len() < body_start + content_length {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            method,
+            path,
+            body: &bytes[body_start..body_start + content_length],
+        }))
+    }
+}
+
+/// Parse the request in `request` and write the response to
+/// `response`, returning its length.
+///
+/// Returns `None` while the request is incomplete.
+pub fn respond<F>(request: &[u8], response: &mut [u8], mut handler: F) -> Option<usize>
+where
+    F: FnMut(&Request, &mut [u8]) -> Option<Response>,
+{
+    let request = match Request::parse(request) {
+        Ok(Some(request)) => request,
+        Ok(None) => return None,
+        Err(InvalidRequest) => {
+            warn!("NET invalid http request");
+            return Some(write_status(response, "400 Bad Request"));
+        }
+    };
+
+    debug!("NET http {} {}", request.method, request.path);
+
+    // leave room for the response header
+    const HEADER_RESERVE: usize = 128;
+    if response.len() <= HEADER_RESERVE {
+        return Some(write_status(response, "500 Internal Server Error"));
+    }
+    let (header, body) = response.split_at_mut(HEADER_RESERVE);
+
+    let mut result = match handler(&request, body) {
+        Some(result) => result,
+        None => return Some(write_status(response, "404 Not Found")),
+    };
+    result.length = usize::min(result.length, body.len());
+
+    let mut cursor = Cursor::new(header);
+    let _ = write!(
+        cursor,
+        "HTTP/1.0 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        result.content_type, result.length
+    );
+    let header_length = cursor.position;
+
+    // move the body up against the header
+    response.copy_within(HEADER_RESERVE..HEADER_RESERVE + result.length, header_length);
+
+    Some(header_length + result.length)
+}
+
+fn write_status(response: &mut [u8], status: &str) -> usize {
+    let mut cursor = Cursor::new(response);
+    let _ = write!(
+        cursor,
+        "HTTP/1.0 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    cursor.position
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// - Cursor -------------------------------------------------------------------
+
+/// `core::fmt::Write` adaptor for a byte buffer
+pub struct Cursor<'a> {
+    buffer: &'a mut [u8],
+    pub position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer,
+            position: 0,
+        }
+    }
+}
+
+impl<'a> Write for Cursor<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let bytes = s.as_bytes();
+        let end = self.position + bytes.len();
+        if end > self.buffer.len() {
+            return Err(core::fmt::Error);
+        }
+        self.buffer[self.position..end].copy_from_slice(bytes);
+        self.position = end;
+        Ok(())
+    }
+}
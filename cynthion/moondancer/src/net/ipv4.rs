@@ -0,0 +1,145 @@
+//! Internet Protocol version 4 and ICMP echo
+
+use super::{checksum, read_u16, write_u16};
+
+use log::{trace, warn};
+
+pub const HEADER_LENGTH: usize = 20;
+pub const UDP_HEADER_LENGTH: usize = 8;
+
+const DEFAULT_TTL: u8 = 64;
+
+/// IP protocol numbers
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Protocol {
+    Icmp,
+    Tcp,
+    Udp,
+    Unknown(u8),
+}
+
+impl From<u8> for Protocol {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Protocol::Icmp,
+            6 => Protocol::Tcp,
+            17 => Protocol::Udp,
+            _ => Protocol::Unknown(value),
+        }
+    }
+}
+
+impl From<Protocol> for u8 {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Icmp => 1,
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+            Protocol::Unknown(value) => value,
+        }
+    }
+}
+
+// - Header -------------------------------------------------------------------
+
+/// The IPv4 header fields we care about
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub header_length: usize,
+    pub total_length: usize,
+    pub protocol: Protocol,
+    pub source: [u8; 4],
+    pub destination: [u8; 4],
+}
+
+impl Header {
+    /// Parse and validate an IPv4 header, ignoring fragments.
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < HEADER_LENGTH || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_length = ((packet[0] & 0x0f) as usize) * 4;
+        let total_length = read_u16(packet, 2) as usize;
+        let fragment = read_u16(packet, 6);
+        if header_length < HEADER_LENGTH || total_length < header_length || total_length > packet.len()
+        {
+            warn!("NET invalid ipv4 header");
+            return None;
+        }
+        if checksum(&packet[..header_length], 0) != 0 {
+            warn!("NET invalid ipv4 header checksum");
+            return None;
+        }
+        // more fragments flag or fragment offset
+        if fragment & 0x3fff != 0 {
+            trace!("NET ignoring ipv4 fragment");
+            return None;
+        }
+
+        let mut source = [0; 4];
+        let mut destination = [0; 4];
+        source.copy_from_slice(&packet[12..16]);
+        destination.copy_from_slice(&packet[16..20]);
+
+        Some(Self {
+            header_length,
+            total_length,
+            protocol: Protocol::from(packet[9]),
+            source,
+            destination,
+        })
+    }
+
+    /// Write the header, without options, to the start of `packet`.
+    pub fn write(&self, packet: &mut [u8]) {
+        packet[0] = 0x45; // version 4, 5 words
+        packet[1] = 0x00; // dscp, ecn
+        write_u16(packet, 2, self.total_length as u16);
+        write_u16(packet, 4, 0); // identification
+        write_u16(packet, 6, 0x4000); // don't fragment
+        packet[8] = DEFAULT_TTL;
+        packet[9] = self.protocol.into();
+        write_u16(packet, 10, 0);
+        packet[12..16].copy_from_slice(&self.source);
+        packet[16..20].copy_from_slice(&self.destination);
+        let checksum = checksum(&packet[..HEADER_LENGTH], 0);
+        write_u16(packet, 10, checksum);
+    }
+
+    /// Partial checksum over the pseudo-header used by TCP and UDP.
+    pub fn pseudo_header_sum(source: &[u8; 4], destination: &[u8; 4], protocol: Protocol, length: usize) -> u32 {
+        let mut sum = 0;
+        for address in [source, destination] {
+            sum += u16::from_be_bytes([address[0], address[1]]) as u32;
+            sum += u16::from_be_bytes([address[2], address[3]]) as u32;
+        }
+        sum += u8::from(protocol) as u32;
+        sum += length as u32;
+        sum
+    }
+}
+
+// - icmp ---------------------------------------------------------------------
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// Answer ICMP echo requests.
+pub fn handle_icmp(message: &[u8], response: &mut [u8]) -> Option<usize> {
+    if message.len() < 8 || message[0] != ICMP_ECHO_REQUEST || message.len() > response.len() {
+        return None;
+    }
+    if checksum(message, 0) != 0 {
+        warn!("NET invalid icmp checksum");
+        return None;
+    }
+
+    let response = &mut response[..message.len()];
+    response.copy_from_slice(message);
+    response[0] = ICMP_ECHO_REPLY;
+    write_u16(response, 2, 0);
+    let checksum = checksum(response, 0);
+    write_u16(response, 2, checksum);
+
+    Some(message.len())
+}
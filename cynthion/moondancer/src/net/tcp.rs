@@ -0,0 +1,230 @@
+//! A single-connection TCP server for HTTP requests
+//!
+//! The server accepts one connection at a time, answers the request
+//! with a single segment and closes the connection. Lost segments
+//! are not retransmitted.
+
+use super::ipv4::{self, Protocol};
+use super::{checksum, http, read_u16, read_u32, write_u16, write_u32, Config};
+
+use log::{debug, trace, warn};
+
+const HEADER_LENGTH: usize = 20;
+const MAX_SEGMENT_SIZE: u16 = 1460;
+
+/// TCP flags
+#[allow(non_snake_case, non_upper_case_globals)]
+mod Flags {
+    pub const Fin: u8 = 0x01;
+    pub const Syn: u8 = 0x02;
+    pub const Rst: u8 = 0x04;
+    pub const Psh: u8 = 0x08;
+    pub const Ack: u8 = 0x10;
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum State {
+    Listen,
+    SynReceived,
+    Established,
+    /// We have sent our response and FIN
+    FinWait,
+    /// The host closed the connection and we have sent our FIN
+    LastAck,
+}
+
+// - Server -------------------------------------------------------------------
+
+/// TCP server buffering requests of up to `N` bytes
+pub struct Server<const N: usize> {
+    port: u16,
+    state: State,
+    remote_address: [u8; 4],
+    remote_port: u16,
+    /// Next sequence number we expect from the host
+    remote_next: u32,
+    /// Next sequence number we will send
+    local_next: u32,
+    initial_sequence: u32,
+    request: heapless::Vec<u8, N>,
+}
+
+impl<const N: usize> Server<N> {
+    pub const fn new(port: u16) -> Self {
+        Self {
+            port,
+            state: State::Listen,
+            remote_address: [0; 4],
+            remote_port: 0,
+            remote_next: 0,
+            local_next: 0,
+            initial_sequence: 0x4d44_0000, // "MD"
+            request: heapless::Vec::new(),
+        }
+    }
+
+    /// Handle a received segment, writing any reply segment to
+    /// `response` and returning its length.
+    pub fn handle<F>(
+        &mut self,
+        config: &Config,
+        header: &ipv4::Header,
+        segment: &[u8],
+        response: &mut [u8],
+        handler: F,
+    ) -> Option<usize>
+    where
+        F: FnMut(&http::Request, &mut [u8]) -> Option<http::Response>,
+    {
+        if segment.len() < HEADER_LENGTH {
+            return None;
+        }
+        let pseudo_header =
+            ipv4::Header::pseudo_header_sum(&header.source, &header.destination, Protocol::Tcp, segment.len());
+        if checksum(segment, pseudo_header) != 0 {
+            warn!("NET invalid tcp checksum");
+            return None;
+        }
+
+        let source_port = read_u16(segment, 0);
+        let destination_port = read_u16(segment, 2);
+        let sequence = read_u32(segment, 4);
+        let acknowledgement = read_u32(segment, 8);
+        let data_offset = ((segment[12] >> 4) as usize) * 4;
+        let flags = segment[13];
+        if data_offset < HEADER_LENGTH || data_offset > segment.len() {
+            return None;
+        }
+        let payload = &segment[data_offset..];
+
+        if destination_port != self.port {
+            trace!("NET ignoring tcp segment for port {}", destination_port);
+            return None;
+        }
+
+        // new connection, replacing any existing one
+        if flags & Flags::Syn != 0 && flags & Flags::Ack == 0 {
+            self.initial_sequence = self.initial_sequence.wrapping_add(0x0001_0000);
+            self.state = State::SynReceived;
+            self.remote_address = header.source;
+            self.remote_port = source_port;
+            self.remote_next = sequence.wrapping_add(1);
+            self.local_next = self.initial_sequence;
+            self.request.clear();
+            debug!("NET tcp connection from {:?}:{}", header.source, source_port);
+            return Some(self.write_segment(config, Flags::Syn | Flags::Ack, response));
+        }
+
+        if self.state == State::Listen
+            || header.source != self.remote_address
+            || source_port != self.remote_port
+        {
+            return None;
+        }
+
+        if flags & Flags::Rst != 0 {
+            trace!("NET tcp connection reset");
+            self.state = State::Listen;
+            return None;
+        }
+
+        if flags & Flags::Ack != 0 && acknowledgement == self.local_next {
+            match self.state {
+                State::SynReceived => self.state = State::Established,
+                State::LastAck => {
+                    self.state = State::Listen;
+                    return None;
+                }
+                _ => (),
+            }
+        }
+
+        // out of order or retransmitted data, acknowledge what we have
+        if sequence != self.remote_next {
+            return Some(self.write_segment(config, Flags::Ack, response));
+        }
+
+        if !payload.is_empty() && self.state == State::Established {
+            if self.request.extend_from_slice(payload).is_err() {
+                warn!("NET tcp request exceeds {} bytes", N);
+                self.state = State::Listen;
+                return Some(self.write_segment(config, Flags::Rst | Flags::Ack, response));
+            }
+            self.remote_next = self.remote_next.wrapping_add(payload.len() as u32);
+
+            // respond once the request is complete
+            let body = &mut response[HEADER_LENGTH..];
+            if let Some(length) = http::respond(&self.request, body, handler) {
+                self.state = State::FinWait;
+                let flags = Flags::Fin | Flags::Psh | Flags::Ack;
+                let segment_length = self.write_header(config, flags, length, response);
+                self.local_next = self.local_next.wrapping_add(length as u32 + 1);
+                return Some(segment_length);
+            }
+        }
+
+        if flags & Flags::Fin != 0 {
+            self.remote_next = self.remote_next.wrapping_add(1);
+            return match self.state {
+                State::FinWait => {
+                    self.state = State::Listen;
+                    Some(self.write_segment(config, Flags::Ack, response))
+                }
+                _ => {
+                    self.state = State::LastAck;
+                    Some(self.write_segment(config, Flags::Fin | Flags::Ack, response))
+                }
+            };
+        }
+
+        if payload.is_empty() {
+            return None;
+        }
+        Some(self.write_segment(config, Flags::Ack, response))
+    }
+
+    /// Write a segment without payload, advancing our sequence number
+    /// for SYN and FIN.
+    fn write_segment(&mut self, config: &Config, flags: u8, response: &mut [u8]) -> usize {
+        let length = self.write_header(config, flags, 0, response);
+        if flags & (Flags::Syn | Flags::Fin) != 0 {
+            self.local_next = self.local_next.wrapping_add(1);
+        }
+        length
+    }
+
+    /// Write the segment header, and checksum the header and payload.
+    fn write_header(&self, config: &Config, flags: u8, payload_length: usize, response: &mut [u8]) -> usize {
+        // SYN segments carry no payload, only the mss option
+        let mss = flags & Flags::Syn != 0;
+        let header_length = if mss { HEADER_LENGTH + 4 } else { HEADER_LENGTH };
+
+        let window = (N - self.request.len()) as u16;
+        write_u16(response, 0, self.port);
+        write_u16(response, 2, self.remote_port);
+        write_u32(response, 4, self.local_next);
+        write_u32(response, 8, if flags & Flags::Ack != 0 { self.remote_next } else { 0 });
+        response[12] = ((header_length / 4) as u8) << 4;
+        response[13] = flags;
+        write_u16(response, 14, window);
+        write_u16(response, 16, 0); // checksum
+        write_u16(response, 18, 0); // urgent pointer
+        if mss {
+            response[20] = 2; // maximum segment size
+            response[21] = 4;
+            write_u16(response, 22, MAX_SEGMENT_SIZE);
+        }
+
+        let segment_length = header_length + payload_length;
+        let pseudo_header = ipv4::Header::pseudo_header_sum(
+            &config.ip_address,
+            &self.remote_address,
+            Protocol::Tcp,
+            segment_length,
+        );
+        let checksum = checksum(&response[..segment_length], pseudo_header);
+        write_u16(response, 16, checksum);
+
+        segment_length
+    }
+}
@@ -55,6 +55,16 @@ pub mod vendor {
     }
 }
 
+/// Endpoint numbers of the bulk endpoints of the vendor interface,
+/// which carry the GCP bulk transport
+pub const GCP_BULK_IN_ENDPOINT: u8 = 0x01;
+pub const GCP_BULK_OUT_ENDPOINT: u8 = 0x02;
+
+/// Endpoint number of the interrupt endpoint of the vendor interface,
+/// which carries moondancer status change records once the host has
+/// enabled them with the `enable_events` verb
+pub const MOONDANCER_EVENT_ENDPOINT: u8 = 0x03;
+
 pub const DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
     descriptor_version: 0x0200,
     device_class: 0x00,    // Composite
@@ -128,6 +138,13 @@ pub const CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDes
                     interval: 0,
                     ..EndpointDescriptor::new()
                 },
+                EndpointDescriptor {
+                    endpoint_address: 0x83, // IN
+                    attributes: 0x03,       // Interrupt
+                    max_packet_size: 16,
+                    interval: 0x04, // 2^(4-1) microframes = 1ms
+                    ..EndpointDescriptor::new()
+                },
             ],
         ),
     ],
@@ -181,6 +198,13 @@ pub const OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
                         interval: 0,
                         ..EndpointDescriptor::new()
                     },
+                    EndpointDescriptor {
+                        endpoint_address: 0x83, // IN
+                        attributes: 0x03,       // Interrupt
+                        max_packet_size: 16,
+                        interval: 0x01, // 1ms
+                        ..EndpointDescriptor::new()
+                    },
                 ],
             ),
         ],
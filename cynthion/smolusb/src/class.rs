@@ -1,3 +1,42 @@
 //! USB device and interface classes
 
+pub mod aoa;
 pub mod cdc;
+pub mod dfu;
+pub mod gadget_zero;
+pub mod midi;
+pub mod ncm;
+
+use crate::control::SetupPacket;
+use crate::error::SmolResult;
+
+/// A device class or function driven by [`crate::device::UsbDevice::poll`]
+///
+/// Handlers return `true` if the event was addressed to the class.
+/// All handlers have default implementations that ignore the event.
+pub trait UsbClass<D> {
+    /// Handle a USB bus reset.
+    fn handle_bus_reset(&mut self) {}
+
+    /// Handle a class or vendor request.
+    fn handle_setup_request(&mut self, _hal_driver: &D, _setup_packet: &SetupPacket) -> SmolResult<bool> {
+        Ok(false)
+    }
+
+    /// Called once the host has selected a configuration.
+    fn handle_set_configuration(&mut self, _hal_driver: &D, _configuration: u8) {}
+
+    /// Called once the host has selected an alternate setting.
+    fn handle_set_interface(&mut self, _hal_driver: &D, _interface: u8, _alternate_setting: u8) {}
+
+    /// Handle a packet received on an OUT endpoint, including the data
+    /// stage of control requests on endpoint zero.
+    fn handle_receive_packet(&mut self, _hal_driver: &D, _endpoint: u8, _packet: &[u8]) -> bool {
+        false
+    }
+
+    /// Handle completion of an IN transfer.
+    fn handle_transfer_complete(&mut self, _hal_driver: &D, _endpoint: u8) -> bool {
+        false
+    }
+}
@@ -0,0 +1,680 @@
+//! Android Open Accessory (AOA) protocol 1.0 and 2.0
+//!
+//! Lets a device emulate an Android phone that supports accessory
+//! mode, so that accessory host software can be tested against it.
+//!
+//! Vendor requests are answered by [`handle_vendor_request`], which
+//! can be installed as a `UsbDevice::cb_vendor_request` callback.
+//! Firmware passes each SETUP packet and any control data it receives
+//! to an [`Accessory`], which records the identification strings,
+//! HID registrations and audio mode sent by the host and reports them
+//! as [`Event`]s. Once the host sends `START` the firmware should
+//! re-enumerate using the accessory mode descriptors below.
+//!
+//! See: <https://source.android.com/docs/core/interaction/accessories/aoa>
+//! and <https://source.android.com/docs/core/interaction/accessories/aoa2>
+
+use crate::control::{Direction, RequestType, SetupPacket};
+use crate::descriptor::*;
+use crate::device::UsbDevice;
+use crate::error::SmolError;
+use crate::traits::{ControlRead, EndpointRead, EndpointWrite, UsbDriverOperations};
+
+use log::{debug, trace, warn};
+
+// - constants ----------------------------------------------------------------
+
+/// AOA protocol version reported in response to `GET_PROTOCOL`
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Google, Inc.
+pub const GOOGLE_VENDOR_ID: u16 = 0x18d1;
+
+/// Product ids used by a device in accessory mode
+#[allow(non_snake_case, non_upper_case_globals)]
+pub mod ProductId {
+    pub const Accessory: u16 = 0x2d00;
+    pub const AccessoryAdb: u16 = 0x2d01;
+    pub const Audio: u16 = 0x2d02;
+    pub const AudioAdb: u16 = 0x2d03;
+    pub const AccessoryAudio: u16 = 0x2d04;
+    pub const AccessoryAudioAdb: u16 = 0x2d05;
+}
+
+/// Number of identification strings defined by the protocol
+pub const STRING_COUNT: usize = 6;
+
+// - Request ------------------------------------------------------------------
+
+/// AOA vendor requests
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[repr(u8)]
+pub enum Request {
+    /// Returns the supported protocol version (1.0)
+    GetProtocol = 51,
+    /// Sends an identification string (1.0)
+    SendString = 52,
+    /// Requests that the device switch to accessory mode (1.0)
+    Start = 53,
+    /// Registers a HID device with the given id (2.0)
+    RegisterHid = 54,
+    /// Unregisters a HID device (2.0)
+    UnregisterHid = 55,
+    /// Sends part of a HID report descriptor (2.0)
+    SetHidReportDescriptor = 56,
+    /// Sends a HID input event (2.0)
+    SendHidEvent = 57,
+    /// Selects the audio output mode (2.0)
+    SetAudioMode = 58,
+}
+
+impl TryFrom<u8> for Request {
+    type Error = SmolError;
+
+    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
+        let result = match value {
+            51 => Request::GetProtocol,
+            52 => Request::SendString,
+            53 => Request::Start,
+            54 => Request::RegisterHid,
+            55 => Request::UnregisterHid,
+            56 => Request::SetHidReportDescriptor,
+            57 => Request::SendHidEvent,
+            58 => Request::SetAudioMode,
+            _ => return Err(SmolError::FailedConversion),
+        };
+        Ok(result)
+    }
+}
+
+impl Request {
+    /// Returns the direction of the request's data stage.
+    pub fn direction(&self) -> Direction {
+        match self {
+            Request::GetProtocol => Direction::DeviceToHost,
+            _ => Direction::HostToDevice,
+        }
+    }
+}
+
+// - StringIndex --------------------------------------------------------------
+
+/// Identification strings sent with `SEND_STRING`
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[repr(u16)]
+pub enum StringIndex {
+    Manufacturer = 0,
+    Model = 1,
+    Description = 2,
+    Version = 3,
+    Uri = 4,
+    Serial = 5,
+}
+
+impl TryFrom<u16> for StringIndex {
+    type Error = SmolError;
+
+    fn try_from(value: u16) -> core::result::Result<Self, Self::Error> {
+        let result = match value {
+            0 => StringIndex::Manufacturer,
+            1 => StringIndex::Model,
+            2 => StringIndex::Description,
+            3 => StringIndex::Version,
+            4 => StringIndex::Uri,
+            5 => StringIndex::Serial,
+            _ => return Err(SmolError::FailedConversion),
+        };
+        Ok(result)
+    }
+}
+
+// - AudioMode ----------------------------------------------------------------
+
+/// Audio output modes selected with `SET_AUDIO_MODE`
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum AudioMode {
+    None,
+    /// 2 channel, 16-bit PCM at 44100 Hz
+    Pcm16Stereo44100,
+    Unknown(u16),
+}
+
+impl From<u16> for AudioMode {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => AudioMode::None,
+            1 => AudioMode::Pcm16Stereo44100,
+            _ => AudioMode::Unknown(value),
+        }
+    }
+}
+
+// - Event --------------------------------------------------------------------
+
+/// Accessory state changes reported to the firmware
+#[derive(Debug, PartialEq)]
+pub enum Event<'a> {
+    /// The host sent an identification string
+    String(StringIndex),
+    /// The host requested a switch to accessory mode
+    Start,
+    /// The host registered a HID device
+    HidRegistered { id: u16, descriptor_length: u16 },
+    /// The host unregistered a HID device
+    HidUnregistered { id: u16 },
+    /// The host sent part of a HID report descriptor
+    HidReportDescriptor { id: u16, offset: u16, data: &'a [u8] },
+    /// The host sent a HID input event
+    HidEvent { id: u16, report: &'a [u8] },
+    /// The host selected an audio mode
+    AudioMode(AudioMode),
+}
+
+// - vendor request handler ---------------------------------------------------
+
+/// Answer AOA vendor requests.
+///
+/// Suitable for use as a `UsbDevice::cb_vendor_request` callback.
+/// Data sent by the host is received on the control endpoint and
+/// should be passed to [`Accessory::handle_receive_control_data`].
+///
+/// `N` is the capacity of that [`Accessory`], requests with a longer
+/// data stage are stalled:
+///
+/// ```ignore
+/// device.cb_vendor_request = Some(aoa::handle_vendor_request::<_, BUFFER_SIZE>);
+/// ```
+pub fn handle_vendor_request<'a, D, const N: usize>(
+    device: &UsbDevice<'a, D>,
+    setup_packet: &SetupPacket,
+    request: u8,
+) where
+    D: ControlRead + EndpointRead + EndpointWrite + UsbDriverOperations,
+{
+    let hal_driver = &device.hal_driver;
+
+    let request = match Request::try_from(request) {
+        Ok(request) if request.direction() == setup_packet.direction() => request,
+        _ => {
+            warn!("AOA stall: unsupported vendor request {:?}", setup_packet);
+            hal_driver.stall_request();
+            return;
+        }
+    };
+
+    trace!("AOA {:?} value:{} index:{}", request, setup_packet.value, setup_packet.index);
+
+    match request {
+        Request::GetProtocol => {
+            let length = setup_packet.length as usize;
+            if let Err(e) = hal_driver.write(0, PROTOCOL_VERSION.to_le_bytes().into_iter().take(length)) {
+                warn!("AOA stall: failed to send protocol version: {:?}", e);
+                hal_driver.stall_request();
+                return;
+            }
+            hal_driver.ack_status_stage(setup_packet);
+        }
+        Request::SendString | Request::SetHidReportDescriptor | Request::SendHidEvent
+            if setup_packet.length as usize > N =>
+        {
+            warn!("AOA stall: {:?} of {} bytes exceeds buffer", request, setup_packet.length);
+            hal_driver.stall_request();
+        }
+        Request::SendString if StringIndex::try_from(setup_packet.index).is_err() => {
+            warn!("AOA stall: invalid string index {}", setup_packet.index);
+            hal_driver.stall_request();
+        }
+        _ => {
+            hal_driver.ack_status_stage(setup_packet);
+        }
+    }
+}
+
+// - Accessory ----------------------------------------------------------------
+
+/// State configured by an accessory host
+///
+/// `N` is the capacity of each identification string and of the
+/// control data buffer.
+pub struct Accessory<const N: usize> {
+    strings: [heapless::String<N>; STRING_COUNT],
+    audio_mode: AudioMode,
+    started: bool,
+
+    /// Request whose data stage is being received
+    pending: Option<SetupPacket>,
+    buffer: [u8; N],
+    received: usize,
+}
+
+impl<const N: usize> Accessory<N> {
+    pub const fn new() -> Self {
+        Self {
+            strings: [
+                heapless::String::new(),
+                heapless::String::new(),
+                heapless::String::new(),
+                heapless::String::new(),
+                heapless::String::new(),
+                heapless::String::new(),
+            ],
+            audio_mode: AudioMode::None,
+            started: false,
+            pending: None,
+            buffer: [0; N],
+            received: 0,
+        }
+    }
+
+    /// Returns an identification string sent by the host.
+    pub fn string(&self, index: StringIndex) -> Option<&str> {
+        let string = &self.strings[index as usize];
+        if string.is_empty() {
+            None
+        } else {
+            Some(string.as_str())
+        }
+    }
+
+    pub fn audio_mode(&self) -> AudioMode {
+        self.audio_mode
+    }
+
+    /// Returns true once the host has sent `START`.
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// Returns the product id the device should enumerate with in
+    /// accessory mode.
+    ///
+    /// The accessory mode descriptors do not include the audio
+    /// interfaces, so the audio product ids are never reported even if
+    /// the host selected an audio mode.
+    pub fn product_id(&self) -> u16 {
+        ProductId::Accessory
+    }
+
+    /// Handle a USB bus reset.
+    pub fn handle_bus_reset(&mut self) {
+        self.pending = None;
+        self.received = 0;
+    }
+
+    /// Record an AOA SETUP packet, returning the event for requests
+    /// without a data stage.
+    pub fn handle_setup_packet(&mut self, setup_packet: &SetupPacket) -> Option<Event<'_>> {
+        self.pending = None;
+        self.received = 0;
+
+        if setup_packet.request_type() != RequestType::Vendor {
+            return None;
+        }
+        let request = Request::try_from(setup_packet.request).ok()?;
+
+        let event = match request {
+            Request::Start => {
+                debug!("AOA start");
+                self.started = true;
+                Event::Start
+            }
+            Request::RegisterHid => Event::HidRegistered {
+                id: setup_packet.value,
+                descriptor_length: setup_packet.index,
+            },
+            Request::UnregisterHid => Event::HidUnregistered {
+                id: setup_packet.value,
+            },
+            Request::SetAudioMode => {
+                self.audio_mode = AudioMode::from(setup_packet.value);
+                Event::AudioMode(self.audio_mode)
+            }
+            Request::SendString | Request::SetHidReportDescriptor | Request::SendHidEvent => {
+                if setup_packet.length as usize > N {
+                    warn!("AOA {:?} of {} bytes exceeds buffer", request, setup_packet.length);
+                } else if setup_packet.length > 0 {
+                    self.pending = Some(setup_packet.clone());
+                }
+                return None;
+            }
+            Request::GetProtocol => return None,
+        };
+
+        Some(event)
+    }
+
+    /// Handle data received on the control endpoint, returning an
+    /// event once the data stage of a request is complete.
+    pub fn handle_receive_control_data(&mut self, data: &[u8]) -> Option<Event<'_>> {
+        let setup_packet = self.pending.as_ref()?;
+        let length = setup_packet.length as usize;
+
+        let count = usize::min(data.len(), length - self.received);
+        self.buffer[self.received..self.received + count].copy_from_slice(&data[..count]);
+        self.received += count;
+        if self.received < length {
+            return None;
+        }
+
+        let setup_packet = self.pending.take()?;
+        let data = &self.buffer[..length];
+        let event = match Request::try_from(setup_packet.request).ok()? {
+            Request::SendString => {
+                let index = StringIndex::try_from(setup_packet.index).ok()?;
+                // strings are NUL terminated
+                let data = match data.iter().position(|byte| *byte == 0) {
+                    Some(end) => &data[..end],
+                    None => data,
+                };
+                let string = &mut self.strings[index as usize];
+                string.clear();
+                match core::str::from_utf8(data) {
+                    Ok(value) => {
+                        let _ = string.push_str(value);
+                    }
+                    Err(_) => warn!("AOA string {:?} is not valid UTF-8", index),
+                }
+                debug!("AOA {:?}: {}", index, string.as_str());
+                Event::String(index)
+            }
+            Request::SetHidReportDescriptor => Event::HidReportDescriptor {
+                id: setup_packet.value,
+                offset: setup_packet.index,
+                data,
+            },
+            Request::SendHidEvent => Event::HidEvent {
+                id: setup_packet.value,
+                report: data,
+            },
+            _ => return None,
+        };
+
+        Some(event)
+    }
+}
+
+impl<const N: usize> Default for Accessory<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// - descriptors --------------------------------------------------------------
+
+/// Vendor specific interface class used by the accessory interface
+pub const CLASS: u8 = 0xff;
+pub const SUBCLASS: u8 = 0xff;
+
+/// Device descriptor used before the host sends `START`
+pub const DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
+    descriptor_version: 0x0200,
+    device_class: 0x00,    // Defined at interface level
+    device_subclass: 0x00, // Defined at interface level
+    device_protocol: 0x00, // Defined at interface level
+    max_packet_size: 64,
+    vendor_id: GOOGLE_VENDOR_ID,
+    product_id: 0x4ee1,            // Nexus/Pixel device (MTP)
+    device_version_number: 0x0040, // Cynthion r04
+    manufacturer_string_index: 1,
+    product_string_index: 2,
+    serial_string_index: 3,
+    num_configurations: 1,
+    ..DeviceDescriptor::new()
+};
+
+/// Device descriptor used in accessory mode
+pub const ACCESSORY_DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
+    product_id: ProductId::Accessory,
+    ..DEVICE_DESCRIPTOR
+};
+
+pub const DEVICE_QUALIFIER_DESCRIPTOR: DeviceQualifierDescriptor = DeviceQualifierDescriptor {
+    descriptor_version: 0x0200,
+    device_class: 0x00,
+    device_subclass: 0x00,
+    device_protocol: 0x00,
+    max_packet_size: 64,
+    num_configurations: 1,
+    ..DeviceQualifierDescriptor::new()
+};
+
+pub const CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDescriptor::new(
+    ConfigurationDescriptorHeader {
+        descriptor_type: DescriptorType::Configuration as u8,
+        configuration_value: 1,
+        configuration_string_index: 0,
+        attributes: 0x80, // 0b1000_0000 = bus-powered
+        max_power: 250,   // 250 * 2 mA = 500 mA
+        ..ConfigurationDescriptorHeader::new()
+    },
+    &[InterfaceDescriptor::new(
+        InterfaceDescriptorHeader {
+            interface_number: 0,
+            alternate_setting: 0,
+            interface_class: CLASS,
+            interface_subclass: SUBCLASS,
+            interface_protocol: 0x00,
+            interface_string_index: 4,
+            ..InterfaceDescriptorHeader::new()
+        },
+        &[
+            EndpointDescriptor {
+                endpoint_address: 0x81, // IN
+                attributes: 0x02,       // Bulk
+                max_packet_size: 512,
+                interval: 0,
+                ..EndpointDescriptor::new()
+            },
+            EndpointDescriptor {
+                endpoint_address: 0x01, // OUT
+                attributes: 0x02,       // Bulk
+                max_packet_size: 512,
+                interval: 0,
+                ..EndpointDescriptor::new()
+            },
+        ],
+    )],
+);
+
+pub const OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
+    ConfigurationDescriptor::new(
+        ConfigurationDescriptorHeader {
+            descriptor_type: DescriptorType::OtherSpeedConfiguration as u8,
+            configuration_value: 1,
+            configuration_string_index: 0,
+            attributes: 0x80, // 0b1000_0000 = bus-powered
+            max_power: 250,   // 250 * 2 mA = 500 mA
+            ..ConfigurationDescriptorHeader::new()
+        },
+        &[InterfaceDescriptor::new(
+            InterfaceDescriptorHeader {
+                interface_number: 0,
+                alternate_setting: 0,
+                interface_class: CLASS,
+                interface_subclass: SUBCLASS,
+                interface_protocol: 0x00,
+                interface_string_index: 4,
+                ..InterfaceDescriptorHeader::new()
+            },
+            &[
+                EndpointDescriptor {
+                    endpoint_address: 0x81, // IN
+                    attributes: 0x02,       // Bulk
+                    max_packet_size: 64,
+                    interval: 0,
+                    ..EndpointDescriptor::new()
+                },
+                EndpointDescriptor {
+                    endpoint_address: 0x01, // OUT
+                    attributes: 0x02,       // Bulk
+                    max_packet_size: 64,
+                    interval: 0,
+                    ..EndpointDescriptor::new()
+                },
+            ],
+        )],
+    );
+
+pub const USB_STRING_DESCRIPTOR_0: StringDescriptorZero =
+    StringDescriptorZero::new(&[LanguageId::EnglishUnitedStates]);
+
+pub const USB_STRING_DESCRIPTOR_1: StringDescriptor = StringDescriptor::new("Great Scott Gadgets");
+pub const USB_STRING_DESCRIPTOR_2: StringDescriptor = StringDescriptor::new("Cynthion Android Device");
+pub const USB_STRING_DESCRIPTOR_3: StringDescriptor = StringDescriptor::new("040");
+pub const USB_STRING_DESCRIPTOR_4: StringDescriptor = StringDescriptor::new("Android Accessory Interface");
+
+pub const USB_STRING_DESCRIPTORS: &[&StringDescriptor] = &[
+    &USB_STRING_DESCRIPTOR_1,
+    &USB_STRING_DESCRIPTOR_2,
+    &USB_STRING_DESCRIPTOR_3,
+    &USB_STRING_DESCRIPTOR_4,
+];
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mock::MockUsbDriver;
+
+    // - fixtures -------------------------------------------------------------
+
+    const BUFFER_SIZE: usize = 64;
+
+    fn device(driver: MockUsbDriver) -> UsbDevice<'static, MockUsbDriver> {
+        let mut device = UsbDevice::new(
+            driver,
+            &DEVICE_DESCRIPTOR,
+            &CONFIGURATION_DESCRIPTOR_0,
+            &USB_STRING_DESCRIPTOR_0,
+            USB_STRING_DESCRIPTORS,
+        );
+        device.cb_vendor_request = Some(handle_vendor_request::<_, BUFFER_SIZE>);
+        device
+    }
+
+    fn setup(request_type: u8, request: Request, value: u16, index: u16, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type,
+            request: request as u8,
+            value,
+            index,
+            length,
+        }
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_get_protocol() {
+        let device = device(MockUsbDriver::new());
+        let setup_packet = setup(0xc0, Request::GetProtocol, 0, 0, 2);
+        device.handle_setup_request(&setup_packet).unwrap();
+        assert_eq!(device.hal_driver.last_write(0), Some(vec![0x02, 0x00]));
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let device = device(MockUsbDriver::new());
+
+        // wrong direction
+        let setup_packet = setup(0x40, Request::GetProtocol, 0, 0, 2);
+        device.handle_setup_request(&setup_packet).unwrap();
+        assert!(device.hal_driver.is_stalled());
+
+        // unknown string
+        device.hal_driver.clear();
+        let setup_packet = setup(0x40, Request::SendString, 0, 6, 4);
+        device.handle_setup_request(&setup_packet).unwrap();
+        assert!(device.hal_driver.is_stalled());
+
+        // string longer than the accessory can hold
+        device.hal_driver.clear();
+        let length = BUFFER_SIZE as u16 + 1;
+        let setup_packet = setup(0x40, Request::SendString, 0, 0, length);
+        device.handle_setup_request(&setup_packet).unwrap();
+        assert!(device.hal_driver.is_stalled());
+    }
+
+    #[test]
+    fn test_send_strings() {
+        let device = device(MockUsbDriver::new());
+        let mut accessory: Accessory<BUFFER_SIZE> = Accessory::new();
+
+        let strings: [(StringIndex, &[u8]); 2] = [
+            (StringIndex::Manufacturer, b"Great Scott Gadgets\0"),
+            (StringIndex::Model, b"Cynthion\0"),
+        ];
+        for (index, data) in strings {
+            let setup_packet = setup(0x40, Request::SendString, 0, index as u16, data.len() as u16);
+            device.handle_setup_request(&setup_packet).unwrap();
+            assert_eq!(accessory.handle_setup_packet(&setup_packet), None);
+            assert_eq!(
+                accessory.handle_receive_control_data(data),
+                Some(Event::String(index))
+            );
+        }
+        assert!(!device.hal_driver.is_stalled());
+
+        assert_eq!(accessory.string(StringIndex::Manufacturer), Some("Great Scott Gadgets"));
+        assert_eq!(accessory.string(StringIndex::Model), Some("Cynthion"));
+        assert_eq!(accessory.string(StringIndex::Serial), None);
+    }
+
+    #[test]
+    fn test_start() {
+        let mut accessory: Accessory<BUFFER_SIZE> = Accessory::new();
+
+        let setup_packet = setup(0x40, Request::SetAudioMode, 1, 0, 0);
+        assert_eq!(
+            accessory.handle_setup_packet(&setup_packet),
+            Some(Event::AudioMode(AudioMode::Pcm16Stereo44100))
+        );
+
+        assert!(!accessory.is_started());
+        let setup_packet = setup(0x40, Request::Start, 0, 0, 0);
+        assert_eq!(accessory.handle_setup_packet(&setup_packet), Some(Event::Start));
+        assert!(accessory.is_started());
+        assert_eq!(accessory.audio_mode(), AudioMode::Pcm16Stereo44100);
+        assert_eq!(accessory.product_id(), ProductId::Accessory);
+    }
+
+    #[test]
+    fn test_hid() {
+        let mut accessory: Accessory<BUFFER_SIZE> = Accessory::new();
+
+        let setup_packet = setup(0x40, Request::RegisterHid, 7, 52, 0);
+        assert_eq!(
+            accessory.handle_setup_packet(&setup_packet),
+            Some(Event::HidRegistered {
+                id: 7,
+                descriptor_length: 52
+            })
+        );
+
+        // a report descriptor split over two packets
+        let descriptor: Vec<u8> = (0..52).collect();
+        let setup_packet = setup(0x40, Request::SetHidReportDescriptor, 7, 0, 52);
+        accessory.handle_setup_packet(&setup_packet);
+        assert_eq!(accessory.handle_receive_control_data(&descriptor[..32]), None);
+        assert_eq!(
+            accessory.handle_receive_control_data(&descriptor[32..]),
+            Some(Event::HidReportDescriptor {
+                id: 7,
+                offset: 0,
+                data: &descriptor
+            })
+        );
+
+        let setup_packet = setup(0x40, Request::SendHidEvent, 7, 0, 3);
+        accessory.handle_setup_packet(&setup_packet);
+        assert_eq!(
+            accessory.handle_receive_control_data(&[1, 2, 3]),
+            Some(Event::HidEvent {
+                id: 7,
+                report: &[1, 2, 3]
+            })
+        );
+    }
+}
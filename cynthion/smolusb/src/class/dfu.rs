@@ -0,0 +1,994 @@
+//! USB Device Firmware Upgrade (DFU) 1.1 class
+//!
+//! Implements both the DFU runtime interface, which lets a host
+//! request that the device detach and re-enumerate in DFU mode, and
+//! the DFU mode interface which transfers firmware images to and from
+//! a [`Flash`] implementation.
+//!
+//! See: "Universal Serial Bus Device Class Specification for Device
+//! Firmware Upgrade" Version 1.1
+
+use crate::control::{Direction, Recipient, RequestType, SetupPacket};
+use crate::descriptor::*;
+use crate::error::{SmolError, SmolResult};
+use crate::traits::{AsByteSliceIterator, EndpointWrite, UsbDriverOperations};
+
+use zerocopy::{AsBytes, FromBytes};
+
+use log::{debug, trace, warn};
+
+use core::mem::size_of;
+
+// - constants ----------------------------------------------------------------
+
+/// Application Specific class code
+pub const CLASS: u8 = 0xfe;
+/// Device Firmware Upgrade subclass code
+pub const SUBCLASS: u8 = 0x01;
+/// Runtime protocol code
+pub const PROTOCOL_RUNTIME: u8 = 0x01;
+/// DFU mode protocol code
+pub const PROTOCOL_DFU_MODE: u8 = 0x02;
+
+/// DFU functional descriptor type
+pub const DESCRIPTOR_TYPE_FUNCTIONAL: u8 = 0x21;
+
+/// DFU specification release number in BCD
+pub const DFU_VERSION: u16 = 0x0110;
+
+/// Functional descriptor attributes
+#[allow(non_snake_case, non_upper_case_globals)]
+pub mod Attribute {
+    /// bitCanDnload: device is capable of download
+    pub const CanDownload: u8 = 1 << 0;
+    /// bitCanUpload: device is capable of upload
+    pub const CanUpload: u8 = 1 << 1;
+    /// bitManifestationTolerant: device can communicate via USB after manifestation
+    pub const ManifestationTolerant: u8 = 1 << 2;
+    /// bitWillDetach: device will perform a bus detach-attach sequence on DFU_DETACH
+    pub const WillDetach: u8 = 1 << 3;
+}
+
+// - Request ------------------------------------------------------------------
+
+/// DFU class-specific requests
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[repr(u8)]
+pub enum Request {
+    Detach = 0,
+    Download = 1,
+    Upload = 2,
+    GetStatus = 3,
+    ClearStatus = 4,
+    GetState = 5,
+    Abort = 6,
+}
+
+impl TryFrom<u8> for Request {
+    type Error = SmolError;
+
+    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
+        let result = match value {
+            0 => Request::Detach,
+            1 => Request::Download,
+            2 => Request::Upload,
+            3 => Request::GetStatus,
+            4 => Request::ClearStatus,
+            5 => Request::GetState,
+            6 => Request::Abort,
+            _ => return Err(SmolError::FailedConversion),
+        };
+        Ok(result)
+    }
+}
+
+// - State --------------------------------------------------------------------
+
+/// DFU device state as reported by `DFU_GETSTATE` and `DFU_GETSTATUS`
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[repr(u8)]
+pub enum State {
+    AppIdle = 0,
+    AppDetach = 1,
+    DfuIdle = 2,
+    DfuDownloadSync = 3,
+    DfuDownloadBusy = 4,
+    DfuDownloadIdle = 5,
+    DfuManifestSync = 6,
+    DfuManifest = 7,
+    DfuManifestWaitReset = 8,
+    DfuUploadIdle = 9,
+    DfuError = 10,
+}
+
+impl State {
+    /// Returns true if the device is operating in DFU mode.
+    pub fn is_dfu_mode(&self) -> bool {
+        !matches!(self, State::AppIdle | State::AppDetach)
+    }
+}
+
+// - Status -------------------------------------------------------------------
+
+/// DFU status codes as reported by `DFU_GETSTATUS`
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[repr(u8)]
+pub enum Status {
+    /// No error condition is present.
+    Ok = 0x00,
+    /// File is not targeted for use by this device.
+    ErrTarget = 0x01,
+    /// File is for this device but fails some vendor-specific verification test.
+    ErrFile = 0x02,
+    /// Device is unable to write memory.
+    ErrWrite = 0x03,
+    /// Memory erase function failed.
+    ErrErase = 0x04,
+    /// Memory erase check failed.
+    ErrCheckErased = 0x05,
+    /// Program memory function failed.
+    ErrProg = 0x06,
+    /// Programmed memory failed verification.
+    ErrVerify = 0x07,
+    /// Cannot program memory due to received address that is out of range.
+    ErrAddress = 0x08,
+    /// Received DFU_DNLOAD with wLength = 0, but device does not think it has all of the data yet.
+    ErrNotDone = 0x09,
+    /// Device's firmware is corrupt. It cannot return to run-time (non-DFU) operations.
+    ErrFirmware = 0x0a,
+    /// iString indicates a vendor-specific error.
+    ErrVendor = 0x0b,
+    /// Device detected unexpected USB reset signaling.
+    ErrUsbReset = 0x0c,
+    /// Device detected unexpected power on reset.
+    ErrPowerOnReset = 0x0d,
+    /// Something went wrong, but the device does not know what it was.
+    ErrUnknown = 0x0e,
+    /// Device stalled an unexpected request.
+    ErrStalledPacket = 0x0f,
+}
+
+// - FunctionalDescriptor -----------------------------------------------------
+
+/// DFU functional descriptor
+#[derive(AsBytes, FromBytes, Clone, Copy)]
+#[repr(C, packed)]
+pub struct FunctionalDescriptor {
+    pub _length: u8,          // 9
+    pub _descriptor_type: u8, // 0x21 = DFU Functional
+    pub attributes: u8,
+    pub detach_timeout: u16,
+    pub transfer_size: u16,
+    pub dfu_version: u16,
+}
+
+impl AsByteSliceIterator for FunctionalDescriptor {}
+
+impl FunctionalDescriptor {
+    pub const fn new() -> Self {
+        Self {
+            _length: size_of::<Self>() as u8,
+            _descriptor_type: DESCRIPTOR_TYPE_FUNCTIONAL,
+            attributes: 0,
+            detach_timeout: 0,
+            transfer_size: 0,
+            dfu_version: DFU_VERSION,
+        }
+    }
+
+    /// Returns the descriptor as a byte array suitable for
+    /// [`InterfaceDescriptor::with_class_descriptor`].
+    pub const fn to_bytes(&self) -> [u8; 9] {
+        let detach_timeout = self.detach_timeout.to_le_bytes();
+        let transfer_size = self.transfer_size.to_le_bytes();
+        let dfu_version = self.dfu_version.to_le_bytes();
+        [
+            self._length,
+            self._descriptor_type,
+            self.attributes,
+            detach_timeout[0],
+            detach_timeout[1],
+            transfer_size[0],
+            transfer_size[1],
+            dfu_version[0],
+            dfu_version[1],
+        ]
+    }
+}
+
+impl Default for FunctionalDescriptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// - Flash --------------------------------------------------------------------
+
+/// Storage backing a DFU mode interface
+///
+/// Offsets are relative to the start of the firmware image.
+pub trait Flash {
+    /// Total size of the firmware image region in bytes.
+    fn capacity(&self) -> usize;
+
+    /// Size of the smallest erasable region in bytes.
+    fn sector_size(&self) -> usize;
+
+    /// Erase the sector starting at the given offset.
+    fn erase(&mut self, offset: usize) -> Result<(), Status>;
+
+    /// Write `data` to previously erased storage at the given offset.
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Status>;
+
+    /// Read into `buffer` from the given offset, returning the number of bytes read.
+    fn read(&mut self, offset: usize, buffer: &mut [u8]) -> Result<usize, Status>;
+
+    /// Called once the host has signalled the end of a download.
+    fn manifest(&mut self) -> Result<(), Status> {
+        Ok(())
+    }
+}
+
+// - Dfu ----------------------------------------------------------------------
+
+/// A DFU interface
+///
+/// `N` is the size of the download buffer and must match the
+/// `transfer_size` advertised in the interface's functional
+/// descriptor.
+pub struct Dfu<F, const N: usize> {
+    flash: F,
+    interface_number: u8,
+    attributes: u8,
+    state: State,
+    status: Status,
+
+    // current download block
+    block_number: u16,
+    block_length: usize,
+    bytes_received: usize,
+    buffer: [u8; N],
+
+    // first offset not yet erased for the current download
+    erased_until: usize,
+
+    // milliseconds left for the host to reset the bus after DFU_DETACH
+    detach_timeout: Option<u16>,
+}
+
+impl<F, const N: usize> Dfu<F, N>
+where
+    F: Flash,
+{
+    /// Create a DFU interface that starts in DFU mode.
+    pub fn new(flash: F, interface_number: u8, attributes: u8) -> Self {
+        Self {
+            flash,
+            interface_number,
+            attributes,
+            state: State::DfuIdle,
+            status: Status::Ok,
+            block_number: 0,
+            block_length: 0,
+            bytes_received: 0,
+            buffer: [0; N],
+            erased_until: 0,
+            detach_timeout: None,
+        }
+    }
+
+    /// Create a DFU interface that starts in runtime mode.
+    pub fn new_runtime(flash: F, interface_number: u8, attributes: u8) -> Self {
+        Self {
+            state: State::AppIdle,
+            ..Self::new(flash, interface_number, attributes)
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// Returns true if the request is addressed to this interface.
+    pub fn is_request_for_interface(&self, setup_packet: &SetupPacket) -> bool {
+        setup_packet.request_type() == RequestType::Class
+            && setup_packet.recipient() == Recipient::Interface
+            && (setup_packet.index & 0xff) as u8 == self.interface_number
+    }
+
+    /// Handle a USB bus reset.
+    ///
+    /// A reset following `DFU_DETACH` switches the interface into DFU
+    /// mode, while a reset after manifestation returns it to runtime
+    /// mode. Callers should check [`Dfu::state`] afterwards to decide
+    /// which set of descriptors to enumerate with.
+    pub fn handle_bus_reset(&mut self) {
+        self.state = match self.state {
+            State::AppIdle => State::AppIdle,
+            State::AppDetach => State::DfuIdle,
+            State::DfuManifestWaitReset => State::AppIdle,
+            State::DfuIdle | State::DfuError => self.state,
+            _ => {
+                self.status = Status::ErrUsbReset;
+                State::DfuError
+            }
+        };
+        self.block_length = 0;
+        self.bytes_received = 0;
+        self.detach_timeout = None;
+        debug!("DFU bus reset -> {:?}", self.state);
+    }
+
+    /// Advance the `DFU_DETACH` timeout by `elapsed` milliseconds.
+    ///
+    /// Devices without [`Attribute::WillDetach`] wait in appDETACH for
+    /// the host to reset the bus. Callers should call this periodically
+    /// so the interface can return to appIDLE if no reset arrives within
+    /// the `wTimeout` given by the host.
+    pub fn handle_tick(&mut self, elapsed: u16) {
+        let remaining = match self.detach_timeout {
+            Some(remaining) => remaining,
+            None => return,
+        };
+        if remaining > elapsed {
+            self.detach_timeout = Some(remaining - elapsed);
+        } else {
+            self.detach_timeout = None;
+            if self.state == State::AppDetach {
+                self.state = State::AppIdle;
+                debug!("DFU detach timed out -> {:?}", self.state);
+            }
+        }
+    }
+
+    /// Handle a class request addressed to this interface.
+    pub fn handle_setup_request<D>(&mut self, hal_driver: &D, setup_packet: &SetupPacket) -> SmolResult<()>
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        let request = match Request::try_from(setup_packet.request) {
+            Ok(request) => request,
+            Err(_) => {
+                warn!("DFU stall: unknown request {}", setup_packet.request);
+                self.stall(hal_driver);
+                return Ok(());
+            }
+        };
+
+        trace!("DFU {:?} in state {:?}", request, self.state);
+
+        match (self.state, request) {
+            // - requests valid in any state --
+            (_, Request::GetStatus) => self.handle_get_status(hal_driver, setup_packet)?,
+            (_, Request::GetState) => {
+                hal_driver.write(0, [self.state as u8].into_iter().take(setup_packet.length as usize))?;
+                hal_driver.ack_status_stage(setup_packet);
+            }
+
+            // - runtime mode --
+            (State::AppIdle, Request::Detach) => {
+                self.state = State::AppDetach;
+                hal_driver.ack_status_stage(setup_packet);
+                if self.attributes & Attribute::WillDetach != 0 {
+                    // we're responsible for re-enumerating
+                    hal_driver.disconnect();
+                    self.handle_bus_reset();
+                    hal_driver.connect();
+                } else {
+                    // wValue holds wTimeout
+                    self.detach_timeout = Some(setup_packet.value);
+                }
+            }
+
+            // - dfu mode: download --
+            (State::DfuIdle | State::DfuDownloadIdle, Request::Download) => {
+                self.handle_download(hal_driver, setup_packet)
+            }
+
+            // - dfu mode: upload --
+            (State::DfuIdle | State::DfuUploadIdle, Request::Upload) => {
+                self.handle_upload(hal_driver, setup_packet)?
+            }
+
+            // - dfu mode: error recovery --
+            (State::DfuError, Request::ClearStatus) => {
+                self.state = State::DfuIdle;
+                self.status = Status::Ok;
+                hal_driver.ack_status_stage(setup_packet);
+            }
+            (
+                State::DfuIdle
+                | State::DfuDownloadSync
+                | State::DfuDownloadIdle
+                | State::DfuManifestSync
+                | State::DfuUploadIdle,
+                Request::Abort,
+            ) => {
+                self.state = State::DfuIdle;
+                self.block_length = 0;
+                self.bytes_received = 0;
+                hal_driver.ack_status_stage(setup_packet);
+            }
+
+            (state, request) => {
+                warn!("DFU stall: invalid request {:?} in state {:?}", request, state);
+                self.stall(hal_driver);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle data received during the data stage of a `DFU_DNLOAD` request.
+    pub fn handle_receive_control_data<D>(&mut self, hal_driver: &D, data: &[u8]) -> SmolResult<()>
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        if self.state != State::DfuDownloadSync || self.bytes_received >= self.block_length {
+            trace!("DFU ignoring {} bytes of control data", data.len());
+            return Ok(());
+        }
+
+        let remaining = self.block_length - self.bytes_received;
+        let length = usize::min(data.len(), remaining);
+        self.buffer[self.bytes_received..self.bytes_received + length]
+            .copy_from_slice(&data[..length]);
+        self.bytes_received += length;
+
+        // acknowledge once the whole block has arrived
+        if self.bytes_received == self.block_length {
+            hal_driver.ack(0, Direction::HostToDevice);
+        }
+
+        Ok(())
+    }
+}
+
+// - request handlers ---------------------------------------------------------
+
+impl<F, const N: usize> Dfu<F, N>
+where
+    F: Flash,
+{
+    fn handle_get_status<D>(&mut self, hal_driver: &D, setup_packet: &SetupPacket) -> SmolResult<()>
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        // advance the state machine
+        match self.state {
+            State::DfuDownloadSync if self.bytes_received == self.block_length => {
+                match self.program_block() {
+                    Ok(()) => self.state = State::DfuDownloadIdle,
+                    Err(status) => self.error(status),
+                }
+            }
+            State::DfuManifestSync => match self.flash.manifest() {
+                Ok(()) if self.attributes & Attribute::ManifestationTolerant != 0 => {
+                    self.state = State::DfuIdle;
+                }
+                Ok(()) => self.state = State::DfuManifest,
+                Err(status) => self.error(status),
+            },
+            _ => (),
+        }
+
+        // bStatus, bwPollTimeout, bState, iString
+        let poll_timeout: u32 = 0;
+        let [t0, t1, t2, _] = poll_timeout.to_le_bytes();
+        let response = [self.status as u8, t0, t1, t2, self.state as u8, 0];
+        hal_driver.write(0, response.into_iter().take(setup_packet.length as usize))?;
+        hal_driver.ack_status_stage(setup_packet);
+
+        // a device that is not manifestation tolerant waits for a reset
+        if self.state == State::DfuManifest {
+            self.state = State::DfuManifestWaitReset;
+        }
+
+        Ok(())
+    }
+
+    fn handle_download<D>(&mut self, hal_driver: &D, setup_packet: &SetupPacket)
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        if self.attributes & Attribute::CanDownload == 0 {
+            warn!("DFU stall: download not supported");
+            self.stall(hal_driver);
+            return;
+        }
+
+        let length = setup_packet.length as usize;
+
+        // zero length download signals the end of the transfer
+        if length == 0 {
+            if self.state == State::DfuIdle {
+                warn!("DFU stall: zero length download without data");
+                self.stall(hal_driver);
+                return;
+            }
+            self.state = State::DfuManifestSync;
+            hal_driver.ack_status_stage(setup_packet);
+            return;
+        }
+
+        if length > N {
+            warn!("DFU stall: block length {} exceeds transfer size {}", length, N);
+            self.stall(hal_driver);
+            return;
+        }
+
+        // starting a new download
+        if self.state == State::DfuIdle {
+            self.erased_until = 0;
+        }
+
+        self.block_number = setup_packet.value;
+        self.block_length = length;
+        self.bytes_received = 0;
+        self.state = State::DfuDownloadSync;
+    }
+
+    fn handle_upload<D>(&mut self, hal_driver: &D, setup_packet: &SetupPacket) -> SmolResult<()>
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        if self.attributes & Attribute::CanUpload == 0 {
+            warn!("DFU stall: upload not supported");
+            self.stall(hal_driver);
+            return Ok(());
+        }
+
+        let offset = setup_packet.value as usize * N;
+        let length = usize::min(setup_packet.length as usize, N);
+        let length = usize::min(length, self.flash.capacity().saturating_sub(offset));
+
+        let bytes_read = match self.flash.read(offset, &mut self.buffer[..length]) {
+            Ok(bytes_read) => bytes_read,
+            Err(status) => {
+                self.error(status);
+                hal_driver.stall_request();
+                return Ok(());
+            }
+        };
+
+        // a short packet ends the upload
+        self.state = if bytes_read < setup_packet.length as usize {
+            State::DfuIdle
+        } else {
+            State::DfuUploadIdle
+        };
+
+        hal_driver.write(0, self.buffer[..bytes_read].iter().copied())?;
+        hal_driver.ack_status_stage(setup_packet);
+
+        Ok(())
+    }
+
+    /// Erase and write the current download block.
+    fn program_block(&mut self) -> Result<(), Status> {
+        let offset = self.block_number as usize * N;
+        let end = offset + self.block_length;
+        if end > self.flash.capacity() {
+            return Err(Status::ErrAddress);
+        }
+
+        // erase any sectors we're about to write to
+        let sector_size = self.flash.sector_size();
+        if self.erased_until < offset {
+            self.erased_until = offset - (offset % sector_size);
+        }
+        while self.erased_until < end {
+            self.flash.erase(self.erased_until)?;
+            self.erased_until += sector_size;
+        }
+
+        self.flash.write(offset, &self.buffer[..self.block_length])?;
+
+        debug!("DFU wrote block {} at 0x{:x}", self.block_number, offset);
+
+        Ok(())
+    }
+
+    fn error(&mut self, status: Status) {
+        warn!("DFU error: {:?} in state {:?}", status, self.state);
+        self.status = status;
+        self.state = State::DfuError;
+    }
+
+    fn stall<D>(&mut self, hal_driver: &D)
+    where
+        D: UsbDriverOperations,
+    {
+        if self.state.is_dfu_mode() {
+            self.status = Status::ErrStalledPacket;
+            self.state = State::DfuError;
+        }
+        hal_driver.stall_request();
+    }
+}
+
+// - descriptors --------------------------------------------------------------
+
+/// Transfer size used by the DFU mode descriptors below
+pub const TRANSFER_SIZE: usize = 256;
+
+pub const FUNCTIONAL_DESCRIPTOR: FunctionalDescriptor = FunctionalDescriptor {
+    attributes: Attribute::CanDownload
+        | Attribute::CanUpload
+        | Attribute::ManifestationTolerant,
+    detach_timeout: 1000, // ms
+    transfer_size: TRANSFER_SIZE as u16,
+    ..FunctionalDescriptor::new()
+};
+
+const FUNCTIONAL_DESCRIPTOR_BYTES: [u8; 9] = FUNCTIONAL_DESCRIPTOR.to_bytes();
+
+/// DFU runtime interface for inclusion in an application's configuration descriptor
+pub const RUNTIME_INTERFACE_DESCRIPTOR_HEADER: InterfaceDescriptorHeader =
+    InterfaceDescriptorHeader {
+        interface_number: 0,
+        alternate_setting: 0,
+        interface_class: CLASS,
+        interface_subclass: SUBCLASS,
+        interface_protocol: PROTOCOL_RUNTIME,
+        interface_string_index: 0,
+        ..InterfaceDescriptorHeader::new()
+    };
+
+pub const DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
+    descriptor_version: 0x0200,
+    device_class: 0x00,    // Defined at interface level
+    device_subclass: 0x00, // Defined at interface level
+    device_protocol: 0x00, // Defined at interface level
+    max_packet_size: 64,
+    vendor_id: 0x1209,             // pid.codes
+    product_id: 0x0002,            // pid.codes test PID
+    device_version_number: 0x0040, // Cynthion r04
+    manufacturer_string_index: 1,
+    product_string_index: 2,
+    serial_string_index: 3,
+    num_configurations: 1,
+    ..DeviceDescriptor::new()
+};
+
+pub const DEVICE_QUALIFIER_DESCRIPTOR: DeviceQualifierDescriptor = DeviceQualifierDescriptor {
+    descriptor_version: 0x0200,
+    device_class: 0x00,
+    device_subclass: 0x00,
+    device_protocol: 0x00,
+    max_packet_size: 64,
+    num_configurations: 1,
+    ..DeviceQualifierDescriptor::new()
+};
+
+pub const CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDescriptor::new(
+    ConfigurationDescriptorHeader {
+        descriptor_type: DescriptorType::Configuration as u8,
+        configuration_value: 1,
+        configuration_string_index: 1,
+        attributes: 0x80, // 0b1000_0000 = bus-powered
+        max_power: 50,    // 50 * 2 mA = 100 mA
+        ..ConfigurationDescriptorHeader::new()
+    },
+    &[InterfaceDescriptor::new(
+        InterfaceDescriptorHeader {
+            interface_number: 0,
+            alternate_setting: 0,
+            interface_class: CLASS,
+            interface_subclass: SUBCLASS,
+            interface_protocol: PROTOCOL_DFU_MODE,
+            interface_string_index: 4,
+            ..InterfaceDescriptorHeader::new()
+        },
+        &[],
+    )
+    .with_class_descriptor(&FUNCTIONAL_DESCRIPTOR_BYTES)],
+);
+
+pub const OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
+    ConfigurationDescriptor::new(
+        ConfigurationDescriptorHeader {
+            descriptor_type: DescriptorType::OtherSpeedConfiguration as u8,
+            configuration_value: 1,
+            configuration_string_index: 1,
+            attributes: 0x80, // 0b1000_0000 = bus-powered
+            max_power: 50,    // 50 * 2 mA = 100 mA
+            ..ConfigurationDescriptorHeader::new()
+        },
+        &[InterfaceDescriptor::new(
+            InterfaceDescriptorHeader {
+                interface_number: 0,
+                alternate_setting: 0,
+                interface_class: CLASS,
+                interface_subclass: SUBCLASS,
+                interface_protocol: PROTOCOL_DFU_MODE,
+                interface_string_index: 4,
+                ..InterfaceDescriptorHeader::new()
+            },
+            &[],
+        )
+        .with_class_descriptor(&FUNCTIONAL_DESCRIPTOR_BYTES)],
+    );
+
+pub const USB_STRING_DESCRIPTOR_0: StringDescriptorZero =
+    StringDescriptorZero::new(&[LanguageId::EnglishUnitedStates]);
+
+pub const USB_STRING_DESCRIPTOR_1: StringDescriptor = StringDescriptor::new("Great Scott Gadgets");
+pub const USB_STRING_DESCRIPTOR_2: StringDescriptor = StringDescriptor::new("Cynthion DFU");
+pub const USB_STRING_DESCRIPTOR_3: StringDescriptor = StringDescriptor::new("040");
+pub const USB_STRING_DESCRIPTOR_4: StringDescriptor = StringDescriptor::new("Firmware");
+
+pub const USB_STRING_DESCRIPTORS: &[&StringDescriptor] = &[
+    &USB_STRING_DESCRIPTOR_1,
+    &USB_STRING_DESCRIPTOR_2,
+    &USB_STRING_DESCRIPTOR_3,
+    &USB_STRING_DESCRIPTOR_4,
+];
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mock::MockUsbDriver;
+
+    use std::vec::Vec;
+
+    // - fixtures -------------------------------------------------------------
+
+    const SECTOR_SIZE: usize = 64;
+    const CAPACITY: usize = 1024;
+
+    /// A RAM-backed flash which enforces erase-before-write
+    struct RamFlash {
+        memory: [u8; CAPACITY],
+        erase_count: usize,
+        manifested: bool,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            Self {
+                memory: [0x00; CAPACITY],
+                erase_count: 0,
+                manifested: false,
+            }
+        }
+    }
+
+    impl Flash for RamFlash {
+        fn capacity(&self) -> usize {
+            CAPACITY
+        }
+
+        fn sector_size(&self) -> usize {
+            SECTOR_SIZE
+        }
+
+        fn erase(&mut self, offset: usize) -> Result<(), Status> {
+            self.memory[offset..offset + SECTOR_SIZE].fill(0xff);
+            self.erase_count += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Status> {
+            let target = &mut self.memory[offset..offset + data.len()];
+            if target.iter().any(|byte| *byte != 0xff) {
+                return Err(Status::ErrCheckErased);
+            }
+            target.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn read(&mut self, offset: usize, buffer: &mut [u8]) -> Result<usize, Status> {
+            buffer.copy_from_slice(&self.memory[offset..offset + buffer.len()]);
+            Ok(buffer.len())
+        }
+
+        fn manifest(&mut self) -> Result<(), Status> {
+            self.manifested = true;
+            Ok(())
+        }
+    }
+
+    const BLOCK_SIZE: usize = 128;
+    const ATTRIBUTES: u8 =
+        Attribute::CanDownload | Attribute::CanUpload | Attribute::ManifestationTolerant;
+
+    fn setup_packet(direction: Direction, request: Request, value: u16, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: direction as u8 | (1 << 5) | 1, // class, interface
+            request: request as u8,
+            value,
+            index: 0,
+            length,
+        }
+    }
+
+    fn get_status(dfu: &mut Dfu<RamFlash, BLOCK_SIZE>, driver: &MockUsbDriver) -> (Status, State) {
+        let setup = setup_packet(Direction::DeviceToHost, Request::GetStatus, 0, 6);
+        dfu.handle_setup_request(driver, &setup).unwrap();
+        let response = driver.last_write(0).expect("no status response");
+        assert_eq!(response.len(), 6);
+        let status = match response[0] {
+            0x00 => Status::Ok,
+            0x0f => Status::ErrStalledPacket,
+            0x08 => Status::ErrAddress,
+            other => panic!("unexpected status {}", other),
+        };
+        assert_eq!(response[4], dfu.state() as u8);
+        (status, dfu.state())
+    }
+
+    fn download(dfu: &mut Dfu<RamFlash, BLOCK_SIZE>, driver: &MockUsbDriver, block: u16, data: &[u8]) {
+        let setup = setup_packet(Direction::HostToDevice, Request::Download, block, data.len() as u16);
+        dfu.handle_setup_request(driver, &setup).unwrap();
+        // data stage arrives in 64 byte packets
+        for chunk in data.chunks(64) {
+            dfu.handle_receive_control_data(driver, chunk).unwrap();
+        }
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_functional_descriptor() {
+        let descriptor = FunctionalDescriptor {
+            attributes: ATTRIBUTES,
+            detach_timeout: 1000,
+            transfer_size: 256,
+            ..FunctionalDescriptor::new()
+        };
+        assert_eq!(descriptor.to_bytes(), descriptor.as_bytes());
+        assert_eq!(
+            descriptor.to_bytes(),
+            [0x09, 0x21, 0x07, 0xe8, 0x03, 0x00, 0x01, 0x10, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_configuration_descriptor() {
+        let mut configuration = CONFIGURATION_DESCRIPTOR_0;
+        let total_length = configuration.set_total_length();
+        let bytes: Vec<u8> = configuration.iter().copied().collect();
+
+        assert_eq!(total_length, 9 + 9 + 9);
+        assert_eq!(bytes.len(), total_length);
+        assert_eq!(&bytes[9..18], &[0x09, 0x04, 0x00, 0x00, 0x00, 0xfe, 0x01, 0x02, 0x04]);
+        assert_eq!(&bytes[18..], &FUNCTIONAL_DESCRIPTOR_BYTES);
+    }
+
+    #[test]
+    fn test_runtime_detach() {
+        let driver = MockUsbDriver::new();
+        let mut dfu: Dfu<RamFlash, BLOCK_SIZE> = Dfu::new_runtime(RamFlash::new(), 0, ATTRIBUTES);
+
+        assert_eq!(get_status(&mut dfu, &driver), (Status::Ok, State::AppIdle));
+
+        let setup = setup_packet(Direction::HostToDevice, Request::Detach, 1000, 0);
+        dfu.handle_setup_request(&driver, &setup).unwrap();
+        assert_eq!(dfu.state(), State::AppDetach);
+        assert!(!driver.is_stalled());
+
+        dfu.handle_tick(999);
+        assert_eq!(dfu.state(), State::AppDetach);
+        dfu.handle_bus_reset();
+        assert_eq!(dfu.state(), State::DfuIdle);
+
+        // the timeout no longer applies once the bus has been reset
+        dfu.handle_tick(1);
+        assert_eq!(dfu.state(), State::DfuIdle);
+    }
+
+    #[test]
+    fn test_runtime_detach_timeout() {
+        let driver = MockUsbDriver::new();
+        let mut dfu: Dfu<RamFlash, BLOCK_SIZE> = Dfu::new_runtime(RamFlash::new(), 0, ATTRIBUTES);
+
+        let setup = setup_packet(Direction::HostToDevice, Request::Detach, 1000, 0);
+        dfu.handle_setup_request(&driver, &setup).unwrap();
+        dfu.handle_tick(999);
+        assert_eq!(dfu.state(), State::AppDetach);
+        dfu.handle_tick(1);
+        assert_eq!(dfu.state(), State::AppIdle);
+
+        // a later reset stays in runtime mode
+        dfu.handle_bus_reset();
+        assert_eq!(dfu.state(), State::AppIdle);
+    }
+
+    #[test]
+    fn test_download() {
+        let driver = MockUsbDriver::new();
+        let mut dfu: Dfu<RamFlash, BLOCK_SIZE> = Dfu::new(RamFlash::new(), 0, ATTRIBUTES);
+
+        let image: Vec<u8> = (0..300).map(|n| (n % 251) as u8).collect();
+        for (block, data) in image.chunks(BLOCK_SIZE).enumerate() {
+            download(&mut dfu, &driver, block as u16, data);
+            assert_eq!(dfu.state(), State::DfuDownloadSync);
+            assert_eq!(driver.last_write(0), Some(Vec::new())); // status stage
+            assert_eq!(get_status(&mut dfu, &driver), (Status::Ok, State::DfuDownloadIdle));
+        }
+
+        // zero length download ends the transfer
+        let setup = setup_packet(Direction::HostToDevice, Request::Download, 3, 0);
+        dfu.handle_setup_request(&driver, &setup).unwrap();
+        assert_eq!(dfu.state(), State::DfuManifestSync);
+        assert_eq!(get_status(&mut dfu, &driver), (Status::Ok, State::DfuIdle));
+
+        let flash = dfu.flash();
+        assert!(flash.manifested);
+        assert_eq!(flash.erase_count, 5); // 300 bytes over 64 byte sectors
+        assert_eq!(&flash.memory[..image.len()], &image[..]);
+        assert!(!driver.is_stalled());
+    }
+
+    #[test]
+    fn test_download_out_of_range() {
+        let driver = MockUsbDriver::new();
+        let mut dfu: Dfu<RamFlash, BLOCK_SIZE> = Dfu::new(RamFlash::new(), 0, ATTRIBUTES);
+
+        let block = (CAPACITY / BLOCK_SIZE) as u16;
+        download(&mut dfu, &driver, block, &[0xaa; BLOCK_SIZE]);
+        assert_eq!(get_status(&mut dfu, &driver), (Status::ErrAddress, State::DfuError));
+
+        // recover
+        let setup = setup_packet(Direction::HostToDevice, Request::ClearStatus, 0, 0);
+        dfu.handle_setup_request(&driver, &setup).unwrap();
+        assert_eq!(get_status(&mut dfu, &driver), (Status::Ok, State::DfuIdle));
+    }
+
+    #[test]
+    fn test_upload() {
+        let driver = MockUsbDriver::new();
+        let mut flash = RamFlash::new();
+        for (n, byte) in flash.memory.iter_mut().enumerate() {
+            *byte = (n % 256) as u8;
+        }
+        let mut dfu: Dfu<RamFlash, BLOCK_SIZE> = Dfu::new(flash, 0, ATTRIBUTES);
+
+        let mut image = Vec::new();
+        for block in 0.. {
+            let setup = setup_packet(Direction::DeviceToHost, Request::Upload, block, BLOCK_SIZE as u16);
+            dfu.handle_setup_request(&driver, &setup).unwrap();
+            let data = driver.last_write(0).unwrap();
+            image.extend_from_slice(&data);
+            if data.len() < BLOCK_SIZE {
+                break;
+            }
+            assert_eq!(dfu.state(), State::DfuUploadIdle);
+        }
+
+        assert_eq!(dfu.state(), State::DfuIdle);
+        assert_eq!(image.len(), CAPACITY);
+        assert_eq!(&image[..], &dfu.flash().memory[..]);
+    }
+
+    #[test]
+    fn test_invalid_request_stalls() {
+        let driver = MockUsbDriver::new();
+        let mut dfu: Dfu<RamFlash, BLOCK_SIZE> = Dfu::new(RamFlash::new(), 0, ATTRIBUTES);
+
+        // zero length download in dfuIDLE is invalid
+        let setup = setup_packet(Direction::HostToDevice, Request::Download, 0, 0);
+        dfu.handle_setup_request(&driver, &setup).unwrap();
+        assert!(driver.is_stalled());
+        assert_eq!(get_status(&mut dfu, &driver), (Status::ErrStalledPacket, State::DfuError));
+
+        // only CLRSTATUS, GETSTATUS and GETSTATE are valid in dfuERROR
+        driver.clear();
+        let setup = setup_packet(Direction::HostToDevice, Request::Abort, 0, 0);
+        dfu.handle_setup_request(&driver, &setup).unwrap();
+        assert!(driver.is_stalled());
+        assert_eq!(dfu.state(), State::DfuError);
+    }
+}
@@ -0,0 +1,648 @@
+//! Linux Gadget Zero compatible test function
+//!
+//! Implements the source/sink and loopback functions of the Linux
+//! `g_zero` gadget driver so that the kernel's `usbtest` driver and
+//! the `testusb` utility can exercise a device controller.
+//!
+//! * source/sink: the bulk IN endpoint continuously sources packets
+//!   filled with a test pattern and the bulk OUT endpoint sinks
+//!   packets, verifying their contents against the same pattern.
+//! * loopback: packets received on the bulk OUT endpoint are returned
+//!   on the bulk IN endpoint.
+//!
+//! Both functions support the vendor control write (`0x5b`) and
+//! control read (`0x5c`) requests used by the `usbtest` control
+//! tests.
+//!
+//! See: `drivers/usb/gadget/function/f_sourcesink.c` and
+//! `drivers/usb/misc/usbtest.c` in the Linux kernel sources.
+
+use crate::class::UsbClass;
+use crate::control::{Direction, Recipient, RequestType, SetupPacket};
+use crate::descriptor::*;
+use crate::error::SmolResult;
+use crate::traits::{EndpointWrite, UsbDriverOperations};
+
+use log::{debug, trace, warn};
+
+// - constants ----------------------------------------------------------------
+
+/// NetChip Technology, Inc.
+pub const VENDOR_ID: u16 = 0x0525;
+/// Linux-USB "Gadget Zero"
+pub const PRODUCT_ID: u16 = 0xa4a0;
+
+/// Vendor specific interface class
+pub const CLASS: u8 = 0xff;
+
+/// Largest bulk packet we will source, sink or loop back
+pub const MAX_PACKET_SIZE: usize = 512;
+
+// - VendorRequest ------------------------------------------------------------
+
+/// Gadget Zero vendor requests
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum VendorRequest {
+    /// Control write test, fills the control buffer
+    ControlWrite,
+    /// Control read test, returns the control buffer
+    ControlRead,
+    Unknown(u8),
+}
+
+impl From<u8> for VendorRequest {
+    fn from(value: u8) -> Self {
+        match value {
+            0x5b => VendorRequest::ControlWrite,
+            0x5c => VendorRequest::ControlRead,
+            _ => VendorRequest::Unknown(value),
+        }
+    }
+}
+
+// - Function -----------------------------------------------------------------
+
+/// The function exposed by the device's single interface
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Function {
+    SourceSink,
+    Loopback,
+}
+
+// - Pattern ------------------------------------------------------------------
+
+/// Data pattern used by the source/sink function
+///
+/// Equivalent to the `pattern` module parameter of `g_zero` and the
+/// `pattern` module parameter of `usbtest`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Pattern {
+    /// All zeroes
+    Zero,
+    /// `(offset % max_packet_size) % 63`
+    Mod63,
+    /// Data is not checked
+    None,
+}
+
+impl Pattern {
+    /// Returns the pattern byte at `offset` within a packet.
+    pub fn byte(&self, offset: usize) -> u8 {
+        match self {
+            Pattern::Mod63 => (offset % 63) as u8,
+            _ => 0,
+        }
+    }
+
+    /// Returns an iterator over a packet of `length` bytes.
+    pub fn packet(&self, length: usize) -> impl Iterator<Item = u8> + '_ {
+        (0..length).map(move |offset| self.byte(offset))
+    }
+
+    /// Returns the offset of the first byte in `packet` that does not
+    /// match the pattern.
+    pub fn check(&self, packet: &[u8]) -> Option<usize> {
+        if *self == Pattern::None {
+            return None;
+        }
+        packet
+            .iter()
+            .enumerate()
+            .position(|(offset, byte)| *byte != self.byte(offset))
+    }
+}
+
+// - GadgetZero ---------------------------------------------------------------
+
+/// Gadget Zero function with an `N` byte control test buffer
+pub struct GadgetZero<const N: usize> {
+    pub function: Function,
+    pub pattern: Pattern,
+    /// Bulk IN endpoint number
+    pub endpoint_in: u8,
+    /// Bulk OUT endpoint number
+    pub endpoint_out: u8,
+    /// Maximum packet size of the bulk endpoints
+    pub max_packet_size: usize,
+
+    /// Number of packets sourced on the IN endpoint
+    pub packets_in: usize,
+    /// Number of packets received on the OUT endpoint
+    pub packets_out: usize,
+    /// Number of sunk packets that did not match the pattern
+    pub errors: usize,
+
+    control_buffer: [u8; N],
+    /// Expected length and bytes received of a pending control write
+    control_write: Option<(usize, usize)>,
+
+    /// An IN packet has been written but not yet acknowledged
+    in_pending: bool,
+    loopback_buffer: [u8; MAX_PACKET_SIZE],
+    loopback_length: usize,
+}
+
+impl<const N: usize> GadgetZero<N> {
+    pub const fn new(
+        function: Function,
+        pattern: Pattern,
+        endpoint_in: u8,
+        endpoint_out: u8,
+        max_packet_size: usize,
+    ) -> Self {
+        Self {
+            function,
+            pattern,
+            endpoint_in: endpoint_in & 0x0f,
+            endpoint_out: endpoint_out & 0x0f,
+            max_packet_size,
+            packets_in: 0,
+            packets_out: 0,
+            errors: 0,
+            control_buffer: [0; N],
+            control_write: None,
+            in_pending: false,
+            loopback_buffer: [0; MAX_PACKET_SIZE],
+            loopback_length: 0,
+        }
+    }
+
+    /// Handle a USB bus reset.
+    pub fn handle_bus_reset(&mut self) {
+        self.control_write = None;
+        self.in_pending = false;
+        self.loopback_length = 0;
+    }
+
+    /// Start the function once the device has been configured.
+    pub fn start<D>(&mut self, hal_driver: &D)
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        debug!("GADGET_ZERO start {:?} {:?}", self.function, self.pattern);
+
+        self.packets_in = 0;
+        self.packets_out = 0;
+        self.errors = 0;
+        self.in_pending = false;
+        self.loopback_length = 0;
+
+        if self.function == Function::SourceSink {
+            self.write_source_packet(hal_driver);
+        }
+        hal_driver.ack(self.endpoint_out, Direction::DeviceToHost);
+    }
+
+    /// Handle a vendor request.
+    pub fn handle_setup_request<D>(&mut self, hal_driver: &D, setup_packet: &SetupPacket) -> SmolResult<()>
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        let request = VendorRequest::from(setup_packet.request);
+        let direction = setup_packet.direction();
+        let length = setup_packet.length as usize;
+
+        trace!("GADGET_ZERO {:?} {:?} length:{}", request, direction, length);
+
+        if setup_packet.request_type() != RequestType::Vendor
+            || setup_packet.recipient() != Recipient::Device
+            || setup_packet.value != 0
+            || setup_packet.index != 0
+            || length > N
+        {
+            warn!("GADGET_ZERO stall: invalid request {:?}", setup_packet);
+            hal_driver.stall_request();
+            return Ok(());
+        }
+
+        match (request, &direction) {
+            (VendorRequest::ControlWrite, Direction::HostToDevice) => {
+                // data stage is passed to handle_receive_control_data
+                self.control_write = if length > 0 { Some((length, 0)) } else { None };
+                hal_driver.ack_status_stage(setup_packet);
+            }
+            (VendorRequest::ControlRead, Direction::DeviceToHost) => {
+                hal_driver.write(0, self.control_buffer[..length].iter().copied())?;
+                hal_driver.ack_status_stage(setup_packet);
+            }
+            _ => {
+                warn!("GADGET_ZERO stall: unsupported request {:?} {:?}", request, direction);
+                hal_driver.stall_request();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle data received on the control endpoint.
+    pub fn handle_receive_control_data(&mut self, data: &[u8]) {
+        let (length, received) = match self.control_write {
+            Some(control_write) => control_write,
+            None => return,
+        };
+
+        let count = usize::min(data.len(), length - received);
+        self.control_buffer[received..received + count].copy_from_slice(&data[..count]);
+
+        let received = received + count;
+        self.control_write = if received < length {
+            Some((length, received))
+        } else {
+            trace!("GADGET_ZERO control write of {} bytes", length);
+            None
+        };
+    }
+
+    /// Handle a packet received on a bulk OUT endpoint.
+    pub fn handle_receive_packet<D>(&mut self, hal_driver: &D, endpoint: u8, packet: &[u8])
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        if endpoint & 0x0f != self.endpoint_out {
+            return;
+        }
+        self.packets_out += 1;
+
+        match self.function {
+            Function::SourceSink => {
+                if let Some(offset) = self.pattern.check(packet) {
+                    warn!(
+                        "GADGET_ZERO sink packet {} mismatch at offset {}: 0x{:02x}",
+                        self.packets_out, offset, packet[offset]
+                    );
+                    self.errors += 1;
+                }
+                hal_driver.ack(self.endpoint_out, Direction::DeviceToHost);
+            }
+            Function::Loopback => {
+                // the OUT endpoint is primed again once the host has
+                // read the packet back
+                let length = usize::min(packet.len(), MAX_PACKET_SIZE);
+                self.loopback_buffer[..length].copy_from_slice(&packet[..length]);
+                self.loopback_length = length;
+                self.write_loopback_packet(hal_driver);
+            }
+        }
+    }
+
+    /// Handle completion of an IN transfer.
+    ///
+    /// The IN FIFO is shared by all endpoints, so a control response
+    /// discards any bulk packet that has not yet been read by the
+    /// host. Completion of a control transfer therefore rewrites the
+    /// pending bulk packet.
+    pub fn handle_transfer_complete<D>(&mut self, hal_driver: &D, endpoint: u8)
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        let endpoint = endpoint & 0x0f;
+
+        if endpoint == self.endpoint_in {
+            self.in_pending = false;
+            self.packets_in += 1;
+            match self.function {
+                Function::SourceSink => self.write_source_packet(hal_driver),
+                Function::Loopback => {
+                    self.loopback_length = 0;
+                    hal_driver.ack(self.endpoint_out, Direction::DeviceToHost);
+                }
+            }
+        } else if endpoint == 0 && self.in_pending {
+            match self.function {
+                Function::SourceSink => self.write_source_packet(hal_driver),
+                Function::Loopback => self.write_loopback_packet(hal_driver),
+            }
+        }
+    }
+
+    fn write_source_packet<D>(&mut self, hal_driver: &D)
+    where
+        D: EndpointWrite,
+    {
+        match hal_driver.write(self.endpoint_in, self.pattern.packet(self.max_packet_size)) {
+            Ok(_) => self.in_pending = true,
+            Err(e) => warn!("GADGET_ZERO failed to write source packet: {:?}", e),
+        }
+    }
+
+    fn write_loopback_packet<D>(&mut self, hal_driver: &D)
+    where
+        D: EndpointWrite,
+    {
+        match hal_driver.write(
+            self.endpoint_in,
+            self.loopback_buffer[..self.loopback_length].iter(),
+        ) {
+            Ok(_) => self.in_pending = true,
+            Err(e) => warn!("GADGET_ZERO failed to write loopback packet: {:?}", e),
+        }
+    }
+}
+
+impl<D, const N: usize> UsbClass<D> for GadgetZero<N>
+where
+    D: EndpointWrite + UsbDriverOperations,
+{
+    fn handle_bus_reset(&mut self) {
+        GadgetZero::handle_bus_reset(self);
+    }
+
+    fn handle_setup_request(&mut self, hal_driver: &D, setup_packet: &SetupPacket) -> SmolResult<bool> {
+        if setup_packet.request_type() != RequestType::Vendor {
+            return Ok(false);
+        }
+        GadgetZero::handle_setup_request(self, hal_driver, setup_packet)?;
+        Ok(true)
+    }
+
+    fn handle_set_configuration(&mut self, hal_driver: &D, configuration: u8) {
+        if configuration == 1 {
+            self.start(hal_driver);
+        }
+    }
+
+    fn handle_receive_packet(&mut self, hal_driver: &D, endpoint: u8, packet: &[u8]) -> bool {
+        if endpoint == 0 {
+            let pending = self.control_write.is_some();
+            self.handle_receive_control_data(packet);
+            pending
+        } else if endpoint & 0x0f == self.endpoint_out {
+            GadgetZero::handle_receive_packet(self, hal_driver, endpoint, packet);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn handle_transfer_complete(&mut self, hal_driver: &D, endpoint: u8) -> bool {
+        GadgetZero::handle_transfer_complete(self, hal_driver, endpoint);
+        endpoint & 0x0f == self.endpoint_in
+    }
+}
+
+// - descriptors --------------------------------------------------------------
+
+pub const DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
+    descriptor_version: 0x0200,
+    device_class: 0x00,    // Defined at interface level
+    device_subclass: 0x00, // Defined at interface level
+    device_protocol: 0x00, // Defined at interface level
+    max_packet_size: 64,
+    vendor_id: VENDOR_ID,
+    product_id: PRODUCT_ID,
+    device_version_number: 0x0040, // Cynthion r04
+    manufacturer_string_index: 1,
+    product_string_index: 2,
+    serial_string_index: 3,
+    num_configurations: 1,
+    ..DeviceDescriptor::new()
+};
+
+pub const DEVICE_QUALIFIER_DESCRIPTOR: DeviceQualifierDescriptor = DeviceQualifierDescriptor {
+    descriptor_version: 0x0200,
+    device_class: 0x00,
+    device_subclass: 0x00,
+    device_protocol: 0x00,
+    max_packet_size: 64,
+    num_configurations: 1,
+    ..DeviceQualifierDescriptor::new()
+};
+
+const INTERFACES_HIGH_SPEED: &[InterfaceDescriptor] = &[interface_descriptor(&[
+    EndpointDescriptor {
+        endpoint_address: 0x81, // IN
+        attributes: 0x02,       // Bulk
+        max_packet_size: 512,
+        interval: 0,
+        ..EndpointDescriptor::new()
+    },
+    EndpointDescriptor {
+        endpoint_address: 0x01, // OUT
+        attributes: 0x02,       // Bulk
+        max_packet_size: 512,
+        interval: 0,
+        ..EndpointDescriptor::new()
+    },
+])];
+
+const INTERFACES_FULL_SPEED: &[InterfaceDescriptor] = &[interface_descriptor(&[
+    EndpointDescriptor {
+        endpoint_address: 0x81, // IN
+        attributes: 0x02,       // Bulk
+        max_packet_size: 64,
+        interval: 0,
+        ..EndpointDescriptor::new()
+    },
+    EndpointDescriptor {
+        endpoint_address: 0x01, // OUT
+        attributes: 0x02,       // Bulk
+        max_packet_size: 64,
+        interval: 0,
+        ..EndpointDescriptor::new()
+    },
+])];
+
+/// Source/sink configuration
+pub const CONFIGURATION_DESCRIPTOR_SOURCE_SINK: ConfigurationDescriptor =
+    configuration_descriptor(DescriptorType::Configuration, 4, INTERFACES_HIGH_SPEED);
+
+/// Loopback configuration
+pub const CONFIGURATION_DESCRIPTOR_LOOPBACK: ConfigurationDescriptor =
+    configuration_descriptor(DescriptorType::Configuration, 5, INTERFACES_HIGH_SPEED);
+
+pub const OTHER_SPEED_CONFIGURATION_DESCRIPTOR_SOURCE_SINK: ConfigurationDescriptor =
+    configuration_descriptor(DescriptorType::OtherSpeedConfiguration, 4, INTERFACES_FULL_SPEED);
+
+pub const OTHER_SPEED_CONFIGURATION_DESCRIPTOR_LOOPBACK: ConfigurationDescriptor =
+    configuration_descriptor(DescriptorType::OtherSpeedConfiguration, 5, INTERFACES_FULL_SPEED);
+
+const fn interface_descriptor<'a>(endpoints: &'a [EndpointDescriptor]) -> InterfaceDescriptor<'a> {
+    InterfaceDescriptor::new(
+        InterfaceDescriptorHeader {
+            interface_number: 0,
+            alternate_setting: 0,
+            interface_class: CLASS,
+            interface_subclass: 0x00,
+            interface_protocol: 0x00,
+            interface_string_index: 0,
+            ..InterfaceDescriptorHeader::new()
+        },
+        endpoints,
+    )
+}
+
+const fn configuration_descriptor<'a>(
+    descriptor_type: DescriptorType,
+    string_index: u8,
+    interfaces: &'a [InterfaceDescriptor<'a>],
+) -> ConfigurationDescriptor<'a> {
+    ConfigurationDescriptor::new(
+        ConfigurationDescriptorHeader {
+            descriptor_type: descriptor_type as u8,
+            configuration_value: 1,
+            configuration_string_index: string_index,
+            attributes: 0x80, // 0b1000_0000 = bus-powered
+            max_power: 50,    // 50 * 2 mA = 100 mA
+            ..ConfigurationDescriptorHeader::new()
+        },
+        interfaces,
+    )
+}
+
+pub const USB_STRING_DESCRIPTOR_0: StringDescriptorZero =
+    StringDescriptorZero::new(&[LanguageId::EnglishUnitedStates]);
+
+pub const USB_STRING_DESCRIPTOR_1: StringDescriptor = StringDescriptor::new("Great Scott Gadgets");
+pub const USB_STRING_DESCRIPTOR_2: StringDescriptor = StringDescriptor::new("Gadget Zero");
+pub const USB_STRING_DESCRIPTOR_3: StringDescriptor = StringDescriptor::new("040");
+pub const USB_STRING_DESCRIPTOR_4: StringDescriptor = StringDescriptor::new("source and sink data");
+pub const USB_STRING_DESCRIPTOR_5: StringDescriptor = StringDescriptor::new("loop input to output");
+
+pub const USB_STRING_DESCRIPTORS: &[&StringDescriptor] = &[
+    &USB_STRING_DESCRIPTOR_1,
+    &USB_STRING_DESCRIPTOR_2,
+    &USB_STRING_DESCRIPTOR_3,
+    &USB_STRING_DESCRIPTOR_4,
+    &USB_STRING_DESCRIPTOR_5,
+];
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mock::MockUsbDriver;
+
+    // - fixtures -------------------------------------------------------------
+
+    const BUFFER_SIZE: usize = 256;
+
+    fn control_write(length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: 0x40, // host to device, vendor, device
+            request: 0x5b,
+            value: 0,
+            index: 0,
+            length,
+        }
+    }
+
+    fn control_read(length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: 0xc0, // device to host, vendor, device
+            request: 0x5c,
+            value: 0,
+            index: 0,
+            length,
+        }
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_pattern() {
+        let packet: Vec<u8> = Pattern::Mod63.packet(128).collect();
+        assert_eq!(packet[0], 0);
+        assert_eq!(packet[62], 62);
+        assert_eq!(packet[63], 0);
+        assert_eq!(packet[127], 1);
+        assert_eq!(Pattern::Mod63.check(&packet), None);
+        assert_eq!(Pattern::Zero.check(&packet), Some(1));
+        assert_eq!(Pattern::None.check(&packet), None);
+    }
+
+    #[test]
+    fn test_control_write_read() {
+        let driver = MockUsbDriver::new();
+        let mut gadget: GadgetZero<BUFFER_SIZE> =
+            GadgetZero::new(Function::SourceSink, Pattern::Zero, 0x81, 0x01, 512);
+
+        let data: Vec<u8> = (0..100).collect();
+        gadget.handle_setup_request(&driver, &control_write(100)).unwrap();
+        gadget.handle_receive_control_data(&data[..64]);
+        gadget.handle_receive_control_data(&data[64..]);
+        assert!(!driver.is_stalled());
+
+        driver.clear();
+        gadget.handle_setup_request(&driver, &control_read(100)).unwrap();
+        assert_eq!(driver.last_write(0), Some(data));
+    }
+
+    #[test]
+    fn test_control_invalid() {
+        let driver = MockUsbDriver::new();
+        let mut gadget: GadgetZero<BUFFER_SIZE> =
+            GadgetZero::new(Function::SourceSink, Pattern::Zero, 0x81, 0x01, 512);
+
+        // too long for the control buffer
+        gadget
+            .handle_setup_request(&driver, &control_write(BUFFER_SIZE as u16 + 1))
+            .unwrap();
+        assert!(driver.is_stalled());
+
+        // wrong direction
+        driver.clear();
+        let mut setup_packet = control_read(8);
+        setup_packet.request_type = 0x40;
+        gadget.handle_setup_request(&driver, &setup_packet).unwrap();
+        assert!(driver.is_stalled());
+    }
+
+    #[test]
+    fn test_source_sink() {
+        let driver = MockUsbDriver::new();
+        let mut gadget: GadgetZero<BUFFER_SIZE> =
+            GadgetZero::new(Function::SourceSink, Pattern::Mod63, 0x81, 0x01, 64);
+
+        gadget.start(&driver);
+        let expected: Vec<u8> = Pattern::Mod63.packet(64).collect();
+        assert_eq!(driver.take_writes(), vec![(1, expected.clone())]);
+        assert_eq!(*driver.primed.borrow(), vec![1]);
+
+        // the next packet is sourced once the first one completes
+        gadget.handle_transfer_complete(&driver, 0x81);
+        assert_eq!(driver.take_writes(), vec![(1, expected.clone())]);
+        assert_eq!(gadget.packets_in, 1);
+
+        // a control transfer clobbers the pending packet
+        gadget.handle_transfer_complete(&driver, 0);
+        assert_eq!(driver.take_writes(), vec![(1, expected.clone())]);
+        assert_eq!(gadget.packets_in, 1);
+
+        // sink
+        gadget.handle_receive_packet(&driver, 1, &expected);
+        gadget.handle_receive_packet(&driver, 1, &[0xff; 64]);
+        assert_eq!(gadget.packets_out, 2);
+        assert_eq!(gadget.errors, 1);
+    }
+
+    #[test]
+    fn test_loopback() {
+        let driver = MockUsbDriver::new();
+        let mut gadget: GadgetZero<BUFFER_SIZE> =
+            GadgetZero::new(Function::Loopback, Pattern::None, 0x81, 0x01, 512);
+
+        gadget.start(&driver);
+        assert!(driver.take_writes().is_empty());
+        driver.primed.take();
+
+        gadget.handle_receive_packet(&driver, 1, &[1, 2, 3]);
+        assert_eq!(driver.take_writes(), vec![(1, vec![1, 2, 3])]);
+        assert!(driver.primed.borrow().is_empty());
+
+        gadget.handle_transfer_complete(&driver, 1);
+        assert_eq!(*driver.primed.borrow(), vec![1]);
+    }
+
+    #[test]
+    fn test_configuration_descriptor() {
+        let mut configuration = CONFIGURATION_DESCRIPTOR_SOURCE_SINK;
+        let total_length = configuration.set_total_length();
+        assert_eq!(total_length, 9 + 9 + 7 + 7);
+
+        let bytes: Vec<u8> = configuration.iter().copied().collect();
+        assert_eq!(bytes[9 + 5], CLASS);
+        assert_eq!(&bytes[18 + 2..18 + 6], &[0x81, 0x02, 0x00, 0x02]);
+    }
+}
@@ -0,0 +1,848 @@
+//! USB MIDI 1.0 streaming class
+//!
+//! A MIDI function consists of an empty Audio Control interface
+//! followed by a MIDI Streaming interface with one bulk OUT and one
+//! bulk IN endpoint. Each virtual MIDI cable is described by a pair of
+//! embedded jacks connected to a pair of external jacks.
+//!
+//! See: "Universal Serial Bus Device Class Definition for MIDI
+//! Devices" Release 1.0
+
+use crate::descriptor::*;
+use crate::error::{SmolError, SmolResult};
+use crate::traits::{EndpointRead, EndpointWrite};
+
+use log::{trace, warn};
+
+// - constants ----------------------------------------------------------------
+
+/// Audio interface class code
+pub const CLASS: u8 = 0x01;
+/// Audio Control interface subclass code
+pub const SUBCLASS_AUDIO_CONTROL: u8 = 0x01;
+/// MIDI Streaming interface subclass code
+pub const SUBCLASS_MIDI_STREAMING: u8 = 0x03;
+
+/// Class-specific interface descriptor type
+pub const DESCRIPTOR_TYPE_CS_INTERFACE: u8 = 0x24;
+/// Class-specific endpoint descriptor type
+pub const DESCRIPTOR_TYPE_CS_ENDPOINT: u8 = 0x25;
+
+/// Class-specific descriptor subtypes
+#[allow(non_snake_case, non_upper_case_globals)]
+pub mod DescriptorSubtype {
+    /// Audio Control and MIDI Streaming interface header
+    pub const Header: u8 = 0x01;
+    pub const MidiInJack: u8 = 0x02;
+    pub const MidiOutJack: u8 = 0x03;
+    pub const Element: u8 = 0x04;
+    /// MIDI Streaming bulk data endpoint
+    pub const General: u8 = 0x01;
+}
+
+/// MIDI jack types
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[repr(u8)]
+pub enum JackType {
+    Embedded = 0x01,
+    External = 0x02,
+}
+
+/// Length of one USB-MIDI event packet
+pub const EVENT_PACKET_SIZE: usize = 4;
+
+// - descriptors --------------------------------------------------------------
+
+/// Number of jacks used to describe each cable
+const JACKS_PER_CABLE: usize = 4;
+/// Length of the jack descriptors used to describe each cable
+const JACK_DESCRIPTORS_LENGTH: usize = 6 + 6 + 9 + 9;
+
+/// Class-specific Audio Control interface header.
+///
+/// `streaming_interface` is the interface number of the MIDI Streaming interface.
+pub const fn audio_control_header(streaming_interface: u8) -> [u8; 9] {
+    let total_length = 9_u16.to_le_bytes();
+    [
+        9,                            // bLength
+        DESCRIPTOR_TYPE_CS_INTERFACE, // bDescriptorType
+        DescriptorSubtype::Header,    // bDescriptorSubtype
+        0x00,                         // bcdADC
+        0x01,
+        total_length[0], // wTotalLength
+        total_length[1],
+        1,                   // bInCollection
+        streaming_interface, // baInterfaceNr(1)
+    ]
+}
+
+/// Length of the class-specific MIDI Streaming interface descriptors
+/// returned by [`midi_streaming_descriptors`].
+pub const fn midi_streaming_descriptors_length(cables: usize) -> usize {
+    7 + cables * JACK_DESCRIPTORS_LENGTH
+}
+
+/// Class-specific MIDI Streaming interface header followed by the jack
+/// descriptors for `cables` virtual cables.
+///
+/// Cable `n` is described by four jacks:
+///
+/// * `4n + 1`: embedded IN jack, fed by the bulk OUT endpoint
+/// * `4n + 2`: external IN jack
+/// * `4n + 3`: embedded OUT jack, sourced from `4n + 2` and feeding the bulk IN endpoint
+/// * `4n + 4`: external OUT jack, sourced from `4n + 1`
+///
+/// `N` must equal [`midi_streaming_descriptors_length`]`(cables)`.
+pub const fn midi_streaming_descriptors<const N: usize>(cables: usize) -> [u8; N] {
+    if N != midi_streaming_descriptors_length(cables) {
+        panic!("invalid midi streaming descriptor length");
+    }
+
+    let mut bytes = [0_u8; N];
+    let total_length = (N as u16).to_le_bytes();
+    let header = [
+        7,                            // bLength
+        DESCRIPTOR_TYPE_CS_INTERFACE, // bDescriptorType
+        DescriptorSubtype::Header,    // bDescriptorSubtype
+        0x00,                         // bcdMSC
+        0x01,
+        total_length[0], // wTotalLength
+        total_length[1],
+    ];
+    let mut offset = 0;
+    (bytes, offset) = copy(bytes, offset, &header);
+
+    let mut cable = 0;
+    while cable < cables {
+        let id = jack_id(cable as u8);
+        (bytes, offset) = copy(bytes, offset, &in_jack(JackType::Embedded, id));
+        (bytes, offset) = copy(bytes, offset, &in_jack(JackType::External, id + 1));
+        (bytes, offset) = copy(bytes, offset, &out_jack(JackType::Embedded, id + 2, id + 1));
+        (bytes, offset) = copy(bytes, offset, &out_jack(JackType::External, id + 3, id));
+        cable += 1;
+    }
+
+    bytes
+}
+
+/// Class-specific MIDI Streaming bulk OUT endpoint descriptor for `N - 4` cables.
+pub const fn endpoint_out_descriptor<const N: usize>() -> [u8; N] {
+    endpoint_descriptor::<N>(0)
+}
+
+/// Class-specific MIDI Streaming bulk IN endpoint descriptor for `N - 4` cables.
+pub const fn endpoint_in_descriptor<const N: usize>() -> [u8; N] {
+    endpoint_descriptor::<N>(2)
+}
+
+/// First jack id used by the given cable.
+pub const fn jack_id(cable: u8) -> u8 {
+    (cable as usize * JACKS_PER_CABLE) as u8 + 1
+}
+
+const fn endpoint_descriptor<const N: usize>(jack_offset: u8) -> [u8; N] {
+    if N < 5 {
+        panic!("invalid midi streaming endpoint descriptor length");
+    }
+    let cables = N - 4;
+
+    let mut bytes = [0_u8; N];
+    bytes[0] = N as u8; // bLength
+    bytes[1] = DESCRIPTOR_TYPE_CS_ENDPOINT; // bDescriptorType
+    bytes[2] = DescriptorSubtype::General; // bDescriptorSubtype
+    bytes[3] = cables as u8; // bNumEmbMIDIJack
+    let mut cable = 0;
+    while cable < cables {
+        bytes[4 + cable] = jack_id(cable as u8) + jack_offset; // baAssocJackID
+        cable += 1;
+    }
+    bytes
+}
+
+const fn in_jack(jack_type: JackType, id: u8) -> [u8; 6] {
+    [
+        6,                             // bLength
+        DESCRIPTOR_TYPE_CS_INTERFACE,  // bDescriptorType
+        DescriptorSubtype::MidiInJack, // bDescriptorSubtype
+        jack_type as u8,               // bJackType
+        id,                            // bJackID
+        0,                             // iJack
+    ]
+}
+
+const fn out_jack(jack_type: JackType, id: u8, source_id: u8) -> [u8; 9] {
+    [
+        9,                              // bLength
+        DESCRIPTOR_TYPE_CS_INTERFACE,   // bDescriptorType
+        DescriptorSubtype::MidiOutJack, // bDescriptorSubtype
+        jack_type as u8,                // bJackType
+        id,                             // bJackID
+        1,                              // bNrInputPins
+        source_id,                      // baSourceID(1)
+        1,                              // baSourcePin(1)
+        0,                              // iJack
+    ]
+}
+
+const fn copy<const N: usize>(
+    mut bytes: [u8; N],
+    offset: usize,
+    source: &[u8],
+) -> ([u8; N], usize) {
+    let mut index = 0;
+    while index < source.len() {
+        bytes[offset + index] = source[index];
+        index += 1;
+    }
+    (bytes, offset + index)
+}
+
+// - CodeIndex ----------------------------------------------------------------
+
+/// USB-MIDI Code Index Number, classifying the MIDI bytes in an event packet
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[repr(u8)]
+pub enum CodeIndex {
+    /// Reserved for future extensions
+    Miscellaneous = 0x0,
+    /// Reserved for future expansion
+    CableEvent = 0x1,
+    /// Two-byte System Common message
+    SystemCommon2 = 0x2,
+    /// Three-byte System Common message
+    SystemCommon3 = 0x3,
+    /// SysEx starts or continues
+    SysExStart = 0x4,
+    /// Single-byte System Common message or SysEx ends with one byte
+    SysExEnd1 = 0x5,
+    /// SysEx ends with two bytes
+    SysExEnd2 = 0x6,
+    /// SysEx ends with three bytes
+    SysExEnd3 = 0x7,
+    NoteOff = 0x8,
+    NoteOn = 0x9,
+    PolyKeyPress = 0xa,
+    ControlChange = 0xb,
+    ProgramChange = 0xc,
+    ChannelPressure = 0xd,
+    PitchBendChange = 0xe,
+    /// Single byte
+    SingleByte = 0xf,
+}
+
+impl From<u8> for CodeIndex {
+    fn from(value: u8) -> Self {
+        match value & 0x0f {
+            0x0 => CodeIndex::Miscellaneous,
+            0x1 => CodeIndex::CableEvent,
+            0x2 => CodeIndex::SystemCommon2,
+            0x3 => CodeIndex::SystemCommon3,
+            0x4 => CodeIndex::SysExStart,
+            0x5 => CodeIndex::SysExEnd1,
+            0x6 => CodeIndex::SysExEnd2,
+            0x7 => CodeIndex::SysExEnd3,
+            0x8 => CodeIndex::NoteOff,
+            0x9 => CodeIndex::NoteOn,
+            0xa => CodeIndex::PolyKeyPress,
+            0xb => CodeIndex::ControlChange,
+            0xc => CodeIndex::ProgramChange,
+            0xd => CodeIndex::ChannelPressure,
+            0xe => CodeIndex::PitchBendChange,
+            _ => CodeIndex::SingleByte,
+        }
+    }
+}
+
+impl CodeIndex {
+    /// Number of MIDI bytes carried by an event packet with this code index.
+    pub fn payload_length(&self) -> usize {
+        match self {
+            CodeIndex::SysExEnd1 | CodeIndex::SingleByte => 1,
+            CodeIndex::SystemCommon2
+            | CodeIndex::SysExEnd2
+            | CodeIndex::ProgramChange
+            | CodeIndex::ChannelPressure => 2,
+            _ => 3,
+        }
+    }
+
+    /// Code index for a complete, non-SysEx MIDI message starting with the given status byte.
+    pub fn from_status(status: u8) -> SmolResult<Self> {
+        let code_index = match status {
+            0x80..=0xef => CodeIndex::from(status >> 4),
+            // MTC quarter frame, song select
+            0xf1 | 0xf3 => CodeIndex::SystemCommon2,
+            // song position pointer
+            0xf2 => CodeIndex::SystemCommon3,
+            // tune request, real-time messages
+            0xf6 | 0xf8..=0xff => CodeIndex::SysExEnd1,
+            _ => return Err(SmolError::FailedConversion),
+        };
+        Ok(code_index)
+    }
+}
+
+// - EventPacket --------------------------------------------------------------
+
+/// A 32-bit USB-MIDI event packet
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct EventPacket {
+    pub cable_number: u8,
+    pub code_index: CodeIndex,
+    pub midi: [u8; 3],
+}
+
+impl EventPacket {
+    /// Create an event packet from a complete, non-SysEx MIDI message.
+    pub fn from_message(cable_number: u8, message: &[u8]) -> SmolResult<Self> {
+        let status = *message.first().ok_or(SmolError::FailedConversion)?;
+        let code_index = CodeIndex::from_status(status)?;
+        if message.len() != code_index.payload_length() {
+            return Err(SmolError::FailedConversion);
+        }
+        let mut midi = [0; 3];
+        midi[..message.len()].copy_from_slice(message);
+        Ok(Self {
+            cable_number: cable_number & 0x0f,
+            code_index,
+            midi,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; EVENT_PACKET_SIZE] {
+        [
+            (self.cable_number << 4) | self.code_index as u8,
+            self.midi[0],
+            self.midi[1],
+            self.midi[2],
+        ]
+    }
+
+    /// The MIDI bytes carried by this packet.
+    pub fn payload(&self) -> &[u8] {
+        &self.midi[..self.code_index.payload_length()]
+    }
+}
+
+impl From<[u8; EVENT_PACKET_SIZE]> for EventPacket {
+    fn from(bytes: [u8; EVENT_PACKET_SIZE]) -> Self {
+        Self {
+            cable_number: bytes[0] >> 4,
+            code_index: CodeIndex::from(bytes[0]),
+            midi: [bytes[1], bytes[2], bytes[3]],
+        }
+    }
+}
+
+// - EventPackets -------------------------------------------------------------
+
+/// Iterator over the event packets in a bulk transfer
+///
+/// Empty padding packets are skipped.
+pub struct EventPackets<'a> {
+    chunks: core::slice::ChunksExact<'a, u8>,
+}
+
+impl<'a> EventPackets<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        if bytes.len() % EVENT_PACKET_SIZE != 0 {
+            warn!("MIDI ignoring {} trailing bytes", bytes.len() % EVENT_PACKET_SIZE);
+        }
+        Self {
+            chunks: bytes.chunks_exact(EVENT_PACKET_SIZE),
+        }
+    }
+}
+
+impl<'a> Iterator for EventPackets<'a> {
+    type Item = EventPacket;
+    fn next(&mut self) -> Option<Self::Item> {
+        for chunk in self.chunks.by_ref() {
+            if chunk == [0, 0, 0, 0] {
+                continue;
+            }
+            return Some(EventPacket::from([chunk[0], chunk[1], chunk[2], chunk[3]]));
+        }
+        None
+    }
+}
+
+// - SysExPackets -------------------------------------------------------------
+
+/// Iterator segmenting a System Exclusive message into event packets
+pub struct SysExPackets<'a> {
+    cable_number: u8,
+    chunks: core::slice::Chunks<'a, u8>,
+}
+
+impl<'a> SysExPackets<'a> {
+    /// `message` must be a complete SysEx message, including the
+    /// leading `0xf0` and trailing `0xf7`.
+    pub fn new(cable_number: u8, message: &'a [u8]) -> SmolResult<Self> {
+        if message.len() < 2 || message[0] != 0xf0 || message[message.len() - 1] != 0xf7 {
+            return Err(SmolError::FailedConversion);
+        }
+        Ok(Self {
+            cable_number: cable_number & 0x0f,
+            chunks: message.chunks(3),
+        })
+    }
+}
+
+impl<'a> Iterator for SysExPackets<'a> {
+    type Item = EventPacket;
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = self.chunks.next()?;
+        let last = self.chunks.len() == 0;
+        let code_index = match (last, chunk.len()) {
+            (false, _) => CodeIndex::SysExStart,
+            (true, 1) => CodeIndex::SysExEnd1,
+            (true, 2) => CodeIndex::SysExEnd2,
+            (true, _) => CodeIndex::SysExEnd3,
+        };
+        let mut midi = [0; 3];
+        midi[..chunk.len()].copy_from_slice(chunk);
+        Some(EventPacket {
+            cable_number: self.cable_number,
+            code_index,
+            midi,
+        })
+    }
+}
+
+// - SysExReceiver ------------------------------------------------------------
+
+/// Reassembles System Exclusive messages received as event packets
+pub struct SysExReceiver<const N: usize> {
+    buffer: heapless::Vec<u8, N>,
+    overflow: bool,
+    complete: bool,
+}
+
+impl<const N: usize> SysExReceiver<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: heapless::Vec::new(),
+            overflow: false,
+            complete: false,
+        }
+    }
+
+    /// Add a SysEx event packet, returning the complete message once
+    /// its final packet has been received.
+    ///
+    /// Packets that are not part of a SysEx message are ignored and
+    /// messages longer than `N` bytes are discarded.
+    pub fn push(&mut self, packet: &EventPacket) -> Option<&[u8]> {
+        let last = match packet.code_index {
+            CodeIndex::SysExStart => false,
+            // single-byte system common message
+            CodeIndex::SysExEnd1 if packet.midi[0] != 0xf7 => return None,
+            CodeIndex::SysExEnd1 | CodeIndex::SysExEnd2 | CodeIndex::SysExEnd3 => true,
+            _ => return None,
+        };
+
+        // a new message discards any previous one
+        if self.complete || packet.midi[0] == 0xf0 {
+            self.buffer.clear();
+            self.overflow = false;
+            self.complete = false;
+        }
+
+        if self.buffer.extend_from_slice(packet.payload()).is_err() {
+            self.overflow = true;
+        }
+
+        if !last {
+            return None;
+        }
+
+        self.complete = true;
+        if self.overflow {
+            warn!("MIDI discarding SysEx message longer than {} bytes", N);
+            return None;
+        }
+
+        Some(&self.buffer)
+    }
+}
+
+impl<const N: usize> Default for SysExReceiver<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// - Midi ---------------------------------------------------------------------
+
+/// Bulk endpoint interface of a MIDI streaming function
+pub struct Midi {
+    /// Bulk OUT endpoint number
+    pub endpoint_out: u8,
+    /// Bulk IN endpoint number
+    pub endpoint_in: u8,
+    /// Maximum packet size of the bulk IN endpoint
+    pub max_packet_size: usize,
+}
+
+impl Midi {
+    pub const fn new(endpoint_out: u8, endpoint_in: u8, max_packet_size: usize) -> Self {
+        Self {
+            endpoint_out: endpoint_out & 0x0f,
+            endpoint_in: endpoint_in & 0x0f,
+            max_packet_size,
+        }
+    }
+
+    /// Write event packets to the bulk IN endpoint, splitting them
+    /// over as many USB packets as required.
+    pub fn write_packets<D, I>(&self, hal_driver: &D, packets: I) -> SmolResult<()>
+    where
+        D: EndpointWrite,
+        I: IntoIterator<Item = EventPacket>,
+    {
+        let packets_per_transfer = usize::max(self.max_packet_size / EVENT_PACKET_SIZE, 1);
+        let mut packets = packets.into_iter().peekable();
+        while packets.peek().is_some() {
+            let bytes = packets
+                .by_ref()
+                .take(packets_per_transfer)
+                .flat_map(|packet| packet.to_bytes());
+            hal_driver.write(self.endpoint_in, bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Send a complete, non-SysEx MIDI message on the given cable.
+    pub fn send<D>(&self, hal_driver: &D, cable_number: u8, message: &[u8]) -> SmolResult<()>
+    where
+        D: EndpointWrite,
+    {
+        let packet = EventPacket::from_message(cable_number, message)?;
+        self.write_packets(hal_driver, [packet])
+    }
+
+    /// Send a complete SysEx message on the given cable.
+    pub fn send_sysex<D>(&self, hal_driver: &D, cable_number: u8, message: &[u8]) -> SmolResult<()>
+    where
+        D: EndpointWrite,
+    {
+        let packets = SysExPackets::new(cable_number, message)?;
+        self.write_packets(hal_driver, packets)
+    }
+
+    /// Read a packet from the bulk OUT endpoint into `buffer`,
+    /// returning the event packets it contains.
+    pub fn read_packets<'b, D>(&self, hal_driver: &D, buffer: &'b mut [u8]) -> SmolResult<EventPackets<'b>>
+    where
+        D: EndpointRead,
+    {
+        let bytes_read = hal_driver.read(self.endpoint_out, buffer)?;
+        trace!("MIDI received {} bytes", bytes_read);
+        Ok(EventPackets::new(&buffer[..bytes_read]))
+    }
+}
+
+// - descriptor constants -----------------------------------------------------
+
+const MIDI_STREAMING_DESCRIPTORS_LENGTH: usize = midi_streaming_descriptors_length(1);
+
+const AUDIO_CONTROL_HEADER: [u8; 9] = audio_control_header(1);
+const MIDI_STREAMING_DESCRIPTORS: [u8; MIDI_STREAMING_DESCRIPTORS_LENGTH] =
+    midi_streaming_descriptors(1);
+const ENDPOINT_OUT_DESCRIPTOR: [u8; 5] = endpoint_out_descriptor();
+const ENDPOINT_IN_DESCRIPTOR: [u8; 5] = endpoint_in_descriptor();
+
+pub const DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
+    descriptor_version: 0x0200,
+    device_class: 0x00,    // Defined at interface level
+    device_subclass: 0x00, // Defined at interface level
+    device_protocol: 0x00, // Defined at interface level
+    max_packet_size: 64,
+    vendor_id: 0x1209,             // pid.codes
+    product_id: 0x0003,            // pid.codes test PID
+    device_version_number: 0x0040, // Cynthion r04
+    manufacturer_string_index: 1,
+    product_string_index: 2,
+    serial_string_index: 3,
+    num_configurations: 1,
+    ..DeviceDescriptor::new()
+};
+
+pub const DEVICE_QUALIFIER_DESCRIPTOR: DeviceQualifierDescriptor = DeviceQualifierDescriptor {
+    descriptor_version: 0x0200,
+    device_class: 0x00,
+    device_subclass: 0x00,
+    device_protocol: 0x00,
+    max_packet_size: 64,
+    num_configurations: 1,
+    ..DeviceQualifierDescriptor::new()
+};
+
+pub const CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDescriptor::new(
+    ConfigurationDescriptorHeader {
+        descriptor_type: DescriptorType::Configuration as u8,
+        configuration_value: 1,
+        configuration_string_index: 1,
+        attributes: 0x80, // 0b1000_0000 = bus-powered
+        max_power: 50,    // 50 * 2 mA = 100 mA
+        ..ConfigurationDescriptorHeader::new()
+    },
+    &[
+        InterfaceDescriptor::new(
+            InterfaceDescriptorHeader {
+                interface_number: 0,
+                alternate_setting: 0,
+                interface_class: CLASS,
+                interface_subclass: SUBCLASS_AUDIO_CONTROL,
+                interface_protocol: 0x00,
+                interface_string_index: 0,
+                ..InterfaceDescriptorHeader::new()
+            },
+            &[],
+        )
+        .with_class_descriptor(&AUDIO_CONTROL_HEADER),
+        InterfaceDescriptor::new(
+            InterfaceDescriptorHeader {
+                interface_number: 1,
+                alternate_setting: 0,
+                interface_class: CLASS,
+                interface_subclass: SUBCLASS_MIDI_STREAMING,
+                interface_protocol: 0x00,
+                interface_string_index: 2,
+                ..InterfaceDescriptorHeader::new()
+            },
+            &[
+                EndpointDescriptor {
+                    endpoint_address: 0x01, // OUT
+                    attributes: 0x02,       // Bulk
+                    max_packet_size: 512,
+                    interval: 0,
+                    ..EndpointDescriptor::new()
+                },
+                EndpointDescriptor {
+                    endpoint_address: 0x81, // IN
+                    attributes: 0x02,       // Bulk
+                    max_packet_size: 512,
+                    interval: 0,
+                    ..EndpointDescriptor::new()
+                },
+            ],
+        )
+        .with_class_descriptor(&MIDI_STREAMING_DESCRIPTORS)
+        .with_endpoint_class_descriptors(&[&ENDPOINT_OUT_DESCRIPTOR, &ENDPOINT_IN_DESCRIPTOR]),
+    ],
+);
+
+pub const OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
+    ConfigurationDescriptor::new(
+        ConfigurationDescriptorHeader {
+            descriptor_type: DescriptorType::OtherSpeedConfiguration as u8,
+            configuration_value: 1,
+            configuration_string_index: 1,
+            attributes: 0x80, // 0b1000_0000 = bus-powered
+            max_power: 50,    // 50 * 2 mA = 100 mA
+            ..ConfigurationDescriptorHeader::new()
+        },
+        &[
+            InterfaceDescriptor::new(
+                InterfaceDescriptorHeader {
+                    interface_number: 0,
+                    alternate_setting: 0,
+                    interface_class: CLASS,
+                    interface_subclass: SUBCLASS_AUDIO_CONTROL,
+                    interface_protocol: 0x00,
+                    interface_string_index: 0,
+                    ..InterfaceDescriptorHeader::new()
+                },
+                &[],
+            )
+            .with_class_descriptor(&AUDIO_CONTROL_HEADER),
+            InterfaceDescriptor::new(
+                InterfaceDescriptorHeader {
+                    interface_number: 1,
+                    alternate_setting: 0,
+                    interface_class: CLASS,
+                    interface_subclass: SUBCLASS_MIDI_STREAMING,
+                    interface_protocol: 0x00,
+                    interface_string_index: 2,
+                    ..InterfaceDescriptorHeader::new()
+                },
+                &[
+                    EndpointDescriptor {
+                        endpoint_address: 0x01, // OUT
+                        attributes: 0x02,       // Bulk
+                        max_packet_size: 64,
+                        interval: 0,
+                        ..EndpointDescriptor::new()
+                    },
+                    EndpointDescriptor {
+                        endpoint_address: 0x81, // IN
+                        attributes: 0x02,       // Bulk
+                        max_packet_size: 64,
+                        interval: 0,
+                        ..EndpointDescriptor::new()
+                    },
+                ],
+            )
+            .with_class_descriptor(&MIDI_STREAMING_DESCRIPTORS)
+            .with_endpoint_class_descriptors(&[&ENDPOINT_OUT_DESCRIPTOR, &ENDPOINT_IN_DESCRIPTOR]),
+        ],
+    );
+
+pub const USB_STRING_DESCRIPTOR_0: StringDescriptorZero =
+    StringDescriptorZero::new(&[LanguageId::EnglishUnitedStates]);
+
+pub const USB_STRING_DESCRIPTOR_1: StringDescriptor = StringDescriptor::new("Great Scott Gadgets");
+pub const USB_STRING_DESCRIPTOR_2: StringDescriptor = StringDescriptor::new("Cynthion MIDI");
+pub const USB_STRING_DESCRIPTOR_3: StringDescriptor = StringDescriptor::new("040");
+
+pub const USB_STRING_DESCRIPTORS: &[&StringDescriptor] = &[
+    &USB_STRING_DESCRIPTOR_1,
+    &USB_STRING_DESCRIPTOR_2,
+    &USB_STRING_DESCRIPTOR_3,
+];
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mock::MockUsbDriver;
+
+    use std::vec::Vec;
+
+    // - fixtures -------------------------------------------------------------
+
+    const SYSEX_IDENTITY_REQUEST: [u8; 6] = [0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7];
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_configuration_descriptor() {
+        let mut configuration = CONFIGURATION_DESCRIPTOR_0;
+        let total_length = configuration.set_total_length();
+        let bytes: Vec<u8> = configuration.iter().copied().collect();
+
+        assert_eq!(total_length, 9 + (9 + 9) + (9 + 7 + 30) + (7 + 5) * 2);
+        assert_eq!(bytes.len(), total_length);
+
+        // walk the descriptors
+        let mut offset = 0;
+        let mut types = Vec::new();
+        while offset < bytes.len() {
+            types.push(bytes[offset + 1]);
+            offset += bytes[offset] as usize;
+        }
+        assert_eq!(offset, bytes.len());
+        assert_eq!(
+            types,
+            [0x02, 0x04, 0x24, 0x04, 0x24, 0x24, 0x24, 0x24, 0x24, 0x05, 0x25, 0x05, 0x25]
+        );
+    }
+
+    #[test]
+    fn test_midi_streaming_descriptors() {
+        let bytes: [u8; midi_streaming_descriptors_length(2)] = midi_streaming_descriptors(2);
+        assert_eq!(&bytes[..7], &[0x07, 0x24, 0x01, 0x00, 0x01, 67, 0x00]);
+
+        // second cable's external OUT jack is sourced from its embedded IN jack
+        let out_jack = &bytes[bytes.len() - 9..];
+        assert_eq!(out_jack, &[0x09, 0x24, 0x03, 0x02, 8, 1, 5, 1, 0]);
+
+        let endpoint: [u8; 6] = endpoint_in_descriptor();
+        assert_eq!(endpoint, [0x06, 0x25, 0x01, 2, 3, 7]);
+    }
+
+    #[test]
+    fn test_event_packet() {
+        let packet = EventPacket::from_message(1, &[0x90, 0x3c, 0x7f]).unwrap();
+        assert_eq!(packet.code_index, CodeIndex::NoteOn);
+        assert_eq!(packet.to_bytes(), [0x19, 0x90, 0x3c, 0x7f]);
+        assert_eq!(EventPacket::from(packet.to_bytes()), packet);
+
+        let packet = EventPacket::from_message(0, &[0xc2, 0x05]).unwrap();
+        assert_eq!(packet.to_bytes(), [0x0c, 0xc2, 0x05, 0x00]);
+        assert_eq!(packet.payload(), &[0xc2, 0x05]);
+
+        let packet = EventPacket::from_message(15, &[0xf8]).unwrap();
+        assert_eq!(packet.to_bytes(), [0xf5, 0xf8, 0x00, 0x00]);
+
+        assert!(EventPacket::from_message(0, &[0x90, 0x3c]).is_err());
+        assert!(EventPacket::from_message(0, &[0xf0, 0x01, 0xf7]).is_err());
+        assert!(EventPacket::from_message(0, &[0x3c]).is_err());
+    }
+
+    #[test]
+    fn test_sysex_segmentation() {
+        let packets: Vec<[u8; 4]> = SysExPackets::new(2, &SYSEX_IDENTITY_REQUEST)
+            .unwrap()
+            .map(|packet| packet.to_bytes())
+            .collect();
+        assert_eq!(
+            packets,
+            [[0x24, 0xf0, 0x7e, 0x7f], [0x27, 0x06, 0x01, 0xf7]]
+        );
+
+        let packets: Vec<[u8; 4]> = SysExPackets::new(0, &[0xf0, 0x01, 0x02, 0xf7])
+            .unwrap()
+            .map(|packet| packet.to_bytes())
+            .collect();
+        assert_eq!(packets, [[0x04, 0xf0, 0x01, 0x02], [0x05, 0xf7, 0x00, 0x00]]);
+
+        let packets: Vec<[u8; 4]> = SysExPackets::new(0, &[0xf0, 0xf7])
+            .unwrap()
+            .map(|packet| packet.to_bytes())
+            .collect();
+        assert_eq!(packets, [[0x06, 0xf0, 0xf7, 0x00]]);
+
+        assert!(SysExPackets::new(0, &[0xf0, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_sysex_reassembly() {
+        let mut receiver: SysExReceiver<16> = SysExReceiver::new();
+        let mut packets = SysExPackets::new(0, &SYSEX_IDENTITY_REQUEST).unwrap();
+
+        assert_eq!(receiver.push(&packets.next().unwrap()), None);
+        assert_eq!(
+            receiver.push(&packets.next().unwrap()),
+            Some(&SYSEX_IDENTITY_REQUEST[..])
+        );
+
+        // oversized messages are discarded
+        let mut receiver: SysExReceiver<4> = SysExReceiver::new();
+        let mut result = None;
+        for packet in SysExPackets::new(0, &SYSEX_IDENTITY_REQUEST).unwrap() {
+            result = receiver.push(&packet).map(|message| message.to_vec());
+        }
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn test_bulk_endpoints() {
+        let driver = MockUsbDriver::new();
+        let midi = Midi::new(0x01, 0x81, 8);
+
+        midi.send(&driver, 0, &[0x80, 0x3c, 0x00]).unwrap();
+        midi.send_sysex(&driver, 1, &[0xf0, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0xf7])
+            .unwrap();
+        assert_eq!(
+            driver.take_writes(),
+            [
+                (1, vec![0x08, 0x80, 0x3c, 0x00]),
+                (1, vec![0x14, 0xf0, 0x01, 0x02, 0x14, 0x03, 0x04, 0x05]),
+                (1, vec![0x17, 0x06, 0x07, 0xf7]),
+            ]
+        );
+
+        driver.push_packet(1, &[0x09, 0x90, 0x3c, 0x7f, 0x00, 0x00, 0x00, 0x00, 0x1b, 0xb0, 0x07, 0x64]);
+        let mut buffer = [0; 64];
+        let packets: Vec<EventPacket> = midi.read_packets(&driver, &mut buffer).unwrap().collect();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].payload(), &[0x90, 0x3c, 0x7f]);
+        assert_eq!(packets[1].cable_number, 1);
+        assert_eq!(packets[1].code_index, CodeIndex::ControlChange);
+    }
+}
@@ -0,0 +1,1227 @@
+//! USB CDC Network Control Model (NCM) class
+//!
+//! An NCM function consists of a Communications Class control
+//! interface with an interrupt IN notification endpoint and a Data
+//! Class interface whose bulk endpoints carry Ethernet frames packed
+//! into NCM Transfer Blocks (NTBs).
+//!
+//! Only the 16-bit NTB format is supported.
+//!
+//! See: "Universal Serial Bus Communications Class Subclass
+//! Specification for Network Control Model Devices" Revision 1.0
+
+use crate::control::SetupPacket;
+use crate::descriptor::*;
+use crate::error::{SmolError, SmolResult};
+use crate::traits::{AsByteSliceIterator, EndpointWrite, UsbDriverOperations};
+
+use zerocopy::{AsBytes, FromBytes};
+
+use log::{debug, trace, warn};
+
+use core::mem::size_of;
+
+// - constants ----------------------------------------------------------------
+
+/// Communications interface class code
+pub const CLASS: u8 = 0x02;
+/// Network Control Model subclass code
+pub const SUBCLASS: u8 = 0x0d;
+/// No class-specific protocol required
+pub const PROTOCOL: u8 = 0x00;
+
+/// Data interface class code
+pub const DATA_CLASS: u8 = 0x0a;
+/// Network Transfer Block protocol code
+pub const DATA_PROTOCOL: u8 = 0x01;
+
+/// Class-specific interface descriptor type
+pub const DESCRIPTOR_TYPE_CS_INTERFACE: u8 = 0x24;
+
+/// Class-specific functional descriptor subtypes
+#[allow(non_snake_case, non_upper_case_globals)]
+pub mod DescriptorSubtype {
+    pub const Header: u8 = 0x00;
+    pub const Union: u8 = 0x06;
+    pub const EthernetNetworking: u8 = 0x0f;
+    pub const Ncm: u8 = 0x1a;
+}
+
+/// Maximum size of an NTB the host may send us, and that we may send the host.
+///
+/// 2048 bytes is the minimum the specification allows.
+pub const NTB_MAX_SIZE: u32 = 2048;
+
+/// Maximum Ethernet frame size, excluding the FCS
+pub const MAX_SEGMENT_SIZE: u16 = 1514;
+
+/// NTB header signature
+pub const NTH16_SIGNATURE: [u8; 4] = *b"NCMH";
+/// NTB datagram pointer table signature, without CRC
+pub const NDP16_SIGNATURE: [u8; 4] = *b"NCM0";
+
+const NTH16_LENGTH: usize = 12;
+const NDP16_HEADER_LENGTH: usize = 8;
+const NDP16_ENTRY_LENGTH: usize = 4;
+
+/// Alignment of datagrams and datagram pointer tables in the NTBs we build
+const NTB_ALIGNMENT: usize = 4;
+
+// - Request ------------------------------------------------------------------
+
+/// NCM class-specific requests
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[repr(u8)]
+pub enum Request {
+    SendEncapsulatedCommand = 0x00,
+    GetEncapsulatedResponse = 0x01,
+    SetEthernetMulticastFilters = 0x40,
+    SetEthernetPowerManagementPatternFilter = 0x41,
+    GetEthernetPowerManagementPatternFilter = 0x42,
+    SetEthernetPacketFilter = 0x43,
+    GetEthernetStatistic = 0x44,
+    GetNtbParameters = 0x80,
+    GetNetAddress = 0x81,
+    SetNetAddress = 0x82,
+    GetNtbFormat = 0x83,
+    SetNtbFormat = 0x84,
+    GetNtbInputSize = 0x85,
+    SetNtbInputSize = 0x86,
+    GetMaxDatagramSize = 0x87,
+    SetMaxDatagramSize = 0x88,
+    GetCrcMode = 0x89,
+    SetCrcMode = 0x8a,
+}
+
+impl TryFrom<u8> for Request {
+    type Error = SmolError;
+
+    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
+        let result = match value {
+            0x00 => Request::SendEncapsulatedCommand,
+            0x01 => Request::GetEncapsulatedResponse,
+            0x40 => Request::SetEthernetMulticastFilters,
+            0x41 => Request::SetEthernetPowerManagementPatternFilter,
+            0x42 => Request::GetEthernetPowerManagementPatternFilter,
+            0x43 => Request::SetEthernetPacketFilter,
+            0x44 => Request::GetEthernetStatistic,
+            0x80 => Request::GetNtbParameters,
+            0x81 => Request::GetNetAddress,
+            0x82 => Request::SetNetAddress,
+            0x83 => Request::GetNtbFormat,
+            0x84 => Request::SetNtbFormat,
+            0x85 => Request::GetNtbInputSize,
+            0x86 => Request::SetNtbInputSize,
+            0x87 => Request::GetMaxDatagramSize,
+            0x88 => Request::SetMaxDatagramSize,
+            0x89 => Request::GetCrcMode,
+            0x8a => Request::SetCrcMode,
+            _ => return Err(SmolError::FailedConversion),
+        };
+        Ok(result)
+    }
+}
+
+// - Notification -------------------------------------------------------------
+
+/// NCM class-specific notifications
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[repr(u8)]
+pub enum Notification {
+    NetworkConnection = 0x00,
+    ResponseAvailable = 0x01,
+    ConnectionSpeedChange = 0x2a,
+}
+
+/// Notification header sent on the interrupt IN endpoint
+#[derive(AsBytes, FromBytes, Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct NotificationHeader {
+    pub request_type: u8, // 0xa1 = device to host, class, interface
+    pub notification: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl AsByteSliceIterator for NotificationHeader {}
+
+impl NotificationHeader {
+    pub const fn new(notification: Notification, value: u16, index: u16, length: u16) -> Self {
+        Self {
+            request_type: 0xa1,
+            notification: notification as u8,
+            value,
+            index,
+            length,
+        }
+    }
+}
+
+// - NtbParameters ------------------------------------------------------------
+
+/// Response to `GET_NTB_PARAMETERS`
+#[derive(AsBytes, FromBytes, Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct NtbParameters {
+    pub _length: u16, // 28
+    pub ntb_formats_supported: u16,
+    pub ntb_in_max_size: u32,
+    pub ndp_in_divisor: u16,
+    pub ndp_in_payload_remainder: u16,
+    pub ndp_in_alignment: u16,
+    pub _reserved: u16,
+    pub ntb_out_max_size: u32,
+    pub ndp_out_divisor: u16,
+    pub ndp_out_payload_remainder: u16,
+    pub ndp_out_alignment: u16,
+    pub ntb_out_max_datagrams: u16,
+}
+
+impl AsByteSliceIterator for NtbParameters {}
+
+impl NtbParameters {
+    pub const fn new() -> Self {
+        Self {
+            _length: size_of::<Self>() as u16,
+            ntb_formats_supported: 0x0001, // 16-bit NTBs only
+            ntb_in_max_size: NTB_MAX_SIZE,
+            ndp_in_divisor: NTB_ALIGNMENT as u16,
+            ndp_in_payload_remainder: 0,
+            ndp_in_alignment: NTB_ALIGNMENT as u16,
+            _reserved: 0,
+            ntb_out_max_size: NTB_MAX_SIZE,
+            ndp_out_divisor: NTB_ALIGNMENT as u16,
+            ndp_out_payload_remainder: 0,
+            ndp_out_alignment: NTB_ALIGNMENT as u16,
+            ntb_out_max_datagrams: 0, // no limit
+        }
+    }
+}
+
+impl Default for NtbParameters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// - functional descriptors ---------------------------------------------------
+
+/// Length of the functional descriptors returned by [`functional_descriptors`]
+pub const FUNCTIONAL_DESCRIPTORS_LENGTH: usize = 5 + 5 + 13 + 6;
+
+/// CDC Header, Union, Ethernet Networking and NCM functional
+/// descriptors for the control interface.
+///
+/// `mac_address_string_index` is the index of a string descriptor
+/// containing the host-side MAC address as 12 hexadecimal digits.
+pub const fn functional_descriptors(
+    control_interface: u8,
+    data_interface: u8,
+    mac_address_string_index: u8,
+) -> [u8; FUNCTIONAL_DESCRIPTORS_LENGTH] {
+    let max_segment_size = MAX_SEGMENT_SIZE.to_le_bytes();
+    [
+        // header
+        5,                            // bFunctionLength
+        DESCRIPTOR_TYPE_CS_INTERFACE, // bDescriptorType
+        DescriptorSubtype::Header,    // bDescriptorSubtype
+        0x20,                         // bcdCDC
+        0x01,
+        // union
+        5,                            // bFunctionLength
+        DESCRIPTOR_TYPE_CS_INTERFACE, // bDescriptorType
+        DescriptorSubtype::Union,     // bDescriptorSubtype
+        control_interface,            // bControlInterface
+        data_interface,               // bSubordinateInterface0
+        // ethernet networking
+        13,                                   // bFunctionLength
+        DESCRIPTOR_TYPE_CS_INTERFACE,         // bDescriptorType
+        DescriptorSubtype::EthernetNetworking, // bDescriptorSubtype
+        mac_address_string_index,             // iMACAddress
+        0x00,                                 // bmEthernetStatistics
+        0x00,
+        0x00,
+        0x00,
+        max_segment_size[0], // wMaxSegmentSize
+        max_segment_size[1],
+        0x00, // wNumberMCFilters
+        0x00,
+        0x00, // bNumberPowerFilters
+        // ncm
+        6,                            // bFunctionLength
+        DESCRIPTOR_TYPE_CS_INTERFACE, // bDescriptorType
+        DescriptorSubtype::Ncm,       // bDescriptorSubtype
+        0x00,                         // bcdNcmVersion
+        0x01,
+        0x00, // bmNetworkCapabilities
+    ]
+}
+
+// - Ntb ----------------------------------------------------------------------
+
+/// A received 16-bit NCM Transfer Block
+pub struct Ntb<'a> {
+    bytes: &'a [u8],
+    pub sequence: u16,
+    ndp_index: usize,
+}
+
+impl<'a> Ntb<'a> {
+    /// Parse and validate the NTB header.
+    pub fn parse(bytes: &'a [u8]) -> SmolResult<Self> {
+        if bytes.len() < NTH16_LENGTH || bytes[0..4] != NTH16_SIGNATURE {
+            return Err(SmolError::FailedConversion);
+        }
+        let header_length = read_u16(bytes, 4);
+        let sequence = read_u16(bytes, 6) as u16;
+        let block_length = read_u16(bytes, 8);
+        let ndp_index = read_u16(bytes, 10);
+
+        if header_length != NTH16_LENGTH || block_length > bytes.len() || ndp_index < NTH16_LENGTH
+        {
+            warn!(
+                "NCM invalid NTB header: length:{} block_length:{} ndp_index:{}",
+                header_length, block_length, ndp_index
+            );
+            return Err(SmolError::FailedConversion);
+        }
+
+        Ok(Self {
+            bytes: &bytes[..block_length],
+            sequence,
+            ndp_index,
+        })
+    }
+
+    /// Returns an iterator over the Ethernet frames in the NTB.
+    pub fn datagrams(&self) -> Datagrams<'a> {
+        Datagrams {
+            bytes: self.bytes,
+            ndp_index: self.ndp_index,
+            entry: 0,
+        }
+    }
+}
+
+/// Iterator over the datagrams of an NTB
+///
+/// Iteration stops at the first malformed datagram pointer table.
+pub struct Datagrams<'a> {
+    bytes: &'a [u8],
+    ndp_index: usize,
+    entry: usize,
+}
+
+impl<'a> Iterator for Datagrams<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.ndp_index == 0 {
+                return None;
+            }
+
+            let ndp = self.ndp_index;
+            if ndp + NDP16_HEADER_LENGTH > self.bytes.len() || self.bytes[ndp..ndp + 4] != NDP16_SIGNATURE
+            {
+                warn!("NCM invalid datagram pointer table at {}", ndp);
+                self.ndp_index = 0;
+                return None;
+            }
+            let ndp_length = read_u16(self.bytes, ndp + 4);
+            let next_ndp_index = read_u16(self.bytes, ndp + 6);
+
+            let entry = ndp + NDP16_HEADER_LENGTH + self.entry * NDP16_ENTRY_LENGTH;
+            if entry + NDP16_ENTRY_LENGTH > ndp + ndp_length
+                || entry + NDP16_ENTRY_LENGTH > self.bytes.len()
+            {
+                warn!("NCM datagram pointer table at {} is not terminated", ndp);
+                self.ndp_index = 0;
+                return None;
+            }
+            let index = read_u16(self.bytes, entry);
+            let length = read_u16(self.bytes, entry + 2);
+
+            // end of table, move on to the next one
+            if index == 0 || length == 0 {
+                // tables must follow each other or the host could make us loop forever
+                if next_ndp_index != 0 && next_ndp_index <= ndp {
+                    warn!(
+                        "NCM datagram pointer table at {} links back to {}",
+                        ndp, next_ndp_index
+                    );
+                    self.ndp_index = 0;
+                    return None;
+                }
+                self.ndp_index = next_ndp_index;
+                self.entry = 0;
+                continue;
+            }
+
+            if index + length > self.bytes.len() {
+                warn!("NCM datagram at {} with length {} is out of bounds", index, length);
+                self.ndp_index = 0;
+                return None;
+            }
+
+            self.entry += 1;
+            return Some(&self.bytes[index..index + length]);
+        }
+    }
+}
+
+// - NtbBuilder ---------------------------------------------------------------
+
+/// Builds a 16-bit NTB in a caller-provided buffer
+///
+/// `N` is the maximum number of datagrams the NTB can hold.
+pub struct NtbBuilder<'a, const N: usize> {
+    buffer: &'a mut [u8],
+    sequence: u16,
+    offset: usize,
+    datagrams: heapless::Vec<(u16, u16), N>,
+}
+
+impl<'a, const N: usize> NtbBuilder<'a, N> {
+    pub fn new(buffer: &'a mut [u8], sequence: u16) -> Self {
+        Self {
+            buffer,
+            sequence,
+            offset: NTH16_LENGTH,
+            datagrams: heapless::Vec::new(),
+        }
+    }
+
+    /// Number of datagrams in the NTB.
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// Returns the space available for the next datagram.
+    ///
+    /// Write the datagram into the returned buffer and then call
+    /// [`NtbBuilder::commit`] with its length.
+    pub fn datagram_buffer(&mut self) -> &mut [u8] {
+        let start = align(self.offset);
+        let end = self.buffer.len().saturating_sub(self.ndp_length(self.datagrams.len() + 1));
+        if start >= end || self.datagrams.is_full() {
+            return &mut [];
+        }
+        &mut self.buffer[start..end]
+    }
+
+    /// Add the datagram written to [`NtbBuilder::datagram_buffer`] to the NTB.
+    pub fn commit(&mut self, length: usize) -> SmolResult<()> {
+        let available = self.datagram_buffer().len();
+        if length == 0 || length > available {
+            return Err(SmolError::FailedConversion);
+        }
+        let start = align(self.offset);
+        self.datagrams
+            .push((start as u16, length as u16))
+            .map_err(|_| SmolError::FailedConversion)?;
+        self.offset = start + length;
+        Ok(())
+    }
+
+    /// Copy a datagram into the NTB.
+    pub fn push(&mut self, datagram: &[u8]) -> SmolResult<()> {
+        let buffer = self.datagram_buffer();
+        if datagram.len() > buffer.len() {
+            return Err(SmolError::FailedConversion);
+        }
+        buffer[..datagram.len()].copy_from_slice(datagram);
+        self.commit(datagram.len())
+    }
+
+    /// Write the NTB header and datagram pointer table, returning the
+    /// completed NTB.
+    pub fn finish(self) -> &'a [u8] {
+        let ndp_index = align(self.offset);
+        let ndp_length = self.ndp_length(self.datagrams.len());
+        let block_length = ndp_index + ndp_length;
+        let buffer = self.buffer;
+
+        // padding
+        buffer[self.offset..ndp_index].fill(0);
+
+        // datagram pointer table
+        buffer[ndp_index..ndp_index + 4].copy_from_slice(&NDP16_SIGNATURE);
+        write_u16(buffer, ndp_index + 4, ndp_length as u16);
+        write_u16(buffer, ndp_index + 6, 0); // wNextNdpIndex
+        let mut entry = ndp_index + NDP16_HEADER_LENGTH;
+        for (index, length) in self.datagrams.iter().chain([(0, 0)].iter()) {
+            write_u16(buffer, entry, *index);
+            write_u16(buffer, entry + 2, *length);
+            entry += NDP16_ENTRY_LENGTH;
+        }
+
+        // header
+        buffer[0..4].copy_from_slice(&NTH16_SIGNATURE);
+        write_u16(buffer, 4, NTH16_LENGTH as u16);
+        write_u16(buffer, 6, self.sequence);
+        write_u16(buffer, 8, block_length as u16);
+        write_u16(buffer, 10, ndp_index as u16);
+
+        &buffer[..block_length]
+    }
+
+    fn ndp_length(&self, datagrams: usize) -> usize {
+        // include the null terminator
+        NDP16_HEADER_LENGTH + (datagrams + 1) * NDP16_ENTRY_LENGTH
+    }
+}
+
+// - NtbReceiver --------------------------------------------------------------
+
+/// Reassembles NTBs from bulk OUT packets
+pub struct NtbReceiver<const N: usize> {
+    buffer: [u8; N],
+    length: usize,
+}
+
+impl<const N: usize> NtbReceiver<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            length: 0,
+        }
+    }
+
+    /// Discard any partially received NTB.
+    pub fn reset(&mut self) {
+        self.length = 0;
+    }
+
+    /// Add a packet received on the bulk OUT endpoint, returning the
+    /// completed NTB once its final packet has been received.
+    ///
+    /// `short_packet` must be set when the packet was shorter than the
+    /// endpoint's maximum packet size.
+    pub fn push(&mut self, packet: &[u8], short_packet: bool) -> Option<&[u8]> {
+        if self.length + packet.len() > N {
+            warn!("NCM discarding NTB longer than {} bytes", N);
+            self.length = 0;
+            return None;
+        }
+        self.buffer[self.length..self.length + packet.len()].copy_from_slice(packet);
+        self.length += packet.len();
+
+        let block_length = if self.length >= NTH16_LENGTH {
+            read_u16(&self.buffer, 8)
+        } else {
+            N
+        };
+
+        if self.length >= block_length || short_packet {
+            let length = self.length;
+            self.length = 0;
+            trace!("NCM received NTB of {} bytes", length);
+            return Some(&self.buffer[..length]);
+        }
+
+        None
+    }
+}
+
+impl<const N: usize> Default for NtbReceiver<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// - Ncm ----------------------------------------------------------------------
+
+/// Control interface of an NCM function
+pub struct Ncm {
+    /// Control interface number
+    pub control_interface: u8,
+    /// Data interface number
+    pub data_interface: u8,
+    /// Interrupt IN notification endpoint number
+    pub endpoint_notify: u8,
+    /// Bulk IN endpoint number
+    pub endpoint_in: u8,
+
+    ntb_input_size: u32,
+    alternate_setting: u8,
+    sequence: u16,
+    /// Connection state to report once the pending notification completes
+    pending_connection: Option<bool>,
+}
+
+impl Ncm {
+    pub const fn new(
+        control_interface: u8,
+        data_interface: u8,
+        endpoint_notify: u8,
+        endpoint_in: u8,
+    ) -> Self {
+        Self {
+            control_interface,
+            data_interface,
+            endpoint_notify: endpoint_notify & 0x0f,
+            endpoint_in: endpoint_in & 0x0f,
+            ntb_input_size: NTB_MAX_SIZE,
+            alternate_setting: 0,
+            sequence: 0,
+            pending_connection: None,
+        }
+    }
+
+    /// Returns true once the host has selected the data interface's
+    /// active alternate setting.
+    pub fn is_active(&self) -> bool {
+        self.alternate_setting == 1
+    }
+
+    /// Maximum NTB size the host is prepared to receive.
+    pub fn ntb_input_size(&self) -> u32 {
+        self.ntb_input_size
+    }
+
+    /// Returns the sequence number for the next NTB we send.
+    pub fn next_sequence(&mut self) -> u16 {
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+        sequence
+    }
+
+    /// Handle a USB bus reset.
+    pub fn handle_bus_reset(&mut self) {
+        self.alternate_setting = 0;
+        self.ntb_input_size = NTB_MAX_SIZE;
+        self.sequence = 0;
+        self.pending_connection = None;
+    }
+
+    /// Handle a class request addressed to the control interface.
+    pub fn handle_setup_request<D>(&mut self, hal_driver: &D, setup_packet: &SetupPacket) -> SmolResult<()>
+    where
+        D: EndpointWrite + UsbDriverOperations,
+    {
+        let request = match Request::try_from(setup_packet.request) {
+            Ok(request) => request,
+            Err(_) => {
+                warn!("NCM stall: unknown request {}", setup_packet.request);
+                hal_driver.stall_request();
+                return Ok(());
+            }
+        };
+
+        debug!("NCM {:?}", request);
+
+        let length = setup_packet.length as usize;
+        match request {
+            Request::GetNtbParameters => {
+                let parameters = NtbParameters::new();
+                hal_driver.write(0, parameters.as_iter().copied().take(length))?;
+                hal_driver.ack_status_stage(setup_packet);
+            }
+            Request::GetNtbInputSize => {
+                let bytes = self.ntb_input_size.to_le_bytes();
+                hal_driver.write(0, bytes.into_iter().take(length))?;
+                hal_driver.ack_status_stage(setup_packet);
+            }
+            Request::GetNtbFormat => {
+                hal_driver.write(0, [0, 0].into_iter().take(length))?;
+                hal_driver.ack_status_stage(setup_packet);
+            }
+            Request::GetMaxDatagramSize => {
+                let bytes = MAX_SEGMENT_SIZE.to_le_bytes();
+                hal_driver.write(0, bytes.into_iter().take(length))?;
+                hal_driver.ack_status_stage(setup_packet);
+            }
+            Request::SetEthernetPacketFilter => {
+                trace!("NCM packet filter: 0b{:b}", setup_packet.value);
+                hal_driver.ack_status_stage(setup_packet);
+            }
+            // requests with a data stage are passed to handle_receive_control_data
+            Request::SetNtbInputSize => {
+                hal_driver.ack_status_stage(setup_packet);
+            }
+            Request::SetNtbFormat if setup_packet.value == 0 => {
+                hal_driver.ack_status_stage(setup_packet);
+            }
+            _ => {
+                warn!("NCM stall: unsupported request {:?}", request);
+                hal_driver.stall_request();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle data received during the data stage of a class request.
+    pub fn handle_receive_control_data(&mut self, setup_packet: &SetupPacket, data: &[u8]) {
+        if let Ok(Request::SetNtbInputSize) = Request::try_from(setup_packet.request) {
+            if data.len() >= 4 {
+                let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                self.ntb_input_size = u32::min(size, NTB_MAX_SIZE);
+                debug!("NCM ntb input size: {}", self.ntb_input_size);
+            }
+        }
+    }
+
+    /// Handle a `SET_INTERFACE` request, notifying the host of the
+    /// connection state when the data interface is activated.
+    pub fn handle_set_interface<D>(&mut self, hal_driver: &D, setup_packet: &SetupPacket) -> SmolResult<()>
+    where
+        D: EndpointWrite,
+    {
+        if setup_packet.index as u8 != self.data_interface {
+            return Ok(());
+        }
+        self.alternate_setting = setup_packet.value as u8;
+        debug!("NCM data interface alternate setting: {}", self.alternate_setting);
+
+        if self.is_active() {
+            self.sequence = 0;
+            self.notify_connection(hal_driver, true, 480_000_000)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send connection speed and network connection notifications.
+    ///
+    /// The network connection notification follows the connection
+    /// speed notification once `handle_transfer_complete` is called
+    /// for the notification endpoint.
+    pub fn notify_connection<D>(&mut self, hal_driver: &D, connected: bool, bitrate: u32) -> SmolResult<()>
+    where
+        D: EndpointWrite,
+    {
+        if connected {
+            let header = NotificationHeader::new(
+                Notification::ConnectionSpeedChange,
+                0,
+                self.control_interface as u16,
+                8,
+            );
+            let bitrate = bitrate.to_le_bytes();
+            hal_driver.write(
+                self.endpoint_notify,
+                header
+                    .as_iter()
+                    .copied()
+                    .chain(bitrate)
+                    .chain(bitrate),
+            )?;
+            self.pending_connection = Some(connected);
+            Ok(())
+        } else {
+            self.pending_connection = None;
+            self.write_network_connection(hal_driver, connected)
+        }
+    }
+
+    /// Handle completion of an IN transfer, sending any pending
+    /// notification.
+    pub fn handle_transfer_complete<D>(&mut self, hal_driver: &D, endpoint: u8) -> SmolResult<()>
+    where
+        D: EndpointWrite,
+    {
+        if endpoint & 0x0f != self.endpoint_notify {
+            return Ok(());
+        }
+        match self.pending_connection.take() {
+            Some(connected) => self.write_network_connection(hal_driver, connected),
+            None => Ok(()),
+        }
+    }
+
+    fn write_network_connection<D>(&self, hal_driver: &D, connected: bool) -> SmolResult<()>
+    where
+        D: EndpointWrite,
+    {
+        let header = NotificationHeader::new(
+            Notification::NetworkConnection,
+            connected as u16,
+            self.control_interface as u16,
+            0,
+        );
+        hal_driver.write(self.endpoint_notify, header.as_iter())?;
+        Ok(())
+    }
+
+    /// Send an NTB on the bulk IN endpoint, returning the number of
+    /// bytes written.
+    ///
+    /// NTBs that fill their last packet are ended with a zero length
+    /// packet, `max_packet_size` is that of the bulk IN endpoint at the
+    /// current bus speed.
+    ///
+    /// Returns [`SmolError::NotConfigured`] until the host has
+    /// activated the data interface, and [`SmolError::Overflow`] if the
+    /// NTB is larger than [`Ncm::ntb_input_size`].
+    pub fn write_ntb<D>(&self, hal_driver: &D, ntb: &[u8], max_packet_size: usize) -> SmolResult<usize>
+    where
+        D: EndpointWrite,
+    {
+        if !self.is_active() {
+            return Err(SmolError::NotConfigured);
+        }
+        if ntb.len() > self.ntb_input_size as usize {
+            warn!(
+                "NCM NTB of {} bytes exceeds host input size {}",
+                ntb.len(),
+                self.ntb_input_size
+            );
+            return Err(SmolError::Overflow);
+        }
+        let bytes_written = hal_driver.write(self.endpoint_in, ntb.iter())?;
+        if !ntb.is_empty() && ntb.len() % usize::max(max_packet_size, 1) == 0 {
+            hal_driver.write(self.endpoint_in, core::iter::empty::<u8>())?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+// - helpers ------------------------------------------------------------------
+
+fn read_u16(bytes: &[u8], offset: usize) -> usize {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]]) as usize
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn align(offset: usize) -> usize {
+    (offset + NTB_ALIGNMENT - 1) & !(NTB_ALIGNMENT - 1)
+}
+
+// - descriptors --------------------------------------------------------------
+
+const FUNCTIONAL_DESCRIPTORS: [u8; FUNCTIONAL_DESCRIPTORS_LENGTH] = functional_descriptors(0, 1, 4);
+
+pub const DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
+    descriptor_version: 0x0200,
+    device_class: CLASS,
+    device_subclass: 0x00,
+    device_protocol: 0x00,
+    max_packet_size: 64,
+    vendor_id: 0x1209,             // pid.codes
+    product_id: 0x0004,            // pid.codes test PID
+    device_version_number: 0x0040, // Cynthion r04
+    manufacturer_string_index: 1,
+    product_string_index: 2,
+    serial_string_index: 3,
+    num_configurations: 1,
+    ..DeviceDescriptor::new()
+};
+
+pub const DEVICE_QUALIFIER_DESCRIPTOR: DeviceQualifierDescriptor = DeviceQualifierDescriptor {
+    descriptor_version: 0x0200,
+    device_class: CLASS,
+    device_subclass: 0x00,
+    device_protocol: 0x00,
+    max_packet_size: 64,
+    num_configurations: 1,
+    ..DeviceQualifierDescriptor::new()
+};
+
+pub const CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDescriptor::new(
+    ConfigurationDescriptorHeader {
+        descriptor_type: DescriptorType::Configuration as u8,
+        configuration_value: 1,
+        configuration_string_index: 1,
+        attributes: 0x80, // 0b1000_0000 = bus-powered
+        max_power: 250,   // 250 * 2 mA = 500 mA
+        ..ConfigurationDescriptorHeader::new()
+    },
+    &[
+        InterfaceDescriptor::new(
+            InterfaceDescriptorHeader {
+                interface_number: 0,
+                alternate_setting: 0,
+                interface_class: CLASS,
+                interface_subclass: SUBCLASS,
+                interface_protocol: PROTOCOL,
+                interface_string_index: 2,
+                ..InterfaceDescriptorHeader::new()
+            },
+            &[EndpointDescriptor {
+                endpoint_address: 0x82, // IN
+                attributes: 0x03,       // Interrupt
+                max_packet_size: 16,
+                interval: 4, // 2^(4-1) * 125us = 1ms
+                ..EndpointDescriptor::new()
+            }],
+        )
+        .with_class_descriptor(&FUNCTIONAL_DESCRIPTORS),
+        InterfaceDescriptor::new(
+            InterfaceDescriptorHeader {
+                interface_number: 1,
+                alternate_setting: 0,
+                interface_class: DATA_CLASS,
+                interface_subclass: 0x00,
+                interface_protocol: DATA_PROTOCOL,
+                interface_string_index: 0,
+                ..InterfaceDescriptorHeader::new()
+            },
+            &[],
+        ),
+        InterfaceDescriptor::new(
+            InterfaceDescriptorHeader {
+                interface_number: 1,
+                alternate_setting: 1,
+                interface_class: DATA_CLASS,
+                interface_subclass: 0x00,
+                interface_protocol: DATA_PROTOCOL,
+                interface_string_index: 0,
+                ..InterfaceDescriptorHeader::new()
+            },
+            &[
+                EndpointDescriptor {
+                    endpoint_address: 0x81, // IN
+                    attributes: 0x02,       // Bulk
+                    max_packet_size: 512,
+                    interval: 0,
+                    ..EndpointDescriptor::new()
+                },
+                EndpointDescriptor {
+                    endpoint_address: 0x01, // OUT
+                    attributes: 0x02,       // Bulk
+                    max_packet_size: 512,
+                    interval: 0,
+                    ..EndpointDescriptor::new()
+                },
+            ],
+        ),
+    ],
+);
+
+pub const OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
+    ConfigurationDescriptor::new(
+        ConfigurationDescriptorHeader {
+            descriptor_type: DescriptorType::OtherSpeedConfiguration as u8,
+            configuration_value: 1,
+            configuration_string_index: 1,
+            attributes: 0x80, // 0b1000_0000 = bus-powered
+            max_power: 250,   // 250 * 2 mA = 500 mA
+            ..ConfigurationDescriptorHeader::new()
+        },
+        &[
+            InterfaceDescriptor::new(
+                InterfaceDescriptorHeader {
+                    interface_number: 0,
+                    alternate_setting: 0,
+                    interface_class: CLASS,
+                    interface_subclass: SUBCLASS,
+                    interface_protocol: PROTOCOL,
+                    interface_string_index: 2,
+                    ..InterfaceDescriptorHeader::new()
+                },
+                &[EndpointDescriptor {
+                    endpoint_address: 0x82, // IN
+                    attributes: 0x03,       // Interrupt
+                    max_packet_size: 16,
+                    interval: 1, // 1ms
+                    ..EndpointDescriptor::new()
+                }],
+            )
+            .with_class_descriptor(&FUNCTIONAL_DESCRIPTORS),
+            InterfaceDescriptor::new(
+                InterfaceDescriptorHeader {
+                    interface_number: 1,
+                    alternate_setting: 0,
+                    interface_class: DATA_CLASS,
+                    interface_subclass: 0x00,
+                    interface_protocol: DATA_PROTOCOL,
+                    interface_string_index: 0,
+                    ..InterfaceDescriptorHeader::new()
+                },
+                &[],
+            ),
+            InterfaceDescriptor::new(
+                InterfaceDescriptorHeader {
+                    interface_number: 1,
+                    alternate_setting: 1,
+                    interface_class: DATA_CLASS,
+                    interface_subclass: 0x00,
+                    interface_protocol: DATA_PROTOCOL,
+                    interface_string_index: 0,
+                    ..InterfaceDescriptorHeader::new()
+                },
+                &[
+                    EndpointDescriptor {
+                        endpoint_address: 0x81, // IN
+                        attributes: 0x02,       // Bulk
+                        max_packet_size: 64,
+                        interval: 0,
+                        ..EndpointDescriptor::new()
+                    },
+                    EndpointDescriptor {
+                        endpoint_address: 0x01, // OUT
+                        attributes: 0x02,       // Bulk
+                        max_packet_size: 64,
+                        interval: 0,
+                        ..EndpointDescriptor::new()
+                    },
+                ],
+            ),
+        ],
+    );
+
+pub const USB_STRING_DESCRIPTOR_0: StringDescriptorZero =
+    StringDescriptorZero::new(&[LanguageId::EnglishUnitedStates]);
+
+pub const USB_STRING_DESCRIPTOR_1: StringDescriptor = StringDescriptor::new("Great Scott Gadgets");
+pub const USB_STRING_DESCRIPTOR_2: StringDescriptor = StringDescriptor::new("Cynthion Network");
+pub const USB_STRING_DESCRIPTOR_3: StringDescriptor = StringDescriptor::new("040");
+/// Host-side MAC address: locally administered, unicast
+pub const USB_STRING_DESCRIPTOR_4: StringDescriptor = StringDescriptor::new("020000c0ffee");
+
+pub const USB_STRING_DESCRIPTORS: &[&StringDescriptor] = &[
+    &USB_STRING_DESCRIPTOR_1,
+    &USB_STRING_DESCRIPTOR_2,
+    &USB_STRING_DESCRIPTOR_3,
+    &USB_STRING_DESCRIPTOR_4,
+];
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::control::Direction;
+    use crate::mock::MockUsbDriver;
+
+    use std::vec::Vec;
+
+    // - fixtures -------------------------------------------------------------
+
+    /// NTB sent by a Linux host containing a single ARP request
+    /// for 10.0.0.1 from 10.0.0.2
+    const NTB_ARP_REQUEST: [u8; 76] = [
+        // NTH16
+        0x4e, 0x43, 0x4d, 0x48, 0x0c, 0x00, 0x01, 0x00, 0x4c, 0x00, 0x38, 0x00,
+        // ethernet
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02, 0x00, 0x00, 0xc0, 0xff, 0xee, 0x08, 0x06,
+        // arp
+        0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01, 0x02, 0x00, 0x00, 0xc0, 0xff, 0xee,
+        0x0a, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x01,
+        // padding
+        0x00, 0x00,
+        // NDP16
+        0x4e, 0x43, 0x4d, 0x30, 0x10, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x2a, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+        // tail padding
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    /// NTB with its datagram pointer table ahead of two datagrams
+    const NTB_TWO_DATAGRAMS: [u8; 40] = [
+        // NTH16
+        0x4e, 0x43, 0x4d, 0x48, 0x0c, 0x00, 0x07, 0x00, 0x28, 0x00, 0x0c, 0x00,
+        // NDP16
+        0x4e, 0x43, 0x4d, 0x30, 0x14, 0x00, 0x00, 0x00, 0x20, 0x00, 0x04, 0x00, 0x24, 0x00,
+        0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // datagrams
+        0xaa, 0xbb, 0xcc, 0xdd, 0x11, 0x22, 0x33, 0x00,
+    ];
+
+    fn setup_packet(direction: Direction, request: Request, value: u16, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: direction as u8 | (1 << 5) | 1, // class, interface
+            request: request as u8,
+            value,
+            index: 0,
+            length,
+        }
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_parse_ntb() {
+        let ntb = Ntb::parse(&NTB_ARP_REQUEST).unwrap();
+        assert_eq!(ntb.sequence, 1);
+        let datagrams: Vec<&[u8]> = ntb.datagrams().collect();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0].len(), 42);
+        assert_eq!(&datagrams[0][12..14], &[0x08, 0x06]);
+
+        let ntb = Ntb::parse(&NTB_TWO_DATAGRAMS).unwrap();
+        let datagrams: Vec<&[u8]> = ntb.datagrams().collect();
+        assert_eq!(datagrams, [&[0xaa, 0xbb, 0xcc, 0xdd][..], &[0x11, 0x22, 0x33][..]]);
+    }
+
+    #[test]
+    fn test_parse_invalid_ntb() {
+        // bad signature
+        let mut bytes = NTB_ARP_REQUEST;
+        bytes[3] = b'X';
+        assert!(Ntb::parse(&bytes).is_err());
+
+        // truncated
+        assert!(Ntb::parse(&NTB_ARP_REQUEST[..40]).is_err());
+
+        // datagram out of bounds
+        let mut bytes = NTB_TWO_DATAGRAMS;
+        bytes[26] = 0xff;
+        let ntb = Ntb::parse(&bytes).unwrap();
+        assert_eq!(ntb.datagrams().count(), 1);
+
+        // datagram pointer table linking back to itself
+        let mut bytes = NTB_TWO_DATAGRAMS;
+        bytes[18] = 0x0c;
+        bytes[20..24].copy_from_slice(&[0; 4]);
+        let ntb = Ntb::parse(&bytes).unwrap();
+        assert_eq!(ntb.datagrams().count(), 0);
+    }
+
+    #[test]
+    fn test_build_ntb() {
+        let mut buffer = [0; 512];
+        let mut builder: NtbBuilder<4> = NtbBuilder::new(&mut buffer, 7);
+        builder.push(&[0xaa, 0xbb, 0xcc, 0xdd]).unwrap();
+        let datagram = builder.datagram_buffer();
+        datagram[..3].copy_from_slice(&[0x11, 0x22, 0x33]);
+        builder.commit(3).unwrap();
+        let ntb = builder.finish();
+
+        assert_eq!(&ntb[..12], &[0x4e, 0x43, 0x4d, 0x48, 0x0c, 0x00, 0x07, 0x00, 0x28, 0x00, 0x14, 0x00]);
+
+        // round trip
+        let ntb = Ntb::parse(ntb).unwrap();
+        assert_eq!(ntb.sequence, 7);
+        let datagrams: Vec<&[u8]> = ntb.datagrams().collect();
+        assert_eq!(datagrams, [&[0xaa, 0xbb, 0xcc, 0xdd][..], &[0x11, 0x22, 0x33][..]]);
+    }
+
+    #[test]
+    fn test_build_ntb_full() {
+        let mut buffer = [0; 64];
+        let mut builder: NtbBuilder<2> = NtbBuilder::new(&mut buffer, 0);
+        assert!(builder.push(&[0; 64]).is_err());
+        builder.push(&[1; 8]).unwrap();
+        builder.push(&[2; 8]).unwrap();
+        assert_eq!(builder.datagram_buffer().len(), 0);
+        assert!(builder.push(&[3; 1]).is_err());
+        assert_eq!(builder.len(), 2);
+    }
+
+    #[test]
+    fn test_receive_ntb() {
+        let mut receiver: NtbReceiver<2048> = NtbReceiver::new();
+        let (first, second) = NTB_ARP_REQUEST.split_at(64);
+        assert_eq!(receiver.push(first, false), None);
+        let ntb = receiver.push(second, true).unwrap();
+        assert_eq!(ntb, &NTB_ARP_REQUEST[..]);
+        assert_eq!(Ntb::parse(ntb).unwrap().datagrams().count(), 1);
+    }
+
+    #[test]
+    fn test_configuration_descriptor() {
+        let mut configuration = CONFIGURATION_DESCRIPTOR_0;
+        let total_length = configuration.set_total_length();
+        let bytes: Vec<u8> = configuration.iter().copied().collect();
+
+        assert_eq!(total_length, 9 + (9 + 29 + 7) + 9 + (9 + 7 + 7));
+        assert_eq!(bytes.len(), total_length);
+        assert_eq!(bytes[4], 2); // bNumInterfaces
+    }
+
+    #[test]
+    fn test_get_ntb_parameters() {
+        let driver = MockUsbDriver::new();
+        let mut ncm = Ncm::new(0, 1, 0x82, 0x81);
+
+        let setup = setup_packet(Direction::DeviceToHost, Request::GetNtbParameters, 0, 28);
+        ncm.handle_setup_request(&driver, &setup).unwrap();
+        let response = driver.last_write(0).unwrap();
+        assert_eq!(response.len(), 28);
+        assert_eq!(&response[0..4], &[0x1c, 0x00, 0x01, 0x00]);
+        assert_eq!(&response[4..8], &NTB_MAX_SIZE.to_le_bytes());
+
+        // unsupported 32-bit format
+        let setup = setup_packet(Direction::HostToDevice, Request::SetNtbFormat, 1, 0);
+        ncm.handle_setup_request(&driver, &setup).unwrap();
+        assert!(driver.is_stalled());
+    }
+
+    #[test]
+    fn test_connection_notification() {
+        let driver = MockUsbDriver::new();
+        let mut ncm = Ncm::new(0, 1, 0x82, 0x81);
+
+        let setup = SetupPacket {
+            request_type: 0x01, // standard, interface
+            request: 11,        // SET_INTERFACE
+            value: 1,
+            index: 1,
+            length: 0,
+        };
+        ncm.handle_set_interface(&driver, &setup).unwrap();
+        assert!(ncm.is_active());
+
+        let writes = driver.take_writes();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0].0, 2);
+        assert_eq!(&writes[0].1[..8], &[0xa1, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00]);
+        assert_eq!(writes[0].1.len(), 16);
+
+        // network connection follows once the first notification completes
+        ncm.handle_transfer_complete(&driver, 0x81).unwrap();
+        assert!(driver.take_writes().is_empty());
+        ncm.handle_transfer_complete(&driver, 0x82).unwrap();
+        let writes = driver.take_writes();
+        assert_eq!(writes, vec![(2, vec![0xa1, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00])]);
+    }
+
+    #[test]
+    fn test_write_ntb() {
+        let driver = MockUsbDriver::new();
+        let mut ncm = Ncm::new(0, 1, 0x82, 0x81);
+        let ntb = [0x4e, 0x43, 0x4d, 0x48];
+
+        assert_eq!(ncm.write_ntb(&driver, &ntb, 512), Err(SmolError::NotConfigured));
+
+        ncm.alternate_setting = 1;
+        assert_eq!(ncm.write_ntb(&driver, &ntb, 512), Ok(4));
+        assert_eq!(driver.last_write(1), Some(ntb.to_vec()));
+
+        driver.fifo_busy.set(true);
+        assert_eq!(ncm.write_ntb(&driver, &ntb, 512), Err(SmolError::FifoBusy));
+    }
+
+    #[test]
+    fn test_write_ntb_zlp() {
+        let driver = MockUsbDriver::new();
+        let mut ncm = Ncm::new(0, 1, 0x82, 0x81);
+        ncm.alternate_setting = 1;
+
+        // ntbs filling their last packet are ended with a zero length packet
+        let ntb = [0xaa; 128];
+        assert_eq!(ncm.write_ntb(&driver, &ntb, 64), Ok(128));
+        assert_eq!(driver.take_writes(), vec![(1, ntb.to_vec()), (1, vec![])]);
+
+        assert_eq!(ncm.write_ntb(&driver, &ntb[..100], 64), Ok(100));
+        assert_eq!(driver.take_writes(), vec![(1, ntb[..100].to_vec())]);
+    }
+
+    #[test]
+    fn test_write_ntb_input_size() {
+        let driver = MockUsbDriver::new();
+        let mut ncm = Ncm::new(0, 1, 0x82, 0x81);
+        ncm.alternate_setting = 1;
+
+        // the host limits the size of the ntbs it receives
+        let setup = setup_packet(Direction::HostToDevice, Request::SetNtbInputSize, 0, 4);
+        ncm.handle_receive_control_data(&setup, &100_u32.to_le_bytes());
+        assert_eq!(ncm.ntb_input_size(), 100);
+
+        let ntb = [0xaa; 128];
+        assert_eq!(ncm.write_ntb(&driver, &ntb, 512), Err(SmolError::Overflow));
+        assert_eq!(ncm.write_ntb(&driver, &ntb[..100], 512), Ok(100));
+    }
+}
@@ -0,0 +1,786 @@
+//! Composite device builder
+//!
+//! Assigns interface numbers, endpoint addresses and string indices to
+//! the functions of a composite device and assembles the resulting
+//! configuration descriptors and string table.
+//!
+//! Each function declares the interfaces, endpoints and strings it
+//! needs with a [`FunctionSpec`]. [`CompositeBuilder::add`] allocates
+//! them and returns an [`Allocation`] holding the interface numbers,
+//! string indices and [`EndpointHandle`]s the function should use at
+//! runtime. Functions with more than one interface are grouped by an
+//! interface association descriptor.
+//!
+//! Class-specific descriptors that refer to other interfaces of the
+//! same function, such as the CDC union descriptor, are written with
+//! interface numbers relative to the function's first interface. The
+//! offsets of those bytes are listed in
+//! [`InterfaceSpec::interface_number_offsets`] and rebased by the
+//! builder.
+
+use crate::control::Direction;
+use crate::descriptor::*;
+use crate::device::Speed;
+use crate::error::{SmolError, SmolResult};
+
+use heapless::Vec;
+use zerocopy::AsBytes;
+
+// - constants ----------------------------------------------------------------
+
+/// Highest endpoint number supported by the LUNA device controller
+pub const MAX_ENDPOINT_NUMBER: u8 = 15;
+
+/// Maximum number of endpoints that can be allocated to one function
+pub const MAX_FUNCTION_ENDPOINTS: usize = 8;
+
+/// Device class, subclass and protocol to use in the device
+/// descriptor when the configuration contains interface associations
+pub const DEVICE_CLASS_MISCELLANEOUS: u8 = 0xef;
+pub const DEVICE_SUBCLASS_COMMON: u8 = 0x02;
+pub const DEVICE_PROTOCOL_INTERFACE_ASSOCIATION: u8 = 0x01;
+
+// - TransferType -------------------------------------------------------------
+
+/// Endpoint transfer type
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum TransferType {
+    Control = 0,
+    Isochronous = 1,
+    Bulk = 2,
+    Interrupt = 3,
+}
+
+// - specifications -----------------------------------------------------------
+
+/// An endpoint requested by a function
+pub struct EndpointSpec {
+    pub direction: Direction,
+    pub transfer_type: TransferType,
+    /// Maximum packet size at high speed
+    pub max_packet_size: u16,
+    /// Maximum packet size at full speed
+    pub full_speed_max_packet_size: u16,
+    /// Polling interval at high speed
+    pub interval: u8,
+    /// Polling interval at full speed
+    pub full_speed_interval: u8,
+}
+
+impl EndpointSpec {
+    /// A bulk endpoint with the maximum packet size for each speed.
+    pub const fn bulk(direction: Direction) -> Self {
+        Self {
+            direction,
+            transfer_type: TransferType::Bulk,
+            max_packet_size: 512,
+            full_speed_max_packet_size: 64,
+            interval: 0,
+            full_speed_interval: 0,
+        }
+    }
+
+    /// An interrupt endpoint polled every millisecond.
+    pub const fn interrupt(direction: Direction, max_packet_size: u16) -> Self {
+        Self {
+            direction,
+            transfer_type: TransferType::Interrupt,
+            max_packet_size,
+            full_speed_max_packet_size: max_packet_size,
+            interval: 4, // 2^(4-1) * 125us = 1ms
+            full_speed_interval: 1,
+        }
+    }
+}
+
+/// An interface, or alternate setting, requested by a function
+///
+/// Alternate settings other than zero share the interface number of
+/// the preceding interface.
+pub struct InterfaceSpec<'a> {
+    pub alternate_setting: u8,
+    pub interface_class: u8,
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+    /// Index into [`FunctionSpec::strings`]
+    pub string: Option<usize>,
+    /// Class-specific descriptors following the interface descriptor
+    pub class_descriptor: &'a [u8],
+    /// Offsets of bytes in `class_descriptor` holding interface numbers
+    /// relative to the function's first interface
+    pub interface_number_offsets: &'a [usize],
+    pub endpoints: &'a [EndpointSpec],
+    /// Class-specific descriptors following each endpoint descriptor
+    pub endpoint_class_descriptors: &'a [&'a [u8]],
+}
+
+impl<'a> InterfaceSpec<'a> {
+    pub const fn new() -> Self {
+        Self {
+            alternate_setting: 0,
+            interface_class: 0,
+            interface_subclass: 0,
+            interface_protocol: 0,
+            string: None,
+            class_descriptor: &[],
+            interface_number_offsets: &[],
+            endpoints: &[],
+            endpoint_class_descriptors: &[],
+        }
+    }
+}
+
+impl<'a> Default for InterfaceSpec<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A function of a composite device
+pub struct FunctionSpec<'a> {
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    /// Index into `strings` describing the function
+    pub string: Option<usize>,
+    pub interfaces: &'a [InterfaceSpec<'a>],
+    pub strings: &'a [&'a StringDescriptor<'a>],
+}
+
+impl<'a> FunctionSpec<'a> {
+    pub const fn new() -> Self {
+        Self {
+            function_class: 0,
+            function_subclass: 0,
+            function_protocol: 0,
+            string: None,
+            interfaces: &[],
+            strings: &[],
+        }
+    }
+}
+
+impl<'a> Default for FunctionSpec<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// - Allocation ---------------------------------------------------------------
+
+/// An endpoint allocated to a function
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EndpointHandle {
+    pub address: u8,
+    pub transfer_type: TransferType,
+    pub max_packet_size: u16,
+    pub full_speed_max_packet_size: u16,
+}
+
+impl EndpointHandle {
+    /// Returns the endpoint number, without the direction bit.
+    pub fn number(&self) -> u8 {
+        self.address & 0x7f
+    }
+
+    /// Returns the maximum packet size for the given bus speed.
+    pub fn max_packet_size(&self, speed: Speed) -> u16 {
+        match speed {
+            Speed::High | Speed::SuperSpeed => self.max_packet_size,
+            _ => self.full_speed_max_packet_size,
+        }
+    }
+}
+
+/// Interface numbers, string indices and endpoints allocated to a function
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    /// Index of the function's first string, zero if it has none
+    pub first_string: u8,
+    pub string_count: u8,
+    /// Endpoints in the order they were declared
+    pub endpoints: Vec<EndpointHandle, MAX_FUNCTION_ENDPOINTS>,
+}
+
+impl Allocation {
+    /// Returns the interface number of the function's nth interface.
+    pub fn interface(&self, index: usize) -> Option<u8> {
+        if index < self.interface_count as usize {
+            Some(self.first_interface + index as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the string index of the function's nth string.
+    pub fn string(&self, index: usize) -> Option<u8> {
+        if index < self.string_count as usize {
+            Some(self.first_string + index as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the function's nth endpoint.
+    pub fn endpoint(&self, index: usize) -> Option<EndpointHandle> {
+        self.endpoints.get(index).copied()
+    }
+}
+
+// - Composite ----------------------------------------------------------------
+
+/// Descriptors of a composite device
+///
+/// `N` is the capacity of each configuration descriptor, excluding its
+/// header, and `S` the capacity of the string table.
+pub struct Composite<'a, const N: usize, const S: usize> {
+    configuration: Vec<u8, N>,
+    other_speed_configuration: Vec<u8, N>,
+    strings: Vec<&'a StringDescriptor<'a>, S>,
+    num_interfaces: u8,
+    interface_associations: bool,
+}
+
+impl<'a, const N: usize, const S: usize> Composite<'a, N, S> {
+    /// Returns the high speed configuration descriptor.
+    pub fn configuration_descriptor(
+        &self,
+        head: ConfigurationDescriptorHeader,
+    ) -> ConfigurationDescriptor<'_> {
+        ConfigurationDescriptor::from_bytes(head, self.num_interfaces, &self.configuration)
+    }
+
+    /// Returns the full speed configuration descriptor as an other
+    /// speed configuration descriptor.
+    pub fn other_speed_configuration_descriptor(
+        &self,
+        mut head: ConfigurationDescriptorHeader,
+    ) -> ConfigurationDescriptor<'_> {
+        head.descriptor_type = DescriptorType::OtherSpeedConfiguration as u8;
+        ConfigurationDescriptor::from_bytes(
+            head,
+            self.num_interfaces,
+            &self.other_speed_configuration,
+        )
+    }
+
+    /// Returns the string table, starting at string index 1.
+    pub fn string_descriptors(&self) -> &[&'a StringDescriptor<'a>] {
+        &self.strings
+    }
+
+    pub fn num_interfaces(&self) -> u8 {
+        self.num_interfaces
+    }
+
+    /// Returns true if the configuration contains interface
+    /// association descriptors, in which case the device descriptor
+    /// should use the `DEVICE_CLASS_MISCELLANEOUS` class triple.
+    pub fn has_interface_associations(&self) -> bool {
+        self.interface_associations
+    }
+}
+
+// - CompositeBuilder ---------------------------------------------------------
+
+/// Builds the descriptors of a composite device
+pub struct CompositeBuilder<'a, const N: usize, const S: usize> {
+    composite: Composite<'a, N, S>,
+    max_endpoint_number: u8,
+    next_endpoint_in: u8,
+    next_endpoint_out: u8,
+}
+
+/// Builder state restored when a function fails to allocate
+struct Checkpoint {
+    configuration: usize,
+    other_speed_configuration: usize,
+    strings: usize,
+    num_interfaces: u8,
+    interface_associations: bool,
+    next_endpoint_in: u8,
+    next_endpoint_out: u8,
+}
+
+impl<'a, const N: usize, const S: usize> CompositeBuilder<'a, N, S> {
+    /// Create a builder for a device with endpoint numbers up to
+    /// `max_endpoint_number` in each direction.
+    ///
+    /// `device_strings` are the manufacturer, product and serial
+    /// strings referenced by the device descriptor and are assigned
+    /// the first string indices.
+    pub fn new(
+        max_endpoint_number: u8,
+        device_strings: &[&'a StringDescriptor<'a>],
+    ) -> SmolResult<Self> {
+        let mut strings = Vec::new();
+        strings
+            .extend_from_slice(device_strings)
+            .map_err(|_| SmolError::OutOfSpace)?;
+
+        Ok(Self {
+            composite: Composite {
+                configuration: Vec::new(),
+                other_speed_configuration: Vec::new(),
+                strings,
+                num_interfaces: 0,
+                interface_associations: false,
+            },
+            max_endpoint_number,
+            next_endpoint_in: 1,
+            next_endpoint_out: 1,
+        })
+    }
+
+    /// Allocate the resources declared by `function` and append its
+    /// descriptors to the configuration.
+    ///
+    /// Returns `SmolError::OutOfEndpoints` if the function needs more
+    /// endpoints than remain, in which case the builder is unchanged.
+    pub fn add(&mut self, function: &FunctionSpec<'a>) -> SmolResult<Allocation> {
+        let checkpoint = self.checkpoint();
+        let result = self.try_add(function);
+        if result.is_err() {
+            self.restore(checkpoint);
+        }
+        result
+    }
+
+    /// Finish building the device.
+    pub fn build(self) -> Composite<'a, N, S> {
+        self.composite
+    }
+
+    fn try_add(&mut self, function: &FunctionSpec<'a>) -> SmolResult<Allocation> {
+        // strings
+        let first_string = self.composite.strings.len() + 1;
+        self.composite
+            .strings
+            .extend_from_slice(function.strings)
+            .map_err(|_| SmolError::OutOfSpace)?;
+        let string_index = |index: Option<usize>| -> SmolResult<u8> {
+            match index {
+                None => Ok(0),
+                Some(index) if index < function.strings.len() => {
+                    u8::try_from(first_string + index).map_err(|_| SmolError::OutOfSpace)
+                }
+                Some(_) => Err(SmolError::FailedConversion),
+            }
+        };
+
+        // interfaces
+        let first_interface = self.composite.num_interfaces;
+        let interface_count = function
+            .interfaces
+            .iter()
+            .filter(|interface| interface.alternate_setting == 0)
+            .count();
+        let interface_count = u8::try_from(interface_count).map_err(|_| SmolError::OutOfSpace)?;
+        self.composite.num_interfaces = first_interface
+            .checked_add(interface_count)
+            .ok_or(SmolError::OutOfSpace)?;
+
+        let mut allocation = Allocation {
+            first_interface,
+            interface_count,
+            first_string: 0,
+            string_count: u8::try_from(function.strings.len()).map_err(|_| SmolError::OutOfSpace)?,
+            endpoints: Vec::new(),
+        };
+        if !function.strings.is_empty() {
+            allocation.first_string = string_index(Some(0))?;
+        }
+
+        if interface_count > 1 {
+            let descriptor = InterfaceAssociationDescriptor {
+                first_interface,
+                interface_count,
+                function_class: function.function_class,
+                function_subclass: function.function_subclass,
+                function_protocol: function.function_protocol,
+                function_string_index: string_index(function.string)?,
+                ..InterfaceAssociationDescriptor::new()
+            };
+            extend(&mut self.composite.configuration, descriptor.as_bytes())?;
+            extend(&mut self.composite.other_speed_configuration, descriptor.as_bytes())?;
+            self.composite.interface_associations = true;
+        }
+
+        let mut interface_number = first_interface;
+        let mut next_interface_number = first_interface;
+        for interface in function.interfaces {
+            if interface.alternate_setting == 0 {
+                interface_number = next_interface_number;
+                next_interface_number += 1;
+            }
+
+            let mut endpoints: Vec<EndpointHandle, MAX_FUNCTION_ENDPOINTS> = Vec::new();
+            for endpoint in interface.endpoints {
+                let handle = self.allocate_endpoint(endpoint)?;
+                endpoints.push(handle).map_err(|_| SmolError::OutOfSpace)?;
+                allocation
+                    .endpoints
+                    .push(handle)
+                    .map_err(|_| SmolError::OutOfSpace)?;
+            }
+
+            let string_index = string_index(interface.string)?;
+            for (buffer, full_speed) in [
+                (&mut self.composite.configuration, false),
+                (&mut self.composite.other_speed_configuration, true),
+            ] {
+                write_interface(
+                    buffer,
+                    interface,
+                    interface_number,
+                    string_index,
+                    first_interface,
+                    &endpoints,
+                    full_speed,
+                )?;
+            }
+        }
+
+        Ok(allocation)
+    }
+
+    fn allocate_endpoint(&mut self, endpoint: &EndpointSpec) -> SmolResult<EndpointHandle> {
+        let (next, direction) = match endpoint.direction {
+            Direction::DeviceToHost => (&mut self.next_endpoint_in, 0x80),
+            Direction::HostToDevice => (&mut self.next_endpoint_out, 0x00),
+        };
+        if *next > self.max_endpoint_number {
+            return Err(SmolError::OutOfEndpoints);
+        }
+        let address = *next | direction;
+        *next += 1;
+
+        Ok(EndpointHandle {
+            address,
+            transfer_type: endpoint.transfer_type,
+            max_packet_size: endpoint.max_packet_size,
+            full_speed_max_packet_size: endpoint.full_speed_max_packet_size,
+        })
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            configuration: self.composite.configuration.len(),
+            other_speed_configuration: self.composite.other_speed_configuration.len(),
+            strings: self.composite.strings.len(),
+            num_interfaces: self.composite.num_interfaces,
+            interface_associations: self.composite.interface_associations,
+            next_endpoint_in: self.next_endpoint_in,
+            next_endpoint_out: self.next_endpoint_out,
+        }
+    }
+
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        self.composite.configuration.truncate(checkpoint.configuration);
+        self.composite
+            .other_speed_configuration
+            .truncate(checkpoint.other_speed_configuration);
+        self.composite.strings.truncate(checkpoint.strings);
+        self.composite.num_interfaces = checkpoint.num_interfaces;
+        self.composite.interface_associations = checkpoint.interface_associations;
+        self.next_endpoint_in = checkpoint.next_endpoint_in;
+        self.next_endpoint_out = checkpoint.next_endpoint_out;
+    }
+}
+
+// - helpers ------------------------------------------------------------------
+
+fn write_interface<const N: usize>(
+    buffer: &mut Vec<u8, N>,
+    interface: &InterfaceSpec,
+    interface_number: u8,
+    string_index: u8,
+    first_interface: u8,
+    endpoints: &[EndpointHandle],
+    full_speed: bool,
+) -> SmolResult<()> {
+    let descriptor = InterfaceDescriptorHeader {
+        interface_number,
+        alternate_setting: interface.alternate_setting,
+        _num_endpoints: endpoints.len() as u8,
+        interface_class: interface.interface_class,
+        interface_subclass: interface.interface_subclass,
+        interface_protocol: interface.interface_protocol,
+        interface_string_index: string_index,
+        ..InterfaceDescriptorHeader::new()
+    };
+    extend(buffer, descriptor.as_bytes())?;
+
+    // class-specific descriptors
+    let start = buffer.len();
+    extend(buffer, interface.class_descriptor)?;
+    for offset in interface.interface_number_offsets {
+        let byte = buffer
+            .get_mut(start + offset)
+            .ok_or(SmolError::FailedConversion)?;
+        *byte += first_interface;
+    }
+
+    // endpoint descriptors
+    for (index, (spec, handle)) in interface.endpoints.iter().zip(endpoints).enumerate() {
+        let (max_packet_size, interval) = if full_speed {
+            (spec.full_speed_max_packet_size, spec.full_speed_interval)
+        } else {
+            (spec.max_packet_size, spec.interval)
+        };
+        let descriptor = EndpointDescriptor {
+            endpoint_address: handle.address,
+            attributes: spec.transfer_type as u8,
+            max_packet_size,
+            interval,
+            ..EndpointDescriptor::new()
+        };
+        extend(buffer, descriptor.as_bytes())?;
+        if let Some(class) = interface.endpoint_class_descriptors.get(index) {
+            extend(buffer, class)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn extend<const N: usize>(buffer: &mut Vec<u8, N>, bytes: &[u8]) -> SmolResult<()> {
+    buffer
+        .extend_from_slice(bytes)
+        .map_err(|_| SmolError::OutOfSpace)
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::SetupPacket;
+    use crate::device::UsbDevice;
+    use crate::mock::MockUsbDriver;
+
+    // - fixtures -------------------------------------------------------------
+
+    const DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
+        device_class: DEVICE_CLASS_MISCELLANEOUS,
+        device_subclass: DEVICE_SUBCLASS_COMMON,
+        device_protocol: DEVICE_PROTOCOL_INTERFACE_ASSOCIATION,
+        max_packet_size: 64,
+        vendor_id: 0x1209,
+        product_id: 0x0001,
+        manufacturer_string_index: 1,
+        product_string_index: 2,
+        serial_string_index: 3,
+        num_configurations: 1,
+        ..DeviceDescriptor::new()
+    };
+
+    const STRING_DESCRIPTOR_0: StringDescriptorZero =
+        StringDescriptorZero::new(&[LanguageId::EnglishUnitedStates]);
+    const MANUFACTURER: StringDescriptor = StringDescriptor::new("Great Scott Gadgets");
+    const PRODUCT: StringDescriptor = StringDescriptor::new("Composite");
+    const SERIAL: StringDescriptor = StringDescriptor::new("0");
+    const SERIAL_FUNCTION: StringDescriptor = StringDescriptor::new("Serial");
+    const VENDOR_FUNCTION: StringDescriptor = StringDescriptor::new("Vendor");
+
+    const CONFIGURATION_HEADER: ConfigurationDescriptorHeader = ConfigurationDescriptorHeader {
+        configuration_value: 1,
+        attributes: 0x80,
+        max_power: 50,
+        ..ConfigurationDescriptorHeader::new()
+    };
+
+    // CDC-ACM: communication interface with a union descriptor
+    // referencing the data interface
+    const SERIAL_INTERFACES: &[InterfaceSpec] = &[
+        InterfaceSpec {
+            interface_class: 0x02,
+            interface_subclass: 0x02,
+            class_descriptor: &[0x05, 0x24, 0x06, 0x00, 0x01], // union: 0, 1
+            interface_number_offsets: &[3, 4],
+            endpoints: &[EndpointSpec::interrupt(Direction::DeviceToHost, 8)],
+            ..InterfaceSpec::new()
+        },
+        InterfaceSpec {
+            interface_class: 0x0a,
+            string: Some(0),
+            endpoints: &[
+                EndpointSpec::bulk(Direction::DeviceToHost),
+                EndpointSpec::bulk(Direction::HostToDevice),
+            ],
+            ..InterfaceSpec::new()
+        },
+    ];
+
+    const SERIAL_FUNCTION_SPEC: FunctionSpec = FunctionSpec {
+        function_class: 0x02,
+        function_subclass: 0x02,
+        string: Some(0),
+        interfaces: SERIAL_INTERFACES,
+        strings: &[&SERIAL_FUNCTION],
+        ..FunctionSpec::new()
+    };
+
+    const VENDOR_INTERFACES: &[InterfaceSpec] = &[InterfaceSpec {
+        interface_class: 0xff,
+        string: Some(0),
+        endpoints: &[
+            EndpointSpec::bulk(Direction::DeviceToHost),
+            EndpointSpec::bulk(Direction::HostToDevice),
+        ],
+        ..InterfaceSpec::new()
+    }];
+
+    const VENDOR_FUNCTION_SPEC: FunctionSpec = FunctionSpec {
+        function_class: 0xff,
+        interfaces: VENDOR_INTERFACES,
+        strings: &[&VENDOR_FUNCTION],
+        ..FunctionSpec::new()
+    };
+
+    const DEVICE_STRINGS: &[&StringDescriptor] = &[&MANUFACTURER, &PRODUCT, &SERIAL];
+
+    fn descriptors<'a, const N: usize, const S: usize>(
+        composite: &Composite<'a, N, S>,
+    ) -> (std::vec::Vec<u8>, std::vec::Vec<u8>) {
+        let mut configuration = composite.configuration_descriptor(CONFIGURATION_HEADER);
+        configuration.set_total_length();
+        let mut other_speed = composite.other_speed_configuration_descriptor(CONFIGURATION_HEADER);
+        other_speed.set_total_length();
+        (
+            configuration.iter().copied().collect(),
+            other_speed.iter().copied().collect(),
+        )
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_allocation() {
+        let mut builder: CompositeBuilder<256, 8> =
+            CompositeBuilder::new(MAX_ENDPOINT_NUMBER, DEVICE_STRINGS).unwrap();
+
+        let serial = builder.add(&SERIAL_FUNCTION_SPEC).unwrap();
+        assert_eq!(serial.first_interface, 0);
+        assert_eq!(serial.interface_count, 2);
+        assert_eq!(serial.string(0), Some(4));
+        let endpoints: std::vec::Vec<u8> = serial.endpoints.iter().map(|e| e.address).collect();
+        assert_eq!(endpoints, [0x81, 0x82, 0x01]);
+
+        let vendor = builder.add(&VENDOR_FUNCTION_SPEC).unwrap();
+        assert_eq!(vendor.interface(0), Some(2));
+        assert_eq!(vendor.interface(1), None);
+        assert_eq!(vendor.string(0), Some(5));
+        assert_eq!(vendor.endpoint(0).unwrap().address, 0x83);
+        assert_eq!(vendor.endpoint(1).unwrap().address, 0x02);
+        assert_eq!(vendor.endpoint(1).unwrap().max_packet_size(Speed::Full), 64);
+
+        let composite = builder.build();
+        assert_eq!(composite.num_interfaces(), 3);
+        assert!(composite.has_interface_associations());
+        assert_eq!(composite.string_descriptors().len(), 5);
+    }
+
+    #[test]
+    fn test_descriptors() {
+        let mut builder: CompositeBuilder<256, 8> =
+            CompositeBuilder::new(MAX_ENDPOINT_NUMBER, DEVICE_STRINGS).unwrap();
+        builder.add(&VENDOR_FUNCTION_SPEC).unwrap();
+        builder.add(&SERIAL_FUNCTION_SPEC).unwrap();
+        let composite = builder.build();
+
+        let (configuration, other_speed) = descriptors(&composite);
+        let total_length = 9 + (9 + 7 + 7) + 8 + (9 + 5 + 7) + (9 + 7 + 7);
+        assert_eq!(configuration.len(), total_length);
+        assert_eq!(&configuration[..5], &[9, 2, total_length as u8, 0, 3]);
+
+        // vendor interface has no interface association
+        assert_eq!(&configuration[9..11], &[9, 4]);
+        assert_eq!(configuration[9 + 2], 0); // interface number
+        assert_eq!(configuration[9 + 8], 4); // string index
+
+        // serial function is associated and its union descriptor rebased
+        let iad = 9 + 23;
+        assert_eq!(&configuration[iad..iad + 8], &[8, 11, 1, 2, 0x02, 0x02, 0x00, 5]);
+        let union = iad + 8 + 9;
+        assert_eq!(&configuration[union..union + 5], &[0x05, 0x24, 0x06, 1, 2]);
+
+        // interrupt endpoint interval and bulk packet sizes depend on speed
+        let interrupt = union + 5;
+        assert_eq!(&configuration[interrupt..interrupt + 7], &[7, 5, 0x82, 0x03, 8, 0, 4]);
+        assert_eq!(&other_speed[interrupt..interrupt + 7], &[7, 5, 0x82, 0x03, 8, 0, 1]);
+        assert_eq!(other_speed[1], DescriptorType::OtherSpeedConfiguration as u8);
+        let bulk = interrupt + 7 + 9;
+        assert_eq!(&configuration[bulk..bulk + 7], &[7, 5, 0x83, 0x02, 0x00, 0x02, 0]);
+        assert_eq!(&other_speed[bulk..bulk + 7], &[7, 5, 0x83, 0x02, 0x40, 0x00, 0]);
+    }
+
+    #[test]
+    fn test_endpoint_budget() {
+        let mut builder: CompositeBuilder<256, 8> =
+            CompositeBuilder::new(3, DEVICE_STRINGS).unwrap();
+        builder.add(&SERIAL_FUNCTION_SPEC).unwrap();
+
+        // serial needs two IN endpoints but only one remains
+        assert_eq!(
+            builder.add(&SERIAL_FUNCTION_SPEC).unwrap_err(),
+            SmolError::OutOfEndpoints
+        );
+
+        // the failed function left no trace
+        let vendor = builder.add(&VENDOR_FUNCTION_SPEC).unwrap();
+        assert_eq!(vendor.first_interface, 2);
+        assert_eq!(vendor.string(0), Some(5));
+        assert_eq!(vendor.endpoint(0).unwrap().address, 0x83);
+
+        let composite = builder.build();
+        assert_eq!(composite.num_interfaces(), 3);
+        assert_eq!(composite.string_descriptors().len(), 5);
+    }
+
+    #[test]
+    fn test_out_of_space() {
+        let mut builder: CompositeBuilder<32, 8> =
+            CompositeBuilder::new(MAX_ENDPOINT_NUMBER, DEVICE_STRINGS).unwrap();
+        assert_eq!(
+            builder.add(&SERIAL_FUNCTION_SPEC).unwrap_err(),
+            SmolError::OutOfSpace
+        );
+        assert_eq!(builder.build().num_interfaces(), 0);
+
+        let builder: SmolResult<CompositeBuilder<32, 2>> =
+            CompositeBuilder::new(MAX_ENDPOINT_NUMBER, DEVICE_STRINGS);
+        assert_eq!(builder.err(), Some(SmolError::OutOfSpace));
+    }
+
+    #[test]
+    fn test_get_configuration_descriptor() {
+        let mut builder: CompositeBuilder<256, 8> =
+            CompositeBuilder::new(MAX_ENDPOINT_NUMBER, DEVICE_STRINGS).unwrap();
+        builder.add(&SERIAL_FUNCTION_SPEC).unwrap();
+        let composite = builder.build();
+        let (expected, _) = descriptors(&composite);
+
+        let configuration_descriptor = composite.configuration_descriptor(CONFIGURATION_HEADER);
+        let device = UsbDevice::new(
+            MockUsbDriver::new(),
+            &DEVICE_DESCRIPTOR,
+            &configuration_descriptor,
+            &STRING_DESCRIPTOR_0,
+            composite.string_descriptors(),
+        );
+
+        let setup_packet = SetupPacket {
+            request_type: 0x80,
+            request: 6, // GetDescriptor
+            value: (DescriptorType::Configuration as u16) << 8,
+            index: 0,
+            length: 255,
+        };
+        device.handle_setup_request(&setup_packet).unwrap();
+        assert_eq!(device.hal_driver.last_write(0), Some(expected));
+    }
+}
@@ -4,7 +4,7 @@ use crate::error::SmolError;
 // - SetupPacket --------------------------------------------------------------
 
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SetupPacket {
     // 0..4 Recipient: 0=Device, 1=Interface, 2=Endpoint, 3=Other, 4-31=Reserved
     // 5..6 Type: 0=Standard, 1=Class, 2=Vendor, 3=Reserved
@@ -55,7 +55,7 @@ impl SetupPacket {
 
 // - SetupPacket.request_type -------------------------------------------------
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum Recipient {
     Device = 0,
@@ -77,7 +77,7 @@ impl From<u8> for Recipient {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum RequestType {
     Standard = 0,
@@ -98,7 +98,7 @@ impl From<u8> for RequestType {
 }
 
 /// USB traffic direction
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum Direction {
     /// Host to device (OUT)
@@ -127,7 +127,7 @@ impl Direction {
 
 // - SetupPacket.request ------------------------------------------------------
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum Request {
     GetStatus = 0,
@@ -171,6 +171,7 @@ impl From<u8> for Request {
 pub enum Feature {
     EndpointHalt = 0,
     DeviceRemoteWakeup = 1,
+    TestMode = 2,
 }
 
 impl TryFrom<u16> for Feature {
@@ -180,8 +181,57 @@ impl TryFrom<u16> for Feature {
         let result = match value {
             0 => Feature::EndpointHalt,
             1 => Feature::DeviceRemoteWakeup,
+            2 => Feature::TestMode,
             _ => return Err(SmolError::FailedConversion),
         };
         Ok(result)
     }
 }
+
+/// USB 2.0 test mode selectors
+///
+/// See USB 2.0 specification, section 7.1.20 and table 9-7.
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum TestMode {
+    TestJ = 1,
+    TestK = 2,
+    TestSe0Nak = 3,
+    TestPacket = 4,
+    TestForceEnable = 5,
+}
+
+impl TryFrom<u8> for TestMode {
+    type Error = SmolError;
+
+    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
+        let result = match value {
+            1 => TestMode::TestJ,
+            2 => TestMode::TestK,
+            3 => TestMode::TestSe0Nak,
+            4 => TestMode::TestPacket,
+            5 => TestMode::TestForceEnable,
+            _ => return Err(SmolError::FailedConversion),
+        };
+        Ok(result)
+    }
+}
+
+/// Data payload transmitted repeatedly in [`TestMode::TestPacket`]
+///
+/// The packet is sent with a DATA0 PID, the sync pattern and PID are
+/// generated by the device controller.
+pub const TEST_PACKET: [u8; 53] = [
+    // JKJKJKJK x9
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // JJKKJJKK x8
+    0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa,
+    // JJJJKKKK x8
+    0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee,
+    // JJJJJJJKKKKKKK x8
+    0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    // JJJJJJJK x8
+    0x7f, 0xbf, 0xdf, 0xef, 0xf7, 0xfb, 0xfd,
+    // JKKKKKKK x10, JK
+    0xfc, 0x7e, 0xbf, 0xdf, 0xef, 0xf7, 0xfb, 0xfd, 0x7e,
+];
@@ -168,6 +168,7 @@ impl Default for DeviceQualifierDescriptor {
 pub struct ConfigurationDescriptor<'a> {
     head: ConfigurationDescriptorHeader,
     tail: &'a [InterfaceDescriptor<'a>],
+    raw: &'a [u8],
 }
 
 impl<'a> ConfigurationDescriptor<'a> {
@@ -176,9 +177,42 @@ impl<'a> ConfigurationDescriptor<'a> {
         tail: &'a [InterfaceDescriptor],
     ) -> Self {
         head._length = size_of::<ConfigurationDescriptorHeader>() as u8;
-        head._num_interfaces = tail.len() as u8;
 
-        Self { head, tail }
+        // alternate settings don't count as separate interfaces
+        let mut num_interfaces = 0;
+        let mut index = 0;
+        while index < tail.len() {
+            if tail[index].head.alternate_setting == 0 {
+                num_interfaces += 1;
+            }
+            index += 1;
+        }
+        head._num_interfaces = num_interfaces;
+
+        Self {
+            head,
+            tail,
+            raw: &[],
+        }
+    }
+
+    /// Create a configuration descriptor from pre-serialized interface,
+    /// endpoint and class-specific descriptors.
+    ///
+    /// Used by descriptors that are assembled at runtime, see
+    /// [`crate::composite`].
+    pub const fn from_bytes(
+        mut head: ConfigurationDescriptorHeader,
+        num_interfaces: u8,
+        raw: &'a [u8],
+    ) -> Self {
+        head._length = size_of::<ConfigurationDescriptorHeader>() as u8;
+        head._num_interfaces = num_interfaces;
+        Self {
+            head,
+            tail: &[],
+            raw,
+        }
     }
 
     /// Calculate and update the descriptor total length field
@@ -191,6 +225,37 @@ impl<'a> ConfigurationDescriptor<'a> {
     pub fn iter(&self) -> ConfigurationDescriptorIterator {
         ConfigurationDescriptorIterator::new(self)
     }
+
+    /// Returns the `wMaxPacketSize` of the endpoint descriptor with the
+    /// given endpoint address.
+    pub fn endpoint_max_packet_size(&self, endpoint_address: u8) -> Option<u16> {
+        let mut bytes = self.iter().copied();
+        loop {
+            let length = bytes.next()? as usize;
+            if length < 2 {
+                return None;
+            }
+            let descriptor_type = bytes.next()?;
+
+            // bEndpointAddress, bmAttributes, wMaxPacketSize
+            let mut fields = [0_u8; 4];
+            let mut count = 0;
+            for byte in bytes.by_ref().take(length - 2) {
+                if count < fields.len() {
+                    fields[count] = byte;
+                }
+                count += 1;
+            }
+
+            if descriptor_type == DescriptorType::Endpoint as u8
+                && count >= fields.len()
+                && fields[0] == endpoint_address
+            {
+                // bits 11..13 hold additional transactions per microframe
+                return Some(u16::from_le_bytes([fields[2], fields[3]]) & 0x7ff);
+            }
+        }
+    }
 }
 
 /// USB configuration descriptor header
@@ -226,7 +291,10 @@ impl ConfigurationDescriptorHeader {
 
 /// USB configuration descriptor iterator
 pub struct ConfigurationDescriptorIterator<'a> {
-    chain: iter::Chain<slice::Iter<'a, u8>, ConfigurationDescriptorTailIterator<'a>>,
+    chain: iter::Chain<
+        iter::Chain<slice::Iter<'a, u8>, ConfigurationDescriptorTailIterator<'a>>,
+        slice::Iter<'a, u8>,
+    >,
 }
 
 impl<'a> ConfigurationDescriptorIterator<'a> {
@@ -236,8 +304,7 @@ impl<'a> ConfigurationDescriptorIterator<'a> {
             .tail
             .iter()
             .flat_map(&|x: &'a InterfaceDescriptor| x.iter());
-        let chain: iter::Chain<slice::Iter<'a, u8>, ConfigurationDescriptorTailIterator<'a>> =
-            head_iter.chain(tail_iter);
+        let chain = head_iter.chain(tail_iter).chain(descriptor.raw.iter());
 
         Self { chain }
     }
@@ -251,8 +318,10 @@ impl<'a> Iterator for ConfigurationDescriptorIterator<'a> {
 }
 
 // type aliases for sanity
-type InterfaceDescriptorIterator<'a> =
-    CompositeIterator<'a, InterfaceDescriptorHeader, EndpointDescriptor>;
+type InterfaceDescriptorIterator<'a> = iter::Chain<
+    iter::Chain<slice::Iter<'a, u8>, slice::Iter<'a, u8>>,
+    EndpointDescriptorsIterator<'a>,
+>;
 type ConfigurationDescriptorTailIterator<'a> = iter::FlatMap<
     slice::Iter<'a, InterfaceDescriptor<'a>>,
     InterfaceDescriptorIterator<'a>,
@@ -264,19 +333,90 @@ type ConfigurationDescriptorTailIterator<'a> = iter::FlatMap<
 /// USB interface descriptor
 pub struct InterfaceDescriptor<'a> {
     head: InterfaceDescriptorHeader,
+    class: &'a [u8],
     tail: &'a [EndpointDescriptor],
+    endpoint_class: &'a [&'a [u8]],
 }
 
 impl<'a> InterfaceDescriptor<'a> {
     pub const fn new(mut head: InterfaceDescriptorHeader, tail: &'a [EndpointDescriptor]) -> Self {
         head._length = size_of::<InterfaceDescriptorHeader>() as u8;
         head._num_endpoints = tail.len() as u8;
-        Self { head, tail }
+        Self {
+            head,
+            class: &[],
+            tail,
+            endpoint_class: &[],
+        }
     }
 
-    pub fn iter(&'a self) -> CompositeIterator<'a, InterfaceDescriptorHeader, EndpointDescriptor> {
-        let iter = CompositeIterator::new(&self.head, self.tail);
-        iter
+    /// Append class-specific descriptors to the interface descriptor.
+    ///
+    /// These are emitted verbatim, after the interface descriptor and
+    /// before any endpoint descriptors.
+    pub const fn with_class_descriptor(mut self, class: &'a [u8]) -> Self {
+        self.class = class;
+        self
+    }
+
+    /// Append class-specific descriptors to the interface's endpoint descriptors.
+    ///
+    /// Each entry is emitted verbatim after the endpoint descriptor
+    /// with the same index.
+    pub const fn with_endpoint_class_descriptors(mut self, endpoint_class: &'a [&'a [u8]]) -> Self {
+        self.endpoint_class = endpoint_class;
+        self
+    }
+
+    pub fn iter(&'a self) -> InterfaceDescriptorIterator<'a> {
+        let head_iter: HeadIterator<'a> = self.head.as_iter();
+        let tail_iter = EndpointDescriptorsIterator::new(self.tail, self.endpoint_class);
+        head_iter.chain(self.class.iter()).chain(tail_iter)
+    }
+}
+
+/// Iterator over an interface's endpoint descriptors and any
+/// class-specific descriptors following them
+pub struct EndpointDescriptorsIterator<'a> {
+    endpoints: &'a [EndpointDescriptor],
+    endpoint_class: &'a [&'a [u8]],
+    // even: endpoint descriptor, odd: class-specific descriptor
+    index: usize,
+    current: slice::Iter<'a, u8>,
+}
+
+impl<'a> EndpointDescriptorsIterator<'a> {
+    fn new(endpoints: &'a [EndpointDescriptor], endpoint_class: &'a [&'a [u8]]) -> Self {
+        Self {
+            endpoints,
+            endpoint_class,
+            index: 0,
+            current: [].iter(),
+        }
+    }
+}
+
+impl<'a> Iterator for EndpointDescriptorsIterator<'a> {
+    type Item = &'a u8;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(byte) = self.current.next() {
+                return Some(byte);
+            }
+            let endpoint = self.index / 2;
+            if endpoint >= self.endpoints.len() {
+                return None;
+            }
+            self.current = if self.index % 2 == 0 {
+                self.endpoints[endpoint].as_iter()
+            } else {
+                match self.endpoint_class.get(endpoint) {
+                    Some(class) => class.iter(),
+                    None => [].iter(),
+                }
+            };
+            self.index += 1;
+        }
     }
 }
 
@@ -313,6 +453,47 @@ impl InterfaceDescriptorHeader {
     }
 }
 
+// - InterfaceAssociationDescriptor -------------------------------------------
+
+/// USB interface association descriptor
+///
+/// Groups the interfaces of a function in a composite device.
+#[derive(AsBytes, FromBytes)]
+#[repr(C, packed)]
+pub struct InterfaceAssociationDescriptor {
+    pub _length: u8,          // 8
+    pub _descriptor_type: u8, // 11 = InterfaceAssociation
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub function_string_index: u8,
+}
+
+impl AsByteSliceIterator for InterfaceAssociationDescriptor {}
+
+impl InterfaceAssociationDescriptor {
+    pub const fn new() -> Self {
+        Self {
+            _length: size_of::<Self>() as u8,
+            _descriptor_type: DescriptorType::InterfaceAssociation as u8,
+            first_interface: 0,
+            interface_count: 0,
+            function_class: 0,
+            function_subclass: 0,
+            function_protocol: 0,
+            function_string_index: 0,
+        }
+    }
+}
+
+impl Default for InterfaceAssociationDescriptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // - EndpointDescriptor -------------------------------------------------------
 
 /// USB endpoint descriptor
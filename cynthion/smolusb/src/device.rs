@@ -1,16 +1,22 @@
 #![allow(dead_code, unused_imports, unused_variables)] // TODO
 
-use crate::control::{Direction, Feature, Recipient, Request, RequestType, SetupPacket};
+use crate::class::UsbClass;
+use crate::control::{
+    Direction, Feature, Recipient, Request, RequestType, SetupPacket, TestMode,
+};
 use crate::descriptor::*;
 use crate::error::{SmolError, SmolResult};
+use crate::event::UsbEvent;
+use crate::observer::{ControlEvent, ControlObserver, StallReason, DATA_STAGE_CAPTURE_LENGTH};
+use crate::personality::Personality;
 use crate::traits::AsByteSliceIterator;
 use crate::traits::{
-    ControlRead, EndpointRead, EndpointWrite, EndpointWriteRef, UnsafeUsbDriverOperations,
-    UsbDriverOperations,
+    ControlRead, EndpointRead, EndpointWrite, UnsafeUsbDriverOperations, UsbDriverOperations,
 };
 
 use log::{debug, error, info, trace, warn};
 
+use core::borrow::Borrow;
 use core::cell::RefCell;
 
 ///! `smolusb` device implementation for Luna USB peripheral
@@ -24,7 +30,7 @@ use core::cell::RefCell;
 /// Note: These match the gateware peripheral so the mapping isn't particularly meaningful in other contexts.
 ///
 /// TODO also, these don't match what I'm seeing from the host side ???
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum Speed {
     Low = 2,        // 1.5 Mbps
@@ -59,10 +65,10 @@ pub enum DeviceState {
 /// `UsbDevice` implements the control portion of the USB
 /// specification and consists of:
 ///
-///     * a hal driver
-///     * a device descriptor
-///     * a configuration descriptor
-///     * a set of string descriptors
+/// * a hal driver
+/// * a device descriptor
+/// * a configuration descriptor
+/// * a set of string descriptors
 ///
 pub struct UsbDevice<'a, D> {
     pub hal_driver: D,
@@ -73,9 +79,16 @@ pub struct UsbDevice<'a, D> {
     string_descriptor_zero: &'a StringDescriptorZero<'a>,
     string_descriptors: &'a [&'a StringDescriptor<'a>],
     pub state: RefCell<DeviceState>,
+    speed: RefCell<Option<Speed>>,
+    suspended_state: RefCell<Option<DeviceState>>,
+    /// Test mode to enter once the status stage has completed
+    test_mode_pending: RefCell<Option<TestMode>>,
     pub reset_count: usize,
     pub feature_remote_wakeup: bool,
 
+    observer: Option<&'a dyn ControlObserver>,
+    clock: fn() -> u32,
+
     pub cb_class_request:
         Option<fn(device: &UsbDevice<'a, D>, setup_packet: &SetupPacket, request: u8)>,
     pub cb_vendor_request:
@@ -86,7 +99,7 @@ pub struct UsbDevice<'a, D> {
 
 impl<'a, D> UsbDevice<'a, D>
 where
-    D: ControlRead + EndpointRead + EndpointWrite + EndpointWriteRef + UsbDriverOperations,
+    D: ControlRead + EndpointRead + EndpointWrite + UsbDriverOperations,
 {
     pub fn new(
         hal_driver: D,
@@ -109,27 +122,130 @@ where
             string_descriptor_zero,
             string_descriptors,
             state: DeviceState::Reset.into(),
+            speed: None.into(),
+            suspended_state: None.into(),
+            test_mode_pending: None.into(),
             reset_count: 0,
             feature_remote_wakeup: false,
 
+            observer: None,
+            clock: || 0,
+
             cb_class_request: None,
             cb_vendor_request: None,
             cb_string_request: None,
         }
     }
 
+    /// Create a device from a [`Personality`].
+    pub fn from_personality(hal_driver: D, personality: &Personality<'a, D>) -> Self {
+        let mut device = Self::new(
+            hal_driver,
+            personality.device_descriptor,
+            personality.configuration_descriptor,
+            personality.string_descriptor_zero,
+            personality.string_descriptors,
+        );
+        device.set_personality(personality);
+        device
+    }
+
     pub fn state(&self) -> DeviceState {
         *self.state.borrow()
     }
+
+    /// Returns the bus speed negotiated at the last connect or reset.
+    pub fn speed(&self) -> Option<Speed> {
+        *self.speed.borrow()
+    }
+
+    /// Returns the configuration descriptor in use at the current bus speed.
+    pub fn active_configuration_descriptor(&self) -> &ConfigurationDescriptor<'a> {
+        match (self.speed(), &self.other_speed_configuration_descriptor) {
+            (Some(Speed::Full), Some(descriptor)) => descriptor,
+            _ => &self.configuration_descriptor,
+        }
+    }
+
+    /// Returns the maximum packet size of the given endpoint address
+    /// as declared by the active configuration.
+    pub fn max_packet_size(&self, endpoint_address: u8) -> Option<u16> {
+        if endpoint_address & 0x7f == 0 {
+            return Some(self.device_descriptor.max_packet_size.into());
+        }
+        self.active_configuration_descriptor()
+            .endpoint_max_packet_size(endpoint_address)
+    }
+
+    /// Attach an observer to receive the device's control transfer
+    /// events, timestamped with the value returned by `clock`.
+    pub fn set_observer(&mut self, observer: &'a dyn ControlObserver, clock: fn() -> u32) {
+        self.observer = Some(observer);
+        self.clock = clock;
+    }
+
+    /// Pass an event to the attached observer.
+    ///
+    /// Request callbacks can use this to report their own data
+    /// stages and stalls.
+    pub fn observe(&self, event: ControlEvent) {
+        if let Some(observer) = self.observer {
+            observer.observe((self.clock)(), &event);
+        }
+    }
+
+    fn set_state(&self, state: DeviceState) {
+        let from = self.state.replace(state);
+        if from != state {
+            self.observe(ControlEvent::StateChange { from, to: state });
+        }
+    }
+
+    fn stall_request(&self, reason: StallReason) {
+        self.observe(ControlEvent::Stall(reason));
+        self.hal_driver.stall_request();
+    }
+
+    fn ack_status_stage(&self, setup_packet: &SetupPacket) {
+        self.hal_driver.ack_status_stage(setup_packet);
+        self.observe(ControlEvent::StatusStage);
+    }
+
+    /// Write the data stage of a control IN transfer.
+    fn write_data_stage<I, B>(&self, iter: I) -> SmolResult<usize>
+    where
+        I: Iterator<Item = B>,
+        B: Borrow<u8>,
+    {
+        let mut data = [0; DATA_STAGE_CAPTURE_LENGTH];
+        let mut captured = 0;
+        let iter = iter.map(|byte| {
+            let byte = *byte.borrow();
+            if captured < data.len() {
+                data[captured] = byte;
+                captured += 1;
+            }
+            byte
+        });
+        let length = self.hal_driver.write(0, iter)?;
+        self.observe(ControlEvent::DataStage {
+            direction: Direction::DeviceToHost,
+            length,
+            data,
+        });
+        Ok(length)
+    }
 }
 
 // Device functions
 impl<'a, D> UsbDevice<'a, D>
 where
-    D: ControlRead + EndpointRead + EndpointWrite + EndpointWriteRef + UsbDriverOperations,
+    D: ControlRead + EndpointRead + EndpointWrite + UsbDriverOperations,
 {
     pub fn connect(&self) -> Speed {
-        self.hal_driver.connect().into()
+        let speed = self.hal_driver.connect().into();
+        self.speed.replace(Some(speed));
+        speed
     }
 
     pub fn disconnect(&self) {
@@ -139,16 +255,66 @@ where
     pub fn reset(&self) -> Speed {
         let speed = self.hal_driver.reset().into();
         // TODO self.reset_count += 1;
-        self.state.replace(DeviceState::Reset.into());
+        self.set_state(DeviceState::Reset);
+        self.speed.replace(Some(speed));
         speed
     }
 
     pub fn bus_reset(&self) -> Speed {
         let speed = self.hal_driver.bus_reset().into();
         // TODO self.reset_count += 1;
-        self.state.replace(DeviceState::Reset.into());
+        self.set_state(DeviceState::Reset);
+        self.speed.replace(Some(speed));
         speed
     }
+
+    /// Replace the device's descriptors and request callbacks with
+    /// those of `personality` and reset the device state.
+    ///
+    /// The host will only see the new personality once the device has
+    /// re-enumerated, see [`UsbDevice::switch_personality`].
+    pub fn set_personality(&mut self, personality: &Personality<'a, D>) {
+        let mut configuration_descriptor = *personality.configuration_descriptor;
+        configuration_descriptor.set_total_length();
+        let mut other_speed_configuration_descriptor = personality.other_speed_configuration_descriptor;
+        if let Some(descriptor) = other_speed_configuration_descriptor.as_mut() {
+            descriptor.set_total_length();
+        }
+
+        self.device_descriptor = personality.device_descriptor;
+        self.configuration_descriptor = configuration_descriptor;
+        self.device_qualifier_descriptor = personality.device_qualifier_descriptor;
+        self.other_speed_configuration_descriptor = other_speed_configuration_descriptor;
+        self.string_descriptor_zero = personality.string_descriptor_zero;
+        self.string_descriptors = personality.string_descriptors;
+        self.cb_class_request = personality.cb_class_request;
+        self.cb_vendor_request = personality.cb_vendor_request;
+        self.cb_string_request = personality.cb_string_request;
+
+        self.set_state(DeviceState::Reset);
+        self.suspended_state.replace(None);
+        self.speed.replace(None);
+        self.feature_remote_wakeup = false;
+    }
+
+    /// Re-enumerate the device with a new personality.
+    ///
+    /// The device is disconnected from the bus, `disconnect_delay` is
+    /// called to give the host time to notice the disconnect and the
+    /// device is reconnected with the new personality.
+    ///
+    /// As with [`UsbDevice::connect`], device controller events must
+    /// be re-enabled by the caller afterwards.
+    pub fn switch_personality<F>(&mut self, personality: &Personality<'a, D>, disconnect_delay: F) -> Speed
+    where
+        F: FnOnce(),
+    {
+        info!("Switching to personality: {}", personality.name);
+        self.disconnect();
+        self.set_personality(personality);
+        disconnect_delay();
+        self.connect()
+    }
 }
 
 // Handle SETUP packet
@@ -157,11 +323,28 @@ where
     D: ControlRead
         + EndpointRead
         + EndpointWrite
-        + EndpointWriteRef
         + UsbDriverOperations
         + UnsafeUsbDriverOperations,
 {
     pub fn handle_setup_request(&self, setup_packet: &SetupPacket) -> SmolResult<()> {
+        self.observe_setup_request(setup_packet);
+        self.dispatch_setup_request(setup_packet)
+    }
+
+    fn observe_setup_request(&self, setup_packet: &SetupPacket) {
+        if self.observer.is_none() {
+            return;
+        }
+        self.observe(ControlEvent::SetupReceived(setup_packet.clone()));
+        self.observe(ControlEvent::Request {
+            recipient: setup_packet.recipient(),
+            direction: setup_packet.direction(),
+            request_type: setup_packet.request_type(),
+            request: setup_packet.request(),
+        });
+    }
+
+    fn dispatch_setup_request(&self, setup_packet: &SetupPacket) -> SmolResult<()> {
         let request_type = setup_packet.request_type();
         let request = setup_packet.request();
 
@@ -184,26 +367,30 @@ where
             (RequestType::Standard, Request::SetFeature) => {
                 self.handle_set_feature(setup_packet)?;
             }
-            (RequestType::Class, Request::ClassOrVendor(request)) => {
+            (RequestType::Standard, Request::SetInterface) => {
+                self.handle_set_interface(setup_packet)?;
+            }
+            // class and vendor requests may reuse standard request numbers
+            (RequestType::Class, _) => {
                 if let Some(cb) = self.cb_class_request {
-                    cb(self, setup_packet, *request);
+                    cb(self, setup_packet, setup_packet.request);
                 } else {
                     warn!(
                         "SETUP stall: unhandled class request {:?} {:?}",
                         request_type, request
                     );
-                    self.hal_driver.stall_request();
+                    self.stall_request(StallReason::UnhandledRequest);
                 }
             }
-            (RequestType::Vendor, Request::ClassOrVendor(request)) => {
+            (RequestType::Vendor, _) => {
                 if let Some(cb) = self.cb_vendor_request {
-                    cb(self, setup_packet, *request);
+                    cb(self, setup_packet, setup_packet.request);
                 } else {
                     warn!(
                         "SETUP stall: unhandled vendor request {:?} {:?}",
                         request_type, request
                     );
-                    self.hal_driver.stall_request();
+                    self.stall_request(StallReason::UnhandledRequest);
                 }
             }
             _ => {
@@ -211,7 +398,7 @@ where
                     "SETUP stall: unhandled request {:?} {:?}",
                     request_type, request
                 );
-                self.hal_driver.stall_request();
+                self.stall_request(StallReason::UnhandledRequest);
             }
         }
 
@@ -229,6 +416,133 @@ where
         Ok(())
     }
 
+    /// Handle an event reported by the hal driver.
+    ///
+    /// Standard requests are answered by the device. Class and vendor
+    /// requests, OUT packets and IN completions are offered to each of
+    /// `classes` in turn. Class and vendor requests that no class
+    /// claims are passed on to the `cb_class_request` and
+    /// `cb_vendor_request` callbacks.
+    ///
+    /// `data` holds the packet for [`UsbEvent::OutPacket`] events.
+    ///
+    /// Returns `false` if the event was an OUT packet or IN completion
+    /// that no class claimed.
+    pub fn poll(
+        &self,
+        event: &UsbEvent,
+        data: &[u8],
+        classes: &mut [&mut dyn UsbClass<D>],
+    ) -> SmolResult<bool> {
+        match event {
+            UsbEvent::BusReset => {
+                self.set_state(DeviceState::Reset);
+                self.suspended_state.replace(None);
+                self.test_mode_pending.replace(None);
+                for class in classes.iter_mut() {
+                    class.handle_bus_reset();
+                }
+                Ok(true)
+            }
+            UsbEvent::Setup(setup_packet) => {
+                self.poll_setup_request(setup_packet, classes)?;
+                Ok(true)
+            }
+            UsbEvent::OutPacket { endpoint, length } => {
+                let packet = &data[..usize::min(*length, data.len())];
+                if *endpoint == 0 && !packet.is_empty() {
+                    self.observe(ControlEvent::data_stage(Direction::HostToDevice, packet));
+                }
+                let mut handled = false;
+                for class in classes.iter_mut() {
+                    if class.handle_receive_packet(&self.hal_driver, *endpoint, packet) {
+                        handled = true;
+                        break;
+                    }
+                }
+
+                // the control endpoint is always ready for the next
+                // data or status stage
+                if *endpoint == 0 {
+                    self.hal_driver.ack(0, Direction::DeviceToHost);
+                }
+
+                Ok(handled)
+            }
+            UsbEvent::InComplete { endpoint } => {
+                if *endpoint == 0 && self.enter_pending_test_mode()? {
+                    return Ok(true);
+                }
+
+                // the IN FIFO is shared by all endpoints so every class
+                // gets to see every completion
+                let mut handled = false;
+                for class in classes.iter_mut() {
+                    handled |= class.handle_transfer_complete(&self.hal_driver, *endpoint);
+                }
+                Ok(handled)
+            }
+            UsbEvent::Suspend => {
+                let state = self.state();
+                if state != DeviceState::Suspend {
+                    self.suspended_state.replace(Some(state));
+                    self.set_state(DeviceState::Suspend);
+                }
+                Ok(true)
+            }
+            UsbEvent::Resume => {
+                if let Some(state) = self.suspended_state.take() {
+                    self.set_state(state);
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    fn poll_setup_request(
+        &self,
+        setup_packet: &SetupPacket,
+        classes: &mut [&mut dyn UsbClass<D>],
+    ) -> SmolResult<()> {
+        self.observe_setup_request(setup_packet);
+
+        match setup_packet.request_type() {
+            RequestType::Class | RequestType::Vendor => {
+                for class in classes.iter_mut() {
+                    if class.handle_setup_request(&self.hal_driver, setup_packet)? {
+                        return Ok(());
+                    }
+                }
+                self.dispatch_setup_request(setup_packet)
+            }
+            _ => {
+                self.dispatch_setup_request(setup_packet)?;
+
+                if self.state() != DeviceState::Configured {
+                    return Ok(());
+                }
+                match setup_packet.request() {
+                    Request::SetConfiguration if setup_packet.value <= 1 => {
+                        for class in classes.iter_mut() {
+                            class.handle_set_configuration(&self.hal_driver, setup_packet.value as u8);
+                        }
+                    }
+                    Request::SetInterface => {
+                        for class in classes.iter_mut() {
+                            class.handle_set_interface(
+                                &self.hal_driver,
+                                setup_packet.index as u8,
+                                setup_packet.value as u8,
+                            );
+                        }
+                    }
+                    _ => (),
+                }
+                Ok(())
+            }
+        }
+    }
+
     // TODO move tx_ack_active flag logic to hal_driver
     fn handle_set_address(&self, setup_packet: &SetupPacket) -> SmolResult<()> {
         // set tx_ack_active flag
@@ -240,6 +554,7 @@ where
         // respond with ack status first before changing device address
         //self.hal_driver.ack_status_stage(setup_packet);
         self.hal_driver.ack(0, Direction::HostToDevice);
+        self.observe(ControlEvent::StatusStage);
 
         // wait for the response packet to get sent
         // TODO a slightly safer approach would be nice
@@ -253,7 +568,7 @@ where
         // activate new address
         let address: u8 = (setup_packet.value & 0x7f) as u8;
         self.hal_driver.set_address(address);
-        self.state.replace(DeviceState::Address.into());
+        self.set_state(DeviceState::Address);
 
         Ok(())
     }
@@ -268,7 +583,7 @@ where
                     "SETUP stall: invalid descriptor type: {} {}",
                     descriptor_type_bits, descriptor_number
                 );
-                self.hal_driver.stall_request();
+                self.stall_request(StallReason::InvalidDescriptorType);
                 return Ok(());
             }
         };
@@ -278,17 +593,15 @@ where
         let requested_length = setup_packet.length as usize;
 
         match (&descriptor_type, descriptor_number) {
-            (DescriptorType::Device, 0) => self
-                .hal_driver
-                .write_ref(0, self.device_descriptor.as_iter().take(requested_length)),
-            (DescriptorType::Configuration, 0) => self.hal_driver.write_ref(
-                0,
-                self.configuration_descriptor.iter().take(requested_length),
-            ),
+            (DescriptorType::Device, 0) => {
+                self.write_data_stage(self.device_descriptor.as_iter().take(requested_length))?;
+            }
+            (DescriptorType::Configuration, 0) => {
+                self.write_data_stage(self.configuration_descriptor.iter().take(requested_length))?;
+            }
             (DescriptorType::DeviceQualifier, 0) => {
                 if let Some(descriptor) = &self.device_qualifier_descriptor {
-                    self.hal_driver
-                        .write_ref(0, descriptor.as_iter().take(requested_length));
+                    self.write_data_stage(descriptor.as_iter().take(requested_length))?;
                 } else {
                     warn!("SETUP stall: no device qualifier descriptor configured");
                     // TODO stall?
@@ -296,16 +609,15 @@ where
             }
             (DescriptorType::OtherSpeedConfiguration, 0) => {
                 if let Some(descriptor) = self.other_speed_configuration_descriptor {
-                    self.hal_driver
-                        .write_ref(0, descriptor.iter().take(requested_length));
+                    self.write_data_stage(descriptor.iter().take(requested_length))?;
                 } else {
                     warn!("SETUP stall: no other speed configuration descriptor configured");
                     // TODO stall?
                 }
             }
-            (DescriptorType::String, 0) => self
-                .hal_driver
-                .write_ref(0, self.string_descriptor_zero.iter().take(requested_length)),
+            (DescriptorType::String, 0) => {
+                self.write_data_stage(self.string_descriptor_zero.iter().take(requested_length))?;
+            }
             (DescriptorType::String, index) => {
                 let offset_index: usize = (index - 1).into();
 
@@ -314,29 +626,28 @@ where
                         cb(self, setup_packet, index);
                     } else {
                         warn!("SETUP stall: unknown string descriptor {}", index);
-                        self.hal_driver.stall_request();
+                        self.stall_request(StallReason::UnknownDescriptor);
                     }
                     return Ok(());
                 }
 
-                self.hal_driver.write(
-                    0,
+                self.write_data_stage(
                     self.string_descriptors[offset_index]
                         .iter()
                         .take(requested_length),
-                )
+                )?;
             }
             _ => {
                 warn!(
                     "SETUP stall: unhandled descriptor {:?}, {}",
                     descriptor_type, descriptor_number
                 );
-                self.hal_driver.stall_request();
+                self.stall_request(StallReason::UnknownDescriptor);
                 return Ok(());
             }
         }
 
-        self.hal_driver.ack_status_stage(setup_packet);
+        self.ack_status_stage(setup_packet);
 
         trace!(
             "SETUP handle_get_descriptor({:?}({}), {}, {})",
@@ -350,17 +661,17 @@ where
     }
 
     fn handle_set_configuration(&self, setup_packet: &SetupPacket) -> SmolResult<()> {
-        self.hal_driver.ack_status_stage(setup_packet);
+        self.ack_status_stage(setup_packet);
 
         trace!("SETUP handle_set_configuration()");
 
         let configuration = setup_packet.value;
         if configuration > 1 {
             warn!("SETUP stall: unknown configuration {}", configuration);
-            self.hal_driver.stall_request();
+            self.stall_request(StallReason::UnknownConfiguration);
             return Ok(());
         }
-        self.state.replace(DeviceState::Configured.into());
+        self.set_state(DeviceState::Configured);
 
         Ok(())
     }
@@ -370,9 +681,28 @@ where
 
         let requested_length = setup_packet.length as usize;
 
-        self.hal_driver
-            .write(0, [1].into_iter().take(requested_length));
-        self.hal_driver.ack_status_stage(setup_packet);
+        self.write_data_stage([1].into_iter().take(requested_length))?;
+        self.ack_status_stage(setup_packet);
+
+        Ok(())
+    }
+
+    /// Alternate settings are owned by the class implementing the
+    /// interface, so we only need to acknowledge the request here.
+    fn handle_set_interface(&self, setup_packet: &SetupPacket) -> SmolResult<()> {
+        trace!(
+            "SETUP handle_set_interface() interface:{} alternate_setting:{}",
+            setup_packet.index,
+            setup_packet.value
+        );
+
+        if self.state() != DeviceState::Configured {
+            warn!("SETUP stall: set interface on unconfigured device");
+            self.stall_request(StallReason::NotConfigured);
+            return Ok(());
+        }
+
+        self.ack_status_stage(setup_packet);
 
         Ok(())
     }
@@ -385,7 +715,7 @@ where
             Ok(feature) => feature,
             Err(e) => {
                 warn!("SETUP stall: invalid clear feature type: {}", feature_bits);
-                self.hal_driver.stall_request();
+                self.stall_request(StallReason::InvalidFeature);
                 return Ok(());
             }
         };
@@ -398,7 +728,7 @@ where
                 let endpoint_address = setup_packet.index as u8;
                 self.hal_driver
                     .clear_feature_endpoint_halt(endpoint_address);
-                self.hal_driver.ack_status_stage(setup_packet);
+                self.ack_status_stage(setup_packet);
                 debug!(
                     "SETUP handle_clear_feature EndpointHalt: 0x{:x}",
                     endpoint_address
@@ -409,7 +739,7 @@ where
                     "SETUP stall: unhandled clear feature {:?}, {:?}",
                     recipient, feature
                 );
-                self.hal_driver.stall_request();
+                self.stall_request(StallReason::UnhandledFeature);
                 return Ok(());
             }
         };
@@ -427,7 +757,7 @@ where
             Ok(feature) => feature,
             Err(e) => {
                 warn!("SETUP stall: invalid set feature type: {}", feature_bits);
-                self.hal_driver.stall_request();
+                self.stall_request(StallReason::InvalidFeature);
                 return Ok(());
             }
         };
@@ -436,18 +766,61 @@ where
             (Recipient::Device, Feature::DeviceRemoteWakeup) => {
                 // TODO self.feature_remote_wakeup = true;
             }
+            (Recipient::Device, Feature::TestMode) => {
+                // the selector is in the high byte of wIndex, the low byte must be zero
+                let [low, selector] = setup_packet.index.to_le_bytes();
+                let test_mode = match TestMode::try_from(selector) {
+                    Ok(test_mode) if low == 0 => test_mode,
+                    _ => {
+                        warn!("SETUP stall: invalid test mode selector: 0x{:x}", setup_packet.index);
+                        self.stall_request(StallReason::InvalidTestMode);
+                        return Ok(());
+                    }
+                };
+                if !self.hal_driver.supports_test_mode(test_mode) {
+                    warn!("SETUP stall: unsupported test mode: {:?}", test_mode);
+                    self.stall_request(StallReason::UnsupportedTestMode);
+                    return Ok(());
+                }
+
+                // the device may only enter the test mode once the
+                // status stage has completed
+                self.test_mode_pending.replace(Some(test_mode));
+                self.ack_status_stage(setup_packet);
+                debug!("SETUP handle_set_feature TestMode: {:?}", test_mode);
+            }
             _ => {
                 warn!(
                     "SETUP stall: unhandled set feature {:?}, {:?}",
                     recipient, feature
                 );
-                self.hal_driver.stall_request();
+                self.stall_request(StallReason::UnhandledFeature);
                 return Ok(());
             }
         };
 
         Ok(())
     }
+
+    /// Enter a test mode requested by SET_FEATURE(TEST_MODE).
+    ///
+    /// Must be called once the status stage of the request has
+    /// completed. [`UsbDevice::poll`] does this when it receives the
+    /// IN completion for endpoint zero, firmware that calls
+    /// [`UsbDevice::handle_setup_request`] directly should call it
+    /// from its own transfer complete handler.
+    ///
+    /// Returns true if a test mode was entered.
+    pub fn enter_pending_test_mode(&self) -> SmolResult<bool> {
+        match self.test_mode_pending.take() {
+            Some(test_mode) => {
+                info!("Entering test mode: {:?}", test_mode);
+                self.hal_driver.enter_test_mode(test_mode)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
 }
 
 /*
@@ -465,3 +838,378 @@ where
 # - Read back configuration number and validate.
 
 */
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockUsbDriver;
+    use crate::observer::{EventRecord, EventRecorder};
+
+    use std::vec::Vec;
+
+    // - fixtures -------------------------------------------------------------
+
+    const DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
+        max_packet_size: 64,
+        num_configurations: 1,
+        ..DeviceDescriptor::new()
+    };
+    const CONFIGURATION_DESCRIPTOR: ConfigurationDescriptor = ConfigurationDescriptor::new(
+        ConfigurationDescriptorHeader {
+            configuration_value: 1,
+            ..ConfigurationDescriptorHeader::new()
+        },
+        &[],
+    );
+    const STRING_DESCRIPTOR_0: StringDescriptorZero =
+        StringDescriptorZero::new(&[LanguageId::EnglishUnitedStates]);
+
+    /// Records the events it is offered and claims vendor requests
+    /// and packets for a single endpoint.
+    #[derive(Default)]
+    struct TestClass {
+        endpoint: u8,
+        events: Vec<&'static str>,
+        configuration: Option<u8>,
+        packet: Vec<u8>,
+    }
+
+    impl<D> UsbClass<D> for TestClass {
+        fn handle_bus_reset(&mut self) {
+            self.events.push("reset");
+        }
+
+        fn handle_setup_request(&mut self, _hal_driver: &D, setup_packet: &SetupPacket) -> SmolResult<bool> {
+            let claimed = setup_packet.request_type() == RequestType::Vendor;
+            if claimed {
+                self.events.push("setup");
+            }
+            Ok(claimed)
+        }
+
+        fn handle_set_configuration(&mut self, _hal_driver: &D, configuration: u8) {
+            self.configuration = Some(configuration);
+        }
+
+        fn handle_receive_packet(&mut self, _hal_driver: &D, endpoint: u8, packet: &[u8]) -> bool {
+            if endpoint != self.endpoint {
+                return false;
+            }
+            self.packet = packet.to_vec();
+            true
+        }
+
+        fn handle_transfer_complete(&mut self, _hal_driver: &D, endpoint: u8) -> bool {
+            self.events.push("complete");
+            endpoint == self.endpoint
+        }
+    }
+
+    fn device<'a>() -> UsbDevice<'a, MockUsbDriver> {
+        UsbDevice::new(
+            MockUsbDriver::new(),
+            &DEVICE_DESCRIPTOR,
+            &CONFIGURATION_DESCRIPTOR,
+            &STRING_DESCRIPTOR_0,
+            &[],
+        )
+    }
+
+    fn setup(request_type: u8, request: u8, value: u16) -> UsbEvent {
+        UsbEvent::Setup(SetupPacket {
+            request_type,
+            request,
+            value,
+            index: 0,
+            length: 0,
+        })
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_poll_setup() {
+        let device = device();
+        let mut class = TestClass::default();
+
+        // vendor requests are claimed by the class
+        device.poll(&setup(0x40, 0x01, 0), &[], &mut [&mut class]).unwrap();
+        assert_eq!(class.events, ["setup"]);
+        assert!(!device.hal_driver.is_stalled());
+
+        // class requests fall through to the device, which stalls
+        device.poll(&setup(0x21, 0x01, 0), &[], &mut [&mut class]).unwrap();
+        assert_eq!(class.events, ["setup"]);
+        assert!(device.hal_driver.is_stalled());
+
+        // classes are told about the configuration once it is set
+        device.hal_driver.clear();
+        device.poll(&setup(0x00, 0x09, 1), &[], &mut [&mut class]).unwrap();
+        assert_eq!(device.state(), DeviceState::Configured);
+        assert_eq!(class.configuration, Some(1));
+    }
+
+    #[test]
+    fn test_observer() {
+        let recorder: EventRecorder<16> = EventRecorder::new();
+        let mut device = device();
+        device.set_observer(&recorder, || 42);
+        let mut class = TestClass::default();
+
+        let get_descriptor = SetupPacket {
+            request_type: 0x80,
+            request: 0x06,
+            value: 0x0100,
+            index: 0,
+            length: 8,
+        };
+        device
+            .poll(&UsbEvent::Setup(get_descriptor.clone()), &[], &mut [&mut class])
+            .unwrap();
+        let class_request = setup(0x21, 0x01, 0);
+        device.poll(&class_request, &[], &mut [&mut class]).unwrap();
+        let set_configuration = setup(0x00, 0x09, 1);
+        device.poll(&set_configuration, &[], &mut [&mut class]).unwrap();
+
+        let mut events = Vec::new();
+        recorder.drain(|EventRecord { timestamp, event }| {
+            assert_eq!(*timestamp, 42);
+            events.push(event.clone());
+        });
+        assert_eq!(recorder.dropped(), 0);
+
+        let setup_received = |event| match event {
+            UsbEvent::Setup(setup_packet) => ControlEvent::SetupReceived(setup_packet),
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            events,
+            [
+                // handled by the device
+                ControlEvent::SetupReceived(get_descriptor),
+                ControlEvent::Request {
+                    recipient: Recipient::Device,
+                    direction: Direction::DeviceToHost,
+                    request_type: RequestType::Standard,
+                    request: Request::GetDescriptor,
+                },
+                ControlEvent::data_stage(
+                    Direction::DeviceToHost,
+                    &device.hal_driver.take_writes()[0].1[..8]
+                ),
+                ControlEvent::StatusStage,
+                // claimed by no class
+                setup_received(class_request),
+                ControlEvent::Request {
+                    recipient: Recipient::Interface,
+                    direction: Direction::HostToDevice,
+                    request_type: RequestType::Class,
+                    request: Request::ClearFeature,
+                },
+                ControlEvent::Stall(StallReason::UnhandledRequest),
+                // changes the device state
+                setup_received(set_configuration),
+                ControlEvent::Request {
+                    recipient: Recipient::Device,
+                    direction: Direction::HostToDevice,
+                    request_type: RequestType::Standard,
+                    request: Request::SetConfiguration,
+                },
+                ControlEvent::StatusStage,
+                ControlEvent::StateChange {
+                    from: DeviceState::Reset,
+                    to: DeviceState::Configured
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_poll_packets() {
+        let device = device();
+        let mut first = TestClass {
+            endpoint: 1,
+            ..TestClass::default()
+        };
+        let mut second = TestClass {
+            endpoint: 2,
+            ..TestClass::default()
+        };
+
+        let event = UsbEvent::OutPacket {
+            endpoint: 2,
+            length: 3,
+        };
+        let handled = device.poll(&event, &[1, 2, 3, 4], &mut [&mut first, &mut second]);
+        assert_eq!(handled, Ok(true));
+        assert!(first.packet.is_empty());
+        assert_eq!(second.packet, [1, 2, 3]);
+
+        // unclaimed packets are returned to the caller
+        let event = UsbEvent::OutPacket {
+            endpoint: 3,
+            length: 0,
+        };
+        let handled = device.poll(&event, &[], &mut [&mut first, &mut second]);
+        assert_eq!(handled, Ok(false));
+
+        // control endpoint is primed after each packet
+        let event = UsbEvent::OutPacket {
+            endpoint: 0,
+            length: 0,
+        };
+        device.poll(&event, &[], &mut [&mut first, &mut second]).unwrap();
+        assert_eq!(*device.hal_driver.primed.borrow(), [0]);
+
+        // all classes see every completion
+        let event = UsbEvent::InComplete { endpoint: 1 };
+        let handled = device.poll(&event, &[], &mut [&mut first, &mut second]);
+        assert_eq!(handled, Ok(true));
+        assert_eq!(first.events, ["complete"]);
+        assert_eq!(second.events, ["complete"]);
+    }
+
+    #[test]
+    fn test_poll_bus_state() {
+        let device = device();
+        let mut class = TestClass::default();
+
+        device.poll(&setup(0x00, 0x09, 1), &[], &mut [&mut class]).unwrap();
+        device.poll(&UsbEvent::Suspend, &[], &mut [&mut class]).unwrap();
+        assert_eq!(device.state(), DeviceState::Suspend);
+        device.poll(&UsbEvent::Resume, &[], &mut [&mut class]).unwrap();
+        assert_eq!(device.state(), DeviceState::Configured);
+
+        device.poll(&UsbEvent::BusReset, &[], &mut [&mut class]).unwrap();
+        assert_eq!(device.state(), DeviceState::Reset);
+        assert_eq!(class.events, ["reset"]);
+    }
+
+    #[test]
+    fn test_max_packet_size() {
+        const ENDPOINTS_HS: [EndpointDescriptor; 2] = [
+            EndpointDescriptor {
+                endpoint_address: 0x01,
+                attributes: 0x02,
+                max_packet_size: 512,
+                ..EndpointDescriptor::new()
+            },
+            EndpointDescriptor {
+                endpoint_address: 0x81,
+                attributes: 0x03,
+                max_packet_size: 0x0800 | 64, // two transactions per microframe
+                ..EndpointDescriptor::new()
+            },
+        ];
+        const ENDPOINTS_FS: [EndpointDescriptor; 1] = [EndpointDescriptor {
+            endpoint_address: 0x01,
+            attributes: 0x02,
+            max_packet_size: 64,
+            ..EndpointDescriptor::new()
+        }];
+        static INTERFACES_HS: [InterfaceDescriptor; 1] =
+            [InterfaceDescriptor::new(InterfaceDescriptorHeader::new(), &ENDPOINTS_HS)];
+        static INTERFACES_FS: [InterfaceDescriptor; 1] =
+            [InterfaceDescriptor::new(InterfaceDescriptorHeader::new(), &ENDPOINTS_FS)];
+        static CONFIGURATION_HS: ConfigurationDescriptor =
+            ConfigurationDescriptor::new(ConfigurationDescriptorHeader::new(), &INTERFACES_HS);
+
+        let mut device = UsbDevice::new(
+            MockUsbDriver::new(),
+            &DEVICE_DESCRIPTOR,
+            &CONFIGURATION_HS,
+            &STRING_DESCRIPTOR_0,
+            &[],
+        );
+        device.other_speed_configuration_descriptor = Some(ConfigurationDescriptor::new(
+            ConfigurationDescriptorHeader::new(),
+            &INTERFACES_FS,
+        ));
+
+        assert_eq!(device.connect(), Speed::High);
+        assert_eq!(device.max_packet_size(0x00), Some(64));
+        assert_eq!(device.max_packet_size(0x01), Some(512));
+        assert_eq!(device.max_packet_size(0x81), Some(64));
+        assert_eq!(device.max_packet_size(0x02), None);
+
+        device.speed.replace(Some(Speed::Full));
+        assert_eq!(device.max_packet_size(0x01), Some(64));
+        assert_eq!(device.max_packet_size(0x81), None);
+    }
+
+    #[test]
+    fn test_set_feature_test_mode() {
+        let device = device();
+        let mut class = TestClass::default();
+
+        let set_test_mode = |index: u16| {
+            UsbEvent::Setup(SetupPacket {
+                request_type: 0x00,
+                request: 0x03,
+                value: 0x0002,
+                index,
+                length: 0,
+            })
+        };
+
+        // invalid selectors and a non-zero low byte are stalled
+        device.poll(&set_test_mode(0x0600), &[], &mut [&mut class]).unwrap();
+        assert!(device.hal_driver.is_stalled());
+        device.hal_driver.stall_count.set(0);
+        device.poll(&set_test_mode(0x0401), &[], &mut [&mut class]).unwrap();
+        assert!(device.hal_driver.is_stalled());
+        device.hal_driver.stall_count.set(0);
+
+        // as are test modes the device controller does not support
+        device.poll(&set_test_mode(0x0100), &[], &mut [&mut class]).unwrap();
+        assert!(device.hal_driver.is_stalled());
+        device.hal_driver.stall_count.set(0);
+        assert_eq!(device.enter_pending_test_mode(), Ok(false));
+
+        // the request is acknowledged before the test mode is entered
+        device.poll(&set_test_mode(0x0400), &[], &mut [&mut class]).unwrap();
+        assert!(!device.hal_driver.is_stalled());
+        assert_eq!(device.hal_driver.take_writes(), [(0, Vec::new())]);
+        assert_eq!(device.hal_driver.test_mode.get(), None);
+
+        // test mode is entered once the status stage has completed
+        let handled = device.poll(&UsbEvent::InComplete { endpoint: 0 }, &[], &mut [&mut class]);
+        assert_eq!(handled, Ok(true));
+        assert_eq!(device.hal_driver.test_mode.get(), Some(TestMode::TestPacket));
+        assert!(class.events.is_empty());
+
+        // a bus reset discards a pending test mode
+        device.poll(&set_test_mode(0x0300), &[], &mut [&mut class]).unwrap();
+        device.poll(&UsbEvent::BusReset, &[], &mut [&mut class]).unwrap();
+        assert_eq!(device.enter_pending_test_mode(), Ok(false));
+        assert_eq!(device.hal_driver.test_mode.get(), Some(TestMode::TestPacket));
+    }
+    #[test]
+    fn test_endpoint_halt() {
+        let device = device();
+        let mut class = TestClass::default();
+        let hal_driver = &device.hal_driver;
+        let mut buffer = [0; 8];
+
+        // halted endpoints refuse reads and writes
+        hal_driver.stall_endpoint_address(0x81, true);
+        hal_driver.stall_endpoint_address(0x02, true);
+        assert_eq!(hal_driver.write(1, [1, 2].into_iter()), Err(SmolError::Stalled));
+        hal_driver.push_packet(2, &[1, 2]);
+        assert_eq!(hal_driver.read(2, &mut buffer), Err(SmolError::Stalled));
+
+        // until the host clears the halt
+        let clear_halt = UsbEvent::Setup(SetupPacket {
+            request_type: 0x02,
+            request: 0x01,
+            value: 0x0000,
+            index: 0x0081,
+            length: 0,
+        });
+        device.poll(&clear_halt, &[], &mut [&mut class]).unwrap();
+        assert_eq!(hal_driver.write(1, [1, 2].into_iter()), Ok(2));
+        assert_eq!(hal_driver.read(2, &mut buffer), Err(SmolError::Stalled));
+    }
+}
@@ -2,6 +2,20 @@
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum SmolError {
     FailedConversion,
+    /// Ran out of hardware endpoints
+    OutOfEndpoints,
+    /// Ran out of room for descriptors, interfaces or strings
+    OutOfSpace,
+    /// The endpoint FIFO still holds a packet that has not been sent
+    FifoBusy,
+    /// The packet received was larger than the buffer provided
+    Overflow,
+    /// The endpoint is halted
+    Stalled,
+    /// The device or interface has not been configured by the host
+    NotConfigured,
+    /// The device controller does not support the operation
+    Unsupported,
 }
 
 // trait:: core::fmt::Display
@@ -34,6 +48,13 @@ impl core::error::Error for SmolError {
         use SmolError::*;
         match self {
             FailedConversion => "Failed to convert packet value",
+            OutOfEndpoints => "Hardware endpoint budget exceeded",
+            OutOfSpace => "Descriptor storage exhausted",
+            FifoBusy => "Endpoint FIFO busy",
+            Overflow => "Packet larger than receive buffer",
+            Stalled => "Endpoint stalled",
+            NotConfigured => "Device not configured",
+            Unsupported => "Operation not supported by device controller",
         }
     }
 }
@@ -0,0 +1,25 @@
+//! USB device controller events
+
+use crate::control::SetupPacket;
+
+/// Events reported by a device controller
+///
+/// Drivers produce these from their interrupt or polling loop and
+/// firmware passes them to [`crate::device::UsbDevice::poll`].
+#[derive(Debug, Clone)]
+pub enum UsbEvent {
+    /// Received a USB bus reset
+    BusReset,
+    /// Received a SETUP packet on the control endpoint
+    Setup(SetupPacket),
+    /// Received a packet on an OUT endpoint
+    ///
+    /// The packet data is held by the buffer the driver read it into.
+    OutPacket { endpoint: u8, length: usize },
+    /// The host has read the packet written to an IN endpoint
+    InComplete { endpoint: u8 },
+    /// The bus has been idle for more than 3ms
+    Suspend,
+    /// The host resumed bus activity after a suspend
+    Resume,
+}
@@ -5,11 +5,19 @@
 //! Simple peripheral-level USB stack
 
 pub mod class;
+pub mod composite;
 pub mod control;
 pub mod descriptor;
 pub mod device;
 pub mod error;
+pub mod event;
+pub mod observer;
+pub mod personality;
 pub mod traits;
+pub mod transfer;
+
+#[cfg(test)]
+pub mod mock;
 
 pub use error::SmolError;
 pub use error::SmolResult;
@@ -0,0 +1,243 @@
+//! A mock hal driver for exercising `smolusb` on the host
+//!
+//! `MockUsbDriver` records everything the stack asks of the hardware
+//! so that tests can inspect the packets written, endpoints stalled
+//! and endpoints primed in response to a given request.
+
+use crate::control::{Direction, SetupPacket, TestMode};
+use crate::error::{SmolError, SmolResult};
+use crate::traits::{
+    ControlRead, EndpointRead, EndpointWrite, UnsafeUsbDriverOperations, UsbDriver,
+    UsbDriverOperations,
+};
+
+use core::borrow::Borrow;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+/// MockUsbDriver
+#[derive(Default)]
+pub struct MockUsbDriver {
+    /// Device address set by the stack.
+    pub address: Cell<u8>,
+    /// Number of times the current control request was stalled.
+    pub stall_count: Cell<usize>,
+    /// Endpoint addresses with their stall state.
+    pub endpoint_stalls: RefCell<Vec<(u8, bool)>>,
+    /// Packets written to IN endpoints as `(endpoint, data)`.
+    pub writes: RefCell<Vec<(u8, Vec<u8>)>>,
+    /// OUT endpoints primed to receive a packet.
+    pub primed: RefCell<Vec<u8>>,
+    /// Pending SETUP packet data.
+    pub control: RefCell<Vec<u8>>,
+    /// Pending OUT packets as `(endpoint, data)`.
+    pub packets: RefCell<VecDeque<(u8, Vec<u8>)>>,
+    /// Simulate an IN FIFO still holding an unsent packet.
+    pub fifo_busy: Cell<bool>,
+    /// Test mode entered by the stack.
+    pub test_mode: Cell<Option<TestMode>>,
+    tx_ack_active: Cell<bool>,
+}
+
+impl MockUsbDriver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a packet for the next call to `EndpointRead::read`.
+    pub fn push_packet(&self, endpoint: u8, data: &[u8]) {
+        self.packets.borrow_mut().push_back((endpoint, data.to_vec()));
+    }
+
+    /// Remove and return all packets written so far.
+    pub fn take_writes(&self) -> Vec<(u8, Vec<u8>)> {
+        self.writes.take()
+    }
+
+    /// Returns the data of the last packet written to the given endpoint.
+    pub fn last_write(&self, endpoint: u8) -> Option<Vec<u8>> {
+        self.writes
+            .borrow()
+            .iter()
+            .rev()
+            .find(|(ep, _)| *ep == endpoint)
+            .map(|(_, data)| data.clone())
+    }
+
+    /// Returns true if the current control request has been stalled.
+    pub fn is_stalled(&self) -> bool {
+        self.stall_count.get() > 0
+    }
+
+    /// Returns true if the given endpoint address is halted.
+    pub fn is_endpoint_stalled(&self, endpoint_address: u8) -> bool {
+        self.endpoint_stalls
+            .borrow()
+            .iter()
+            .rev()
+            .find(|(address, _)| *address == endpoint_address)
+            .map(|(_, state)| *state)
+            .unwrap_or(false)
+    }
+
+    /// Clear all recorded state.
+    pub fn clear(&self) {
+        self.stall_count.set(0);
+        self.endpoint_stalls.take();
+        self.writes.take();
+        self.primed.take();
+        self.fifo_busy.set(false);
+    }
+}
+
+// - trait: UsbDriverOperations -----------------------------------------------
+
+impl UsbDriverOperations for MockUsbDriver {
+    fn connect(&self) -> u8 {
+        0 // High
+    }
+
+    fn disconnect(&self) {}
+
+    fn reset(&self) -> u8 {
+        self.address.set(0);
+        0
+    }
+
+    fn bus_reset(&self) -> u8 {
+        self.address.set(0);
+        0
+    }
+
+    fn ack_status_stage(&self, packet: &SetupPacket) {
+        self.ack(0, Direction::from(packet.request_type));
+    }
+
+    fn ack(&self, endpoint: u8, direction: Direction) {
+        match direction {
+            Direction::DeviceToHost => self.primed.borrow_mut().push(endpoint),
+            Direction::HostToDevice => {
+                let _ = self.write(endpoint, [0_u8; 0].into_iter());
+                // the host collects our ZLP immediately
+                self.tx_ack_active.set(false);
+            }
+        }
+    }
+
+    fn set_address(&self, address: u8) {
+        self.address.set(address & 0x7f);
+    }
+
+    fn stall_request(&self) {
+        self.stall_count.set(self.stall_count.get() + 1);
+    }
+
+    fn stall_endpoint_address(&self, endpoint: u8, state: bool) {
+        self.endpoint_stalls.borrow_mut().push((endpoint, state));
+    }
+
+    fn stall_endpoint_in(&self, endpoint: u8) {
+        self.endpoint_stalls
+            .borrow_mut()
+            .push((endpoint | 0x80, true));
+    }
+
+    fn stall_endpoint_out(&self, endpoint: u8) {
+        self.endpoint_stalls.borrow_mut().push((endpoint, true));
+    }
+
+    fn clear_feature_endpoint_halt(&self, endpoint_address: u8) {
+        self.endpoint_stalls
+            .borrow_mut()
+            .push((endpoint_address, false));
+    }
+
+    fn supports_test_mode(&self, mode: TestMode) -> bool {
+        matches!(mode, TestMode::TestSe0Nak | TestMode::TestPacket)
+    }
+
+    fn enter_test_mode(&self, mode: TestMode) -> SmolResult<()> {
+        self.test_mode.set(Some(mode));
+        Ok(())
+    }
+}
+
+// - trait: UnsafeUsbDriverOperations -----------------------------------------
+
+impl UnsafeUsbDriverOperations for MockUsbDriver {
+    unsafe fn set_tx_ack_active(&self) {
+        self.tx_ack_active.set(true);
+    }
+    unsafe fn clear_tx_ack_active(&self) {
+        self.tx_ack_active.set(false);
+    }
+    unsafe fn is_tx_ack_active(&self) -> bool {
+        self.tx_ack_active.get()
+    }
+}
+
+// - trait: Read/Write traits -------------------------------------------------
+
+impl ControlRead for MockUsbDriver {
+    fn read_control(&self, buffer: &mut [u8]) -> usize {
+        let control = self.control.take();
+        let bytes_read = usize::min(control.len(), buffer.len());
+        buffer[..bytes_read].copy_from_slice(&control[..bytes_read]);
+        bytes_read
+    }
+}
+
+impl EndpointRead for MockUsbDriver {
+    fn read(&self, endpoint: u8, buffer: &mut [u8]) -> SmolResult<usize> {
+        if endpoint & 0xf != 0 && self.is_endpoint_stalled(endpoint & 0xf) {
+            return Err(SmolError::Stalled);
+        }
+        let mut packets = self.packets.borrow_mut();
+        match packets.front() {
+            Some((ep, _)) if *ep == endpoint => (),
+            _ => return Ok(0),
+        }
+        let (_, data) = packets.pop_front().unwrap();
+        let bytes_read = usize::min(data.len(), buffer.len());
+        buffer[..bytes_read].copy_from_slice(&data[..bytes_read]);
+        if bytes_read < data.len() {
+            return Err(SmolError::Overflow);
+        }
+        Ok(bytes_read)
+    }
+}
+
+impl EndpointWrite for MockUsbDriver {
+    fn write<I, B>(&self, endpoint: u8, iter: I) -> SmolResult<usize>
+    where
+        I: Iterator<Item = B>,
+        B: Borrow<u8>,
+    {
+        match self.try_write(endpoint, iter) {
+            Ok(bytes_written) => Ok(bytes_written),
+            Err(nb::Error::WouldBlock) => Err(SmolError::FifoBusy),
+            Err(nb::Error::Other(e)) => Err(e),
+        }
+    }
+
+    fn try_write<I, B>(&self, endpoint: u8, iter: I) -> nb::Result<usize, SmolError>
+    where
+        I: Iterator<Item = B>,
+        B: Borrow<u8>,
+    {
+        if self.fifo_busy.get() {
+            return Err(nb::Error::WouldBlock);
+        }
+        if endpoint & 0xf != 0 && self.is_endpoint_stalled(endpoint | 0x80) {
+            return Err(nb::Error::Other(SmolError::Stalled));
+        }
+        let data: Vec<u8> = iter.map(|byte| *byte.borrow()).collect();
+        let bytes_written = data.len();
+        self.writes.borrow_mut().push((endpoint & 0xf, data));
+        Ok(bytes_written)
+    }
+}
+
+impl UsbDriver for MockUsbDriver {}
@@ -0,0 +1,238 @@
+//! Control transfer observers
+//!
+//! Logging from within the control request handlers changes bus
+//! timing enough to hide, or cause, the very enumeration problems it
+//! is meant to diagnose. Instead, a [`ControlObserver`] can be
+//! attached to a [`crate::device::UsbDevice`] to receive timestamped
+//! [`ControlEvent`]s as they happen.
+//!
+//! [`EventRecorder`] records events into a fixed-size ring buffer
+//! that can be dumped once the device is idle:
+//!
+//! ```ignore
+//! let recorder: EventRecorder<128> = EventRecorder::new();
+//! usb0.set_observer(&recorder, || timer.counter());
+//!
+//! // ... enumerate ...
+//!
+//! recorder.drain(|record| info!("{:>10} {:?}", record.timestamp, record.event));
+//! ```
+
+use crate::control::{Direction, Recipient, Request, RequestType, SetupPacket};
+use crate::device::DeviceState;
+
+use heapless::Deque;
+
+use core::cell::{Cell, RefCell};
+
+/// Number of data stage bytes captured by [`ControlEvent::DataStage`]
+pub const DATA_STAGE_CAPTURE_LENGTH: usize = 8;
+
+// - ControlEvent -------------------------------------------------------------
+
+/// Reason the device stalled a control request
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StallReason {
+    /// No handler for the request
+    UnhandledRequest,
+    /// The descriptor type could not be parsed
+    InvalidDescriptorType,
+    /// No such descriptor
+    UnknownDescriptor,
+    /// No such configuration
+    UnknownConfiguration,
+    /// The request requires a configured device
+    NotConfigured,
+    /// The feature selector could not be parsed
+    InvalidFeature,
+    /// No handler for the feature
+    UnhandledFeature,
+    /// The test mode selector is invalid
+    InvalidTestMode,
+    /// The device controller does not support the test mode
+    UnsupportedTestMode,
+}
+
+/// Events observed during a control transfer
+#[derive(Debug, PartialEq, Clone)]
+pub enum ControlEvent {
+    /// Received a SETUP packet
+    SetupReceived(SetupPacket),
+    /// The SETUP packet as decoded by the device
+    Request {
+        recipient: Recipient,
+        direction: Direction,
+        request_type: RequestType,
+        request: Request,
+    },
+    /// Data stage, `data` holds up to the first
+    /// [`DATA_STAGE_CAPTURE_LENGTH`] bytes of the `length` bytes transferred
+    DataStage {
+        direction: Direction,
+        length: usize,
+        data: [u8; DATA_STAGE_CAPTURE_LENGTH],
+    },
+    /// Status stage acknowledged by the device
+    StatusStage,
+    /// The device stalled the request
+    Stall(StallReason),
+    /// The device changed state
+    StateChange { from: DeviceState, to: DeviceState },
+}
+
+impl ControlEvent {
+    /// Create a [`ControlEvent::DataStage`] event for the given bytes.
+    pub fn data_stage(direction: Direction, bytes: &[u8]) -> Self {
+        let mut data = [0; DATA_STAGE_CAPTURE_LENGTH];
+        let captured = usize::min(bytes.len(), DATA_STAGE_CAPTURE_LENGTH);
+        data[..captured].copy_from_slice(&bytes[..captured]);
+        ControlEvent::DataStage {
+            direction,
+            length: bytes.len(),
+            data,
+        }
+    }
+}
+
+// - ControlObserver ----------------------------------------------------------
+
+/// Receives control transfer events from a [`crate::device::UsbDevice`]
+///
+/// Observers are called from within the control request handlers and
+/// should return as quickly as possible.
+pub trait ControlObserver {
+    fn observe(&self, timestamp: u32, event: &ControlEvent);
+}
+
+// - EventRecorder ------------------------------------------------------------
+
+/// A recorded [`ControlEvent`]
+#[derive(Debug, PartialEq, Clone)]
+pub struct EventRecord {
+    pub timestamp: u32,
+    pub event: ControlEvent,
+}
+
+/// A [`ControlObserver`] that records the last `N` events
+///
+/// Once the buffer is full the oldest event is dropped to make room
+/// for each new event.
+pub struct EventRecorder<const N: usize> {
+    records: RefCell<Deque<EventRecord, N>>,
+    dropped: Cell<usize>,
+}
+
+impl<const N: usize> EventRecorder<N> {
+    pub const fn new() -> Self {
+        Self {
+            records: RefCell::new(Deque::new()),
+            dropped: Cell::new(0),
+        }
+    }
+
+    /// Number of events recorded.
+    pub fn len(&self) -> usize {
+        self.records.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.borrow().is_empty()
+    }
+
+    /// Number of events dropped since the recorder was last cleared.
+    pub fn dropped(&self) -> usize {
+        self.dropped.get()
+    }
+
+    /// Remove and return the oldest event.
+    pub fn pop(&self) -> Option<EventRecord> {
+        self.records.borrow_mut().pop_front()
+    }
+
+    /// Remove all events, passing each one to `f` oldest first.
+    pub fn drain<F>(&self, mut f: F)
+    where
+        F: FnMut(&EventRecord),
+    {
+        while let Some(record) = self.pop() {
+            f(&record);
+        }
+    }
+
+    pub fn clear(&self) {
+        self.records.borrow_mut().clear();
+        self.dropped.set(0);
+    }
+}
+
+impl<const N: usize> Default for EventRecorder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ControlObserver for EventRecorder<N> {
+    fn observe(&self, timestamp: u32, event: &ControlEvent) {
+        let mut records = self.records.borrow_mut();
+        if records.is_full() {
+            records.pop_front();
+            self.dropped.set(self.dropped.get() + 1);
+        }
+        let _ = records.push_back(EventRecord {
+            timestamp,
+            event: event.clone(),
+        });
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_data_stage_capture() {
+        let event = ControlEvent::data_stage(Direction::DeviceToHost, &[1, 2, 3]);
+        assert_eq!(
+            event,
+            ControlEvent::DataStage {
+                direction: Direction::DeviceToHost,
+                length: 3,
+                data: [1, 2, 3, 0, 0, 0, 0, 0],
+            }
+        );
+
+        let bytes: [u8; 18] = core::array::from_fn(|n| n as u8);
+        let event = ControlEvent::data_stage(Direction::DeviceToHost, &bytes);
+        assert_eq!(
+            event,
+            ControlEvent::DataStage {
+                direction: Direction::DeviceToHost,
+                length: 18,
+                data: [0, 1, 2, 3, 4, 5, 6, 7],
+            }
+        );
+    }
+
+    #[test]
+    fn test_recorder_overwrites_oldest() {
+        let recorder: EventRecorder<2> = EventRecorder::new();
+        recorder.observe(1, &ControlEvent::StatusStage);
+        recorder.observe(2, &ControlEvent::Stall(StallReason::UnhandledRequest));
+        recorder.observe(3, &ControlEvent::StatusStage);
+        assert_eq!(recorder.len(), 2);
+        assert_eq!(recorder.dropped(), 1);
+
+        let mut timestamps = [0; 2];
+        let mut count = 0;
+        recorder.drain(|record| {
+            timestamps[count] = record.timestamp;
+            count += 1;
+        });
+        assert_eq!(timestamps, [2, 3]);
+        assert!(recorder.is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+//! Runtime switchable device personalities
+//!
+//! A [`Personality`] holds everything that makes up a complete USB
+//! device definition. Switching personalities disconnects the device
+//! from the bus, resets its state and reconnects with the new
+//! descriptors so that the host enumerates it from scratch:
+//!
+//! ```ignore
+//! let mut switch = PersonalitySwitch::new(personalities.len());
+//!
+//! loop {
+//!     // a GCP verb or button press calls switch.request(index)
+//!     if let Some(index) = switch.take_request() {
+//!         device.switch_personality(&personalities[index], || delay_ms(100));
+//!     }
+//!     ...
+//! }
+//! ```
+//!
+//! Class handlers that implement [`crate::class::UsbClass`] are passed
+//! to [`crate::device::UsbDevice::poll`] by the firmware for the
+//! active personality.
+
+use crate::control::SetupPacket;
+use crate::descriptor::*;
+use crate::device::UsbDevice;
+
+use log::{debug, warn};
+
+/// Request callback as used by [`UsbDevice`]
+pub type RequestCallback<'a, D> = fn(device: &UsbDevice<'a, D>, setup_packet: &SetupPacket, request: u8);
+
+// - Personality --------------------------------------------------------------
+
+/// A complete device definition
+pub struct Personality<'a, D> {
+    pub name: &'static str,
+    pub device_descriptor: &'a DeviceDescriptor,
+    pub configuration_descriptor: &'a ConfigurationDescriptor<'a>,
+    pub device_qualifier_descriptor: Option<&'a DeviceQualifierDescriptor>,
+    pub other_speed_configuration_descriptor: Option<ConfigurationDescriptor<'a>>,
+    pub string_descriptor_zero: &'a StringDescriptorZero<'a>,
+    pub string_descriptors: &'a [&'a StringDescriptor<'a>],
+
+    pub cb_class_request: Option<RequestCallback<'a, D>>,
+    pub cb_vendor_request: Option<RequestCallback<'a, D>>,
+    pub cb_string_request: Option<RequestCallback<'a, D>>,
+}
+
+impl<'a, D> Personality<'a, D> {
+    pub const fn new(
+        name: &'static str,
+        device_descriptor: &'a DeviceDescriptor,
+        configuration_descriptor: &'a ConfigurationDescriptor<'a>,
+        string_descriptor_zero: &'a StringDescriptorZero<'a>,
+        string_descriptors: &'a [&'a StringDescriptor<'a>],
+    ) -> Self {
+        Self {
+            name,
+            device_descriptor,
+            configuration_descriptor,
+            device_qualifier_descriptor: None,
+            other_speed_configuration_descriptor: None,
+            string_descriptor_zero,
+            string_descriptors,
+            cb_class_request: None,
+            cb_vendor_request: None,
+            cb_string_request: None,
+        }
+    }
+}
+
+// - PersonalitySwitch --------------------------------------------------------
+
+/// Tracks the active personality and any pending request to switch
+///
+/// Requests are usually made from within a control transfer, e.g. a
+/// GCP verb, so the switch itself is deferred until the firmware
+/// calls [`PersonalitySwitch::take_request`] once the transfer has
+/// completed.
+#[derive(Debug)]
+pub struct PersonalitySwitch {
+    active: usize,
+    count: usize,
+    requested: Option<usize>,
+}
+
+impl PersonalitySwitch {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            active: 0,
+            count,
+            requested: None,
+        }
+    }
+
+    /// Index of the active personality.
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    /// Number of personalities available.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Request a switch to the personality at `index`.
+    ///
+    /// Returns false if there is no such personality.
+    pub fn request(&mut self, index: usize) -> bool {
+        if index >= self.count {
+            warn!("PersonalitySwitch: no personality at index {}", index);
+            return false;
+        }
+        self.requested = Some(index);
+        true
+    }
+
+    /// Request a switch to the personality following the active one.
+    pub fn request_next(&mut self) {
+        if self.count > 0 {
+            self.requested = Some((self.active + 1) % self.count);
+        }
+    }
+
+    /// Returns the index of a pending request and makes it the active
+    /// personality.
+    ///
+    /// Requests for the already active personality are ignored.
+    pub fn take_request(&mut self) -> Option<usize> {
+        match self.requested.take() {
+            Some(index) if index != self.active => {
+                debug!("PersonalitySwitch: {} -> {}", self.active, index);
+                self.active = index;
+                Some(index)
+            }
+            _ => None,
+        }
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{DeviceState, Speed};
+    use crate::mock::MockUsbDriver;
+    use crate::traits::UsbDriverOperations;
+
+    // - fixtures -------------------------------------------------------------
+
+    const DEVICE_DESCRIPTOR_A: DeviceDescriptor = DeviceDescriptor {
+        max_packet_size: 64,
+        product_id: 0xaaaa,
+        num_configurations: 1,
+        ..DeviceDescriptor::new()
+    };
+    const DEVICE_DESCRIPTOR_B: DeviceDescriptor = DeviceDescriptor {
+        max_packet_size: 64,
+        product_id: 0xbbbb,
+        num_configurations: 1,
+        ..DeviceDescriptor::new()
+    };
+    const CONFIGURATION_DESCRIPTOR: ConfigurationDescriptor = ConfigurationDescriptor::new(
+        ConfigurationDescriptorHeader {
+            configuration_value: 1,
+            ..ConfigurationDescriptorHeader::new()
+        },
+        &[],
+    );
+    const STRING_DESCRIPTOR_0: StringDescriptorZero =
+        StringDescriptorZero::new(&[LanguageId::EnglishUnitedStates]);
+
+    fn vendor_request(device: &UsbDevice<MockUsbDriver>, _setup_packet: &SetupPacket, _request: u8) {
+        device.hal_driver.stall_request();
+    }
+
+    fn get_device_descriptor() -> SetupPacket {
+        SetupPacket {
+            request_type: 0x80,
+            request: 0x06,
+            value: 0x0100,
+            index: 0,
+            length: 18,
+        }
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_switch_requests() {
+        let mut switch = PersonalitySwitch::new(3);
+        assert_eq!(switch.take_request(), None);
+
+        assert!(!switch.request(3));
+        assert!(switch.request(0));
+        assert_eq!(switch.take_request(), None);
+
+        assert!(switch.request(2));
+        assert_eq!(switch.take_request(), Some(2));
+        assert_eq!(switch.active(), 2);
+        assert_eq!(switch.take_request(), None);
+
+        switch.request_next();
+        assert_eq!(switch.take_request(), Some(0));
+    }
+
+    #[test]
+    fn test_switch_personality() {
+        let personality_a = Personality::new(
+            "a",
+            &DEVICE_DESCRIPTOR_A,
+            &CONFIGURATION_DESCRIPTOR,
+            &STRING_DESCRIPTOR_0,
+            &[],
+        );
+        let personality_b = Personality {
+            cb_vendor_request: Some(vendor_request),
+            ..Personality::new(
+                "b",
+                &DEVICE_DESCRIPTOR_B,
+                &CONFIGURATION_DESCRIPTOR,
+                &STRING_DESCRIPTOR_0,
+                &[],
+            )
+        };
+
+        let mut device = UsbDevice::from_personality(MockUsbDriver::new(), &personality_a);
+        device.connect();
+        device.hal_driver.set_address(12);
+        device.state.replace(DeviceState::Configured);
+
+        let mut disconnected = false;
+        let speed = device.switch_personality(&personality_b, || disconnected = true);
+        assert!(disconnected);
+        assert_eq!(speed, Speed::High);
+        assert_eq!(device.state(), DeviceState::Reset);
+        assert!(device.cb_vendor_request.is_some());
+
+        // the host sees the new device descriptor
+        device.handle_setup_request(&get_device_descriptor()).unwrap();
+        let descriptor = device.hal_driver.last_write(0).unwrap();
+        assert_eq!(descriptor[10..12], 0xbbbb_u16.to_le_bytes());
+    }
+}
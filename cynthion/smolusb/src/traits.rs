@@ -1,7 +1,9 @@
-use crate::control::{Direction, SetupPacket};
+use crate::control::{Direction, SetupPacket, TestMode};
+use crate::error::{SmolError, SmolResult};
 
 use zerocopy::AsBytes;
 
+use core::borrow::Borrow;
 use core::slice;
 
 // - Read/Write ---------------------------------------------------------------
@@ -11,22 +13,36 @@ pub trait ControlRead {
 }
 
 pub trait EndpointRead {
-    fn read(&self, endpoint: u8, buffer: &mut [u8]) -> usize;
+    /// Read a received packet into `buffer`, returning the number of
+    /// bytes read.
+    ///
+    /// Returns [`SmolError::Overflow`] if the packet did not fit in
+    /// `buffer`, in which case the remaining bytes are discarded, or
+    /// [`SmolError::Stalled`] if the endpoint is halted.
+    fn read(&self, endpoint: u8, buffer: &mut [u8]) -> SmolResult<usize>;
 }
 
-// These two should be one trait
-// TODO return bytes_written
-
 pub trait EndpointWrite {
-    fn write<'a, I>(&self, endpoint: u8, iter: I)
+    /// Write a packet to the endpoint, waiting for any previous packet
+    /// to be sent first. Accepts iterators over either `u8` or `&u8`.
+    ///
+    /// Returns the number of bytes written, [`SmolError::FifoBusy`]
+    /// if the previous packet was not sent in time, or
+    /// [`SmolError::Stalled`] if the endpoint is halted.
+    fn write<I, B>(&self, endpoint: u8, iter: I) -> SmolResult<usize>
     where
-        I: Iterator<Item = u8>;
-}
-
-pub trait EndpointWriteRef {
-    fn write_ref<'a, I>(&self, endpoint: u8, iter: I)
+        I: Iterator<Item = B>,
+        B: Borrow<u8>;
+
+    /// Write a packet to the endpoint without waiting.
+    ///
+    /// Returns [`nb::Error::WouldBlock`] if the previous packet has not
+    /// yet been sent, or [`SmolError::Stalled`] if the endpoint is
+    /// halted.
+    fn try_write<I, B>(&self, endpoint: u8, iter: I) -> nb::Result<usize, SmolError>
     where
-        I: Iterator<Item = &'a u8>;
+        I: Iterator<Item = B>,
+        B: Borrow<u8>;
 }
 
 // - UsbDriverOperations ------------------------------------------------------
@@ -57,6 +73,21 @@ pub trait UsbDriverOperations {
 
     /// Clear any halt condition on the target endpoint, and clear the data toggle bit.
     fn clear_feature_endpoint_halt(&self, endpoint_address: u8);
+
+    /// Returns true if the device controller can enter the given
+    /// USB 2.0 test mode.
+    ///
+    /// Requests for unsupported test modes are stalled during the
+    /// SETUP stage.
+    fn supports_test_mode(&self, mode: TestMode) -> bool;
+
+    /// Place the device controller in the given USB 2.0 test mode.
+    ///
+    /// Called once the status stage of the SET_FEATURE request has
+    /// completed, and only for test modes the device controller
+    /// supports. The device can only leave a test mode by being
+    /// power cycled.
+    fn enter_test_mode(&self, mode: TestMode) -> SmolResult<()>;
 }
 
 pub trait UnsafeUsbDriverOperations {
@@ -70,7 +101,6 @@ pub trait UsbDriver:
     ControlRead
     + EndpointRead
     + EndpointWrite
-    + EndpointWriteRef
     + UsbDriverOperations
     + UnsafeUsbDriverOperations
 {
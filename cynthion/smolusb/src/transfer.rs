@@ -0,0 +1,448 @@
+//! Transfer-level bulk and interrupt endpoint API
+//!
+//! The device controller moves a single packet at a time. The types in
+//! this module split a transfer of arbitrary length into packets of
+//! the endpoint's maximum packet size and track its progress as the
+//! controller reports each packet.
+//!
+//! For example, sending a buffer on a bulk IN endpoint:
+//!
+//! ```ignore
+//! let max_packet_size = device.max_packet_size(0x81).unwrap_or(512);
+//! let mut transfer = InTransfer::new(0x81, &data, max_packet_size);
+//! transfer.start(&device.hal_driver)?;
+//!
+//! // ... then, for every `UsbEvent::InComplete { endpoint }`:
+//! if let TransferStatus::Complete(length) =
+//!     transfer.handle_in_complete(&device.hal_driver, endpoint)?
+//! {
+//!     ...
+//! }
+//! ```
+
+use crate::control::Direction;
+use crate::error::{SmolError, SmolResult};
+use crate::traits::{EndpointWrite, UsbDriverOperations};
+
+use log::trace;
+
+/// Progress of a transfer
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TransferStatus {
+    /// More packets remain to be transferred
+    Pending,
+    /// The transfer has completed with the given number of bytes
+    Complete(usize),
+}
+
+// - InTransfer ---------------------------------------------------------------
+
+/// A device to host transfer
+///
+/// The transfer is terminated by a short packet. If the length of the
+/// data is a multiple of the maximum packet size a zero length packet
+/// is appended so the host does not wait for more data.
+///
+/// The data may be borrowed, or owned by the transfer when it must
+/// outlive the buffer it was received in, e.g. a `heapless::Vec`.
+pub struct InTransfer<B> {
+    endpoint: u8,
+    data: B,
+    max_packet_size: usize,
+    /// Number of bytes written to the FIFO so far
+    offset: usize,
+    zlp_pending: bool,
+    complete: bool,
+}
+
+impl<B> InTransfer<B>
+where
+    B: AsRef<[u8]>,
+{
+    pub fn new(endpoint: u8, data: B, max_packet_size: u16) -> Self {
+        let max_packet_size = usize::max(max_packet_size.into(), 1);
+        let zlp_pending = data.as_ref().len() % max_packet_size == 0;
+        Self {
+            endpoint: endpoint & 0x7f,
+            data,
+            max_packet_size,
+            offset: 0,
+            zlp_pending,
+            complete: false,
+        }
+    }
+
+    /// Only terminate the transfer with a zero length packet if there
+    /// is no data to send.
+    ///
+    /// Used when the host will stop reading once it has received the
+    /// length it asked for, e.g. the data stage of a control request.
+    pub fn without_zlp(mut self) -> Self {
+        self.zlp_pending = self.data.as_ref().is_empty();
+        self
+    }
+
+    /// Terminate the transfer as the data stage of a control request
+    /// asking for `requested` bytes.
+    ///
+    /// The host stops reading once it has received the length it
+    /// asked for, so a zero length packet is only appended if the
+    /// data is shorter than that and fills its last packet.
+    pub fn with_requested_length(mut self, requested: usize) -> Self {
+        let length = self.data.as_ref().len();
+        self.zlp_pending =
+            length == 0 || (length < requested && length % self.max_packet_size == 0);
+        self
+    }
+
+    pub fn endpoint(&self) -> u8 {
+        self.endpoint
+    }
+
+    /// Number of bytes written to the endpoint so far.
+    pub fn bytes_transferred(&self) -> usize {
+        self.offset
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Write the first packet of the transfer.
+    pub fn start<D>(&mut self, hal_driver: &D) -> SmolResult<()>
+    where
+        D: EndpointWrite,
+    {
+        self.write_next(hal_driver).map(|_| ())
+    }
+
+    /// Call when the host has read a packet from an IN endpoint.
+    ///
+    /// Writes the next packet of the transfer and returns
+    /// [`TransferStatus::Complete`] once the host has read the final
+    /// packet. Completions for other endpoints are ignored.
+    pub fn handle_in_complete<D>(&mut self, hal_driver: &D, endpoint: u8) -> SmolResult<TransferStatus>
+    where
+        D: EndpointWrite,
+    {
+        if self.complete {
+            return Ok(TransferStatus::Complete(self.offset));
+        }
+        if endpoint & 0x7f != self.endpoint {
+            return Ok(TransferStatus::Pending);
+        }
+
+        if self.write_next(hal_driver)? {
+            Ok(TransferStatus::Pending)
+        } else {
+            trace!("InTransfer complete on endpoint {}: {} bytes", self.endpoint, self.offset);
+            self.complete = true;
+            Ok(TransferStatus::Complete(self.offset))
+        }
+    }
+
+    /// Returns false if there was nothing left to write.
+    fn write_next<D>(&mut self, hal_driver: &D) -> SmolResult<bool>
+    where
+        D: EndpointWrite,
+    {
+        let data = self.data.as_ref();
+        if self.offset < data.len() {
+            let end = usize::min(self.offset + self.max_packet_size, data.len());
+            let bytes_written = hal_driver.write(self.endpoint, data[self.offset..end].iter())?;
+            self.offset += bytes_written;
+            Ok(true)
+        } else if self.zlp_pending {
+            hal_driver.write(self.endpoint, [0_u8; 0].into_iter())?;
+            self.zlp_pending = false;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+// - OutTransfer --------------------------------------------------------------
+
+/// A host to device transfer
+///
+/// Packets are accumulated until either a short packet or the
+/// requested number of bytes has been received.
+pub struct OutTransfer<'a> {
+    endpoint: u8,
+    buffer: &'a mut [u8],
+    length: usize,
+    max_packet_size: usize,
+    received: usize,
+    complete: bool,
+}
+
+impl<'a> OutTransfer<'a> {
+    /// Receive up to `length` bytes into `buffer`.
+    pub fn new(endpoint: u8, buffer: &'a mut [u8], length: usize, max_packet_size: u16) -> Self {
+        let length = usize::min(length, buffer.len());
+        Self {
+            endpoint: endpoint & 0x7f,
+            buffer,
+            length,
+            max_packet_size: max_packet_size.into(),
+            received: 0,
+            complete: false,
+        }
+    }
+
+    pub fn endpoint(&self) -> u8 {
+        self.endpoint
+    }
+
+    /// Number of bytes received so far.
+    pub fn bytes_transferred(&self) -> usize {
+        self.received
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Returns the data received so far.
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[..self.received]
+    }
+
+    /// Prime the endpoint to receive the first packet of the transfer.
+    pub fn start<D>(&mut self, hal_driver: &D)
+    where
+        D: UsbDriverOperations,
+    {
+        hal_driver.ack(self.endpoint, Direction::DeviceToHost);
+    }
+
+    /// Call when a packet has been received on an OUT endpoint.
+    ///
+    /// Returns [`TransferStatus::Complete`] once the transfer has
+    /// received a short packet or the requested length, otherwise the
+    /// endpoint is primed for the next packet. Packets for other
+    /// endpoints are ignored.
+    pub fn handle_receive_packet<D>(
+        &mut self,
+        hal_driver: &D,
+        endpoint: u8,
+        packet: &[u8],
+    ) -> SmolResult<TransferStatus>
+    where
+        D: UsbDriverOperations,
+    {
+        if self.complete {
+            return Ok(TransferStatus::Complete(self.received));
+        }
+        if endpoint & 0x7f != self.endpoint {
+            return Ok(TransferStatus::Pending);
+        }
+
+        let remaining = self.length - self.received;
+        if packet.len() > remaining {
+            return Err(SmolError::Overflow);
+        }
+        self.buffer[self.received..self.received + packet.len()].copy_from_slice(packet);
+        self.received += packet.len();
+
+        if packet.len() < self.max_packet_size || self.received == self.length {
+            trace!("OutTransfer complete on endpoint {}: {} bytes", self.endpoint, self.received);
+            self.complete = true;
+            return Ok(TransferStatus::Complete(self.received));
+        }
+
+        hal_driver.ack(self.endpoint, Direction::DeviceToHost);
+        Ok(TransferStatus::Pending)
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockUsbDriver;
+
+    use std::vec::Vec;
+
+    // - fixtures -------------------------------------------------------------
+
+    /// Runs an IN transfer to completion and returns the packets written.
+    fn run_in_transfer(data: &[u8], max_packet_size: u16) -> (usize, Vec<Vec<u8>>) {
+        let hal = MockUsbDriver::new();
+        let mut transfer = InTransfer::new(0x81, data, max_packet_size);
+        transfer.start(&hal).unwrap();
+
+        let mut length = None;
+        for _ in 0..100 {
+            if let TransferStatus::Complete(n) = transfer.handle_in_complete(&hal, 1).unwrap() {
+                length = Some(n);
+                break;
+            }
+        }
+
+        let packets = hal.take_writes().into_iter().map(|(ep, data)| {
+            assert_eq!(ep, 1);
+            data
+        });
+        (length.expect("transfer did not complete"), packets.collect())
+    }
+
+    // - tests ----------------------------------------------------------------
+
+    #[test]
+    fn test_in_transfer_short_packet() {
+        let data: Vec<u8> = (0..150).collect();
+        let (length, packets) = run_in_transfer(&data, 64);
+
+        assert_eq!(length, 150);
+        let sizes: Vec<usize> = packets.iter().map(|p| p.len()).collect();
+        assert_eq!(sizes, [64, 64, 22]);
+        assert_eq!(packets.concat(), data);
+    }
+
+    #[test]
+    fn test_in_transfer_appends_zlp() {
+        let data = [0xaa; 128];
+        let (length, packets) = run_in_transfer(&data, 64);
+
+        assert_eq!(length, 128);
+        let sizes: Vec<usize> = packets.iter().map(|p| p.len()).collect();
+        assert_eq!(sizes, [64, 64, 0]);
+
+        let (length, packets) = run_in_transfer(&[], 64);
+        assert_eq!(length, 0);
+        assert_eq!(packets, [Vec::<u8>::new()]);
+    }
+
+    #[test]
+    fn test_in_transfer_without_zlp() {
+        let hal = MockUsbDriver::new();
+        let data = [0xaa; 128];
+        let mut transfer = InTransfer::new(0x80, &data, 64).without_zlp();
+        transfer.start(&hal).unwrap();
+
+        assert_eq!(transfer.handle_in_complete(&hal, 0), Ok(TransferStatus::Pending));
+        assert_eq!(transfer.handle_in_complete(&hal, 0), Ok(TransferStatus::Complete(128)));
+        let sizes: Vec<usize> = hal.take_writes().iter().map(|(_, p)| p.len()).collect();
+        assert_eq!(sizes, [64, 64]);
+
+        // an empty transfer still needs a packet
+        let mut transfer = InTransfer::new(0x80, &[], 64).without_zlp();
+        transfer.start(&hal).unwrap();
+        assert_eq!(transfer.handle_in_complete(&hal, 0), Ok(TransferStatus::Complete(0)));
+        assert_eq!(hal.take_writes(), [(0, Vec::new())]);
+    }
+
+    #[test]
+    fn test_in_transfer_with_requested_length() {
+        let hal = MockUsbDriver::new();
+        let data = [0xaa; 128];
+
+        // shorter than requested, the host needs a zero length packet
+        let mut transfer = InTransfer::new(0x80, &data, 64).with_requested_length(255);
+        transfer.start(&hal).unwrap();
+        transfer.handle_in_complete(&hal, 0).unwrap();
+        assert_eq!(transfer.handle_in_complete(&hal, 0), Ok(TransferStatus::Pending));
+        assert_eq!(transfer.handle_in_complete(&hal, 0), Ok(TransferStatus::Complete(128)));
+        let sizes: Vec<usize> = hal.take_writes().iter().map(|(_, p)| p.len()).collect();
+        assert_eq!(sizes, [64, 64, 0]);
+
+        // exactly as requested, the host stops reading by itself
+        let mut transfer = InTransfer::new(0x80, &data, 64).with_requested_length(128);
+        transfer.start(&hal).unwrap();
+        transfer.handle_in_complete(&hal, 0).unwrap();
+        assert_eq!(transfer.handle_in_complete(&hal, 0), Ok(TransferStatus::Complete(128)));
+        let sizes: Vec<usize> = hal.take_writes().iter().map(|(_, p)| p.len()).collect();
+        assert_eq!(sizes, [64, 64]);
+
+        // short packets end the transfer
+        let mut transfer = InTransfer::new(0x80, &data[..100], 64).with_requested_length(255);
+        transfer.start(&hal).unwrap();
+        assert_eq!(transfer.handle_in_complete(&hal, 0), Ok(TransferStatus::Pending));
+        assert_eq!(transfer.handle_in_complete(&hal, 0), Ok(TransferStatus::Complete(100)));
+    }
+
+    #[test]
+    fn test_in_transfer_owned_data() {
+        let hal = MockUsbDriver::new();
+        let data: heapless::Vec<u8, 128> = (0..100).collect();
+        let mut transfer = InTransfer::new(0x81, data, 64);
+        transfer.start(&hal).unwrap();
+
+        assert_eq!(transfer.handle_in_complete(&hal, 1), Ok(TransferStatus::Pending));
+        assert_eq!(transfer.handle_in_complete(&hal, 1), Ok(TransferStatus::Complete(100)));
+        let packets: Vec<Vec<u8>> = hal.take_writes().into_iter().map(|(_, p)| p).collect();
+        assert_eq!(packets.concat(), (0..100).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_in_transfer_ignores_other_endpoints() {
+        let hal = MockUsbDriver::new();
+        let data = [0x55; 10];
+        let mut transfer = InTransfer::new(0x82, &data, 64);
+        transfer.start(&hal).unwrap();
+
+        assert_eq!(transfer.handle_in_complete(&hal, 1), Ok(TransferStatus::Pending));
+        assert_eq!(transfer.handle_in_complete(&hal, 2), Ok(TransferStatus::Complete(10)));
+        assert!(transfer.is_complete());
+        assert_eq!(hal.take_writes().len(), 1);
+    }
+
+    #[test]
+    fn test_out_transfer_short_packet() {
+        let hal = MockUsbDriver::new();
+        let mut buffer = [0; 256];
+        let mut transfer = OutTransfer::new(0x01, &mut buffer, 256, 64);
+        transfer.start(&hal);
+        assert_eq!(*hal.primed.borrow(), [1]);
+
+        assert_eq!(
+            transfer.handle_receive_packet(&hal, 1, &[1; 64]),
+            Ok(TransferStatus::Pending)
+        );
+        assert_eq!(
+            transfer.handle_receive_packet(&hal, 2, &[9; 64]),
+            Ok(TransferStatus::Pending)
+        );
+        assert_eq!(
+            transfer.handle_receive_packet(&hal, 1, &[2; 10]),
+            Ok(TransferStatus::Complete(74))
+        );
+        assert_eq!(*hal.primed.borrow(), [1, 1]);
+        assert_eq!(transfer.data()[..64], [1; 64]);
+        assert_eq!(transfer.data()[64..], [2; 10]);
+    }
+
+    #[test]
+    fn test_out_transfer_requested_length() {
+        let hal = MockUsbDriver::new();
+        let mut buffer = [0; 256];
+        let mut transfer = OutTransfer::new(0x01, &mut buffer, 128, 64);
+        transfer.start(&hal);
+
+        assert_eq!(
+            transfer.handle_receive_packet(&hal, 1, &[1; 64]),
+            Ok(TransferStatus::Pending)
+        );
+        assert_eq!(
+            transfer.handle_receive_packet(&hal, 1, &[2; 64]),
+            Ok(TransferStatus::Complete(128))
+        );
+        // the endpoint is not primed once the transfer completes
+        assert_eq!(*hal.primed.borrow(), [1, 1]);
+    }
+
+    #[test]
+    fn test_out_transfer_overflow() {
+        let hal = MockUsbDriver::new();
+        let mut buffer = [0; 32];
+        let mut transfer = OutTransfer::new(0x01, &mut buffer, 100, 64);
+
+        assert_eq!(
+            transfer.handle_receive_packet(&hal, 1, &[1; 64]),
+            Err(SmolError::Overflow)
+        );
+    }
+}